@@ -0,0 +1,201 @@
+//! # batch requests
+//!
+//! Master serves many keys in one request at `POST /batch/get`,
+//! `POST /batch/put` and `POST /batch/delete`. `?bucket=<name>` selects a
+//! named bucket, defaults to the default bucket.
+//!
+//! request and response bodies are sequences of frames, a frame being a 4 byte
+//! big endian length followed by as many bytes.
+//!
+//! * get and delete requests carry one frame per key
+//! * put requests carry a key frame followed by a value frame per key
+//!
+//! responses hold an item per key in request order, a 2 byte big endian status
+//! code followed by a frame. Gets answer 307 with the location of the value,
+//! or with `?proxy=true` 200 with the value itself. Puts answer 201 and deletes
+//! 204 once volume servers stored or removed the value. Other codes carry the
+//! same meaning as for single key requests. `?ttl=` applies to all values of a
+//! put.
+//!
+//! items are streamed back while the request body is read, keys before a
+//! malformed frame are already processed. Such a frame ends the response with
+//! a 400 item describing it. Bodies are limited to `MAX_BATCH_SIZE` bytes.
+
+use std::io::{self, Error, ErrorKind, Read, Write};
+
+/// prefix of batch endpoints
+pub const BATCH_PREFIX: &str = "/batch/";
+
+/// largest batch request body
+pub const MAX_BATCH_SIZE: u64 = 64 << 20;
+
+/// writes a length prefixed frame
+pub fn write_frame(out: &mut impl Write, data: &[u8]) -> io::Result<()> {
+    if data.len() > u32::max_value() as usize {
+        return Err(Error::new(ErrorKind::InvalidInput, "frame too large"));
+    }
+
+    out.write_all(&(data.len() as u32).to_be_bytes())?;
+    out.write_all(data)
+}
+
+/// reads the length of a frame, returns `None` at end of input. The frame
+/// data is left to be read
+pub fn read_length(input: &mut impl Read) -> io::Result<Option<u64>> {
+    let mut len = [0; 4];
+
+    // end of input is only valid between frames
+    match input.read(&mut len[..1])? {
+        0 => return Ok(None),
+        _ => input.read_exact(&mut len[1..])?,
+    }
+
+    Ok(Some(u64::from(u32::from_be_bytes(len))))
+}
+
+/// reads a frame, returns `None` at end of input
+pub fn read_frame(input: &mut impl Read) -> io::Result<Option<Vec<u8>>> {
+    let len = match read_length(input)? {
+        Some(len) => len,
+        None => return Ok(None),
+    };
+
+    let mut data = Vec::new();
+    input.take(len).read_to_end(&mut data)?;
+
+    if data.len() as u64 != len {
+        return Err(Error::new(ErrorKind::UnexpectedEof, "truncated frame"));
+    }
+
+    Ok(Some(data))
+}
+
+/// Reader failing once its input exceeds a limit
+pub(crate) struct Limited<R> {
+    inner: R,
+    left: u64,
+}
+
+impl<R: Read> Limited<R> {
+    pub fn new(inner: R, limit: u64) -> Limited<R> {
+        Limited { inner, left: limit }
+    }
+}
+
+impl<R: Read> Read for Limited<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // a byte past the limit tells input longer than it from input ending
+        // right at it
+        let max = self.left.saturating_add(1).min(buf.len() as u64) as usize;
+        let read = self.inner.read(&mut buf[..max])?;
+
+        if read as u64 > self.left {
+            return Err(Error::new(ErrorKind::InvalidData, "batch too large"));
+        }
+
+        self.left -= read as u64;
+        Ok(read)
+    }
+}
+
+/// writes a response item
+pub fn write_item(out: &mut impl Write, status: u16, data: &[u8]) -> io::Result<()> {
+    out.write_all(&status.to_be_bytes())?;
+    write_frame(out, data)
+}
+
+/// reads a response item, returns `None` at end of input
+pub fn read_item(input: &mut impl Read) -> io::Result<Option<(u16, Vec<u8>)>> {
+    let mut status = [0; 2];
+
+    match input.read(&mut status[..1])? {
+        0 => return Ok(None),
+        _ => input.read_exact(&mut status[1..])?,
+    }
+
+    match read_frame(input)? {
+        Some(data) => Ok(Some((u16::from_be_bytes(status), data))),
+        None => Err(Error::new(ErrorKind::UnexpectedEof, "missing item body")),
+    }
+}
+
+/// encodes body of a get or delete request
+pub fn encode_keys(keys: &[&str]) -> Vec<u8> {
+    let mut body = Vec::new();
+
+    for key in keys {
+        // writes to a vector do not fail
+        write_frame(&mut body, key.as_bytes()).unwrap();
+    }
+
+    body
+}
+
+/// encodes body of a put request
+pub fn encode_values(values: &[(&str, &[u8])]) -> Vec<u8> {
+    let mut body = Vec::new();
+
+    for (key, value) in values {
+        write_frame(&mut body, key.as_bytes()).unwrap();
+        write_frame(&mut body, value).unwrap();
+    }
+
+    body
+}
+
+/// decodes items of a batch response
+pub fn decode_items(mut body: &[u8]) -> io::Result<Vec<(u16, Vec<u8>)>> {
+    let mut items = Vec::new();
+
+    while let Some(item) = read_item(&mut body)? {
+        items.push(item);
+    }
+
+    Ok(items)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_batch_framing() {
+        let body = encode_values(&[("key1", &b"value1"[..]), ("key2", &b""[..])]);
+        let mut input = &body[..];
+
+        assert_eq!(read_frame(&mut input).unwrap(), Some(b"key1".to_vec()));
+        assert_eq!(read_frame(&mut input).unwrap(), Some(b"value1".to_vec()));
+        assert_eq!(read_frame(&mut input).unwrap(), Some(b"key2".to_vec()));
+        assert_eq!(read_frame(&mut input).unwrap(), Some(vec![]));
+        assert_eq!(read_frame(&mut input).unwrap(), None);
+
+        // truncated frames
+        let body = encode_keys(&["key1"]);
+        assert!(read_frame(&mut &body[..body.len() - 1]).is_err());
+        assert!(read_frame(&mut &body[..2]).is_err());
+
+        let mut out = Vec::new();
+        write_item(&mut out, 307, b"http://volume1/store/key1").unwrap();
+        write_item(&mut out, 404, b"").unwrap();
+
+        assert_eq!(
+            decode_items(&out).unwrap(),
+            vec![(307, b"http://volume1/store/key1".to_vec()), (404, vec![])]
+        );
+        assert!(decode_items(&out[..3]).is_err());
+    }
+
+    #[test]
+    fn test_batch_limit() {
+        let body = encode_keys(&["key1", "key2"]);
+
+        let mut input = Limited::new(&body[..], body.len() as u64);
+        assert_eq!(read_frame(&mut input).unwrap(), Some(b"key1".to_vec()));
+        assert_eq!(read_frame(&mut input).unwrap(), Some(b"key2".to_vec()));
+        assert_eq!(read_frame(&mut input).unwrap(), None);
+
+        let mut input = Limited::new(&body[..], body.len() as u64 - 1);
+        assert_eq!(read_frame(&mut input).unwrap(), Some(b"key1".to_vec()));
+        assert!(read_frame(&mut input).is_err());
+    }
+}
@@ -0,0 +1,445 @@
+use argparse::{ArgumentParser, List, Store, StoreOption, StoreTrue};
+
+use kalavara::client::{Client, Result};
+use kalavara::tls::TlsConfig;
+
+use std::env;
+use std::fs::File;
+use std::io::{copy, stderr, stdout, Read};
+use std::process::exit;
+use std::time::Duration;
+
+/// output of a command, records being `field=value` pairs
+enum Output {
+    /// text message of master
+    Message(String),
+
+    /// a single record, printed a field per line
+    Record(Vec<(String, String)>),
+
+    /// records printed as a table, with a note shown when there are none
+    Table(Vec<Vec<(String, String)>>, &'static str),
+
+    /// nothing left to print, results were streamed to stdout
+    Streamed,
+}
+
+fn main() {
+    let mut master =
+        env::var("KALAVARA_MASTER").unwrap_or_else(|_| "http://localhost:6000".to_string());
+    let mut tls = TlsConfig {
+        ca_file: env::var("KALAVARA_TLS_CA").ok(),
+        ..TlsConfig::default()
+    };
+    let mut json = false;
+    let mut timeout: u64 = 3600;
+    let mut command = String::new();
+    let mut args: Vec<String> = Vec::new();
+
+    {
+        // this block limits scope of borrows by ap.refer() method
+        let mut cli = ArgumentParser::new();
+        cli.set_description(
+            "kalavara cluster administration. Commands: volumes, rebalance, fsck, \
+             snapshot, gc, stats and webhooks, see `kalavara-admin <command> --help`",
+        );
+
+        cli.refer(&mut master).add_option(
+            &["-m", "--master"],
+            Store,
+            "Master server, defaults to $KALAVARA_MASTER or http://localhost:6000",
+        );
+
+        cli.refer(&mut tls.ca_file).add_option(
+            &["--tls-ca"],
+            StoreOption,
+            "PEM CA bundle to verify master, defaults to $KALAVARA_TLS_CA",
+        );
+
+        cli.refer(&mut json)
+            .add_option(&["--json"], StoreTrue, "Print results as json");
+
+        cli.refer(&mut timeout).add_option(
+            &["--timeout"],
+            Store,
+            "Seconds to wait for master, operations moving values take a while",
+        );
+
+        cli.refer(&mut command)
+            .required()
+            .add_argument("command", Store, "Command to run");
+
+        cli.refer(&mut args)
+            .add_argument("arguments", List, "Arguments of the command");
+
+        cli.stop_on_first_argument(true);
+        cli.parse_args_or_exit();
+    }
+
+    let client = Client::new(&master)
+        .tls(tls)
+        .timeout(Duration::from_secs(timeout));
+
+    args.insert(0, format!("kalavara-admin {}", command));
+
+    let result = match command.as_str() {
+        "volumes" => volumes(&client, args),
+        "rebalance" => simple(&client, args, "POST", "rebalance"),
+        "fsck" => simple(&client, args, "GET", "fsck"),
+        "snapshot" => snapshot(&client, args),
+        "gc" => simple(&client, args, "POST", "gc"),
+        "stats" => simple(&client, args, "GET", "stats"),
+        "webhooks" => webhooks(&client, args),
+        _ => {
+            eprintln!(
+                "unknown command {}, should be volumes, rebalance, fsck, snapshot, gc, \
+                 stats or webhooks",
+                command
+            );
+            exit(2);
+        }
+    };
+
+    match result {
+        Ok(output) => {
+            // fsck finding problems fails, for scripts
+            let failed = command == "fsck" && !is_empty(&output);
+
+            match output {
+                Output::Streamed => {}
+                _ if json => println!("{}", to_json(&output)),
+                _ => print_text(&output),
+            }
+
+            if failed {
+                exit(1);
+            }
+        }
+        Err(e) => {
+            eprintln!("kalavara-admin {}: {}", command, e);
+            exit(1);
+        }
+    }
+}
+
+/// parses arguments of a command, exits on errors and on --help
+fn parse(cli: &ArgumentParser, args: Vec<String>) {
+    if let Err(code) = cli.parse(args, &mut stdout(), &mut stderr()) {
+        exit(code);
+    }
+}
+
+/// sends a request to an admin endpoint and reads the response
+fn call(client: &Client, method: &str, path: &str, body: &str) -> Result<String> {
+    let mut text = String::new();
+    client
+        .admin(method, path, body.as_bytes())?
+        .read_to_string(&mut text)?;
+    Ok(text)
+}
+
+/// lists, adds, removes and drains volume servers
+fn volumes(client: &Client, args: Vec<String>) -> Result<Output> {
+    let mut action = String::new();
+    let mut url: Option<String> = None;
+
+    {
+        let mut cli = ArgumentParser::new();
+        cli.set_description(
+            "Lists volume servers, or adds, removes or drains one. Drained volumes \
+             receive no new values and their values are moved to other volumes, \
+             after which they can be removed.",
+        );
+        cli.refer(&mut action).required().add_argument(
+            "action",
+            Store,
+            "list, add, remove or drain",
+        );
+        cli.refer(&mut url)
+            .add_argument("url", StoreOption, "Url of the volume server");
+        parse(&cli, args);
+    }
+
+    let path = match action.as_str() {
+        "list" => {
+            let text = call(client, "GET", "volumes", "")?;
+            return Ok(Output::Table(parse_records(&text), "no volume servers"));
+        }
+        "add" => "add-volume",
+        "remove" => "remove-volume",
+        "drain" => "drain-volume",
+        _ => {
+            eprintln!(
+                "unknown action {}, should be list, add, remove or drain",
+                action
+            );
+            exit(2);
+        }
+    };
+
+    let url = match url {
+        Some(url) => url,
+        None => {
+            eprintln!("url of the volume server required");
+            exit(2);
+        }
+    };
+
+    let text = call(client, "POST", path, &url)?;
+    Ok(match path {
+        "drain-volume" => Output::Record(parse_record(&text)),
+        _ => Output::Message(text),
+    })
+}
+
+/// lists, creates and deletes webhooks and handles their dead letters
+fn webhooks(client: &Client, args: Vec<String>) -> Result<Output> {
+    let mut action = String::new();
+    let mut arg: Option<String> = None;
+
+    {
+        let mut cli = ArgumentParser::new();
+        cli.set_description(
+            "Lists webhooks, creates one from settings such as \
+             name=thumbs&prefix=uploads/&secret=s3cr3t&url=http://pipeline/hook \
+             or deletes one by name. dead-letters lists changes webhooks failed \
+             to take and retry delivers them again, those of a webhook if named.",
+        );
+        cli.refer(&mut action).required().add_argument(
+            "action",
+            Store,
+            "list, create, delete, dead-letters or retry",
+        );
+        cli.refer(&mut arg).add_argument(
+            "argument",
+            StoreOption,
+            "Settings or name of the webhook",
+        );
+        parse(&cli, args);
+    }
+
+    let (method, path) = match action.as_str() {
+        "list" => {
+            let text = call(client, "GET", "webhooks", "")?;
+            return Ok(Output::Table(parse_records(&text), "no webhooks"));
+        }
+        "dead-letters" => {
+            let text = call(client, "GET", "dead-letters", "")?;
+            return Ok(Output::Table(parse_records(&text), "no dead letters"));
+        }
+        "retry" => {
+            let text = call(
+                client,
+                "POST",
+                "retry-dead-letters",
+                &arg.unwrap_or_default(),
+            )?;
+            return Ok(Output::Record(parse_record(&text)));
+        }
+        "create" => ("POST", "create-webhook"),
+        "delete" => ("POST", "delete-webhook"),
+        _ => {
+            eprintln!(
+                "unknown action {}, should be list, create, delete, dead-letters or retry",
+                action
+            );
+            exit(2);
+        }
+    };
+
+    match arg {
+        Some(arg) => Ok(Output::Message(call(client, method, path, &arg)?)),
+        None => {
+            eprintln!("settings or name of the webhook required");
+            exit(2);
+        }
+    }
+}
+
+/// runs a command without arguments
+fn simple(client: &Client, args: Vec<String>, method: &str, path: &str) -> Result<Output> {
+    let description = match path {
+        "rebalance" => {
+            "Moves values from volumes holding more than their share to the least loaded ones."
+        }
+        "fsck" => "Lists values missing on volume servers, fails if there are any.",
+        "gc" => "Removes expired keys and compacts volume servers.",
+        _ => "Counts volume servers, buckets, values and bytes.",
+    };
+
+    {
+        let mut cli = ArgumentParser::new();
+        cli.set_description(description);
+        parse(&cli, args);
+    }
+
+    let text = call(client, method, path, "")?;
+    Ok(match path {
+        "fsck" => Output::Table(parse_records(&text), "no problems found"),
+        _ => Output::Record(parse_record(&text)),
+    })
+}
+
+/// writes a snapshot of the index to a file, or to stdout
+fn snapshot(client: &Client, args: Vec<String>) -> Result<Output> {
+    let mut file = String::new();
+
+    {
+        let mut cli = ArgumentParser::new();
+        cli.set_description(
+            "Writes index entries of master to a file, as pairs of key and value \
+             frames of batch requests.",
+        );
+        cli.refer(&mut file)
+            .required()
+            .add_argument("file", Store, "File to write, - for stdout");
+        parse(&cli, args);
+    }
+
+    let mut value = client.admin("GET", "snapshot", b"")?;
+
+    if file == "-" {
+        copy(&mut value, &mut stdout().lock())?;
+        return Ok(Output::Streamed);
+    }
+
+    let written = copy(&mut value, &mut File::create(&file)?)?;
+    Ok(Output::Record(vec![
+        ("file".to_owned(), file),
+        ("bytes".to_owned(), written.to_string()),
+    ]))
+}
+
+/// parses a `field=value&field=value` line
+fn parse_record(line: &str) -> Vec<(String, String)> {
+    line.trim()
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let mut parts = pair.splitn(2, '=');
+            let field = parts.next().unwrap_or("").to_owned();
+            (field, parts.next().unwrap_or("").to_owned())
+        })
+        .collect()
+}
+
+/// parses a record per line
+fn parse_records(text: &str) -> Vec<Vec<(String, String)>> {
+    text.lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(parse_record)
+        .collect()
+}
+
+fn is_empty(output: &Output) -> bool {
+    match output {
+        Output::Table(records, _) => records.is_empty(),
+        _ => false,
+    }
+}
+
+fn print_text(output: &Output) {
+    match output {
+        Output::Message(text) => println!("{}", text.trim()),
+        Output::Record(record) => {
+            let width = record
+                .iter()
+                .map(|(field, _)| field.len())
+                .max()
+                .unwrap_or(0);
+
+            for (field, value) in record {
+                println!("{:width$}  {}", field, value, width = width);
+            }
+        }
+        Output::Table(records, note) if records.is_empty() => println!("{}", note),
+        Output::Streamed => {}
+        Output::Table(records, _) => {
+            // columns are those of the first record
+            let fields: Vec<&str> = records[0].iter().map(|(field, _)| field.as_str()).collect();
+            let value = |record: &[(String, String)], field: &str| -> String {
+                record
+                    .iter()
+                    .find(|(name, _)| name == field)
+                    .map(|(_, value)| value.clone())
+                    .unwrap_or_default()
+            };
+
+            let widths: Vec<usize> = fields
+                .iter()
+                .map(|field| {
+                    records
+                        .iter()
+                        .map(|record| value(record, field).len())
+                        .max()
+                        .unwrap_or(0)
+                        .max(field.len())
+                })
+                .collect();
+
+            let header: Vec<String> = fields
+                .iter()
+                .zip(widths.iter())
+                .map(|(field, width)| format!("{:width$}", field.to_uppercase(), width = width))
+                .collect();
+            println!("{}", header.join("  ").trim_end());
+
+            for record in records {
+                let row: Vec<String> = fields
+                    .iter()
+                    .zip(widths.iter())
+                    .map(|(field, width)| format!("{:width$}", value(record, field), width = width))
+                    .collect();
+                println!("{}", row.join("  ").trim_end());
+            }
+        }
+    }
+}
+
+fn to_json(output: &Output) -> String {
+    match output {
+        Output::Message(text) => format!("{{\"message\":{}}}", json_string(text.trim())),
+        Output::Record(record) => json_object(record),
+        Output::Table(records, _) => {
+            let objects: Vec<String> = records.iter().map(|record| json_object(record)).collect();
+            format!("[{}]", objects.join(","))
+        }
+        Output::Streamed => String::new(),
+    }
+}
+
+/// json object of a record, numbers are written as such
+fn json_object(record: &[(String, String)]) -> String {
+    let members: Vec<String> = record
+        .iter()
+        .map(|(field, value)| {
+            let value = match value.parse::<u64>() {
+                Ok(number) => number.to_string(),
+                Err(_) => json_string(value),
+            };
+            format!("{}:{}", json_string(field), value)
+        })
+        .collect();
+
+    format!("{{{}}}", members.join(","))
+}
+
+fn json_string(text: &str) -> String {
+    let mut quoted = String::with_capacity(text.len() + 2);
+    quoted.push('"');
+
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+
+    quoted.push('"');
+    quoted
+}
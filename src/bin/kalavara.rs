@@ -0,0 +1,394 @@
+use argparse::{ArgumentParser, List, Store, StoreOption, StoreTrue};
+
+use kalavara::client::{Client, Result};
+use kalavara::tls::TlsConfig;
+
+use std::env;
+use std::fs::{create_dir_all, read_dir, File};
+use std::io::{copy, stderr, stdin, stdout};
+use std::path::Path;
+use std::process::exit;
+use std::time::Duration;
+
+/// prefix marking keys among paths given to cp
+const KEY_PREFIX: &str = "kv:";
+
+fn main() {
+    let mut master =
+        env::var("KALAVARA_MASTER").unwrap_or_else(|_| "http://localhost:6000".to_string());
+    let mut bucket = env::var("KALAVARA_BUCKET").ok();
+    let mut token = env::var("KALAVARA_TOKEN").ok();
+    let mut tls = TlsConfig {
+        ca_file: env::var("KALAVARA_TLS_CA").ok(),
+        ..TlsConfig::default()
+    };
+    let mut retries: u32 = 2;
+    let mut timeout: u64 = 30;
+    let mut command = String::new();
+    let mut args: Vec<String> = Vec::new();
+
+    {
+        // this block limits scope of borrows by ap.refer() method
+        let mut cli = ArgumentParser::new();
+        cli.set_description(
+            "kalavara client. Commands: get, put, rm, ls, stat and cp, \
+             see `kalavara <command> --help`",
+        );
+
+        cli.refer(&mut master).add_option(
+            &["-m", "--master"],
+            Store,
+            "Master server, defaults to $KALAVARA_MASTER or http://localhost:6000",
+        );
+
+        cli.refer(&mut bucket).add_option(
+            &["-b", "--bucket"],
+            StoreOption,
+            "Bucket, defaults to $KALAVARA_BUCKET or the default bucket",
+        );
+
+        cli.refer(&mut token).add_option(
+            &["--token"],
+            StoreOption,
+            "Token of a private bucket, defaults to $KALAVARA_TOKEN",
+        );
+
+        cli.refer(&mut tls.ca_file).add_option(
+            &["--tls-ca"],
+            StoreOption,
+            "PEM CA bundle to verify servers, defaults to $KALAVARA_TLS_CA",
+        );
+
+        cli.refer(&mut retries).add_option(
+            &["--retries"],
+            Store,
+            "Retries of requests failing for a while",
+        );
+
+        cli.refer(&mut timeout)
+            .add_option(&["--timeout"], Store, "Seconds to wait for servers");
+
+        cli.refer(&mut command)
+            .required()
+            .add_argument("command", Store, "Command to run");
+
+        cli.refer(&mut args)
+            .add_argument("arguments", List, "Arguments of the command");
+
+        cli.stop_on_first_argument(true);
+        cli.parse_args_or_exit();
+    }
+
+    let mut client = Client::new(&master)
+        .tls(tls)
+        .retries(retries)
+        .timeout(Duration::from_secs(timeout));
+
+    if let Some(bucket) = bucket {
+        client = client.bucket(&bucket);
+    }
+    if let Some(token) = token {
+        client = client.token(&token);
+    }
+
+    args.insert(0, format!("kalavara {}", command));
+
+    let result = match command.as_str() {
+        "get" => get(&client, args),
+        "put" => put(&client, args),
+        "rm" => rm(&client, args),
+        "ls" => ls(&client, args),
+        "stat" => stat(&client, args),
+        "cp" => cp(&client, args),
+        _ => {
+            eprintln!(
+                "unknown command {}, should be get, put, rm, ls, stat or cp",
+                command
+            );
+            exit(2);
+        }
+    };
+
+    if let Err(e) = result {
+        eprintln!("kalavara {}: {}", command, e);
+        exit(1);
+    }
+}
+
+/// parses arguments of a command, exits on errors and on --help
+fn parse(cli: &ArgumentParser, args: Vec<String>) {
+    if let Err(code) = cli.parse(args, &mut stdout(), &mut stderr()) {
+        exit(code);
+    }
+}
+
+/// writes value of a key to a file, or to stdout
+fn get(client: &Client, args: Vec<String>) -> Result<()> {
+    let mut key = String::new();
+    let mut file: Option<String> = None;
+
+    {
+        let mut cli = ArgumentParser::new();
+        cli.set_description("Writes value of a key to a file or stdout.");
+        cli.refer(&mut key)
+            .required()
+            .add_argument("key", Store, "Key");
+        cli.refer(&mut file).add_argument(
+            "file",
+            StoreOption,
+            "File to write, stdout if not given",
+        );
+        parse(&cli, args);
+    }
+
+    match file {
+        Some(file) => download(client, &key, Path::new(&file)),
+        None => {
+            let mut value = client.get(&key)?;
+            copy(&mut value, &mut stdout().lock())?;
+            Ok(())
+        }
+    }
+}
+
+/// stores a file, or stdin, as value of a key
+fn put(client: &Client, args: Vec<String>) -> Result<()> {
+    let mut key = String::new();
+    let mut file: Option<String> = None;
+
+    {
+        let mut cli = ArgumentParser::new();
+        cli.set_description("Stores a file or stdin as value of a key.");
+        cli.refer(&mut key)
+            .required()
+            .add_argument("key", Store, "Key");
+        cli.refer(&mut file).add_argument(
+            "file",
+            StoreOption,
+            "File to store, stdin if not given or -",
+        );
+        parse(&cli, args);
+    }
+
+    match file.as_deref() {
+        Some(file) if file != "-" => client.put(&key, File::open(file)?),
+        _ => {
+            // requests are sent again on redirects, so stdin is kept in a
+            // temporary file to be read more than once
+            let mut spool = tempfile::tempfile()?;
+            copy(&mut stdin().lock(), &mut spool)?;
+            client.put(&key, spool)
+        }
+    }
+}
+
+/// deletes keys
+fn rm(client: &Client, args: Vec<String>) -> Result<()> {
+    let mut keys: Vec<String> = Vec::new();
+
+    {
+        let mut cli = ArgumentParser::new();
+        cli.set_description("Deletes keys and their values.");
+        cli.refer(&mut keys)
+            .required()
+            .add_argument("keys", List, "Keys to delete");
+        parse(&cli, args);
+    }
+
+    for key in keys {
+        client.delete(&key)?;
+    }
+
+    Ok(())
+}
+
+/// lists keys starting with a prefix
+fn ls(client: &Client, args: Vec<String>) -> Result<()> {
+    let mut prefix = String::new();
+
+    {
+        let mut cli = ArgumentParser::new();
+        cli.set_description("Lists keys starting with a prefix.");
+        cli.refer(&mut prefix)
+            .add_argument("prefix", Store, "Prefix, all keys if not given");
+        parse(&cli, args);
+    }
+
+    for key in client.list(&prefix)? {
+        println!("{}", key);
+    }
+
+    Ok(())
+}
+
+/// prints size and headers of a value
+fn stat(client: &Client, args: Vec<String>) -> Result<()> {
+    let mut key = String::new();
+
+    {
+        let mut cli = ArgumentParser::new();
+        cli.set_description("Prints size and headers of a value.");
+        cli.refer(&mut key)
+            .required()
+            .add_argument("key", Store, "Key");
+        parse(&cli, args);
+    }
+
+    let meta = client.head(&key)?;
+
+    match meta.size {
+        Some(size) => println!("size: {}", size),
+        None => println!("size: unknown"),
+    }
+    for (field, value) in meta.headers.iter() {
+        println!("{}: {}", field, value);
+    }
+
+    Ok(())
+}
+
+/// copies between keys and local files
+fn cp(client: &Client, args: Vec<String>) -> Result<()> {
+    let mut recursive = false;
+    let mut source = String::new();
+    let mut target = String::new();
+
+    {
+        let mut cli = ArgumentParser::new();
+        cli.set_description(
+            "Copies between local files and keys, written as kv:<key>. \
+             With -r directories are copied to keys under a prefix and back.",
+        );
+        cli.refer(&mut recursive).add_option(
+            &["-r", "--recursive"],
+            StoreTrue,
+            "Copy directories, or all keys under a prefix",
+        );
+        cli.refer(&mut source).required().add_argument(
+            "source",
+            Store,
+            "File, directory or kv:<key>",
+        );
+        cli.refer(&mut target).required().add_argument(
+            "target",
+            Store,
+            "File, directory or kv:<key>",
+        );
+        parse(&cli, args);
+    }
+
+    match (as_key(&source), as_key(&target), recursive) {
+        (None, Some(key), false) => upload(client, Path::new(&source), &file_key(&source, key)),
+        (None, Some(prefix), true) => upload_dir(client, Path::new(&source), &as_prefix(prefix)),
+        (Some(key), None, false) => {
+            let target = Path::new(&target);
+            if target.is_dir() {
+                let name = key.rsplit('/').next().unwrap_or(key);
+                download(client, key, &target.join(name))
+            } else {
+                download(client, key, target)
+            }
+        }
+        (Some(prefix), None, true) => download_dir(client, &as_prefix(prefix), Path::new(&target)),
+        (Some(from), Some(to), false) => copy_key(client, from, &file_key(from, to)),
+        (Some(from), Some(to), true) => {
+            let (from, to) = (as_prefix(from), as_prefix(to));
+
+            for key in client.list(&from)? {
+                copy_key(client, &key, &format!("{}{}", to, &key[from.len()..]))?;
+            }
+            Ok(())
+        }
+        (None, None, _) => {
+            eprintln!("source or target should be a key, written kv:<key>");
+            exit(2);
+        }
+    }
+}
+
+/// key written as kv:<key>, `None` for local paths
+fn as_key(arg: &str) -> Option<&str> {
+    arg.strip_prefix(KEY_PREFIX)
+}
+
+/// prefix of keys under a directory, ending with a slash unless empty
+fn as_prefix(prefix: &str) -> String {
+    if prefix.is_empty() || prefix.ends_with('/') {
+        prefix.to_owned()
+    } else {
+        format!("{}/", prefix)
+    }
+}
+
+/// key a file is copied to, keys ending with a slash get the file name
+fn file_key(source: &str, key: &str) -> String {
+    if key.is_empty() || key.ends_with('/') {
+        let name = source.rsplit('/').next().unwrap_or(source);
+        format!("{}{}", key, name)
+    } else {
+        key.to_owned()
+    }
+}
+
+fn upload(client: &Client, path: &Path, key: &str) -> Result<()> {
+    client.put(key, File::open(path)?)?;
+    println!("{} -> {}{}", path.display(), KEY_PREFIX, key);
+    Ok(())
+}
+
+/// uploads files under `dir` to keys under `prefix`
+fn upload_dir(client: &Client, dir: &Path, prefix: &str) -> Result<()> {
+    for entry in read_dir(dir)? {
+        let path = entry?.path();
+        let name = path.file_name().unwrap().to_string_lossy().into_owned();
+
+        if path.is_dir() {
+            upload_dir(client, &path, &format!("{}{}/", prefix, name))?;
+        } else {
+            upload(client, &path, &format!("{}{}", prefix, name))?;
+        }
+    }
+
+    Ok(())
+}
+
+fn download(client: &Client, key: &str, path: &Path) -> Result<()> {
+    let mut value = client.get(key)?;
+    copy(&mut value, &mut File::create(path)?)?;
+    println!("{}{} -> {}", KEY_PREFIX, key, path.display());
+    Ok(())
+}
+
+/// downloads keys under `prefix` to files under `dir`
+fn download_dir(client: &Client, prefix: &str, dir: &Path) -> Result<()> {
+    for key in client.list(prefix)? {
+        let relative = &key[prefix.len()..];
+
+        // keys are not written outside of dir
+        if relative
+            .split('/')
+            .any(|part| part.is_empty() || part == "." || part == "..")
+        {
+            eprintln!("skipping {}{}, not a file name", KEY_PREFIX, key);
+            continue;
+        }
+
+        let path = dir.join(relative);
+        if let Some(parent) = path.parent() {
+            create_dir_all(parent)?;
+        }
+        download(client, &key, &path)?;
+    }
+
+    Ok(())
+}
+
+/// copies value of a key to another key
+fn copy_key(client: &Client, from: &str, to: &str) -> Result<()> {
+    let mut spool = tempfile::tempfile()?;
+    copy(&mut client.get(from)?, &mut spool)?;
+    client.put(to, spool)?;
+
+    println!("{}{} -> {}{}", KEY_PREFIX, from, KEY_PREFIX, to);
+    Ok(())
+}
@@ -1,11 +1,26 @@
-use argparse::{ArgumentParser, List, Store};
-use kalavara::master;
+use argparse::{ArgumentParser, List, Store, StoreOption};
+use kalavara::cluster::ClusterConfig;
+use kalavara::index::Backend;
+use kalavara::limit::LimitConfig;
+use kalavara::master::MasterBuilder;
+use kalavara::s3::{S3Config, DEFAULT_REGION};
+use kalavara::tls::TlsConfig;
+
+use std::process::exit;
 
 fn main() {
     let mut port: u16 = 6000;
     let mut data_dir = "/tmp/kalavaradb".to_string();
     let mut volumes: Vec<String> = Vec::new();
     let mut threads = num_cpus::get() as u16;
+    let mut cluster = ClusterConfig::default();
+    let mut tls = TlsConfig::default();
+    let mut limits = LimitConfig::default();
+    let mut index = "rocksdb".to_string();
+    let mut s3_port: Option<u16> = None;
+    let mut s3_access_key: Option<String> = None;
+    let mut s3_secret_key: Option<String> = None;
+    let mut s3_region = DEFAULT_REGION.to_string();
 
     {
         // this block limits scope of borrows by ap.refer() method
@@ -17,18 +32,164 @@ fn main() {
         cli.refer(&mut data_dir)
             .add_option(&["-d", "--data_dir"], Store, "Database directory");
 
+        cli.refer(&mut index).add_option(
+            &["--index"],
+            Store,
+            "Index backend: rocksdb (default), sled or memory",
+        );
+
         cli.refer(&mut threads).add_option(
             &["-t", "--threads"],
             Store,
-            "Number of threads, defaults to number of cpu cores",
+            "Requests handled at once, defaults to number of cpu cores",
         );
 
         cli.refer(&mut volumes)
             .add_option(&["-v", "--volumes"], List, "Volumes");
 
+        cli.refer(&mut tls.cert).add_option(
+            &["--tls-cert"],
+            StoreOption,
+            "PEM certificate, serves https if given",
+        );
+
+        cli.refer(&mut tls.key)
+            .add_option(&["--tls-key"], StoreOption, "PEM private key");
+
+        cli.refer(&mut tls.ca_file).add_option(
+            &["--tls-ca"],
+            StoreOption,
+            "PEM CA bundle to verify volume servers",
+        );
+
+        cli.refer(&mut cluster.id)
+            .add_option(&["--cluster-id"], StoreOption, "Cluster id");
+
+        cli.refer(&mut cluster.join_token).add_option(
+            &["--join-token"],
+            StoreOption,
+            "Token volumes need to register",
+        );
+
+        cli.refer(&mut limits.rate).add_option(
+            &["--rate-limit"],
+            StoreOption,
+            "Requests per second accepted from all clients",
+        );
+
+        cli.refer(&mut limits.burst).add_option(
+            &["--burst"],
+            StoreOption,
+            "Requests accepted at once from all clients",
+        );
+
+        cli.refer(&mut limits.client_rate).add_option(
+            &["--client-rate-limit"],
+            StoreOption,
+            "Requests per second accepted from a client address",
+        );
+
+        cli.refer(&mut limits.client_burst).add_option(
+            &["--client-burst"],
+            StoreOption,
+            "Requests accepted at once from a client address",
+        );
+
+        cli.refer(&mut limits.client_concurrency).add_option(
+            &["--client-concurrency"],
+            StoreOption,
+            "Requests of a client address queued or in progress at once",
+        );
+
+        cli.refer(&mut limits.queue).add_option(
+            &["--queue-size"],
+            Store,
+            "Requests waiting for a worker before new ones are rejected",
+        );
+
+        cli.refer(&mut limits.connections).add_option(
+            &["--max-connections"],
+            Store,
+            "Connections open at once before new ones wait to be accepted",
+        );
+
+        cli.refer(&mut s3_port).add_option(
+            &["--s3-port"],
+            StoreOption,
+            "Port of the s3 gateway, disabled unless given",
+        );
+
+        cli.refer(&mut s3_access_key).add_option(
+            &["--s3-access-key"],
+            StoreOption,
+            "Access key of s3 clients",
+        );
+
+        cli.refer(&mut s3_secret_key).add_option(
+            &["--s3-secret-key"],
+            StoreOption,
+            "Secret key of s3 clients",
+        );
+
+        cli.refer(&mut s3_region).add_option(
+            &["--s3-region"],
+            Store,
+            "Region s3 requests are signed for, us-east-1 by default",
+        );
+
         cli.parse_args_or_exit();
     }
 
+    if tls.cert.is_some() != tls.key.is_some() {
+        eprintln!("both --tls-cert and --tls-key are required to serve https");
+        exit(2);
+    }
+
+    let rates = [
+        limits.rate,
+        limits.burst,
+        limits.client_rate,
+        limits.client_burst,
+    ];
+    if rates
+        .iter()
+        .any(|rate| rate.map_or(false, |rate| rate <= 0.0))
+    {
+        eprintln!("rate limits should be positive");
+        exit(2);
+    }
+
+    if limits.client_concurrency == Some(0) {
+        eprintln!("--client-concurrency should be positive");
+        exit(2);
+    }
+
+    if limits.connections == 0 {
+        eprintln!("--max-connections should be positive");
+        exit(2);
+    }
+
+    let s3 = match (s3_port, s3_access_key, s3_secret_key) {
+        (Some(port), Some(access_key), Some(secret_key)) => {
+            let mut s3 = S3Config::new(port, &access_key, &secret_key);
+            s3.region = s3_region;
+            Some(s3)
+        }
+        (None, None, None) => None,
+        _ => {
+            eprintln!("--s3-port, --s3-access-key and --s3-secret-key go together");
+            exit(2);
+        }
+    };
+
+    let index = match Backend::parse(&index) {
+        Ok(index) => index,
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(2);
+        }
+    };
+
     // remote trailing slashes from volume server urls
     for volume in volumes.iter_mut() {
         if volume.ends_with('/') {
@@ -41,5 +202,26 @@ fn main() {
         port, data_dir, threads, volumes
     );
 
-    master::start(port, &data_dir, threads, volumes);
+    let mut builder = MasterBuilder::new(&data_dir)
+        .port(port)
+        .threads(threads)
+        .volumes(volumes)
+        .cluster(cluster)
+        .tls(tls)
+        .limits(limits)
+        .index(index);
+
+    if let Some(s3) = s3 {
+        builder = builder.s3(s3);
+    }
+
+    let server = builder.start();
+
+    match server {
+        Ok(server) => server.join(),
+        Err(e) => {
+            eprintln!("failed to start master: {}", e);
+            exit(1);
+        }
+    }
 }
@@ -1,6 +1,15 @@
-use argparse::{ArgumentParser, Store, StoreOption};
+use argparse::{ArgumentParser, Store, StoreOption, StoreTrue};
 
-use kalavara::volume::start;
+use kalavara::cluster::ClusterConfig;
+use kalavara::compress;
+use kalavara::encryption::KeyRing;
+use kalavara::limit::LimitConfig;
+use kalavara::packed::PackedConfig;
+use kalavara::scrub::ScrubConfig;
+use kalavara::store::Engine;
+use kalavara::tls::TlsConfig;
+use kalavara::volume::VolumeBuilder;
+use std::path::Path;
 use std::process::exit;
 
 fn main() {
@@ -9,6 +18,15 @@ fn main() {
     let mut threads = num_cpus::get() as u16;
     let mut master: Option<String> = None;
     let mut base: Option<String> = None;
+    let mut cluster = ClusterConfig::default();
+    let mut tls = TlsConfig::default();
+    let mut limits = LimitConfig::default();
+    let mut scrub = ScrubConfig::default();
+    let mut compression = "none".to_string();
+    let mut key_file: Option<String> = None;
+    let mut dedup = false;
+    let mut engine = "files".to_string();
+    let mut packed = PackedConfig::default();
 
     {
         // this block limits scope of borrows by ap.refer() method
@@ -22,7 +40,7 @@ fn main() {
         cli.refer(&mut threads).add_option(
             &["-t", "--threads"],
             Store,
-            "Number of threads, defaults to number of cpu cores",
+            "Requests handled at once, defaults to number of cpu cores",
         );
 
         cli.refer(&mut master).add_option(
@@ -37,9 +55,197 @@ fn main() {
             "Base url of server to register with master",
         );
 
+        cli.refer(&mut tls.cert).add_option(
+            &["--tls-cert"],
+            StoreOption,
+            "PEM certificate, serves https if given",
+        );
+
+        cli.refer(&mut tls.key)
+            .add_option(&["--tls-key"], StoreOption, "PEM private key");
+
+        cli.refer(&mut tls.ca_file).add_option(
+            &["--tls-ca"],
+            StoreOption,
+            "PEM CA bundle to verify master server",
+        );
+
+        cli.refer(&mut cluster.id)
+            .add_option(&["--cluster-id"], StoreOption, "Cluster id");
+
+        cli.refer(&mut cluster.join_token).add_option(
+            &["--join-token"],
+            StoreOption,
+            "Token to register with master",
+        );
+
+        cli.refer(&mut limits.rate).add_option(
+            &["--rate-limit"],
+            StoreOption,
+            "Requests per second accepted from all clients",
+        );
+
+        cli.refer(&mut limits.burst).add_option(
+            &["--burst"],
+            StoreOption,
+            "Requests accepted at once from all clients",
+        );
+
+        cli.refer(&mut limits.client_rate).add_option(
+            &["--client-rate-limit"],
+            StoreOption,
+            "Requests per second accepted from a client address",
+        );
+
+        cli.refer(&mut limits.client_burst).add_option(
+            &["--client-burst"],
+            StoreOption,
+            "Requests accepted at once from a client address",
+        );
+
+        cli.refer(&mut limits.client_concurrency).add_option(
+            &["--client-concurrency"],
+            StoreOption,
+            "Requests of a client address queued or in progress at once",
+        );
+
+        cli.refer(&mut limits.queue).add_option(
+            &["--queue-size"],
+            Store,
+            "Requests waiting for a worker before new ones are rejected",
+        );
+
+        cli.refer(&mut limits.connections).add_option(
+            &["--max-connections"],
+            Store,
+            "Connections open at once before new ones wait to be accepted",
+        );
+
+        cli.refer(&mut scrub.rate).add_option(
+            &["--scrub-rate"],
+            StoreOption,
+            "Bytes per second read to check stored values, off if not given",
+        );
+
+        cli.refer(&mut scrub.interval).add_option(
+            &["--scrub-interval"],
+            Store,
+            "Seconds between checks of all stored values, defaults to a day",
+        );
+
+        cli.refer(&mut compression).add_option(
+            &["--compression"],
+            Store,
+            "Compression of stored values: zstd, lz4 or none (default)",
+        );
+
+        cli.refer(&mut key_file).add_option(
+            &["--encryption-key-file"],
+            StoreOption,
+            "File of master keys to encrypt stored values with, the first one wraps new keys",
+        );
+
+        cli.refer(&mut dedup).add_option(
+            &["--dedup"],
+            StoreTrue,
+            "Store identical values once, data directory has to support hard links",
+        );
+
+        cli.refer(&mut engine).add_option(
+            &["--engine"],
+            Store,
+            "Storage engine: files (default), a file per value, packed into segment files, \
+             or memory",
+        );
+
+        cli.refer(&mut packed.segment_size).add_option(
+            &["--segment-size"],
+            Store,
+            "Bytes after which a segment of packed values is sealed, defaults to 1 GiB",
+        );
+
+        cli.refer(&mut packed.compact_interval).add_option(
+            &["--compact-interval"],
+            Store,
+            "Seconds between compactions of packed values, defaults to 10 minutes",
+        );
+
         cli.parse_args_or_exit();
     }
 
+    if tls.cert.is_some() != tls.key.is_some() {
+        eprintln!("both --tls-cert and --tls-key are required to serve https");
+        exit(2);
+    }
+
+    let rates = [
+        limits.rate,
+        limits.burst,
+        limits.client_rate,
+        limits.client_burst,
+    ];
+    if rates
+        .iter()
+        .any(|rate| rate.map_or(false, |rate| rate <= 0.0))
+    {
+        eprintln!("rate limits should be positive");
+        exit(2);
+    }
+
+    if limits.client_concurrency == Some(0) {
+        eprintln!("--client-concurrency should be positive");
+        exit(2);
+    }
+
+    if limits.connections == 0 {
+        eprintln!("--max-connections should be positive");
+        exit(2);
+    }
+
+    if scrub.rate == Some(0) || scrub.interval == 0 {
+        eprintln!("scrub rate and interval should be positive");
+        exit(2);
+    }
+
+    let compression = match compress::parse_setting(&compression) {
+        Ok(compression) => compression,
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(2);
+        }
+    };
+
+    let keys = match key_file.map(|path| KeyRing::load(Path::new(&path))) {
+        Some(Ok(keys)) => Some(keys),
+        Some(Err(e)) => {
+            eprintln!("failed to load encryption keys: {}", e);
+            exit(2);
+        }
+        None => None,
+    };
+
+    if engine != "files" && (dedup || scrub.rate.is_some()) {
+        eprintln!("--dedup and --scrub-rate are only supported by the files engine");
+        exit(2);
+    }
+
+    let engine = match engine.as_str() {
+        "files" => Engine::Files { dedup },
+        "packed" => Engine::Packed(packed),
+        "memory" => Engine::Memory,
+        _ => {
+            eprintln!("engine should be files, packed or memory");
+            exit(2);
+        }
+    };
+
+    if let Engine::Packed(ref packed) = engine {
+        if packed.segment_size == 0 || packed.compact_interval == 0 {
+            eprintln!("segment size and compact interval should be positive");
+            exit(2);
+        }
+    }
+
     if master.is_some() && base.is_none() {
         eprintln!("base url is required to register with master");
         exit(2);
@@ -55,5 +261,28 @@ fn main() {
         port, data_dir, threads, master
     );
 
-    start(port, data_dir, threads, master, base);
+    let mut builder = VolumeBuilder::new(&data_dir)
+        .port(port)
+        .threads(threads)
+        .cluster(cluster)
+        .tls(tls)
+        .limits(limits)
+        .scrub(scrub)
+        .compression(compression)
+        .engine(engine);
+
+    if let (Some(master), Some(base)) = (master, base) {
+        builder = builder.master(&master).base(&base);
+    }
+    if let Some(keys) = keys {
+        builder = builder.keys(keys);
+    }
+
+    match builder.start() {
+        Ok(server) => server.join(),
+        Err(e) => {
+            eprintln!("failed to start volume: {}", e);
+            exit(1);
+        }
+    }
 }
@@ -0,0 +1,282 @@
+//! # buckets
+//!
+//! Buckets are named namespaces with their own settings, stored in master
+//! database. Keys of a bucket are served at `/bucket/<name>/<key>`, keys under
+//! `/store/` belong to the default bucket.
+//!
+//! create (or update) a bucket with
+//!
+//! ```sh
+//! curl -XPOST -d "name=logs&replicas=2&ttl=86400&versioning=true&quota=1073741824&access=read-only" \
+//!     http://localhost:6000/admin/create-bucket
+//! ```
+//!
+//! * `replicas` - number of volume servers each value is stored on, defaults to 1
+//! * `ttl` - seconds after which keys expire, can be overridden per key with `?ttl=`
+//! * `versioning` - keeps previous values on update, fetched with `?version=`
+//! * `quota` - maximum total size of values in bytes
+//! * `quota_objects` - maximum number of values
+//! * `erasure` - `<k>+<m>` stores values as `k` data and `m` parity shards
+//!   instead of replicas, see [erasure](../erasure/index.html)
+//! * `compression` - `zstd`, `lz4` or `none`, overrides the compression of
+//!   volume servers, see [compress](../compress/index.html)
+//! * `access` - `public` (default), `read-only` or `private:<token>`. Requests to
+//!   private buckets need the token in `X-Bucket-Token` header
+//!
+//! empty buckets are deleted with
+//!
+//! ```sh
+//! curl -XPOST -d logs http://localhost:6000/admin/delete-bucket
+//! ```
+
+use crate::cluster::constant_time_eq;
+use crate::compress::{self, Encoding};
+use crate::erasure;
+use crate::{encode_param, parse_params};
+
+/// header carrying the token of private buckets
+pub const BUCKET_TOKEN_HEADER: &str = "X-Bucket-Token";
+
+/// Access policy of a bucket
+#[derive(Clone, Debug, PartialEq)]
+pub enum Access {
+    /// anyone can read and write
+    Public,
+
+    /// writes are rejected
+    ReadOnly,
+
+    /// requests need the token
+    Private(String),
+}
+
+/// Bucket settings
+#[derive(Clone, Debug, PartialEq)]
+pub struct Bucket {
+    /// name of the bucket, empty for default bucket
+    pub name: String,
+
+    /// number of volume servers a value is stored on
+    pub replicas: usize,
+
+    /// default time to live of keys in seconds
+    pub ttl: Option<u64>,
+
+    /// keep previous values on update
+    pub versioning: bool,
+
+    /// maximum total size of values in bytes
+    pub quota: Option<u64>,
+
+    /// maximum number of values
+    pub quota_objects: Option<u64>,
+
+    /// data and parity shards of erasure coded values
+    pub erasure: Option<(usize, usize)>,
+
+    /// compression of values, volume servers decide if not set
+    pub compression: Option<Option<Encoding>>,
+
+    /// access policy
+    pub access: Access,
+}
+
+impl Default for Bucket {
+    fn default() -> Self {
+        Bucket {
+            name: String::new(),
+            replicas: 1,
+            ttl: None,
+            versioning: false,
+            quota: None,
+            quota_objects: None,
+            erasure: None,
+            compression: None,
+            access: Access::Public,
+        }
+    }
+}
+
+impl Bucket {
+    /// Parses bucket settings from `name=value&..` pairs
+    pub fn parse(settings: &str) -> Result<Bucket, String> {
+        let mut bucket = Bucket::default();
+
+        for (field, value) in parse_params(settings.trim()) {
+            match field.as_str() {
+                "name" => bucket.name = value,
+                "replicas" => {
+                    bucket.replicas = parse_number(&field, &value)? as usize;
+                    if bucket.replicas == 0 {
+                        return Err("replicas should be at least 1".to_owned());
+                    }
+                }
+                "ttl" => bucket.ttl = Some(parse_number(&field, &value)?),
+                "versioning" => {
+                    bucket.versioning = match value.as_str() {
+                        "true" => true,
+                        "false" => false,
+                        _ => return Err(format!("invalid versioning {}", value)),
+                    }
+                }
+                "quota" => bucket.quota = Some(parse_number(&field, &value)?),
+                "quota_objects" => bucket.quota_objects = Some(parse_number(&field, &value)?),
+                "erasure" => bucket.erasure = Some(erasure::parse_setting(&value)?),
+                "compression" => bucket.compression = Some(compress::parse_setting(&value)?),
+                "access" => {
+                    bucket.access = match value.as_str() {
+                        "public" => Access::Public,
+                        "read-only" => Access::ReadOnly,
+                        _ if value.starts_with("private:") && value.len() > 8 => {
+                            Access::Private(value[8..].to_owned())
+                        }
+                        _ => return Err(format!("invalid access policy {}", value)),
+                    }
+                }
+                _ => return Err(format!("unknown setting {}", field)),
+            }
+        }
+
+        if !valid_name(&bucket.name) {
+            return Err(format!("invalid bucket name {}", bucket.name));
+        }
+
+        Ok(bucket)
+    }
+
+    /// Encodes settings in the format accepted by `parse`
+    pub fn encode(&self) -> String {
+        let mut settings = format!(
+            "name={}&replicas={}",
+            encode_param(&self.name),
+            self.replicas
+        );
+
+        if let Some(ttl) = self.ttl {
+            settings.push_str(&format!("&ttl={}", ttl));
+        }
+
+        if self.versioning {
+            settings.push_str("&versioning=true");
+        }
+
+        if let Some(quota) = self.quota {
+            settings.push_str(&format!("&quota={}", quota));
+        }
+
+        if let Some(objects) = self.quota_objects {
+            settings.push_str(&format!("&quota_objects={}", objects));
+        }
+
+        if let Some((data, parity)) = self.erasure {
+            settings.push_str(&format!("&erasure={}+{}", data, parity));
+        }
+
+        if let Some(compression) = self.compression {
+            let name = compression.map_or("none", Encoding::name);
+            settings.push_str(&format!("&compression={}", name));
+        }
+
+        match self.access {
+            Access::Public => {}
+            Access::ReadOnly => settings.push_str("&access=read-only"),
+            Access::Private(ref token) => settings.push_str(&format!(
+                "&access={}",
+                encode_param(&format!("private:{}", token))
+            )),
+        }
+
+        settings
+    }
+
+    /// checks access policy for a read or write request
+    pub(crate) fn allows(&self, write: bool, token: Option<&str>) -> bool {
+        match self.access {
+            Access::Public => true,
+            Access::ReadOnly => !write,
+            Access::Private(ref expected) => token
+                .map(|token| constant_time_eq(expected.as_bytes(), token.as_bytes()))
+                .unwrap_or(false),
+        }
+    }
+}
+
+fn parse_number(field: &str, value: &str) -> Result<u64, String> {
+    value
+        .parse::<u64>()
+        .map_err(|_| format!("invalid {} {}", field, value))
+}
+
+/// bucket names are used in urls, restrict them to a safe set
+pub(crate) fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 63
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_bucket_parse() {
+        let bucket = Bucket::parse(
+            "name=logs&replicas=2&ttl=60&versioning=true&quota=1024&quota_objects=8&erasure=4+2&compression=none&access=read-only",
+        )
+        .unwrap();
+
+        assert_eq!(bucket.name, "logs");
+        assert_eq!(bucket.replicas, 2);
+        assert_eq!(bucket.ttl, Some(60));
+        assert!(bucket.versioning);
+        assert_eq!(bucket.quota, Some(1024));
+        assert_eq!(bucket.quota_objects, Some(8));
+        assert_eq!(bucket.erasure, Some((4, 2)));
+        assert_eq!(bucket.compression, Some(None));
+        assert_eq!(bucket.access, Access::ReadOnly);
+
+        assert_eq!(Bucket::parse(&bucket.encode()), Ok(bucket));
+
+        assert!(Bucket::parse("replicas=2").is_err());
+        assert!(Bucket::parse("name=a/b").is_err());
+        assert!(Bucket::parse("name=logs&replicas=0").is_err());
+        assert!(Bucket::parse("name=logs&ttl=soon").is_err());
+        assert!(Bucket::parse("name=logs&versioning=yes").is_err());
+        assert!(
+            !Bucket::parse("name=logs&versioning=false")
+                .unwrap()
+                .versioning
+        );
+        assert!(Bucket::parse("name=logs&access=private:").is_err());
+        assert!(Bucket::parse("name=logs&color=red").is_err());
+        assert!(Bucket::parse("name=logs&erasure=4").is_err());
+        assert!(Bucket::parse("name=logs&compression=gzip").is_err());
+    }
+
+    #[test]
+    fn test_bucket_encode() {
+        let mut bucket = Bucket::parse("name=logs").unwrap();
+
+        for token in &["a%41", "a&b=c", "a b+c", " ", "private:x"] {
+            bucket.access = Access::Private((*token).to_owned());
+            assert_eq!(Bucket::parse(&bucket.encode()), Ok(bucket.clone()));
+        }
+    }
+
+    #[test]
+    fn test_bucket_access() {
+        let mut bucket = Bucket::parse("name=logs").unwrap();
+        assert!(bucket.allows(true, None));
+
+        bucket.access = Access::ReadOnly;
+        assert!(bucket.allows(false, None));
+        assert!(!bucket.allows(true, None));
+
+        bucket.access = Access::Private("secret".to_owned());
+        assert!(bucket.allows(true, Some("secret")));
+        assert!(!bucket.allows(false, Some("guess")));
+        assert!(!bucket.allows(false, None));
+    }
+}
@@ -0,0 +1,512 @@
+//! # change feed
+//!
+//! Master appends every put and delete it commits to a log of changes,
+//! numbered by sequence, in the same index write as the change itself.
+//! Consumers watch the log for changes under a key prefix
+//!
+//! ```sh
+//! curl "http://localhost:6000/watch?bucket=logs&prefix=team-a/&since=42"
+//! ```
+//!
+//! answers with changes after sequence number `since`, a line each, as soon
+//! as there are any or after `timeout` seconds (30 by default, at most 300).
+//! The `X-Sequence` header holds the sequence number to pass as `since` next,
+//! so consumers resume where they left off after reconnecting. Without
+//! `since` changes from now on are watched. `bucket` defaults to the default
+//! bucket, private buckets need their token.
+//!
+//! ```text
+//! seq=43&op=put&bucket=logs&size=1024&key=team-a/report
+//! seq=44&op=delete&bucket=logs&size=1024&key=team-a/draft
+//! ```
+//!
+//! requests accepting `text/event-stream` get the changes as server-sent
+//! events instead, with the sequence number as event id and the operation as
+//! event type. The stream stays open, reconnecting clients resume from their
+//! `Last-Event-ID`. Open watches are served on threads of their own rather
+//! than by request workers, at most 1024 at once. Further watches are
+//! answered with 503.
+//!
+//! The last 100000 changes are kept. Watching from a sequence number that
+//! is no longer kept fails with 410, consumers then list keys again.
+
+use std::io::{self, Write};
+use std::str;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::index::{Batch, IndexStore};
+use crate::listener::{Header, Request, Response};
+use crate::parse_params;
+use crate::server::{Shutdown, POLL_INTERVAL};
+
+/// header holding the sequence number to watch from next
+pub const SEQUENCE_HEADER: &str = "X-Sequence";
+
+/// number of changes kept
+pub const CHANGE_RETENTION: u64 = 100_000;
+
+/// index key prefix of changes
+const CHANGE_PREFIX: &str = "\u{0}meta/change/";
+
+/// media type of server-sent events
+pub(crate) const EVENT_STREAM: &str = "text/event-stream";
+
+/// interval of comments keeping idle event streams open
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// changes sent in an event at most
+const EVENT_BATCH: usize = 100;
+
+/// watches open at once, further ones are answered with 503
+pub const MAX_WATCHES: usize = 1024;
+
+/// Operation of a change
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Op {
+    /// value stored
+    Put,
+
+    /// key deleted or expired
+    Delete,
+}
+
+impl Op {
+    pub fn name(self) -> &'static str {
+        match self {
+            Op::Put => "put",
+            Op::Delete => "delete",
+        }
+    }
+}
+
+/// Committed change of a key
+#[derive(Clone, Debug, PartialEq)]
+pub struct Change {
+    /// sequence number, 0 until the change is written
+    pub seq: u64,
+
+    pub op: Op,
+
+    /// bucket name, empty for default bucket
+    pub bucket: String,
+
+    pub key: String,
+
+    /// size of the value stored or deleted
+    pub size: u64,
+
+    /// version of the value in versioned buckets
+    pub version: Option<u64>,
+}
+
+impl Change {
+    /// Parses a change from a `seq=..&op=..&..&key=..` line. key comes last
+    /// and is taken as is
+    pub fn parse(line: &str) -> Result<Change, String> {
+        let indx = line
+            .find("&key=")
+            .ok_or_else(|| format!("no key in change {}", line))?;
+
+        let mut change = Change {
+            seq: 0,
+            op: Op::Put,
+            bucket: String::new(),
+            key: line[indx + "&key=".len()..].to_owned(),
+            size: 0,
+            version: None,
+        };
+
+        for (field, value) in parse_params(&line[..indx]) {
+            let number = || {
+                value
+                    .parse::<u64>()
+                    .map_err(|_| format!("invalid {} {}", field, value))
+            };
+
+            match field.as_str() {
+                "seq" => change.seq = number()?,
+                "op" => {
+                    change.op = match value.as_str() {
+                        "put" => Op::Put,
+                        "delete" => Op::Delete,
+                        _ => return Err(format!("invalid op {}", value)),
+                    }
+                }
+                "bucket" => change.bucket = value.clone(),
+                "size" => change.size = number()?,
+                "version" => change.version = Some(number()?),
+                _ => return Err(format!("unknown field {}", field)),
+            }
+        }
+
+        Ok(change)
+    }
+
+    /// Encodes change in the format accepted by `parse`
+    pub fn encode(&self) -> String {
+        let mut line = format!(
+            "seq={}&op={}&bucket={}&size={}",
+            self.seq,
+            self.op.name(),
+            self.bucket,
+            self.size
+        );
+
+        if let Some(version) = self.version {
+            line.push_str(&format!("&version={}", version));
+        }
+
+        line.push_str(&format!("&key={}", self.key));
+        line
+    }
+}
+
+/// index key of change `seq`, zero padded to sort by sequence
+fn change_key(seq: u64) -> String {
+    format!("{}{:020}", CHANGE_PREFIX, seq)
+}
+
+/// sequence number of an index key of a change
+fn change_seq(key: &[u8]) -> Option<u64> {
+    str::from_utf8(key.get(CHANGE_PREFIX.len()..)?)
+        .ok()?
+        .parse()
+        .ok()
+}
+
+/// Keys a watch is interested in
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Filter {
+    pub bucket: String,
+    pub prefix: String,
+}
+
+impl Filter {
+    fn matches(&self, change: &Change) -> bool {
+        change.bucket == self.bucket && change.key.starts_with(&self.prefix)
+    }
+}
+
+/// Log of changes kept in the index of master
+pub(crate) struct ChangeLog {
+    index: Arc<dyn IndexStore>,
+    retention: u64,
+
+    /// sequence number of the last change written
+    last: Mutex<u64>,
+
+    /// notifies watches of new changes
+    appended: Condvar,
+
+    /// watches end when master stops
+    shutdown: Shutdown,
+
+    /// number of open watches
+    watches: AtomicUsize,
+}
+
+/// Slot of an open watch, given back when dropped
+pub(crate) struct Watch {
+    pub log: Arc<ChangeLog>,
+}
+
+impl Drop for Watch {
+    fn drop(&mut self) {
+        self.log.watches.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl ChangeLog {
+    /// log continuing after the last change kept in `index`
+    pub fn open(index: Arc<dyn IndexStore>, retention: u64, shutdown: Shutdown) -> ChangeLog {
+        let last = index
+            .scan_prefix(CHANGE_PREFIX.as_bytes())
+            .filter_map(|(key, _)| change_seq(&key))
+            .last()
+            .unwrap_or(0);
+
+        ChangeLog {
+            index,
+            retention,
+            last: Mutex::new(last),
+            appended: Condvar::new(),
+            shutdown,
+            watches: AtomicUsize::new(0),
+        }
+    }
+
+    /// takes a slot for a watch, `None` if `MAX_WATCHES` are open
+    pub fn watch(self: &Arc<Self>) -> Option<Watch> {
+        if self.watches.fetch_add(1, Ordering::SeqCst) >= MAX_WATCHES {
+            self.watches.fetch_sub(1, Ordering::SeqCst);
+            return None;
+        }
+
+        Some(Watch { log: self.clone() })
+    }
+
+    /// sequence number of the last change
+    pub fn last(&self) -> u64 {
+        *self.last.lock().unwrap()
+    }
+
+    /// sequence number of the oldest change kept
+    pub fn first(&self) -> u64 {
+        self.index
+            .scan_prefix(CHANGE_PREFIX.as_bytes())
+            .find_map(|(key, _)| change_seq(&key))
+            .unwrap_or_else(|| self.last() + 1)
+    }
+
+    /// writes `batch` to the index along with `changes`, which are numbered
+    /// in order of writes. changes falling out of retention are removed
+    pub fn write(&self, mut batch: Batch, changes: Vec<Change>) -> io::Result<()> {
+        let mut last = self.last.lock().unwrap();
+        let mut seq = *last;
+
+        for mut change in changes {
+            seq += 1;
+            change.seq = seq;
+            batch.put(change_key(seq).as_bytes(), change.encode().as_bytes());
+
+            if seq > self.retention {
+                batch.delete(change_key(seq - self.retention).as_bytes());
+            }
+        }
+
+        self.index.write(batch)?;
+
+        *last = seq;
+        self.appended.notify_all();
+        Ok(())
+    }
+
+    /// changes after `since` matching `filter`, at most `limit` of them.
+    /// returns them with the sequence number of the last change looked at
+    pub fn read(&self, since: u64, filter: &Filter, limit: usize) -> (Vec<Change>, u64) {
+        let last = self.last();
+        let mut seq = since;
+        let mut changes = Vec::new();
+
+        let start = change_key(since + 1);
+        let entries = self
+            .index
+            .scan_from(start.as_bytes())
+            .take_while(|(key, _)| key.starts_with(CHANGE_PREFIX.as_bytes()));
+
+        for (_, value) in entries {
+            let change = match str::from_utf8(&value)
+                .map_err(|e| e.to_string())
+                .and_then(Change::parse)
+            {
+                Ok(change) => change,
+                Err(_) => continue,
+            };
+
+            // changes being written are read once they are all written
+            if change.seq > last {
+                break;
+            }
+
+            seq = change.seq;
+            if filter.matches(&change) {
+                changes.push(change);
+                if changes.len() >= limit {
+                    return (changes, seq);
+                }
+            }
+        }
+
+        (changes, seq.max(last))
+    }
+
+    /// waits at most `timeout` for a change after `seq`. returns false if
+    /// there was none or master stops meanwhile
+    pub fn wait(&self, seq: u64, timeout: Duration) -> bool {
+        let until = Instant::now() + timeout;
+        let mut last = self.last.lock().unwrap();
+
+        while *last <= seq {
+            let now = Instant::now();
+            if self.shutdown.is_set() || now >= until {
+                return false;
+            }
+
+            last = self
+                .appended
+                .wait_timeout(last, (until - now).min(POLL_INTERVAL))
+                .unwrap()
+                .0;
+        }
+
+        true
+    }
+
+    /// answers `req` with changes after `since` as server-sent events until
+    /// the client goes away or master stops
+    pub fn stream(&self, filter: &Filter, mut since: u64, req: Request) {
+        let (resp, mut writer) = Response::stream();
+        let resp = resp
+            .with_header(Header::from_bytes(b"Content-Type", EVENT_STREAM.as_bytes()).unwrap())
+            .with_header(Header::from_bytes(b"Cache-Control", b"no-cache").unwrap());
+        if req.respond(resp).is_err() {
+            return;
+        }
+
+        loop {
+            let (changes, seq) = self.read(since, filter, EVENT_BATCH);
+            since = seq;
+
+            let mut events = String::new();
+            for change in changes {
+                events.push_str(&format!(
+                    "id: {}\nevent: {}\ndata: {}\n\n",
+                    change.seq,
+                    change.op.name(),
+                    change.encode()
+                ));
+            }
+
+            if events.is_empty() {
+                if self.wait(since, KEEPALIVE_INTERVAL) {
+                    continue;
+                }
+                if self.shutdown.is_set() {
+                    return;
+                }
+
+                // comments keep proxies from closing idle streams and find
+                // clients that went away
+                events.push_str(":\n\n");
+            }
+
+            if send(&mut writer, &events).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// sends `text` to the client right away
+fn send(writer: &mut impl Write, text: &str) -> io::Result<()> {
+    writer.write_all(text.as_bytes())?;
+    writer.flush()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::index::MemoryIndex;
+
+    fn change(op: Op, bucket: &str, key: &str) -> Change {
+        Change {
+            seq: 0,
+            op,
+            bucket: bucket.to_owned(),
+            key: key.to_owned(),
+            size: 5,
+            version: None,
+        }
+    }
+
+    #[test]
+    fn test_change_encode_parse() {
+        let mut put = change(Op::Put, "logs", "a&key=b");
+        put.seq = 7;
+        put.version = Some(3);
+
+        let line = put.encode();
+        assert_eq!(
+            line,
+            "seq=7&op=put&bucket=logs&size=5&version=3&key=a&key=b"
+        );
+        assert_eq!(Change::parse(&line), Ok(put));
+
+        assert!(Change::parse("seq=1&op=put").is_err());
+        assert!(Change::parse("seq=1&op=move&key=a").is_err());
+        assert!(Change::parse("seq=x&op=put&key=a").is_err());
+    }
+
+    #[test]
+    fn test_change_log() {
+        let index: Arc<dyn IndexStore> = Arc::new(MemoryIndex::new());
+        let log = ChangeLog::open(index.clone(), 3, Shutdown::default());
+        assert_eq!(log.last(), 0);
+        assert_eq!(log.first(), 1);
+
+        let mut batch = Batch::default();
+        batch.put(b"a", b"value");
+        log.write(batch, vec![change(Op::Put, "", "a")]).unwrap();
+        assert_eq!(index.get(b"a").unwrap(), Some(b"value".to_vec()));
+
+        for key in &["b", "c", "d"] {
+            log.write(Batch::default(), vec![change(Op::Delete, "", key)])
+                .unwrap();
+        }
+
+        // oldest changes fall out of retention
+        assert_eq!(log.last(), 4);
+        assert_eq!(log.first(), 2);
+
+        let all = Filter::default();
+        let (changes, seq) = log.read(1, &all, 10);
+        let keys: Vec<&str> = changes.iter().map(|change| change.key.as_str()).collect();
+        assert_eq!(keys, vec!["b", "c", "d"]);
+        assert_eq!(changes[0].seq, 2);
+        assert_eq!(changes[0].op, Op::Delete);
+        assert_eq!(seq, 4);
+
+        // reads stop at the limit
+        let (changes, seq) = log.read(1, &all, 2);
+        assert_eq!(changes.len(), 2);
+        assert_eq!(seq, 3);
+
+        // changes not matching are skipped
+        let filter = Filter {
+            bucket: String::new(),
+            prefix: "c".to_owned(),
+        };
+        let (changes, seq) = log.read(3, &filter, 10);
+        assert!(changes.is_empty());
+        assert_eq!(seq, 4);
+
+        assert!(!log.wait(4, Duration::from_millis(10)));
+        assert!(log.wait(3, Duration::from_millis(10)));
+
+        // sequence continues after reopening
+        let log = ChangeLog::open(index, 3, Shutdown::default());
+        assert_eq!(log.last(), 4);
+    }
+
+    #[test]
+    fn test_change_log_shutdown() {
+        let shutdown = Shutdown::default();
+        let log = ChangeLog::open(Arc::new(MemoryIndex::new()), 3, shutdown.clone());
+
+        shutdown.signal();
+        let started = Instant::now();
+        assert!(!log.wait(0, Duration::from_secs(10)));
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_change_log_watches() {
+        let log = Arc::new(ChangeLog::open(
+            Arc::new(MemoryIndex::new()),
+            3,
+            Shutdown::default(),
+        ));
+
+        let mut watches: Vec<Watch> = (0..MAX_WATCHES).filter_map(|_| log.watch()).collect();
+        assert_eq!(watches.len(), MAX_WATCHES);
+        assert!(log.watch().is_none());
+
+        // closed watches give their slot back
+        watches.pop();
+        let watch = log.watch();
+        assert!(watch.is_some());
+        assert!(log.watch().is_none());
+    }
+}
@@ -0,0 +1,256 @@
+//! # checksums
+//!
+//! Volume servers compute MD5 and SHA-256 digests of values while storing
+//! them and keep them next to the value. Uploads may carry the expected
+//! digest, base64 encoded in `Content-MD5` or hex encoded in
+//! `X-Content-SHA256`, uploads not matching it are rejected with 400.
+//! Digests are returned in the same headers when the whole value is read.
+//! Uploads redirected by master carry the size master accounted in a `size`
+//! query param, bodies of another length are rejected as well.
+//!
+//! ```sh
+//! curl -XPUT -L -H "Content-MD5: $(openssl md5 -binary file | base64)" \
+//!     --data-binary @file http://localhost:6000/store/key
+//! ```
+
+use md5::Context;
+use openssl::base64;
+use openssl::sha::Sha256;
+
+use std::io::{self, Read};
+
+use crate::get_header;
+use crate::listener::Request;
+
+/// header carrying base64 encoded MD5 digest of a value
+pub const CONTENT_MD5_HEADER: &str = "Content-MD5";
+
+/// header carrying hex encoded SHA-256 digest of a value
+pub const SHA256_HEADER: &str = "X-Content-SHA256";
+
+/// query param with the size master accounted for an upload
+pub const SIZE_PARAM: &str = "size";
+
+/// Digests of a stored value
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Checksum {
+    pub md5: [u8; 16],
+    pub sha256: [u8; 32],
+}
+
+impl Checksum {
+    /// Encodes digests as `name=hex` lines
+    pub fn encode(&self) -> String {
+        format!(
+            "md5={}\nsha256={}\n",
+            to_hex(&self.md5),
+            to_hex(&self.sha256)
+        )
+    }
+
+    /// Decodes digests encoded by `encode`
+    pub fn decode(raw: &str) -> Option<Checksum> {
+        let mut checksum = Checksum {
+            md5: [0; 16],
+            sha256: [0; 32],
+        };
+        let (mut md5, mut sha256) = (false, false);
+
+        for line in raw.lines() {
+            let mut parts = line.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some("md5"), Some(hex)) => md5 = from_hex(hex, &mut checksum.md5),
+                (Some("sha256"), Some(hex)) => sha256 = from_hex(hex, &mut checksum.sha256),
+                _ => {}
+            }
+        }
+
+        if md5 && sha256 {
+            Some(checksum)
+        } else {
+            None
+        }
+    }
+
+    /// headers announcing the digests
+    pub fn headers(&self) -> Vec<(&'static str, String)> {
+        vec![
+            (CONTENT_MD5_HEADER, base64::encode_block(&self.md5)),
+            (SHA256_HEADER, to_hex(&self.sha256)),
+        ]
+    }
+}
+
+/// Digests an upload is expected to have
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Expected {
+    length: Option<u64>,
+    md5: Option<[u8; 16]>,
+    sha256: Option<[u8; 32]>,
+}
+
+impl Expected {
+    /// reads expected digests from request headers
+    pub fn from_request(req: &Request) -> Result<Expected, String> {
+        let mut expected = Expected {
+            length: req.body_length().map(|length| length as u64),
+            ..Expected::default()
+        };
+
+        if let Some(value) = get_header(req, CONTENT_MD5_HEADER) {
+            let mut md5 = [0; 16];
+            match base64::decode_block(value.trim()) {
+                Ok(ref digest) if digest.len() == md5.len() => md5.copy_from_slice(digest),
+                _ => return Err(format!("invalid {} header", CONTENT_MD5_HEADER)),
+            }
+            expected.md5 = Some(md5);
+        }
+
+        if let Some(value) = get_header(req, SHA256_HEADER) {
+            let mut sha256 = [0; 32];
+            if !from_hex(value.trim(), &mut sha256) {
+                return Err(format!("invalid {} header", SHA256_HEADER));
+            }
+            expected.sha256 = Some(sha256);
+        }
+
+        Ok(expected)
+    }
+
+    /// expects a body of exactly `size` bytes, as accounted by master
+    pub fn with_size(mut self, size: u64) -> Result<Expected, String> {
+        if self.length.map_or(false, |length| length != size) {
+            return Err(format!("upload of {} bytes differs from its size", size));
+        }
+
+        self.length = Some(size);
+        Ok(self)
+    }
+
+    /// bytes worth reading of a body, one past the expected length so that
+    /// longer bodies are noticed
+    pub fn limit(&self) -> u64 {
+        self.length.map_or(u64::max_value(), |length| length + 1)
+    }
+
+    /// checks length and digests of the received value
+    pub fn verify(&self, length: u64, checksum: &Checksum) -> Result<(), String> {
+        if self.length.map_or(false, |expected| expected != length) {
+            return Err("upload length mismatch".to_owned());
+        }
+
+        if self.md5.map_or(false, |md5| md5 != checksum.md5) {
+            return Err(format!("{} mismatch", CONTENT_MD5_HEADER));
+        }
+
+        if self
+            .sha256
+            .map_or(false, |sha256| sha256 != checksum.sha256)
+        {
+            return Err(format!("{} mismatch", SHA256_HEADER));
+        }
+
+        Ok(())
+    }
+}
+
+/// Reader computing digests of the data read through it
+pub(crate) struct DigestReader<R: Read> {
+    inner: R,
+    md5: Context,
+    sha256: Sha256,
+}
+
+impl<R: Read> DigestReader<R> {
+    pub fn new(inner: R) -> Self {
+        DigestReader {
+            inner,
+            md5: Context::new(),
+            sha256: Sha256::new(),
+        }
+    }
+
+    /// digests of all data read
+    pub fn finish(self) -> Checksum {
+        Checksum {
+            md5: self.md5.compute().0,
+            sha256: self.sha256.finish(),
+        }
+    }
+}
+
+impl<R: Read> Read for DigestReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+
+        self.md5.consume(&buf[..len]);
+        self.sha256.update(&buf[..len]);
+
+        Ok(len)
+    }
+}
+
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// decodes hex into `out`, returns false unless it is filled exactly
+pub(crate) fn from_hex(hex: &str, out: &mut [u8]) -> bool {
+    if hex.len() != out.len() * 2 || !hex.is_ascii() {
+        return false;
+    }
+
+    for (indx, byte) in out.iter_mut().enumerate() {
+        match u8::from_str_radix(&hex[indx * 2..indx * 2 + 2], 16) {
+            Ok(value) => *byte = value,
+            Err(_) => return false,
+        }
+    }
+
+    true
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_checksum() {
+        let mut reader = DigestReader::new("value".as_bytes());
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).unwrap();
+
+        let checksum = reader.finish();
+        assert_eq!(to_hex(&checksum.md5), "2063c1608d6e0baf80249c42e2be5804");
+        assert_eq!(
+            to_hex(&checksum.sha256),
+            "cd42404d52ad55ccfa9aca4adc828aa5800ad9d385a0671fbcbf724118320619"
+        );
+
+        assert_eq!(Checksum::decode(&checksum.encode()), Some(checksum.clone()));
+        assert_eq!(Checksum::decode("md5=00"), None);
+        assert_eq!(
+            checksum.headers()[0],
+            (CONTENT_MD5_HEADER, "IGPBYI1uC6+AJJxC4r5YBA==".to_owned())
+        );
+
+        let mut expected = Expected {
+            length: Some(5),
+            md5: Some(checksum.md5),
+            sha256: None,
+        };
+        assert!(expected.verify(5, &checksum).is_ok());
+        assert!(expected.verify(4, &checksum).is_err());
+
+        expected.sha256 = Some([0; 32]);
+        assert!(expected.verify(5, &checksum).is_err());
+
+        assert!(Expected::default()
+            .with_size(5)
+            .unwrap()
+            .verify(6, &checksum)
+            .is_err());
+        assert!(expected.clone().with_size(5).is_ok());
+        assert!(expected.with_size(6).is_err());
+    }
+}
@@ -0,0 +1,642 @@
+//! # client
+//!
+//! Blocking client of a kalavara cluster. Requests go to master and follow
+//! its redirects to volume servers, so values are streamed between the
+//! program and volumes.
+//!
+//! ```no_run
+//! use kalavara::client::Client;
+//! use std::time::Duration;
+//!
+//! let client = Client::new("http://localhost:6000")
+//!     .retries(3)
+//!     .timeout(Duration::from_secs(5))
+//!     .cache_locations(10_000);
+//!
+//! client.put_bytes("key", b"value")?;
+//! assert_eq!(client.get("key")?.bytes()?, b"value");
+//! assert_eq!(client.list("k")?, vec!["key"]);
+//! client.delete("key")?;
+//! # Ok::<(), kalavara::client::Error>(())
+//! ```
+//!
+//! requests failing to connect, timing out, or answered with 429 or 503 are
+//! retried, waiting twice as long before each retry. Other statuses are
+//! returned as [`Error`](enum.Error.html) right away.
+//!
+//! keys may hold any text, they are percent encoded in urls and listed and
+//! watched decoded.
+//!
+//! `watch` waits for changes of keys under a prefix, see
+//! [changes](../changes/index.html).
+//!
+//! `admin` calls [admin endpoints](../master/index.html) of master, as
+//! `kalavara-admin` does.
+//!
+//! with `cache_locations` volume urls of values read are remembered, and
+//! later reads go to the volume directly. Locations found stale are
+//! forgotten and the read is sent to master again.
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::io::{self, Cursor, Read, Seek, SeekFrom};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use crate::bucket::BUCKET_TOKEN_HEADER;
+use crate::changes::{Change, SEQUENCE_HEADER};
+use crate::http::{self, Response};
+use crate::s3::{from_key, to_key, uri_encode};
+use crate::tls::TlsConfig;
+use crate::{ADMIN_PREFIX, BUCKET_PREFIX, STORE_PREFIX, WATCH_PATH};
+
+/// redirects followed for a request
+const MAX_REDIRECTS: usize = 5;
+
+/// keys requested per page when listing, the most master answers with
+const LIST_PAGE: usize = 1000;
+
+/// Error of a request, statuses map to responses of master
+#[derive(Debug)]
+pub enum Error {
+    /// Invalid request, 400
+    BadRequest(String),
+
+    /// Authentication required, 401
+    Unauthorized,
+
+    /// Access denied by bucket policy, 403
+    Forbidden,
+
+    /// Key not found, 404
+    NotFound,
+
+    /// Method not allowed, 405
+    NotAllowed,
+
+    /// Too many requests, 429, with seconds to wait if known
+    RateLimited(Option<u64>),
+
+    /// Error occured, 500
+    ServerError,
+
+    /// Unavailable, 503
+    Unavailable,
+
+    /// Bucket quota exceeded, 507
+    InsufficientStorage,
+
+    /// Any other status, with the response body
+    Status(u16, String),
+
+    /// Redirected more than 5 times
+    TooManyRedirects,
+
+    /// Connecting, sending or receiving failed
+    Io(io::Error),
+}
+
+/// Result of a request
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    /// error of a response with `body`
+    fn from_status(resp: &Response, body: String) -> Error {
+        match resp.status_code {
+            400 => Error::BadRequest(body),
+            401 => Error::Unauthorized,
+            403 => Error::Forbidden,
+            404 => Error::NotFound,
+            405 => Error::NotAllowed,
+            429 => Error::RateLimited(
+                resp.header("Retry-After")
+                    .and_then(|secs| secs.parse::<u64>().ok()),
+            ),
+            500 => Error::ServerError,
+            503 => Error::Unavailable,
+            507 => Error::InsufficientStorage,
+            status => Error::Status(status, body),
+        }
+    }
+
+    /// checks whether a retry may succeed
+    fn is_transient(&self) -> bool {
+        match self {
+            Error::Io(_) | Error::RateLimited(_) | Error::Unavailable => true,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::BadRequest(msg) => write!(f, "bad request: {}", msg),
+            Error::Unauthorized => write!(f, "unauthorized"),
+            Error::Forbidden => write!(f, "forbidden"),
+            Error::NotFound => write!(f, "key not found"),
+            Error::NotAllowed => write!(f, "method not allowed"),
+            Error::RateLimited(_) => write!(f, "rate limited"),
+            Error::ServerError => write!(f, "server error"),
+            Error::Unavailable => write!(f, "service unavailable"),
+            Error::InsufficientStorage => write!(f, "quota exceeded"),
+            Error::Status(status, msg) => write!(f, "unexpected status {}: {}", status, msg),
+            Error::TooManyRedirects => write!(f, "too many redirects"),
+            Error::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+/// Size and headers of a stored value
+#[derive(Clone, Debug)]
+pub struct Metadata {
+    /// size in bytes, if the server sent it
+    pub size: Option<u64>,
+
+    /// response headers of the value, digests among them
+    pub headers: Vec<(String, String)>,
+}
+
+impl Metadata {
+    fn from_response(resp: &Response) -> Metadata {
+        Metadata {
+            size: resp
+                .header("Content-Length")
+                .and_then(|len| len.parse::<u64>().ok()),
+            headers: resp.headers.clone(),
+        }
+    }
+
+    /// returns value of header `name`, compared case insensitively
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(field, _)| field.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Value read from the cluster, streamed as it is read
+pub struct Value {
+    /// size and headers of the value
+    pub meta: Metadata,
+    body: Box<dyn Read + Send>,
+}
+
+impl Value {
+    /// reads the complete value
+    pub fn bytes(mut self) -> io::Result<Vec<u8>> {
+        let mut value = Vec::new();
+        self.body.read_to_end(&mut value)?;
+        Ok(value)
+    }
+}
+
+impl Read for Value {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.body.read(buf)
+    }
+}
+
+/// Request body, rewound for redirects and retries
+trait Body: Read + Seek {}
+
+impl<T: Read + Seek> Body for T {}
+
+/// volume urls of values, the oldest forgotten first
+struct Locations {
+    capacity: usize,
+    urls: HashMap<String, String>,
+    order: VecDeque<String>,
+}
+
+impl Locations {
+    fn new(capacity: usize) -> Self {
+        Locations {
+            capacity,
+            urls: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    fn get(&self, path: &str) -> Option<String> {
+        self.urls.get(path).cloned()
+    }
+
+    fn insert(&mut self, path: &str, url: String) {
+        if self.urls.insert(path.to_owned(), url).is_none() {
+            self.order.push_back(path.to_owned());
+        }
+
+        while self.urls.len() > self.capacity {
+            match self.order.pop_front() {
+                Some(oldest) => self.urls.remove(&oldest),
+                None => break,
+            };
+        }
+    }
+
+    fn remove(&mut self, path: &str) {
+        if self.urls.remove(path).is_some() {
+            self.order.retain(|cached| cached != path);
+        }
+    }
+}
+
+/// Client of a kalavara master
+pub struct Client {
+    master: String,
+    bucket: Option<String>,
+    token: Option<String>,
+    tls: TlsConfig,
+    retries: u32,
+    retry_delay: Duration,
+    timeout: Duration,
+    locations: Option<Mutex<Locations>>,
+}
+
+impl Client {
+    /// client of the master at `master`, using its default bucket
+    pub fn new(master: &str) -> Self {
+        Client {
+            master: master.trim_end_matches('/').to_owned(),
+            bucket: None,
+            token: None,
+            tls: TlsConfig::default(),
+            retries: 2,
+            retry_delay: Duration::from_millis(100),
+            timeout: http::TIMEOUT,
+            locations: None,
+        }
+    }
+
+    /// Named bucket to use instead of the default one
+    pub fn bucket(mut self, name: &str) -> Self {
+        self.bucket = Some(name.to_owned());
+        self
+    }
+
+    /// Token of a private bucket, sent in `X-Bucket-Token` header
+    pub fn token(mut self, token: &str) -> Self {
+        self.token = Some(token.to_owned());
+        self
+    }
+
+    /// TLS settings to verify https servers with
+    pub fn tls(mut self, tls: TlsConfig) -> Self {
+        self.tls = tls;
+        self
+    }
+
+    /// Number of retries of transient failures, 2 by default
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Pause before the first retry, doubled for each further one
+    pub fn retry_delay(mut self, delay: Duration) -> Self {
+        self.retry_delay = delay;
+        self
+    }
+
+    /// Connect, read and write timeout of each request, 30 seconds by default
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Remembers volume urls of up to `capacity` values read
+    pub fn cache_locations(mut self, capacity: usize) -> Self {
+        self.locations = Some(Mutex::new(Locations::new(capacity)));
+        self
+    }
+
+    /// reads value of a key
+    pub fn get(&self, key: &str) -> Result<Value> {
+        let (resp, body) = self.read("GET", &self.path(key))?;
+
+        Ok(Value {
+            meta: Metadata::from_response(&resp),
+            body,
+        })
+    }
+
+    /// reads size and headers of a value without its content
+    pub fn head(&self, key: &str) -> Result<Metadata> {
+        let (resp, _) = self.read("HEAD", &self.path(key))?;
+        Ok(Metadata::from_response(&resp))
+    }
+
+    /// stores a value streamed from `value`, which is read again from its
+    /// start when the request is redirected or retried
+    pub fn put<R: Read + Seek>(&self, key: &str, mut value: R) -> Result<()> {
+        let path = self.path(key);
+        self.forget(&path);
+
+        self.retry(|| self.follow("PUT", &self.url(&path), Some(&mut value)))?;
+        Ok(())
+    }
+
+    /// stores a value held in memory
+    pub fn put_bytes(&self, key: &str, value: &[u8]) -> Result<()> {
+        self.put(key, Cursor::new(value))
+    }
+
+    /// deletes a key and its value
+    pub fn delete(&self, key: &str) -> Result<()> {
+        let path = self.path(key);
+        self.forget(&path);
+
+        self.retry(|| self.follow("DELETE", &self.url(&path), None))?;
+        Ok(())
+    }
+
+    /// lists keys starting with `prefix` in order
+    pub fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        let mut keys: Vec<String> = Vec::new();
+
+        loop {
+            let mut path = format!(
+                "{}?prefix={}&limit={}",
+                self.path(""),
+                uri_encode(&to_key(prefix), true),
+                LIST_PAGE
+            );
+            if let Some(last) = keys.last() {
+                path.push_str(&format!("&after={}", uri_encode(last, true)));
+            }
+
+            let (_, _, mut body) = self.retry(|| self.follow("GET", &self.url(&path), None))?;
+            let mut page = String::new();
+            body.read_to_string(&mut page)?;
+
+            let listed = keys.len();
+            keys.extend(
+                page.lines()
+                    .filter(|key| !key.is_empty())
+                    .map(str::to_owned),
+            );
+
+            if keys.len() - listed < LIST_PAGE {
+                return Ok(keys.iter().map(|key| from_key(key)).collect());
+            }
+        }
+    }
+
+    /// changes of keys starting with `prefix` after sequence number `since`,
+    /// or from now on if not given. master waits up to `wait` for changes,
+    /// keep it below `timeout`. returns the changes with the sequence number
+    /// to watch from next. changes no longer kept fail with status 410
+    pub fn watch(
+        &self,
+        prefix: &str,
+        since: Option<u64>,
+        wait: Duration,
+    ) -> Result<(Vec<Change>, u64)> {
+        let mut path = format!(
+            "{}?bucket={}&prefix={}&timeout={}",
+            WATCH_PATH,
+            uri_encode(self.bucket.as_deref().unwrap_or(""), true),
+            uri_encode(&to_key(prefix), true),
+            wait.as_secs()
+        );
+        if let Some(since) = since {
+            path.push_str(&format!("&since={}", since));
+        }
+
+        let (_, resp, mut body) = self.retry(|| self.follow("GET", &self.url(&path), None))?;
+
+        let seq = resp
+            .header(SEQUENCE_HEADER)
+            .and_then(|seq| seq.parse::<u64>().ok())
+            .ok_or_else(|| Error::Status(resp.status_code, "no sequence".to_owned()))?;
+
+        let mut text = String::new();
+        body.read_to_string(&mut text)?;
+
+        let changes = text
+            .lines()
+            .filter(|line| !line.is_empty())
+            .map(|line| {
+                Change::parse(line).map(|mut change| {
+                    change.key = from_key(&change.key);
+                    change
+                })
+            })
+            .collect::<std::result::Result<Vec<Change>, String>>()
+            .map_err(|e| Error::Status(resp.status_code, e))?;
+
+        Ok((changes, seq))
+    }
+
+    /// sends a request to an admin endpoint of master, `path` following
+    /// `/admin/`. Operations moving values take a while, raise `timeout`
+    pub fn admin(&self, method: &str, path: &str, body: &[u8]) -> Result<Value> {
+        let url = self.url(&format!("{}{}", ADMIN_PREFIX, path));
+        let (_, resp, reader) =
+            self.retry(|| self.follow(method, &url, Some(&mut Cursor::new(body))))?;
+
+        Ok(Value {
+            meta: Metadata::from_response(&resp),
+            body: reader,
+        })
+    }
+
+    /// path of a key on master, the key percent encoded as s3 object keys
+    /// are so that keys with `?`, `#`, `%` or spaces round trip
+    fn path(&self, key: &str) -> String {
+        match self.bucket {
+            Some(ref bucket) => format!("{}{}/{}", BUCKET_PREFIX, bucket, to_key(key)),
+            None => format!("{}{}", STORE_PREFIX, to_key(key)),
+        }
+    }
+
+    /// url of a path on master
+    fn url(&self, path: &str) -> String {
+        format!("{}{}", self.master, path)
+    }
+
+    /// sends a read request, to the volume holding the value if its location
+    /// is cached
+    fn read(&self, method: &str, path: &str) -> Result<(Response, Box<dyn Read + Send>)> {
+        let cached = self
+            .locations
+            .as_ref()
+            .and_then(|locations| locations.lock().unwrap().get(path));
+
+        if let Some(url) = cached {
+            match self.follow(method, &url, None) {
+                Ok((_, resp, body)) => return Ok((resp, body)),
+                Err(_) => self.forget(path),
+            }
+        }
+
+        let (url, resp, body) = self.retry(|| self.follow(method, &self.url(path), None))?;
+
+        // values streamed by master itself have no location of their own
+        if let Some(ref locations) = self.locations {
+            if url != self.url(path) {
+                locations.lock().unwrap().insert(path, url);
+            }
+        }
+
+        Ok((resp, body))
+    }
+
+    /// forgets the cached location of a path
+    fn forget(&self, path: &str) {
+        if let Some(ref locations) = self.locations {
+            locations.lock().unwrap().remove(path);
+        }
+    }
+
+    /// runs `send` until it succeeds, fails for good or retries run out
+    fn retry<T>(&self, mut send: impl FnMut() -> Result<T>) -> Result<T> {
+        let mut delay = self.retry_delay;
+        let mut retries = 0;
+
+        loop {
+            match send() {
+                Err(ref e) if e.is_transient() && retries < self.retries => {
+                    let wait = match e {
+                        Error::RateLimited(Some(secs)) => delay.max(Duration::from_secs(*secs)),
+                        _ => delay,
+                    };
+
+                    thread::sleep(wait);
+                    delay *= 2;
+                    retries += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// sends a request to `url` and follows redirects. returns the url that
+    /// answered along with the response and a reader of its body
+    fn follow(
+        &self,
+        method: &str,
+        url: &str,
+        mut body: Option<&mut dyn Body>,
+    ) -> Result<(String, Response, Box<dyn Read + Send>)> {
+        let mut headers = Vec::new();
+        if let Some(ref token) = self.token {
+            headers.push((BUCKET_TOKEN_HEADER, token.as_str()));
+        }
+
+        let mut url = url.to_owned();
+
+        for _ in 0..=MAX_REDIRECTS {
+            let (resp, reader) = match body {
+                Some(ref mut body) => {
+                    let length = body.seek(SeekFrom::End(0))?;
+                    body.seek(SeekFrom::Start(0))?;
+                    http::send(
+                        method,
+                        &url,
+                        &headers,
+                        body,
+                        length,
+                        &self.tls,
+                        self.timeout,
+                    )?
+                }
+                None => http::send(
+                    method,
+                    &url,
+                    &headers,
+                    &mut io::empty(),
+                    0,
+                    &self.tls,
+                    self.timeout,
+                )?,
+            };
+
+            match resp.status_code {
+                301 | 302 | 307 | 308 => match resp.header("Location") {
+                    Some(location) => url = location.to_owned(),
+                    None => return Err(Error::Status(resp.status_code, "no location".to_owned())),
+                },
+                200..=299 => return Ok((url, resp, reader)),
+                _ => {
+                    let mut text = String::new();
+                    let _ = reader.take(4096).read_to_string(&mut text);
+                    return Err(Error::from_status(&resp, text));
+                }
+            }
+        }
+
+        Err(Error::TooManyRedirects)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_locations_evict_oldest() {
+        let mut locations = Locations::new(2);
+        locations.insert("/store/a", "http://volume1/store/a".to_owned());
+        locations.insert("/store/b", "http://volume2/store/b".to_owned());
+        locations.insert("/store/a", "http://volume2/store/a".to_owned());
+        locations.insert("/store/c", "http://volume1/store/c".to_owned());
+
+        assert_eq!(locations.get("/store/a"), None);
+        assert_eq!(
+            locations.get("/store/b"),
+            Some("http://volume2/store/b".to_owned())
+        );
+
+        locations.remove("/store/b");
+        assert_eq!(locations.get("/store/b"), None);
+        assert_eq!(locations.order.len(), 1);
+    }
+
+    #[test]
+    fn test_client_paths() {
+        let client = Client::new("http://master:6000/");
+        assert_eq!(
+            client.url(&client.path("key")),
+            "http://master:6000/store/key"
+        );
+
+        let client = client.bucket("logs");
+        assert_eq!(client.path("a/b"), "/bucket/logs/a/b");
+        assert_eq!(client.path(""), "/bucket/logs/");
+        assert_eq!(
+            client.path("a&b?c#d%e f"),
+            "/bucket/logs/a&b%3Fc%23d%25e%20f"
+        );
+    }
+
+    #[test]
+    fn test_client_unreachable() {
+        let client = Client::new("http://localhost:1")
+            .retries(1)
+            .retry_delay(Duration::from_millis(1));
+
+        assert!(match client.get("key") {
+            Err(Error::Io(_)) => true,
+            _ => false,
+        });
+    }
+}
@@ -0,0 +1,209 @@
+//! # cluster membership
+//!
+//! Volume servers registering themselves with master present either the
+//! cluster join token in `X-Join-Token` header, or a client certificate. Master
+//! serving https with a CA bundle asks for one, and accepts volumes whose
+//! certificate is issued by that CA for the host of their advertised base url.
+//! Before a volume is added, master fetches `/admin/cluster-id` from that base
+//! url and compares it with its own cluster id.
+//!
+//! Registration is open if master has neither a join token nor a CA bundle.
+//! With only a CA bundle, volumes need a certificate, so master has to serve
+//! https.
+//!
+//! Uploads and deletes master redirects to a volume list the other volumes of
+//! the value in a `replicas` param, which the volume forwards them to. In
+//! clusters with a join token master signs that list with it, and volumes
+//! refuse to forward to lists without a valid signature. Volumes of clusters
+//! without one only forward to servers answering their cluster id.
+
+use openssl::nid::Nid;
+use openssl::x509::{X509Ref, X509};
+
+use std::net::IpAddr;
+
+use crate::checksum::to_hex;
+use crate::listener::Request;
+use crate::s3::hmac;
+use crate::{get_header, http};
+
+/// header carrying the join token
+pub const JOIN_TOKEN_HEADER: &str = "X-Join-Token";
+
+/// query param carrying the signature of the replicas of an upload or delete
+pub(crate) const REPLICAS_SIG_PARAM: &str = "replicas_sig";
+
+/// Credentials a volume server presents to master
+#[derive(Default)]
+pub(crate) struct Credentials {
+    /// join token of `X-Join-Token` header
+    pub token: Option<String>,
+
+    /// client certificate, verified against the CA bundle of master
+    pub certificate: Option<X509>,
+}
+
+impl Credentials {
+    /// credentials presented with a request
+    pub(crate) fn of(req: &Request) -> Self {
+        Credentials {
+            token: get_header(req, JOIN_TOKEN_HEADER),
+            certificate: req.certificate().cloned(),
+        }
+    }
+}
+
+/// Cluster membership settings shared by master and volume servers
+#[derive(Clone, Debug, Default)]
+pub struct ClusterConfig {
+    /// cluster identifier, served by volumes and checked by master
+    pub id: Option<String>,
+
+    /// shared secret required to register volumes
+    pub join_token: Option<String>,
+}
+
+impl ClusterConfig {
+    /// cluster id served by volumes, empty if none configured
+    pub(crate) fn id(&self) -> &str {
+        self.id.as_ref().map(String::as_str).unwrap_or("")
+    }
+
+    /// checks token presented by a volume against the join token
+    pub(crate) fn verify_token(&self, token: Option<&str>) -> bool {
+        match (&self.join_token, token) {
+            (Some(expected), Some(token)) => {
+                constant_time_eq(expected.as_bytes(), token.as_bytes())
+            }
+            _ => false,
+        }
+    }
+
+    /// signature of comma separated `replicas` a volume forwards to, `None`
+    /// without a join token
+    pub(crate) fn sign_replicas(&self, replicas: &str) -> Option<String> {
+        self.join_token
+            .as_ref()
+            .map(|token| to_hex(&hmac(token.as_bytes(), replicas.as_bytes())))
+    }
+
+    /// checks the signature of `replicas` presented to a volume
+    pub(crate) fn verify_replicas(&self, replicas: &str, sig: Option<&str>) -> bool {
+        match (self.sign_replicas(replicas), sig) {
+            (Some(expected), Some(sig)) => constant_time_eq(expected.as_bytes(), sig.as_bytes()),
+            _ => false,
+        }
+    }
+}
+
+/// checks whether `cert` is issued for the host of `url`, by its subject
+/// alternative names or, if it has none, its common name
+pub(crate) fn certifies(cert: &X509Ref, url: &str) -> bool {
+    let host = match http::parse_url(url) {
+        Ok((_, host, _, _)) => host,
+        Err(_) => return false,
+    };
+    let ip = host.parse::<IpAddr>().ok();
+
+    if let Some(names) = cert.subject_alt_names() {
+        return names
+            .iter()
+            .any(|name| match (name.dnsname(), name.ipaddress()) {
+                (Some(dns), _) => dns.eq_ignore_ascii_case(&host),
+                (_, Some(addr)) => ip.is_some_and(|ip| match ip {
+                    IpAddr::V4(ip) => addr == ip.octets(),
+                    IpAddr::V6(ip) => addr == ip.octets(),
+                }),
+                _ => false,
+            });
+    }
+
+    cert.subject_name()
+        .entries_by_nid(Nid::COMMONNAME)
+        .filter_map(|entry| entry.data().to_string().ok())
+        .any(|name| name.eq_ignore_ascii_case(&host))
+}
+
+/// compares secrets without leaking position of first mismatch
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[test]
+fn test_verify_token() {
+    let cluster = ClusterConfig {
+        id: None,
+        join_token: Some("secret".to_owned()),
+    };
+
+    assert!(cluster.verify_token(Some("secret")));
+    assert!(!cluster.verify_token(Some("secreT")));
+    assert!(!cluster.verify_token(Some("secret2")));
+    assert!(!cluster.verify_token(None));
+    assert!(!ClusterConfig::default().verify_token(Some("")));
+}
+
+#[test]
+fn test_sign_replicas() {
+    let cluster = ClusterConfig {
+        id: None,
+        join_token: Some("secret".to_owned()),
+    };
+
+    let sig = cluster.sign_replicas("http://v2,http://v3").unwrap();
+    assert!(cluster.verify_replicas("http://v2,http://v3", Some(&sig)));
+    assert!(!cluster.verify_replicas("http://v2,http://evil", Some(&sig)));
+    assert!(!cluster.verify_replicas("http://v2,http://v3", None));
+
+    // lists are not signed without a token
+    assert_eq!(ClusterConfig::default().sign_replicas("http://v2"), None);
+    assert!(!ClusterConfig::default().verify_replicas("http://v2", Some("")));
+}
+
+#[test]
+fn test_certifies() {
+    use openssl::hash::MessageDigest;
+    use openssl::pkey::PKey;
+    use openssl::rsa::Rsa;
+    use openssl::x509::extension::SubjectAlternativeName;
+    use openssl::x509::X509NameBuilder;
+
+    let pkey = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+    let certificate = |san: bool| {
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", "volume1").unwrap();
+        let name = name.build();
+
+        let mut builder = X509::builder().unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_pubkey(&pkey).unwrap();
+        if san {
+            let san = SubjectAlternativeName::new()
+                .dns("Volume2.example")
+                .ip("10.0.0.2")
+                .ip("::2")
+                .build(&builder.x509v3_context(None, None))
+                .unwrap();
+            builder.append_extension(san).unwrap();
+        }
+        builder.sign(&pkey, MessageDigest::sha256()).unwrap();
+        builder.build()
+    };
+
+    let cert = certificate(false);
+    assert!(certifies(&cert, "https://volume1:7000"));
+    assert!(!certifies(&cert, "https://volume2:7000"));
+
+    // common name is ignored when alternative names are given
+    let cert = certificate(true);
+    assert!(!certifies(&cert, "https://volume1:7000"));
+    assert!(certifies(&cert, "https://volume2.example:7000"));
+    assert!(certifies(&cert, "https://10.0.0.2:7000"));
+    assert!(certifies(&cert, "https://[::2]:7000"));
+    assert!(!certifies(&cert, "https://10.0.0.3:7000"));
+    assert!(!certifies(&cert, "volume2.example"));
+}
@@ -0,0 +1,176 @@
+//! Compression of stored values.
+//!
+//! Volume servers started with `--compression zstd` (or `lz4`) compress values
+//! as they are written, buckets created with `compression=<encoding>` have
+//! their values compressed whatever the volume default is. Master passes the
+//! bucket setting on to volumes as `?compression=` query param of uploads,
+//! `none` stores a value as sent.
+//!
+//! The encoding and size of a compressed value are kept in its `.sum` file.
+//! Clients sending a matching `Accept-Encoding` header get the stored bytes
+//! with `Content-Encoding`, others the decompressed value. Digests are always
+//! those of the decompressed value.
+
+use std::io::{self, copy, Read, Write};
+
+/// query param selecting compression of an upload
+pub const COMPRESSION_PARAM: &str = "compression";
+
+/// zstd level, favouring speed as values are compressed while uploaded
+const ZSTD_LEVEL: i32 = 3;
+
+/// Compression applied to stored values
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Encoding {
+    Zstd,
+    Lz4,
+}
+
+impl Encoding {
+    /// name used in settings and `Content-Encoding` headers
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::Zstd => "zstd",
+            Encoding::Lz4 => "lz4",
+        }
+    }
+}
+
+/// parses a compression setting, `none` turns compression off
+pub fn parse_setting(value: &str) -> Result<Option<Encoding>, String> {
+    match value {
+        "zstd" => Ok(Some(Encoding::Zstd)),
+        "lz4" => Ok(Some(Encoding::Lz4)),
+        "none" => Ok(None),
+        _ => Err(format!("invalid compression {}", value)),
+    }
+}
+
+/// compresses `value` into `dest`, returns the size of the value
+pub(crate) fn compress<W: Write>(
+    encoding: Encoding,
+    value: &mut impl Read,
+    dest: W,
+) -> io::Result<u64> {
+    match encoding {
+        Encoding::Zstd => {
+            let mut encoder = zstd::stream::write::Encoder::new(dest, ZSTD_LEVEL)?;
+            let size = copy(value, &mut encoder)?;
+            encoder.finish()?;
+            Ok(size)
+        }
+        Encoding::Lz4 => {
+            let mut encoder = lz4::EncoderBuilder::new().build(dest)?;
+            let size = copy(value, &mut encoder)?;
+            encoder.finish().1?;
+            Ok(size)
+        }
+    }
+}
+
+/// decompresses a stored value while it is read
+pub(crate) fn decompress<'a>(
+    encoding: Encoding,
+    stored: impl Read + Send + 'a,
+) -> io::Result<Box<dyn Read + Send + 'a>> {
+    Ok(match encoding {
+        Encoding::Zstd => Box::new(zstd::stream::read::Decoder::new(stored)?),
+        Encoding::Lz4 => Box::new(lz4::Decoder::new(stored)?),
+    })
+}
+
+/// lines recording the compression of a value in its `.sum` file
+pub(crate) fn encode_stored(encoding: Encoding, size: u64) -> String {
+    format!("encoding={}\nsize={}\n", encoding.name(), size)
+}
+
+/// encoding and decompressed size of a value from its `.sum` file,
+/// `None` for values stored as sent
+pub(crate) fn decode_stored(raw: &str) -> Option<(Encoding, u64)> {
+    let mut encoding = None;
+    let mut size = None;
+
+    for line in raw.lines() {
+        let mut parts = line.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some("encoding"), Some(name)) => encoding = parse_setting(name).ok()?,
+            (Some("size"), Some(value)) => size = value.parse::<u64>().ok(),
+            _ => {}
+        }
+    }
+
+    Some((encoding?, size?))
+}
+
+/// checks whether an `Accept-Encoding` header allows `encoding`. an explicit
+/// entry wins over `*`, entries with `q=0` refuse the encoding
+pub(crate) fn accepts(header: &str, encoding: Encoding) -> bool {
+    let mut wildcard = false;
+
+    for entry in header.split(',') {
+        let mut params = entry.split(';').map(str::trim);
+        let name = params.next().unwrap_or_default();
+
+        let refused = params.any(|param| {
+            param.starts_with("q=") && param[2..].parse::<f32>().map_or(false, |q| q <= 0.0)
+        });
+
+        if name.eq_ignore_ascii_case(encoding.name()) {
+            return !refused;
+        }
+
+        if name == "*" {
+            wildcard = !refused;
+        }
+    }
+
+    wildcard
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_compress_setting() {
+        assert_eq!(parse_setting("zstd"), Ok(Some(Encoding::Zstd)));
+        assert_eq!(parse_setting("lz4"), Ok(Some(Encoding::Lz4)));
+        assert_eq!(parse_setting("none"), Ok(None));
+        assert!(parse_setting("gzip").is_err());
+
+        let raw = format!("key=/key\n{}md5=00\n", encode_stored(Encoding::Lz4, 42));
+        assert_eq!(decode_stored(&raw), Some((Encoding::Lz4, 42)));
+        assert_eq!(decode_stored("key=/key\nmd5=00\n"), None);
+        assert_eq!(decode_stored("encoding=zstd\n"), None);
+    }
+
+    #[test]
+    fn test_compress_accepts() {
+        assert!(accepts("zstd", Encoding::Zstd));
+        assert!(accepts("gzip, ZSTD;q=0.5", Encoding::Zstd));
+        assert!(accepts("gzip, *", Encoding::Lz4));
+        assert!(!accepts("gzip, br", Encoding::Zstd));
+        assert!(!accepts("zstd;q=0", Encoding::Zstd));
+        assert!(!accepts("*, lz4;q=0", Encoding::Lz4));
+        assert!(!accepts("", Encoding::Zstd));
+    }
+
+    #[test]
+    fn test_compress_round_trip() {
+        let value = "{\"level\": \"info\"}\n".repeat(100);
+
+        for encoding in [Encoding::Zstd, Encoding::Lz4].iter().cloned() {
+            let mut stored = Vec::new();
+            let size = compress(encoding, &mut value.as_bytes(), &mut stored).unwrap();
+            assert_eq!(size, value.len() as u64);
+            assert!(stored.len() < value.len());
+
+            let mut restored = String::new();
+            decompress(encoding, &stored[..])
+                .unwrap()
+                .read_to_string(&mut restored)
+                .unwrap();
+            assert_eq!(restored, value);
+        }
+    }
+}
@@ -0,0 +1,110 @@
+//! # deduplication
+//!
+//! Volume servers started with `--dedup` store identical values once. Values
+//! are kept by the SHA-256 digest of their content under `<data_dir>/blobs`,
+//! and keys are hard links to them, so that the link count of a blob is the
+//! number of keys referring to it plus one. Uploading a value that is already
+//! stored only adds a link, deleting or overwriting a key removes the blob
+//! along with its last reference.
+//!
+//! ```sh
+//! volume -p 7000 -d /tmp/kalavarastore --dedup
+//! ```
+//!
+//! `data_dir` has to be on a file system supporting hard links. Values stored
+//! before dedup was turned on stay as they are.
+
+use std::fs::{metadata, read_to_string, remove_file};
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use crate::checksum::{to_hex, Checksum};
+use crate::volume::checksum_path;
+
+/// directory under data dir holding content addressed blobs
+pub const BLOBS_DIR: &str = "blobs";
+
+/// path of the blob holding a value with `sha256` digest
+pub(crate) fn blob_path(data_dir: &Path, sha256: &[u8]) -> PathBuf {
+    let hex = to_hex(sha256);
+
+    data_dir
+        .join(BLOBS_DIR)
+        .join(&hex[0..1])
+        .join(&hex[1..2])
+        .join(&hex[2..])
+}
+
+/// blob a stored value is a link to, `None` if it is a file of its own
+pub(crate) fn linked_blob(data_dir: &Path, path: &Path) -> Option<PathBuf> {
+    let raw = read_to_string(checksum_path(path)).ok()?;
+    let blob = blob_path(data_dir, &Checksum::decode(&raw)?.sha256);
+
+    match (metadata(path), metadata(&blob)) {
+        (Ok(value), Ok(linked)) if value.dev() == linked.dev() && value.ino() == linked.ino() => {
+            Some(blob)
+        }
+        _ => None,
+    }
+}
+
+/// removes a blob no key links to anymore, returns whether it was removed
+pub(crate) fn release(blob: &Path) -> io::Result<bool> {
+    if metadata(blob)?.nlink() > 1 {
+        return Ok(false);
+    }
+
+    remove_file(blob)?;
+    let _ = remove_file(checksum_path(blob));
+    Ok(true)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs::{create_dir_all, hard_link, write};
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_dedup_links() {
+        let data_dir = tempdir().unwrap();
+        let checksum = Checksum {
+            md5: [0; 16],
+            sha256: [0xab; 32],
+        };
+
+        let blob = blob_path(data_dir.path(), &checksum.sha256);
+        assert!(blob.starts_with(data_dir.path().join(BLOBS_DIR).join("a").join("b")));
+
+        create_dir_all(blob.parent().unwrap()).unwrap();
+        write(&blob, "value").unwrap();
+        write(checksum_path(&blob), checksum.encode()).unwrap();
+
+        let keys: Vec<PathBuf> = ["first", "second"]
+            .iter()
+            .map(|name| data_dir.path().join(name))
+            .collect();
+        for key in keys.iter() {
+            hard_link(&blob, key).unwrap();
+            write(checksum_path(key), checksum.encode()).unwrap();
+        }
+
+        // a copy with the same digests is not a link
+        let copy = data_dir.path().join("copy");
+        write(&copy, "value").unwrap();
+        write(checksum_path(&copy), checksum.encode()).unwrap();
+
+        assert_eq!(linked_blob(data_dir.path(), &keys[0]), Some(blob.clone()));
+        assert_eq!(linked_blob(data_dir.path(), &copy), None);
+
+        assert!(!release(&blob).unwrap());
+        remove_file(&keys[0]).unwrap();
+        assert!(!release(&blob).unwrap());
+        remove_file(&keys[1]).unwrap();
+
+        assert!(release(&blob).unwrap());
+        assert!(!blob.exists());
+        assert!(!checksum_path(&blob).exists());
+    }
+}
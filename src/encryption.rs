@@ -0,0 +1,483 @@
+//! Encryption of stored values.
+//!
+//! Volume servers started with `--encryption-key-file` encrypt values before
+//! they reach the disk. Each value is encrypted with a random data key, kept
+//! in its `.sum` file wrapped (encrypted) by a master key from the key file.
+//! Values are split in chunks of 64 KiB encrypted with AES-256-GCM, each with
+//! its own tag, so that they are verified while streamed. Compressed values
+//! are compressed before they are encrypted.
+//!
+//! The key file holds a master key per line, as `<id>:<64 hex digits>`
+//!
+//! ```sh
+//! echo "2019-06:$(openssl rand -hex 32)" > /etc/kalavara/keys
+//! ```
+//!
+//! The first key wraps data keys of new values, the others unwrap keys of
+//! values stored before. Master keys are rotated by adding a new first line,
+//! restarting the volume and calling `POST /admin/rewrap-keys`, which wraps
+//! the data keys of all values with the new key. Retired keys can be removed
+//! from the file once it is done.
+
+use openssl::rand::rand_bytes;
+use openssl::symm::{decrypt_aead, encrypt_aead, Cipher};
+
+use std::collections::HashMap;
+use std::fs::read_to_string;
+use std::io::{self, Error, ErrorKind, Read, Write};
+use std::path::Path;
+
+use crate::checksum::{from_hex, to_hex};
+
+/// bytes of a value encrypted under one tag
+const CHUNK_SIZE: usize = 64 * 1024;
+
+const TAG_SIZE: usize = 16;
+const NONCE_SIZE: usize = 12;
+const KEY_SIZE: usize = 32;
+
+/// Key encrypting a value, or wrapping data keys
+pub(crate) type Key = [u8; KEY_SIZE];
+
+/// Master keys of a volume server
+#[derive(Clone)]
+pub struct KeyRing {
+    /// id of the key wrapping new data keys
+    current: String,
+
+    keys: HashMap<String, Key>,
+}
+
+impl KeyRing {
+    /// loads master keys from a key file
+    pub fn load(path: &Path) -> io::Result<KeyRing> {
+        KeyRing::parse(&read_to_string(path)?).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+    }
+
+    /// parses `<id>:<hex key>` lines, the first one being the current key.
+    /// blank lines and lines starting with `#` are skipped
+    pub fn parse(raw: &str) -> Result<KeyRing, String> {
+        let mut current = None;
+        let mut keys = HashMap::new();
+
+        let lines = raw
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'));
+
+        for line in lines {
+            // key material is kept out of errors
+            let (id, hex) = match line.find(':') {
+                Some(indx) => (&line[..indx], &line[indx + 1..]),
+                None => return Err("key lines should be <id>:<hex key>".to_owned()),
+            };
+
+            let mut key = [0; KEY_SIZE];
+            if id.is_empty() || id.contains('=') || !from_hex(hex, &mut key) {
+                return Err(format!("invalid key {}", id));
+            }
+
+            if keys.insert(id.to_owned(), key).is_some() {
+                return Err(format!("duplicate key {}", id));
+            }
+            current.get_or_insert_with(|| id.to_owned());
+        }
+
+        match current {
+            Some(current) => Ok(KeyRing { current, keys }),
+            None => Err("no keys in key file".to_owned()),
+        }
+    }
+
+    /// creates a data key for a new value. returns it with the `.sum` file
+    /// lines recording it wrapped
+    pub(crate) fn new_data_key(&self) -> io::Result<(Key, String)> {
+        let mut key = [0; KEY_SIZE];
+        rand_bytes(&mut key)?;
+
+        let lines = self.wrap(&key)?;
+        Ok((key, lines))
+    }
+
+    /// encrypts a data key with the current master key. the key id is
+    /// authenticated along, so that wrapped keys can not be moved between ids
+    fn wrap(&self, key: &Key) -> io::Result<String> {
+        let mut nonce = [0; NONCE_SIZE];
+        rand_bytes(&mut nonce)?;
+
+        let mut tag = [0; TAG_SIZE];
+        let sealed = encrypt_aead(
+            Cipher::aes_256_gcm(),
+            &self.keys[&self.current],
+            Some(&nonce),
+            self.current.as_bytes(),
+            key,
+            &mut tag,
+        )?;
+
+        Ok(format!(
+            "key_id={}\nwrapped_key={}{}{}\n",
+            self.current,
+            to_hex(&nonce),
+            to_hex(&sealed),
+            to_hex(&tag)
+        ))
+    }
+
+    /// data key of a value from its `.sum` file, `None` for values stored
+    /// unencrypted
+    pub(crate) fn data_key(&self, raw: &str) -> io::Result<Option<Key>> {
+        let (id, hex) = match wrapped_key(raw) {
+            Some(wrapped) => wrapped,
+            None => return Ok(None),
+        };
+
+        let master = match self.keys.get(id) {
+            Some(master) => master,
+            None => return Err(invalid(&format!("unknown master key {}", id))),
+        };
+
+        let mut wrapped = [0; NONCE_SIZE + KEY_SIZE + TAG_SIZE];
+        if !from_hex(hex, &mut wrapped) {
+            return Err(invalid("invalid wrapped key"));
+        }
+
+        let (nonce, sealed) = wrapped.split_at(NONCE_SIZE);
+        let (sealed, tag) = sealed.split_at(KEY_SIZE);
+
+        let plain = decrypt_aead(
+            Cipher::aes_256_gcm(),
+            master,
+            Some(nonce),
+            id.as_bytes(),
+            sealed,
+            tag,
+        )
+        .map_err(|_| invalid("wrapped key does not match its master key"))?;
+
+        let mut key = [0; KEY_SIZE];
+        key.copy_from_slice(&plain);
+        Ok(Some(key))
+    }
+
+    /// `.sum` file with the data key wrapped by the current master key,
+    /// `None` if it already is or the value is not encrypted
+    pub(crate) fn rewrap(&self, raw: &str) -> io::Result<Option<String>> {
+        match wrapped_key(raw) {
+            Some((id, _)) if id != self.current => {}
+            _ => return Ok(None),
+        }
+
+        let key = match self.data_key(raw)? {
+            Some(key) => key,
+            None => return Ok(None),
+        };
+
+        let mut rewrapped: String = raw
+            .lines()
+            .filter(|line| !line.starts_with("key_id=") && !line.starts_with("wrapped_key="))
+            .map(|line| format!("{}\n", line))
+            .collect();
+        rewrapped.push_str(&self.wrap(&key)?);
+
+        Ok(Some(rewrapped))
+    }
+}
+
+/// checks whether a `.sum` file records an encrypted value
+pub(crate) fn is_encrypted(raw: &str) -> bool {
+    wrapped_key(raw).is_some()
+}
+
+/// id of the master key and the wrapped data key in a `.sum` file
+fn wrapped_key(raw: &str) -> Option<(&str, &str)> {
+    Some((field(raw, "key_id=")?, field(raw, "wrapped_key=")?))
+}
+
+fn field<'a>(raw: &'a str, name: &str) -> Option<&'a str> {
+    raw.lines()
+        .find(|line| line.starts_with(name))
+        .map(|line| &line[name.len()..])
+}
+
+fn invalid(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+/// nonce of a chunk. data keys encrypt a single value, so chunks are told
+/// apart by their number. marking the last chunk makes truncation detectable
+fn chunk_nonce(indx: u64, last: bool) -> [u8; NONCE_SIZE] {
+    let mut nonce = [0; NONCE_SIZE];
+    nonce[3..11].copy_from_slice(&indx.to_be_bytes());
+    nonce[11] = last as u8;
+    nonce
+}
+
+/// size of a value encrypted into `len` bytes
+pub(crate) fn plain_size(len: u64) -> u64 {
+    let sealed_chunk = (CHUNK_SIZE + TAG_SIZE) as u64;
+    let chunks = len.div_ceil(sealed_chunk).max(1);
+
+    len.saturating_sub(chunks * TAG_SIZE as u64)
+}
+
+/// Encrypts a value written to it into `inner`
+pub(crate) struct Encryptor<W: Write> {
+    inner: W,
+    key: Key,
+
+    /// plaintext of the chunk being filled
+    chunk: Vec<u8>,
+
+    /// number of the chunk being filled
+    indx: u64,
+}
+
+impl<W: Write> Encryptor<W> {
+    pub fn new(inner: W, key: &Key) -> Self {
+        Encryptor {
+            inner,
+            key: *key,
+            chunk: Vec::with_capacity(CHUNK_SIZE),
+            indx: 0,
+        }
+    }
+
+    fn seal(&mut self, last: bool) -> io::Result<()> {
+        let mut tag = [0; TAG_SIZE];
+        let sealed = encrypt_aead(
+            Cipher::aes_256_gcm(),
+            &self.key,
+            Some(&chunk_nonce(self.indx, last)),
+            &[],
+            &self.chunk,
+            &mut tag,
+        )?;
+
+        self.inner.write_all(&sealed)?;
+        self.inner.write_all(&tag)?;
+
+        self.chunk.clear();
+        self.indx += 1;
+        Ok(())
+    }
+
+    /// encrypts the last chunk, which is empty for empty values
+    pub fn finish(mut self) -> io::Result<W> {
+        self.seal(true)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for Encryptor<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        // full chunks are sealed once more data follows, the last one is
+        // sealed by `finish`
+        if self.chunk.len() == CHUNK_SIZE && !data.is_empty() {
+            self.seal(false)?;
+        }
+
+        let len = data.len().min(CHUNK_SIZE - self.chunk.len());
+        self.chunk.extend_from_slice(&data[..len]);
+        Ok(len)
+    }
+
+    /// chunks are written whole, so nothing is flushed before `finish`
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Decrypts a value read from `inner`. chunks failing verification, and
+/// values cut short, give `InvalidData` errors
+pub(crate) struct Decryptor<R: Read> {
+    inner: R,
+    key: Key,
+
+    /// encrypted bytes read ahead
+    sealed: Vec<u8>,
+
+    /// plaintext of the current chunk and the offset read up to
+    chunk: Vec<u8>,
+    pos: usize,
+
+    /// number of the next chunk
+    indx: u64,
+
+    /// the last chunk was decrypted
+    done: bool,
+}
+
+impl<R: Read> Decryptor<R> {
+    pub fn new(inner: R, key: &Key) -> Self {
+        Decryptor {
+            inner,
+            key: *key,
+            sealed: Vec::with_capacity(CHUNK_SIZE + TAG_SIZE + 1),
+            chunk: Vec::new(),
+            pos: 0,
+            indx: 0,
+            done: false,
+        }
+    }
+
+    fn open_chunk(&mut self) -> io::Result<()> {
+        // a byte past a full chunk tells whether it is the last one
+        let wanted = CHUNK_SIZE + TAG_SIZE + 1;
+
+        while self.sealed.len() < wanted {
+            let filled = self.sealed.len();
+            self.sealed.resize(wanted, 0);
+
+            let read = match self.inner.read(&mut self.sealed[filled..]) {
+                Ok(read) => read,
+                Err(e) => {
+                    self.sealed.truncate(filled);
+                    return Err(e);
+                }
+            };
+
+            self.sealed.truncate(filled + read);
+            if read == 0 {
+                break;
+            }
+        }
+
+        let last = self.sealed.len() < wanted;
+        let len = if last { self.sealed.len() } else { wanted - 1 };
+        if len < TAG_SIZE {
+            return Err(invalid("encrypted value is truncated"));
+        }
+
+        let (sealed, tag) = self.sealed[..len].split_at(len - TAG_SIZE);
+        self.chunk = decrypt_aead(
+            Cipher::aes_256_gcm(),
+            &self.key,
+            Some(&chunk_nonce(self.indx, last)),
+            &[],
+            sealed,
+            tag,
+        )
+        .map_err(|_| invalid("encrypted value failed verification"))?;
+
+        self.sealed.drain(..len);
+        self.pos = 0;
+        self.indx += 1;
+        self.done = last;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for Decryptor<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.chunk.len() {
+            if self.done {
+                return Ok(0);
+            }
+            self.open_chunk()?;
+        }
+
+        let len = buf.len().min(self.chunk.len() - self.pos);
+        buf[..len].copy_from_slice(&self.chunk[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const KEYS: &str = "# rotated yearly\n\
+                        new:000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f\n\
+                        \n\
+                        old:1f1e1d1c1b1a191817161514131211100f0e0d0c0b0a09080706050403020100\n";
+
+    fn encrypt(value: &[u8], key: &Key) -> Vec<u8> {
+        let mut encryptor = Encryptor::new(Vec::new(), key);
+        encryptor.write_all(value).unwrap();
+        encryptor.finish().unwrap()
+    }
+
+    fn decrypt(sealed: &[u8], key: &Key) -> io::Result<Vec<u8>> {
+        let mut value = Vec::new();
+        Decryptor::new(sealed, key).read_to_end(&mut value)?;
+        Ok(value)
+    }
+
+    #[test]
+    fn test_encryption_chunks() {
+        let key = [7; KEY_SIZE];
+
+        for size in [
+            0,
+            1,
+            CHUNK_SIZE - 1,
+            CHUNK_SIZE,
+            CHUNK_SIZE + 1,
+            3 * CHUNK_SIZE,
+        ]
+        .iter()
+        {
+            let value: Vec<u8> = (0..*size).map(|indx| (indx % 251) as u8).collect();
+            let sealed = encrypt(&value, &key);
+
+            assert_eq!(plain_size(sealed.len() as u64), *size as u64);
+            assert_eq!(decrypt(&sealed, &key).unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn test_encryption_tampering() {
+        let key = [7; KEY_SIZE];
+        let value = "value".repeat(CHUNK_SIZE / 2);
+        let sealed = encrypt(value.as_bytes(), &key);
+
+        // no plaintext in the output
+        assert!(!sealed.windows(5).any(|window| window == b"value"));
+
+        let mut flipped = sealed.clone();
+        flipped[10] ^= 1;
+        let err = decrypt(&flipped, &key).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+        // cut at a chunk boundary
+        let cut = &sealed[..CHUNK_SIZE + TAG_SIZE];
+        assert!(decrypt(cut, &key).is_err());
+
+        assert!(decrypt(&sealed, &[8; KEY_SIZE]).is_err());
+    }
+
+    #[test]
+    fn test_encryption_keys() {
+        let keys = KeyRing::parse(KEYS).unwrap();
+        assert_eq!(keys.current, "new");
+        assert_eq!(keys.keys.len(), 2);
+
+        assert!(KeyRing::parse("").is_err());
+        assert!(KeyRing::parse("a:00").is_err());
+        assert!(KeyRing::parse("0001020304").is_err());
+        assert!(KeyRing::parse(&format!("{}{}", KEYS, KEYS)).is_err());
+
+        let (key, lines) = keys.new_data_key().unwrap();
+        let raw = format!("key=/key\n{}md5=00\n", lines);
+        assert!(is_encrypted(&raw));
+        assert_eq!(keys.data_key(&raw).unwrap(), Some(key));
+        assert_eq!(keys.data_key("key=/key\n").unwrap(), None);
+        assert_eq!(keys.rewrap(&raw).unwrap(), None);
+
+        // values stored under a retired key are rewrapped with the current one
+        let old = KeyRing::parse(&KEYS[KEYS.find("old:").unwrap()..]).unwrap();
+        let (key, lines) = old.new_data_key().unwrap();
+        let raw = format!("key=/key\n{}md5=00\n", lines);
+        assert!(keys
+            .data_key(&raw.replace("key_id=old", "key_id=new"))
+            .is_err());
+
+        let rewrapped = keys.rewrap(&raw).unwrap().unwrap();
+        assert!(rewrapped.starts_with("key=/key\nmd5=00\nkey_id=new\n"));
+        assert_eq!(keys.data_key(&rewrapped).unwrap(), Some(key));
+        assert!(old.data_key(&rewrapped).is_err());
+    }
+}
@@ -0,0 +1,804 @@
+//! # erasure coding
+//!
+//! Buckets created with `erasure=<k>+<m>` store values as `k` data shards and
+//! `m` parity shards on `k + m` distinct volume servers instead of full
+//! replicas. Master splits values when they are uploaded and rebuilds them
+//! from any `k` shards when they are read, so up to `m` shards can be lost.
+//! Values are sent to master itself, which answers uploads with 201 and
+//! serves reads. Uploads need a `Content-Length`, as shards are streamed to
+//! volumes while the value is coded a stripe of `k` times 64 KiB at a time.
+//!
+//! ```sh
+//! curl -XPOST -d "name=archive&erasure=4+2" http://localhost:6000/admin/create-bucket
+//! curl -XPUT --data-binary @file http://localhost:6000/bucket/archive/key
+//! ```
+//!
+//! replicated values of a bucket are converted in background once the bucket
+//! has an erasure setting
+//!
+//! ```sh
+//! curl -XPOST -d "name=logs&replicas=2&erasure=4+2" http://localhost:6000/admin/create-bucket
+//! curl -XPOST -d logs http://localhost:6000/admin/convert-erasure
+//! ```
+//!
+//! Shards are Reed-Solomon coded over GF(2^8) with a systematic Vandermonde
+//! matrix, data shards hold the value itself. Each shard holds its piece of
+//! every stripe in turn, values coded by earlier versions are a single stripe.
+
+use std::io::{self, Read, Write};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+
+use crate::http;
+use crate::multipart::upload_id;
+use crate::tls::TlsConfig;
+
+/// bytes each shard holds of a stripe
+pub(crate) const STRIPE_SIZE: u64 = 64 * 1024;
+
+/// blobs of shards are stored on volumes as if in this bucket, which is not a
+/// valid bucket name
+const SHARDS_BUCKET: &str = "~shards";
+
+/// creates an id for shards of a value
+pub(crate) fn shards_id() -> String {
+    upload_id()
+}
+
+/// name of the blob of a shard on volume servers
+pub(crate) fn shard_blob(id: &str, index: usize) -> String {
+    format!("{}/{}/{}", SHARDS_BUCKET, id, index)
+}
+
+/// checks whether a blob is a shard
+pub(crate) fn is_shard_blob(blob: &str) -> bool {
+    blob.starts_with(SHARDS_BUCKET) && blob[SHARDS_BUCKET.len()..].starts_with('/')
+}
+
+/// parses an erasure setting `<k>+<m>`
+pub(crate) fn parse_setting(setting: &str) -> Result<(usize, usize), String> {
+    let invalid = || format!("invalid erasure setting {}", setting);
+
+    let indx = setting.find('+').ok_or_else(invalid)?;
+    let data = setting[..indx].parse::<usize>().map_err(|_| invalid())?;
+    let parity = setting[indx + 1..]
+        .parse::<usize>()
+        .map_err(|_| invalid())?;
+
+    if data == 0 || parity == 0 || data + parity > 256 {
+        return Err(invalid());
+    }
+
+    Ok((data, parity))
+}
+
+/// GF(2^8) with generator polynomial x^8 + x^4 + x^3 + x^2 + 1
+struct Field {
+    exp: [u8; 512],
+    log: [u8; 256],
+}
+
+impl Field {
+    fn new() -> Self {
+        let mut field = Field {
+            exp: [0; 512],
+            log: [0; 256],
+        };
+
+        let mut x: u16 = 1;
+        for indx in 0..255 {
+            field.exp[indx] = x as u8;
+            field.log[x as usize] = indx as u8;
+
+            x <<= 1;
+            if x & 0x100 != 0 {
+                x ^= 0x11d;
+            }
+        }
+
+        for indx in 255..512 {
+            field.exp[indx] = field.exp[indx - 255];
+        }
+
+        field
+    }
+
+    fn mul(&self, a: u8, b: u8) -> u8 {
+        if a == 0 || b == 0 {
+            return 0;
+        }
+
+        self.exp[self.log[a as usize] as usize + self.log[b as usize] as usize]
+    }
+
+    fn div(&self, a: u8, b: u8) -> u8 {
+        if a == 0 {
+            return 0;
+        }
+
+        self.exp[self.log[a as usize] as usize + 255 - self.log[b as usize] as usize]
+    }
+
+    fn pow(&self, a: u8, n: usize) -> u8 {
+        if n == 0 {
+            return 1;
+        }
+
+        if a == 0 {
+            return 0;
+        }
+
+        self.exp[(self.log[a as usize] as usize * n) % 255]
+    }
+
+    /// `out ^= coefficient * input`, bytewise
+    fn mul_add(&self, coefficient: u8, input: &[u8], out: &mut [u8]) {
+        if coefficient == 0 {
+            return;
+        }
+
+        let mut table = [0u8; 256];
+        for (x, product) in table.iter_mut().enumerate() {
+            *product = self.mul(coefficient, x as u8);
+        }
+
+        for (out, input) in out.iter_mut().zip(input) {
+            *out ^= table[*input as usize];
+        }
+    }
+}
+
+type Matrix = Vec<Vec<u8>>;
+
+fn multiply(field: &Field, a: &[Vec<u8>], b: &[Vec<u8>]) -> Matrix {
+    a.iter()
+        .map(|row| {
+            (0..b[0].len())
+                .map(|col| {
+                    row.iter()
+                        .zip(b)
+                        .fold(0, |acc, (x, b_row)| acc ^ field.mul(*x, b_row[col]))
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// inverts a square matrix by gauss-jordan elimination, `None` if singular
+fn invert(field: &Field, matrix: &[Vec<u8>]) -> Option<Matrix> {
+    let size = matrix.len();
+
+    // matrix augmented with identity
+    let mut work: Matrix = matrix
+        .iter()
+        .enumerate()
+        .map(|(indx, row)| {
+            let mut row = row.clone();
+            row.extend((0..size).map(|col| (col == indx) as u8));
+            row
+        })
+        .collect();
+
+    for col in 0..size {
+        let pivot = (col..size).find(|&row| work[row][col] != 0)?;
+        work.swap(col, pivot);
+
+        let scale = work[col][col];
+        for value in work[col].iter_mut() {
+            *value = field.div(*value, scale);
+        }
+
+        for row in 0..size {
+            let factor = work[row][col];
+            if row == col || factor == 0 {
+                continue;
+            }
+
+            let pivot_row = work[col].clone();
+            for (value, pivot) in work[row].iter_mut().zip(pivot_row) {
+                *value ^= field.mul(factor, pivot);
+            }
+        }
+    }
+
+    Some(work.into_iter().map(|row| row[size..].to_vec()).collect())
+}
+
+/// Reed-Solomon coder for `data` data and `parity` parity shards
+pub(crate) struct Codec {
+    data: usize,
+    parity: usize,
+    field: Field,
+
+    /// rows producing each shard from data shards, top rows are identity
+    matrix: Matrix,
+}
+
+impl Codec {
+    pub fn new(data: usize, parity: usize) -> Codec {
+        assert!(data > 0 && parity > 0 && data + parity <= 256);
+        let field = Field::new();
+
+        // any `data` rows of a vandermonde matrix are independent, which
+        // stays true after making the top rows identity
+        let vandermonde: Matrix = (0..data + parity)
+            .map(|row| (0..data).map(|col| field.pow(row as u8, col)).collect())
+            .collect();
+        let top = invert(&field, &vandermonde[..data]).unwrap();
+        let matrix = multiply(&field, &vandermonde, &top);
+
+        Codec {
+            data,
+            parity,
+            field,
+            matrix,
+        }
+    }
+
+    /// stripes of a value of `size` bytes, coded `stripe` bytes per shard at
+    /// a time or as a whole if `None`
+    pub fn stripes(&self, size: u64, stripe: Option<u64>) -> Stripes {
+        Stripes {
+            remaining: size,
+            width: stripe.map_or(u64::max_value(), |stripe| stripe * self.data as u64),
+            data: self.data as u64,
+            first: true,
+        }
+    }
+
+    /// size of each shard of a value of `size` bytes
+    pub fn shard_size(&self, size: u64, stripe: Option<u64>) -> u64 {
+        self.stripes(size, stripe).map(|(_, shard)| shard).sum()
+    }
+
+    /// splits a value into data shards, zero padded to equal size, followed by
+    /// parity shards
+    pub fn encode(&self, value: &[u8]) -> Vec<Vec<u8>> {
+        let shard_size = value.len().div_ceil(self.data).max(1);
+
+        let mut shards: Vec<Vec<u8>> = (0..self.data)
+            .map(|indx| {
+                let start = (indx * shard_size).min(value.len());
+                let end = (start + shard_size).min(value.len());
+
+                let mut shard = value[start..end].to_vec();
+                shard.resize(shard_size, 0);
+                shard
+            })
+            .collect();
+
+        for row in self.data..self.data + self.parity {
+            let mut shard = vec![0; shard_size];
+            for (coefficient, input) in self.matrix[row].iter().zip(shards.iter()) {
+                self.field.mul_add(*coefficient, input, &mut shard);
+            }
+            shards.push(shard);
+        }
+
+        shards
+    }
+
+    /// fills in missing shards, returns false if fewer than `data` shards of
+    /// equal size are present
+    pub fn reconstruct(&self, shards: &mut [Option<Vec<u8>>]) -> bool {
+        if shards.len() != self.data + self.parity {
+            return false;
+        }
+
+        let shard_size = match shards.iter().flatten().next() {
+            Some(shard) => shard.len(),
+            None => return false,
+        };
+
+        // shards of another size are damaged
+        for shard in shards.iter_mut() {
+            if shard
+                .as_ref()
+                .map_or(false, |shard| shard.len() != shard_size)
+            {
+                *shard = None;
+            }
+        }
+
+        let present: Vec<usize> = (0..shards.len())
+            .filter(|&indx| shards[indx].is_some())
+            .take(self.data)
+            .collect();
+        if present.len() < self.data {
+            return false;
+        }
+
+        // rows of present shards produce them from data shards, inverted they
+        // produce data shards from present ones
+        if present
+            .iter()
+            .enumerate()
+            .any(|(indx, &shard)| indx != shard)
+        {
+            let rows: Matrix = present
+                .iter()
+                .map(|&indx| self.matrix[indx].clone())
+                .collect();
+            let decode = match invert(&self.field, &rows) {
+                Some(decode) => decode,
+                None => return false,
+            };
+
+            let data: Vec<Vec<u8>> = decode
+                .iter()
+                .map(|row| {
+                    let mut shard = vec![0; shard_size];
+                    for (coefficient, &indx) in row.iter().zip(present.iter()) {
+                        let input = shards[indx].as_ref().unwrap();
+                        self.field.mul_add(*coefficient, input, &mut shard);
+                    }
+                    shard
+                })
+                .collect();
+
+            for (indx, shard) in data.into_iter().enumerate() {
+                shards[indx] = Some(shard);
+            }
+        }
+
+        for row in self.data..self.data + self.parity {
+            if shards[row].is_some() {
+                continue;
+            }
+
+            let mut shard = vec![0; shard_size];
+            for (coefficient, input) in self.matrix[row].iter().zip(shards.iter()) {
+                self.field
+                    .mul_add(*coefficient, input.as_ref().unwrap(), &mut shard);
+            }
+            shards[row] = Some(shard);
+        }
+
+        true
+    }
+
+    /// rebuilds a value of `size` bytes from its shards, missing ones `None`
+    pub fn decode(&self, mut shards: Vec<Option<Vec<u8>>>, size: u64) -> Option<Vec<u8>> {
+        if !self.reconstruct(&mut shards) {
+            return None;
+        }
+
+        let mut value: Vec<u8> = shards
+            .into_iter()
+            .take(self.data)
+            .flat_map(Option::unwrap)
+            .collect();
+
+        if (value.len() as u64) < size {
+            return None;
+        }
+
+        value.truncate(size as usize);
+        Some(value)
+    }
+
+    /// codes `size` bytes of a value a stripe at a time, writing the pieces
+    /// of each shard to its output
+    pub fn encode_stream<W: Write>(
+        &self,
+        value: &mut dyn Read,
+        size: u64,
+        stripe: Option<u64>,
+        outputs: &mut [W],
+    ) -> io::Result<()> {
+        let mut buffer = Vec::new();
+
+        for (len, _) in self.stripes(size, stripe) {
+            buffer.resize(len as usize, 0);
+            value.read_exact(&mut buffer)?;
+
+            for (shard, output) in self.encode(&buffer).iter().zip(outputs.iter_mut()) {
+                output.write_all(shard)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Bytes of the value and of each shard in the stripes of a value
+pub(crate) struct Stripes {
+    remaining: u64,
+    width: u64,
+    data: u64,
+    first: bool,
+}
+
+impl Iterator for Stripes {
+    type Item = (u64, u64);
+
+    fn next(&mut self) -> Option<(u64, u64)> {
+        // empty values have a stripe of padding
+        if self.remaining == 0 && !self.first {
+            return None;
+        }
+        self.first = false;
+
+        let len = self.remaining.min(self.width);
+        self.remaining -= len;
+        Some((len, len.div_ceil(self.data).max(1)))
+    }
+}
+
+/// Reads shards of a value from volume servers a stripe at a time, from as
+/// few of them as needed to rebuild it
+pub(crate) struct StripeReader {
+    codec: Codec,
+
+    /// urls and sizes of shards
+    shards: Vec<(String, u64)>,
+
+    /// shards being read, with the offset they are read at
+    readers: Vec<Option<(Box<dyn Read + Send>, u64)>>,
+
+    /// shards found missing or damaged
+    failed: Vec<bool>,
+
+    stripes: Stripes,
+
+    /// offset of the current stripe in shards
+    offset: u64,
+
+    tls: TlsConfig,
+}
+
+impl StripeReader {
+    /// reads shards at `shards`, leaving out shard `skip`
+    pub fn new(
+        codec: Codec,
+        shards: Vec<(String, u64)>,
+        size: u64,
+        stripe: Option<u64>,
+        skip: Option<usize>,
+        tls: TlsConfig,
+    ) -> Self {
+        let stripes = codec.stripes(size, stripe);
+        let failed = (0..shards.len()).map(|indx| Some(indx) == skip).collect();
+
+        StripeReader {
+            codec,
+            readers: shards.iter().map(|_| None).collect(),
+            shards,
+            failed,
+            stripes,
+            offset: 0,
+            tls,
+        }
+    }
+
+    /// reads the next stripe, returns all its shards with missing ones
+    /// rebuilt and the number of value bytes it holds
+    pub fn next_stripe(&mut self) -> io::Result<Option<(Vec<Vec<u8>>, u64)>> {
+        let (len, size) = match self.stripes.next() {
+            Some(stripe) => stripe,
+            None => return Ok(None),
+        };
+
+        let mut pieces: Vec<Option<Vec<u8>>> = self.shards.iter().map(|_| None).collect();
+        let mut present = 0;
+
+        for (indx, piece) in pieces.iter_mut().enumerate() {
+            if present == self.codec.data {
+                break;
+            }
+            if self.failed[indx] {
+                continue;
+            }
+
+            match self.read_piece(indx, size) {
+                Ok(read) => {
+                    *piece = Some(read);
+                    present += 1;
+                }
+                Err(_) => {
+                    self.failed[indx] = true;
+                    self.readers[indx] = None;
+                }
+            }
+        }
+        self.offset += size;
+
+        if !self.codec.reconstruct(&mut pieces) {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "too few shards to rebuild value",
+            ));
+        }
+
+        Ok(Some((pieces.into_iter().flatten().collect(), len)))
+    }
+
+    /// reads `size` bytes of shard `indx` at the current stripe
+    fn read_piece(&mut self, indx: usize, size: u64) -> io::Result<Vec<u8>> {
+        // shards are opened when first needed, readers left behind are
+        // opened again where the stripe starts
+        let behind = match self.readers[indx] {
+            Some((_, offset)) => offset != self.offset,
+            None => true,
+        };
+        if behind {
+            self.readers[indx] = Some((self.open(indx)?, self.offset));
+        }
+
+        let (reader, offset) = self.readers[indx].as_mut().unwrap();
+        let mut piece = vec![0; size as usize];
+        reader.read_exact(&mut piece)?;
+        *offset += size;
+
+        Ok(piece)
+    }
+
+    /// opens shard `indx` at the current stripe
+    fn open(&self, indx: usize) -> io::Result<Box<dyn Read + Send>> {
+        let (ref url, size) = self.shards[indx];
+
+        let range = format!("bytes={}-", self.offset);
+        let headers: Vec<(&str, &str)> = match self.offset {
+            0 => vec![],
+            _ => vec![("Range", &range)],
+        };
+
+        // shards of another size are damaged
+        let (resp, body) = http::open("GET", url, &headers, &self.tls)?;
+        let length = resp
+            .header("Content-Length")
+            .and_then(|length| length.parse::<u64>().ok());
+
+        match resp.status_code {
+            200 | 206 if length == Some(size - self.offset) => Ok(body),
+            _ => Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("failed to fetch shard {}", url),
+            )),
+        }
+    }
+}
+
+/// Reader of an erasure coded value, rebuilt a stripe at a time
+pub(crate) struct CodedReader {
+    stripes: StripeReader,
+    buffer: Vec<u8>,
+    pos: usize,
+}
+
+impl CodedReader {
+    /// reads the first stripe, so that values too damaged to be rebuilt
+    /// fail before anything is sent
+    pub fn open(stripes: StripeReader) -> io::Result<Self> {
+        let mut reader = CodedReader {
+            stripes,
+            buffer: Vec::new(),
+            pos: 0,
+        };
+        reader.fill()?;
+
+        Ok(reader)
+    }
+
+    /// rebuilds the next stripe, returns false at the end of the value
+    fn fill(&mut self) -> io::Result<bool> {
+        let (shards, len) = match self.stripes.next_stripe()? {
+            Some(stripe) => stripe,
+            None => return Ok(false),
+        };
+
+        let shards = shards.into_iter().map(Some).collect();
+        self.buffer = self
+            .stripes
+            .codec
+            .decode(shards, len)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "failed to rebuild value"))?;
+        self.pos = 0;
+
+        Ok(true)
+    }
+}
+
+impl Read for CodedReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.buffer.len() {
+            if buf.is_empty() || !self.fill()? {
+                return Ok(0);
+            }
+        }
+
+        let len = buf.len().min(self.buffer.len() - self.pos);
+        buf[..len].copy_from_slice(&self.buffer[self.pos..self.pos + len]);
+        self.pos += len;
+
+        Ok(len)
+    }
+}
+
+/// creates a pipe handing data written on one thread to a reader on another,
+/// at most a write ahead
+pub(crate) fn pipe() -> (PipeWriter, PipeReader) {
+    let (sender, receiver) = sync_channel(1);
+
+    (
+        PipeWriter(sender),
+        PipeReader {
+            receiver,
+            buffer: Vec::new(),
+            pos: 0,
+        },
+    )
+}
+
+/// Writing end of a pipe, the reader sees the end once it is dropped
+pub(crate) struct PipeWriter(SyncSender<Vec<u8>>);
+
+impl Write for PipeWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0
+            .send(buf.to_vec())
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "pipe reader gone"))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Reading end of a pipe
+pub(crate) struct PipeReader {
+    receiver: Receiver<Vec<u8>>,
+    buffer: Vec<u8>,
+    pos: usize,
+}
+
+impl Read for PipeReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.buffer.len() {
+            match self.receiver.recv() {
+                Ok(data) => {
+                    self.buffer = data;
+                    self.pos = 0;
+                }
+                Err(_) => return Ok(0),
+            }
+        }
+
+        let len = buf.len().min(self.buffer.len() - self.pos);
+        buf[..len].copy_from_slice(&self.buffer[self.pos..self.pos + len]);
+        self.pos += len;
+
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_field() {
+        let field = Field::new();
+
+        for a in 1..=255u8 {
+            assert_eq!(field.div(field.mul(a, 7), 7), a);
+            assert_eq!(field.mul(a, field.div(1, a)), 1);
+        }
+        assert_eq!(field.mul(2, 0x80), 0x1d);
+        assert_eq!(field.pow(2, 8), 0x1d);
+    }
+
+    #[test]
+    fn test_erasure_codec() {
+        let codec = Codec::new(4, 2);
+        let value: Vec<u8> = (0..1001u32).map(|x| (x * 7 % 251) as u8).collect();
+
+        let shards = codec.encode(&value);
+        assert_eq!(shards.len(), 6);
+        assert!(shards.iter().all(|shard| shard.len() == 251));
+
+        // data shards hold the value
+        assert_eq!(&shards[0][..], &value[..251]);
+
+        // any two shards can be lost
+        for first in 0..6 {
+            for second in first..6 {
+                let mut damaged: Vec<Option<Vec<u8>>> = shards.iter().cloned().map(Some).collect();
+                damaged[first] = None;
+                damaged[second] = None;
+
+                let mut rebuilt = damaged.clone();
+                assert!(codec.reconstruct(&mut rebuilt));
+                assert_eq!(
+                    rebuilt,
+                    shards.iter().cloned().map(Some).collect::<Vec<_>>()
+                );
+
+                assert_eq!(codec.decode(damaged, 1001), Some(value.clone()));
+            }
+        }
+
+        let mut damaged: Vec<Option<Vec<u8>>> = shards.into_iter().map(Some).collect();
+        damaged[0] = None;
+        damaged[3] = None;
+        damaged[5] = None;
+        assert_eq!(codec.decode(damaged, 1001), None);
+
+        // empty values have a shard of padding
+        let shards = codec.encode(b"");
+        assert_eq!(shards[0], vec![0]);
+        assert_eq!(
+            codec.decode(shards.into_iter().map(Some).collect(), 0),
+            Some(vec![])
+        );
+    }
+
+    #[test]
+    fn test_erasure_stripes() {
+        let codec = Codec::new(4, 2);
+
+        let stripes: Vec<(u64, u64)> = codec.stripes(1001, Some(100)).collect();
+        assert_eq!(stripes, vec![(400, 100), (400, 100), (201, 51)]);
+        assert_eq!(codec.shard_size(1001, Some(100)), 251);
+        assert_eq!(codec.shard_size(800, Some(100)), 200);
+
+        // values coded before striping are one stripe
+        assert_eq!(codec.shard_size(1001, None), 251);
+        assert_eq!(
+            codec.stripes(0, Some(100)).collect::<Vec<_>>(),
+            vec![(0, 1)]
+        );
+
+        let value: Vec<u8> = (0..1001u32).map(|x| (x * 7 % 251) as u8).collect();
+        let mut shards = vec![Vec::new(); 6];
+        codec
+            .encode_stream(&mut &value[..], 1001, Some(100), &mut shards)
+            .unwrap();
+        assert!(shards.iter().all(|shard| shard.len() == 251));
+
+        // data shards hold a piece of each stripe in turn
+        assert_eq!(&shards[0][..100], &value[..100]);
+        assert_eq!(&shards[0][100..200], &value[400..500]);
+        assert_eq!(&shards[1][200..251], &value[851..902]);
+
+        // values shorter than their size are not coded
+        let mut short = &value[..1000];
+        assert!(codec
+            .encode_stream(&mut short, 1001, Some(100), &mut vec![Vec::new(); 6])
+            .is_err());
+    }
+
+    #[test]
+    fn test_pipe() {
+        let (mut writer, mut reader) = pipe();
+
+        let handle = std::thread::spawn(move || {
+            let mut data = Vec::new();
+            reader.read_to_end(&mut data).unwrap();
+            data
+        });
+
+        writer.write_all(b"erasure ").unwrap();
+        writer.write_all(b"coded").unwrap();
+        drop(writer);
+        assert_eq!(handle.join().unwrap(), b"erasure coded");
+
+        // writes fail once the reader is gone
+        let (mut writer, reader) = pipe();
+        drop(reader);
+        assert!(writer.write_all(b"value").is_err());
+    }
+
+    #[test]
+    fn test_erasure_setting() {
+        assert_eq!(parse_setting("4+2"), Ok((4, 2)));
+        assert!(parse_setting("4").is_err());
+        assert!(parse_setting("0+2").is_err());
+        assert!(parse_setting("4+0").is_err());
+        assert!(parse_setting("200+100").is_err());
+
+        assert_eq!(shard_blob("1f", 3), "~shards/1f/3");
+        assert!(is_shard_blob("~shards/1f/3"));
+        assert!(!is_shard_blob("~shardsx/1f/3"));
+    }
+}
@@ -0,0 +1,485 @@
+//! Minimal blocking http client used for calls between master and volume
+//! servers. `https://` urls are wrapped in tls and verified according to the
+//! given `TlsConfig`.
+
+use std::io::{copy, BufRead, BufReader, Error, ErrorKind, Read, Result, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use crate::tls::{to_io_error, TlsConfig};
+
+/// connect, read and write timeout of a connection
+pub(crate) const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Response of a http request
+#[derive(Debug)]
+pub struct Response {
+    /// http status code
+    pub status_code: u16,
+
+    /// response headers in the order they were received
+    pub headers: Vec<(String, String)>,
+
+    /// response body
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    /// returns value of header `name`, compared case insensitively
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(field, _)| field.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// returns body as utf8 text
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+}
+
+/// splits url into scheme, host, port and path
+pub(crate) fn parse_url(url: &str) -> Result<(bool, String, u16, String)> {
+    let (secure, rest) = if url.starts_with("https://") {
+        (true, &url[8..])
+    } else if url.starts_with("http://") {
+        (false, &url[7..])
+    } else {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "unsupported url scheme",
+        ));
+    };
+
+    let (authority, path) = match rest.find('/') {
+        Some(indx) => (&rest[..indx], &rest[indx..]),
+        None => (rest, "/"),
+    };
+
+    // ipv6 addresses are enclosed in brackets as they contain colons
+    let (host, port) = if authority.starts_with('[') {
+        match authority.find(']') {
+            Some(indx) => (&authority[1..indx], &authority[indx + 1..]),
+            None => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "unterminated ipv6 address",
+                ))
+            }
+        }
+    } else {
+        match authority.rfind(':') {
+            Some(indx) => (&authority[..indx], &authority[indx..]),
+            None => (authority, ""),
+        }
+    };
+
+    let port = match port {
+        "" if secure => 443,
+        "" => 80,
+        port if port.starts_with(':') => port[1..]
+            .parse::<u16>()
+            .map_err(|_| Error::new(ErrorKind::InvalidInput, "invalid port"))?,
+        _ => return Err(Error::new(ErrorKind::InvalidInput, "invalid port")),
+    };
+
+    Ok((secure, host.to_owned(), port, path.to_owned()))
+}
+
+/// percent-encodes bytes that may not appear in a request target, such as
+/// spaces and line breaks. existing escapes are kept as they are
+fn encode_path(path: &str) -> String {
+    let mut encoded = String::with_capacity(path.len());
+    for &byte in path.as_bytes() {
+        match byte {
+            0..=0x20
+            | 0x7f..=0xff
+            | b'"'
+            | b'<'
+            | b'>'
+            | b'\\'
+            | b'^'
+            | b'`'
+            | b'{'
+            | b'|'
+            | b'}' => encoded.push_str(&format!("%{:02X}", byte)),
+            _ => encoded.push(byte as char),
+        }
+    }
+    encoded
+}
+
+#[test]
+fn test_parse_url() {
+    let (secure, host, port, path) = parse_url("https://volume1:7000/store/key").unwrap();
+    assert!(secure);
+    assert_eq!(host, "volume1");
+    assert_eq!(port, 7000);
+    assert_eq!(path, "/store/key");
+
+    let (secure, host, port, path) = parse_url("http://master").unwrap();
+    assert!(!secure);
+    assert_eq!(host, "master");
+    assert_eq!(port, 80);
+    assert_eq!(path, "/");
+
+    let (_, host, port, path) = parse_url("http://[::1]:7000/store/key").unwrap();
+    assert_eq!(host, "::1");
+    assert_eq!(port, 7000);
+    assert_eq!(path, "/store/key");
+
+    let (_, host, port, _) = parse_url("https://[fe80::1]").unwrap();
+    assert_eq!(host, "fe80::1");
+    assert_eq!(port, 443);
+
+    assert!(parse_url("ftp://master").is_err());
+    assert!(parse_url("http://[::1:7000/").is_err());
+    assert!(parse_url("http://[::1]7000/").is_err());
+    assert!(parse_url("http://master:port/").is_err());
+}
+
+#[test]
+fn test_authority() {
+    assert_eq!(authority("volume1", 7000, false), "volume1:7000");
+    assert_eq!(authority("volume1", 80, false), "volume1");
+    assert_eq!(authority("volume1", 443, true), "volume1");
+    assert_eq!(authority("volume1", 80, true), "volume1:80");
+    assert_eq!(authority("::1", 7000, true), "[::1]:7000");
+}
+
+#[test]
+fn test_chunked() {
+    let body = "5;ext=1\r\nhello\r\n7\r\n, world\r\n0\r\nX-Trailer: 1\r\n\r\nnext";
+    let mut reader = std::io::BufReader::new(body.as_bytes());
+    let mut decoded = String::new();
+    Chunked::new(&mut reader)
+        .read_to_string(&mut decoded)
+        .unwrap();
+    assert_eq!(decoded, "hello, world");
+
+    // nothing past the last chunk is consumed
+    let mut rest = String::new();
+    reader.read_to_string(&mut rest).unwrap();
+    assert_eq!(rest, "next");
+
+    for body in &["5\r\nhel", "5\r\nhelloX\r\n0\r\n\r\n", "zz\r\n"] {
+        let mut decoded = Vec::new();
+        assert!(Chunked::new(body.as_bytes())
+            .read_to_end(&mut decoded)
+            .is_err());
+    }
+}
+
+#[test]
+fn test_encode_path() {
+    assert_eq!(encode_path("/store/key?ttl=10"), "/store/key?ttl=10");
+    assert_eq!(encode_path("/a b%20c"), "/a%20b%20c");
+    assert_eq!(
+        encode_path("/key HTTP/1.0\r\nX-Admin: 1"),
+        "/key%20HTTP/1.0%0D%0AX-Admin:%201"
+    );
+    assert_eq!(encode_path("/caf\u{e9}"), "/caf%C3%A9");
+}
+
+#[test]
+fn test_exchange_rejects_line_breaks() {
+    let stream = std::io::Cursor::new(Vec::new());
+    let result = exchange(
+        stream,
+        "GET",
+        "volume",
+        "/key",
+        &[("X-Token", "secret\r\nX-Admin: 1")],
+        &mut &b""[..],
+        0,
+    );
+    match result {
+        Err(err) => assert_eq!(err.kind(), ErrorKind::InvalidInput),
+        Ok(_) => panic!("header with line break was sent"),
+    }
+}
+
+/// Sends a request to `url` and reads the complete response.
+/// Redirects are not followed.
+pub fn request(
+    method: &str,
+    url: &str,
+    headers: &[(&str, &str)],
+    body: &[u8],
+    tls: &TlsConfig,
+) -> Result<Response> {
+    let mut reader = body;
+    request_stream(method, url, headers, &mut reader, body.len() as u64, tls)
+}
+
+/// Sends a request with `length` bytes of body streamed from a reader
+pub fn request_stream(
+    method: &str,
+    url: &str,
+    headers: &[(&str, &str)],
+    body: &mut dyn Read,
+    length: u64,
+    tls: &TlsConfig,
+) -> Result<Response> {
+    let (mut response, mut reader) = send(method, url, headers, body, length, tls, TIMEOUT)?;
+    reader.read_to_end(&mut response.body)?;
+
+    Ok(response)
+}
+
+/// Sends a request without body and returns the response with a reader of
+/// its body, for bodies too large to be held in memory
+pub fn open(
+    method: &str,
+    url: &str,
+    headers: &[(&str, &str)],
+    tls: &TlsConfig,
+) -> Result<(Response, Box<dyn Read + Send>)> {
+    send(method, url, headers, &mut &b""[..], 0, tls, TIMEOUT)
+}
+
+/// connects to the server of `url` and exchanges request and response head,
+/// giving up on connecting, reading or writing after `timeout`
+pub(crate) fn send(
+    method: &str,
+    url: &str,
+    headers: &[(&str, &str)],
+    body: &mut dyn Read,
+    length: u64,
+    tls: &TlsConfig,
+    timeout: Duration,
+) -> Result<(Response, Box<dyn Read + Send>)> {
+    let (secure, host, port, path) = parse_url(url)?;
+
+    let stream = connect(&host, port, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+
+    if secure {
+        let stream = tls
+            .connector()?
+            .connect(&host, stream)
+            .map_err(to_io_error)?;
+        exchange(
+            stream,
+            method,
+            &authority(&host, port, secure),
+            &path,
+            headers,
+            body,
+            length,
+        )
+    } else {
+        exchange(
+            stream,
+            method,
+            &authority(&host, port, secure),
+            &path,
+            headers,
+            body,
+            length,
+        )
+    }
+}
+
+/// value of the `Host` header, with the port unless it is the default one of
+/// the scheme
+fn authority(host: &str, port: u16, secure: bool) -> String {
+    let host = if host.contains(':') {
+        format!("[{}]", host)
+    } else {
+        host.to_owned()
+    };
+
+    match (port, secure) {
+        (443, true) | (80, false) => host,
+        _ => format!("{}:{}", host, port),
+    }
+}
+
+/// connects to the first reachable address of `host`
+fn connect(host: &str, port: u16, timeout: Duration) -> Result<TcpStream> {
+    let mut last_err = Error::new(ErrorKind::NotFound, "host has no address");
+
+    for addr in (host, port).to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_err = e,
+        }
+    }
+
+    Err(last_err)
+}
+
+/// writes request to the stream and parses the response head. HTTP/1.0 is
+/// used so that servers close the connection after responding, chunked
+/// bodies of servers answering with HTTP/1.1 anyway are decoded
+fn exchange<S: Read + Write + Send + 'static>(
+    mut stream: S,
+    method: &str,
+    host: &str,
+    path: &str,
+    headers: &[(&str, &str)],
+    body: &mut dyn Read,
+    length: u64,
+) -> Result<(Response, Box<dyn Read + Send>)> {
+    // line breaks would let values inject further headers or requests
+    let injected = |text: &str| text.contains(|c| c == '\r' || c == '\n');
+    if injected(method) || headers.iter().any(|(f, v)| injected(f) || injected(v)) {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "line break in request header",
+        ));
+    }
+
+    let mut head = format!(
+        "{} {} HTTP/1.0\r\nHost: {}\r\nContent-Length: {}\r\n",
+        method,
+        encode_path(path),
+        host,
+        length
+    );
+
+    for (field, value) in headers {
+        head.push_str(&format!("{}: {}\r\n", field, value));
+    }
+    head.push_str("\r\n");
+
+    stream.write_all(head.as_bytes())?;
+    if copy(&mut body.take(length), &mut stream)? != length {
+        return Err(Error::new(
+            ErrorKind::UnexpectedEof,
+            "body shorter than length",
+        ));
+    }
+    stream.flush()?;
+
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+
+    let status_code = line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|code| code.parse::<u16>().ok())
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "malformed status line"))?;
+
+    let mut response = Response {
+        status_code,
+        headers: Vec::new(),
+        body: Vec::new(),
+    };
+
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            break;
+        }
+
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+
+        if let Some(indx) = header.find(':') {
+            response.headers.push((
+                header[..indx].trim().to_owned(),
+                header[indx + 1..].trim().to_owned(),
+            ));
+        }
+    }
+
+    let chunked = response
+        .header("Transfer-Encoding")
+        .is_some_and(|encoding| encoding.eq_ignore_ascii_case("chunked"));
+
+    // tls peers may close without notifying, read only as much as announced
+    let body: Box<dyn Read + Send> = match response
+        .header("Content-Length")
+        .and_then(|len| len.parse::<u64>().ok())
+    {
+        _ if chunked => Box::new(Chunked::new(reader)),
+        Some(len) => Box::new(reader.take(len)),
+        None => Box::new(reader),
+    };
+
+    Ok((response, body))
+}
+
+/// Reader decoding a body in chunked transfer encoding, trailers are skipped
+struct Chunked<R> {
+    inner: R,
+
+    /// bytes left in the current chunk, `None` once the last one was read
+    left: Option<u64>,
+}
+
+impl<R: BufRead> Chunked<R> {
+    fn new(inner: R) -> Self {
+        Chunked {
+            inner,
+            left: Some(0),
+        }
+    }
+
+    /// reads a line without its line break
+    fn read_line(&mut self) -> Result<String> {
+        let mut line = String::new();
+        if self.inner.read_line(&mut line)? == 0 {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "chunked body ended early",
+            ));
+        }
+
+        Ok(line.trim_end_matches(['\r', '\n']).to_owned())
+    }
+
+    /// reads the size line of the next chunk, and the trailers after the
+    /// last one
+    fn next_chunk(&mut self) -> Result<u64> {
+        let line = self.read_line()?;
+        let size = line.split(';').next().unwrap_or_default().trim();
+        let size = u64::from_str_radix(size, 16)
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "malformed chunk size"))?;
+
+        if size == 0 {
+            while !self.read_line()?.is_empty() {}
+        }
+
+        Ok(size)
+    }
+}
+
+impl<R: BufRead> Read for Chunked<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let left = match self.left {
+            Some(0) => self.next_chunk()?,
+            Some(left) => left,
+            None => return Ok(0),
+        };
+
+        if left == 0 {
+            self.left = None;
+            return Ok(0);
+        }
+
+        let max = buf.len().min(left as usize);
+        let read = self.inner.read(&mut buf[..max])?;
+        if read == 0 && max > 0 {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "chunked body ended early",
+            ));
+        }
+
+        // chunks end with a line break
+        self.left = Some(left - read as u64);
+        if self.left == Some(0) && !self.read_line()?.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidData, "malformed chunk"));
+        }
+
+        Ok(read)
+    }
+}
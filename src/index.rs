@@ -0,0 +1,393 @@
+//! # index backends
+//!
+//! Master keeps its index, records of values along with bucket settings,
+//! quotas and uploads in progress, in an [`IndexStore`](trait.IndexStore.html),
+//! an ordered key value store. Backends are chosen with `--index`
+//!
+//! * `rocksdb` - the default, needs the `rocksdb` cargo feature
+//! * `sled` - pure Rust embedded store, needs the `sled` cargo feature
+//! * `memory` - kept in memory and lost on restart, for tests
+//!
+//! ```sh
+//! master -p 6000 -d /tmp/kalavadb --index sled
+//! ```
+//!
+//! builds without the default features do not link rocksdb
+//!
+//! ```sh
+//! cargo build --no-default-features --features sled
+//! ```
+
+use std::collections::BTreeMap;
+use std::io::{self, Error, ErrorKind};
+use std::sync::{Arc, RwLock};
+
+/// Key and value of an index entry
+pub type Entry = (Box<[u8]>, Box<[u8]>);
+
+/// Entries of an index in key order
+pub type Entries<'a> = Box<dyn Iterator<Item = Entry> + 'a>;
+
+/// Ordered key value store holding the index of master
+pub trait IndexStore: Send + Sync {
+    /// value of a key, `None` if there is none
+    fn get(&self, key: &[u8]) -> io::Result<Option<Vec<u8>>>;
+
+    /// sets the value of a key
+    fn put(&self, key: &[u8], value: &[u8]) -> io::Result<()>;
+
+    /// removes a key, missing keys are ignored
+    fn delete(&self, key: &[u8]) -> io::Result<()>;
+
+    /// entries with keys from `start` on
+    fn scan_from(&self, start: &[u8]) -> Entries<'_>;
+
+    /// applies all changes of a batch, or none of them
+    fn write(&self, batch: Batch) -> io::Result<()>;
+
+    /// all entries
+    fn scan(&self) -> Entries<'_> {
+        self.scan_from(b"")
+    }
+
+    /// entries with keys starting with `prefix`
+    fn scan_prefix(&self, prefix: &[u8]) -> Entries<'_> {
+        let prefix = prefix.to_vec();
+
+        Box::new(
+            self.scan_from(&prefix)
+                .take_while(move |(key, _)| key.starts_with(&prefix)),
+        )
+    }
+}
+
+/// Change to an index
+#[derive(Clone, Debug, PartialEq)]
+pub enum Op {
+    Put(Vec<u8>, Vec<u8>),
+    Delete(Vec<u8>),
+}
+
+/// Changes written to an index at once
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Batch {
+    pub ops: Vec<Op>,
+}
+
+impl Batch {
+    /// sets the value of a key
+    pub fn put(&mut self, key: &[u8], value: &[u8]) {
+        self.ops.push(Op::Put(key.to_vec(), value.to_vec()));
+    }
+
+    /// removes a key
+    pub fn delete(&mut self, key: &[u8]) {
+        self.ops.push(Op::Delete(key.to_vec()));
+    }
+}
+
+/// Index backend of a master
+#[derive(Clone)]
+pub enum Backend {
+    /// rocksdb database in the data directory
+    RocksDb,
+
+    /// sled database in the data directory
+    Sled,
+
+    /// entries kept in memory
+    Memory,
+
+    /// backend provided by the embedder
+    Custom(Arc<dyn IndexStore>),
+}
+
+impl Default for Backend {
+    fn default() -> Self {
+        Backend::RocksDb
+    }
+}
+
+impl Backend {
+    /// Parses backend from its `--index` name
+    pub fn parse(name: &str) -> Result<Backend, String> {
+        match name {
+            "rocksdb" => Ok(Backend::RocksDb),
+            "sled" => Ok(Backend::Sled),
+            "memory" => Ok(Backend::Memory),
+            _ => Err(format!(
+                "invalid index {}, should be rocksdb, sled or memory",
+                name
+            )),
+        }
+    }
+
+    /// opens the backend on a data directory
+    pub(crate) fn open(self, data_dir: &str) -> io::Result<Arc<dyn IndexStore>> {
+        Ok(match self {
+            Backend::RocksDb => open_rocksdb(data_dir)?,
+            Backend::Sled => open_sled(data_dir)?,
+            Backend::Memory => Arc::new(MemoryIndex::default()),
+            Backend::Custom(index) => index,
+        })
+    }
+}
+
+#[cfg(feature = "rocksdb")]
+fn open_rocksdb(data_dir: &str) -> io::Result<Arc<dyn IndexStore>> {
+    Ok(Arc::new(RocksIndex::open(data_dir)?))
+}
+
+#[cfg(not(feature = "rocksdb"))]
+fn open_rocksdb(_data_dir: &str) -> io::Result<Arc<dyn IndexStore>> {
+    Err(unsupported("rocksdb"))
+}
+
+#[cfg(feature = "sled")]
+fn open_sled(data_dir: &str) -> io::Result<Arc<dyn IndexStore>> {
+    Ok(Arc::new(SledIndex::open(data_dir)?))
+}
+
+#[cfg(not(feature = "sled"))]
+fn open_sled(_data_dir: &str) -> io::Result<Arc<dyn IndexStore>> {
+    Err(unsupported("sled"))
+}
+
+/// error opening a backend left out of the build
+#[allow(dead_code)]
+fn unsupported(feature: &str) -> Error {
+    Error::new(
+        ErrorKind::Other,
+        format!("built without the {} feature", feature),
+    )
+}
+
+/// error of a backend as io error
+#[allow(dead_code)]
+fn other(e: impl ToString) -> Error {
+    Error::new(ErrorKind::Other, e.to_string())
+}
+
+/// Index kept in memory
+#[derive(Default)]
+pub struct MemoryIndex {
+    entries: RwLock<BTreeMap<Vec<u8>, Vec<u8>>>,
+}
+
+impl MemoryIndex {
+    pub fn new() -> MemoryIndex {
+        MemoryIndex::default()
+    }
+}
+
+impl IndexStore for MemoryIndex {
+    fn get(&self, key: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        Ok(self.entries.read().unwrap().get(key).cloned())
+    }
+
+    fn put(&self, key: &[u8], value: &[u8]) -> io::Result<()> {
+        let mut entries = self.entries.write().unwrap();
+        entries.insert(key.to_vec(), value.to_vec());
+        Ok(())
+    }
+
+    fn delete(&self, key: &[u8]) -> io::Result<()> {
+        self.entries.write().unwrap().remove(key);
+        Ok(())
+    }
+
+    /// entries are copied, so that the index can change while they are read
+    fn scan_from(&self, start: &[u8]) -> Entries<'_> {
+        let entries: Vec<Entry> = self
+            .entries
+            .read()
+            .unwrap()
+            .range(start.to_vec()..)
+            .map(|(key, value)| (key.clone().into(), value.clone().into()))
+            .collect();
+
+        Box::new(entries.into_iter())
+    }
+
+    fn write(&self, batch: Batch) -> io::Result<()> {
+        let mut entries = self.entries.write().unwrap();
+
+        for op in batch.ops {
+            match op {
+                Op::Put(key, value) => entries.insert(key, value),
+                Op::Delete(key) => entries.remove(&key),
+            };
+        }
+
+        Ok(())
+    }
+}
+
+/// Index in a rocksdb database
+#[cfg(feature = "rocksdb")]
+pub struct RocksIndex {
+    db: rocksdb::DB,
+}
+
+#[cfg(feature = "rocksdb")]
+impl RocksIndex {
+    pub fn open(data_dir: &str) -> io::Result<RocksIndex> {
+        let db = rocksdb::DB::open_default(data_dir).map_err(other)?;
+        Ok(RocksIndex { db })
+    }
+}
+
+#[cfg(feature = "rocksdb")]
+impl IndexStore for RocksIndex {
+    fn get(&self, key: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        let value = self.db.get(key).map_err(other)?;
+        Ok(value.map(|value| value.to_vec()))
+    }
+
+    fn put(&self, key: &[u8], value: &[u8]) -> io::Result<()> {
+        self.db.put(key, value).map_err(other)
+    }
+
+    fn delete(&self, key: &[u8]) -> io::Result<()> {
+        self.db.delete(key).map_err(other)
+    }
+
+    fn scan_from(&self, start: &[u8]) -> Entries<'_> {
+        use rocksdb::{Direction, IteratorMode};
+
+        Box::new(
+            self.db
+                .iterator(IteratorMode::From(start, Direction::Forward)),
+        )
+    }
+
+    fn write(&self, batch: Batch) -> io::Result<()> {
+        let mut written = rocksdb::WriteBatch::default();
+
+        for op in batch.ops {
+            match op {
+                Op::Put(key, value) => written.put(&key, &value),
+                Op::Delete(key) => written.delete(&key),
+            }
+            .map_err(other)?;
+        }
+
+        self.db.write(written).map_err(other)
+    }
+}
+
+/// Index in a sled database
+#[cfg(feature = "sled")]
+pub struct SledIndex {
+    db: sled::Db,
+}
+
+#[cfg(feature = "sled")]
+impl SledIndex {
+    pub fn open(data_dir: &str) -> io::Result<SledIndex> {
+        let db = sled::open(data_dir).map_err(other)?;
+        Ok(SledIndex { db })
+    }
+}
+
+#[cfg(feature = "sled")]
+impl IndexStore for SledIndex {
+    fn get(&self, key: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        let value = self.db.get(key).map_err(other)?;
+        Ok(value.map(|value| value.to_vec()))
+    }
+
+    fn put(&self, key: &[u8], value: &[u8]) -> io::Result<()> {
+        self.db.insert(key, value).map(|_| ()).map_err(other)
+    }
+
+    fn delete(&self, key: &[u8]) -> io::Result<()> {
+        self.db.remove(key).map(|_| ()).map_err(other)
+    }
+
+    /// entries after a read error are left out, as rocksdb does
+    fn scan_from(&self, start: &[u8]) -> Entries<'_> {
+        Box::new(
+            self.db
+                .range(start.to_vec()..)
+                .map_while(Result::ok)
+                .map(|(key, value)| (Box::from(&key[..]), Box::from(&value[..]))),
+        )
+    }
+
+    fn write(&self, batch: Batch) -> io::Result<()> {
+        let mut written = sled::Batch::default();
+
+        for op in batch.ops {
+            match op {
+                Op::Put(key, value) => written.insert(key, value),
+                Op::Delete(key) => written.remove(key),
+            }
+        }
+
+        self.db.apply_batch(written).map_err(other)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile::tempdir;
+
+    /// checks the behaviour all backends share
+    fn check_index(index: &dyn IndexStore) {
+        index.put(b"b/1", b"one").unwrap();
+        index.put(b"b/2", b"two").unwrap();
+        index.put(b"a", b"first").unwrap();
+        index.put(b"c", b"last").unwrap();
+
+        assert_eq!(index.get(b"b/1").unwrap(), Some(b"one".to_vec()));
+        assert_eq!(index.get(b"b/3").unwrap(), None);
+
+        let keys: Vec<Box<[u8]>> = index.scan().map(|(key, _)| key).collect();
+        assert_eq!(keys.len(), 4);
+        assert_eq!(&*keys[0], b"a");
+
+        let values: Vec<Box<[u8]>> = index.scan_prefix(b"b/").map(|(_, value)| value).collect();
+        assert_eq!(values.len(), 2);
+        assert_eq!(&*values[1], b"two");
+
+        let mut batch = Batch::default();
+        batch.delete(b"b/1");
+        batch.put(b"b/3", b"three");
+        index.write(batch).unwrap();
+
+        assert_eq!(index.get(b"b/1").unwrap(), None);
+        assert_eq!(index.scan_prefix(b"b/").count(), 2);
+
+        index.delete(b"c").unwrap();
+        index.delete(b"c").unwrap();
+        assert_eq!(index.scan_from(b"b/3").count(), 1);
+    }
+
+    #[test]
+    fn test_index_backends() {
+        check_index(&MemoryIndex::new());
+
+        if cfg!(feature = "rocksdb") {
+            let data_dir = tempdir().unwrap();
+            let index = Backend::RocksDb.open(data_dir.path().to_str().unwrap());
+            check_index(&*index.unwrap());
+        }
+
+        if cfg!(feature = "sled") {
+            let data_dir = tempdir().unwrap();
+            let index = Backend::Sled.open(data_dir.path().to_str().unwrap());
+            check_index(&*index.unwrap());
+        }
+    }
+
+    #[test]
+    fn test_index_parse() {
+        assert!(match Backend::parse("memory") {
+            Ok(Backend::Memory) => true,
+            _ => false,
+        });
+        assert!(Backend::parse("leveldb").is_err());
+    }
+}
@@ -26,13 +26,39 @@
 //! ```sh
 //! curl -XPOST -d "http://newvolume.server" http://localhost:6000/admin/add-volume
 //! ```
-
-use tiny_http::{Method, Request};
+//!
+//! 5. create a bucket and insert a key-value in it
+//!
+//! ```sh
+//! curl -XPOST -d "name=logs&replicas=2" http://localhost:6000/admin/create-bucket
+//! curl -XPUT -L -d value http://localhost:6000/bucket/logs/key
+//! ```
+//!
+//! 6. list keys starting with a prefix
+//!
+//! ```sh
+//! curl "http://localhost:6000/store/?prefix=team-a/"
+//! ```
+//!
+//! 7. watch changes of keys starting with a prefix
+//!
+//! ```sh
+//! curl "http://localhost:6000/watch?prefix=team-a/&since=0"
+//! ```
+//!
+//! Rust programs use [client](client/index.html) instead.
+//!
+//! volumes are drained, removed and rebalanced with `kalavara-admin`, see
+//! [master](master/index.html).
 
 use std::io::Read;
 
+use crate::listener::{Method, Request};
+
 const STORE_PREFIX: &str = "/store/";
+const BUCKET_PREFIX: &str = "/bucket/";
 const ADMIN_PREFIX: &str = "/admin/";
+const WATCH_PATH: &str = "/watch";
 
 /// returns the key from url string by removing /store/ prefix and query params if any
 fn get_key(url: &str, prefix: &str) -> String {
@@ -49,12 +75,123 @@ fn get_key(url: &str, prefix: &str) -> String {
     }
 }
 
+/// splits `name=value&name2=value2` pairs without decoding them
+fn split_params(query: &str) -> Vec<(String, String)> {
+    query
+        .split('&')
+        .filter(|param| !param.is_empty())
+        .map(|param| match param.find('=') {
+            Some(indx) => (param[..indx].to_owned(), param[indx + 1..].to_owned()),
+            None => (param.to_owned(), String::new()),
+        })
+        .collect()
+}
+
+/// parses `name=value&name2=value2` pairs with percent encoded names and
+/// values, used for query strings and admin request bodies
+fn parse_params(query: &str) -> Vec<(String, String)> {
+    split_params(query)
+        .into_iter()
+        .map(|(name, value)| (decode_param(&name), decode_param(&value)))
+        .collect()
+}
+
+/// decodes `%XX` escapes of a param, malformed escapes are kept as they are
+fn decode_param(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut indx = 0;
+
+    while indx < bytes.len() {
+        let escape = bytes
+            .get(indx + 1..indx + 3)
+            .filter(|_| bytes[indx] == b'%')
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+
+        match escape {
+            Some(byte) => {
+                decoded.push(byte);
+                indx += 3;
+            }
+            None => {
+                decoded.push(bytes[indx]);
+                indx += 1;
+            }
+        }
+    }
+
+    String::from_utf8(decoded).unwrap_or_else(|_| text.to_owned())
+}
+
+/// percent-encodes a name or value of params read back with `parse_params`.
+/// Printable characters other than those delimiting params are kept readable
+pub(crate) fn encode_param(text: &str) -> String {
+    let mut encoded = String::with_capacity(text.len());
+
+    for byte in text.bytes() {
+        match byte {
+            b'%' | b'&' | b'=' | b'+' | b'#' => encoded.push_str(&format!("%{:02X}", byte)),
+            byte if byte.is_ascii_graphic() => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+
+    encoded
+}
+
+/// returns value of query param `name` from url string
+fn get_param(url: &str, name: &str) -> Option<String> {
+    let query = match url.find('?') {
+        Some(indx) => &url[indx + 1..],
+        None => return None,
+    };
+
+    parse_params(query)
+        .into_iter()
+        .find(|(param, _)| param == name)
+        .map(|(_, value)| value)
+}
+
+/// returns value of header `name` of a request
+fn get_header(req: &Request, name: &'static str) -> Option<String> {
+    req.headers()
+        .iter()
+        .find(|header| header.field.equiv(name))
+        .map(|header| header.value.as_str().to_owned())
+}
+
 #[test]
 fn test_get_key() {
     let url = "/store/originalkey?q=this&that=that#foo";
     assert_eq!(get_key(url, "/store/"), String::from("originalkey"));
 }
 
+#[test]
+fn test_get_param() {
+    let url = "/store/key?ttl=60&versioned&replicas=http://v1,http://v2";
+    assert_eq!(get_param(url, "ttl"), Some("60".to_owned()));
+    assert_eq!(get_param(url, "versioned"), Some("".to_owned()));
+    assert_eq!(
+        get_param(url, "replicas"),
+        Some("http://v1,http://v2".to_owned())
+    );
+    assert_eq!(get_param(url, "version"), None);
+    assert_eq!(get_param("/store/key", "ttl"), None);
+
+    let url = "/store/?prefix=a%26b%3Fc%23d%25e%20f&after=100%&x%3Dy=%zz";
+    assert_eq!(get_param(url, "prefix"), Some("a&b?c#d%e f".to_owned()));
+    assert_eq!(get_param(url, "after"), Some("100%".to_owned()));
+    assert_eq!(get_param(url, "x=y"), Some("%zz".to_owned()));
+
+    let value = "a%41&b=c d";
+    let query = format!("{}={}", encode_param("x&y"), encode_param(value));
+    assert_eq!(
+        parse_params(&query),
+        vec![("x&y".to_owned(), value.to_owned())]
+    );
+}
+
 /// Trait that send http response to a request
 /// ResponseKind Should implement this
 trait Respond {
@@ -71,19 +208,26 @@ trait Service: Sync + Send {
     /// Get a key from store
     fn get(&self, key: String) -> Self::Response;
 
-    /// Save/Update key in store
-    fn save(&self, key: String, value: impl Read) -> Self::Response;
+    /// Save/Update key in store, `length` is the size of `value` if known
+    fn save(&self, key: String, value: impl Read, length: Option<u64>) -> Self::Response;
 
     /// Remove a key from store
     fn delete(&self, key: String) -> Self::Response;
 
     /// Dispatch a request to respective handler methods
-    fn dispatch(&self, mut req: Request) {
+    fn dispatch(&self, req: Request) {
         let key = get_key(req.url(), STORE_PREFIX);
+        self.dispatch_key(key, req);
+    }
 
+    /// Dispatch a request for `key` to respective handler methods
+    fn dispatch_key(&self, key: String, mut req: Request) {
         let resp = match *req.method() {
-            Method::Get => self.get(key),
-            Method::Post | Method::Put => self.save(key, req.as_reader()),
+            Method::Get | Method::Head => self.get(key),
+            Method::Post | Method::Put => {
+                let length = req.body_length().map(|length| length as u64);
+                self.save(key, req.as_reader(), length)
+            }
             Method::Delete => self.delete(key),
             _ => Default::default(),
         };
@@ -94,5 +238,30 @@ trait Service: Sync + Send {
 
 #[macro_use]
 mod macros;
+pub mod batch;
+pub mod bucket;
+pub mod changes;
+pub mod checksum;
+pub mod client;
+pub mod cluster;
+pub mod compress;
+pub mod dedup;
+pub mod encryption;
+pub mod erasure;
+pub mod http;
+pub mod index;
+pub mod limit;
+mod listener;
 pub mod master;
+pub mod multipart;
+pub mod packed;
+pub mod quota;
+mod range;
+mod record;
+pub mod s3;
+pub mod scrub;
+pub mod server;
+pub mod store;
+pub mod tls;
 pub mod volume;
+pub mod webhook;
@@ -0,0 +1,663 @@
+//! # rate limits and load shedding
+//!
+//! Requests are admitted on the runtime of the listener. An admitted request
+//! takes a token from the bucket of its client address and from the global
+//! bucket, requests without a token are rejected with 429 and a `Retry-After`
+//! header. Its body is received next, then it waits in a bounded queue for
+//! one of `--threads` workers, which handle requests on the blocking pool of
+//! the runtime. Requests arriving while the queue is full are rejected right
+//! away with 503.
+//!
+//! workers only take requests whose body arrived, so clients slowly uploading
+//! values hold connections but no worker. `--client-concurrency` bounds the
+//! requests a client address has receiving, queued or in progress at once,
+//! further ones are rejected with 429.
+//!
+//! limits are set with `--rate-limit`, `--client-rate-limit` (requests per
+//! second) and the matching `--burst` options, queue length with
+//! `--queue-size` and open connections with `--max-connections`, see
+//! [listener](../listener/index.html). Rate and concurrency limits are off by
+//! default.
+//!
+//! counters of admitted and rejected requests are served ahead of the queue
+//! at `GET /admin/metrics`, so they stay available under overload
+//!
+//! ```sh
+//! curl http://localhost:6000/admin/metrics
+//! ```
+
+use crate::listener::{Header, Method, Request, Server};
+use crate::server::{Shutdown, POLL_INTERVAL};
+
+use tokio::sync::mpsc;
+
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// path of metrics endpoint
+const METRICS_PATH: &str = "/admin/metrics";
+
+/// number of tracked clients above which idle ones are forgotten
+const MAX_CLIENTS: usize = 10_000;
+
+/// Rate limit and queue settings
+#[derive(Clone, Debug)]
+pub struct LimitConfig {
+    /// requests per second accepted from all clients
+    pub rate: Option<f64>,
+
+    /// requests accepted at once from all clients, defaults to `rate`
+    pub burst: Option<f64>,
+
+    /// requests per second accepted from a client address
+    pub client_rate: Option<f64>,
+
+    /// requests accepted at once from a client address, defaults to `client_rate`
+    pub client_burst: Option<f64>,
+
+    /// requests of a client address queued or in progress at once
+    pub client_concurrency: Option<usize>,
+
+    /// requests waiting for a worker, beyond which requests are shed
+    pub queue: usize,
+
+    /// connections open at once, further ones wait to be accepted
+    pub connections: usize,
+}
+
+impl Default for LimitConfig {
+    fn default() -> Self {
+        LimitConfig {
+            rate: None,
+            burst: None,
+            client_rate: None,
+            client_burst: None,
+            client_concurrency: None,
+            queue: 1024,
+            connections: 10_000,
+        }
+    }
+}
+
+/// Why a request was rejected
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Reason {
+    /// global rate limit
+    RateLimit,
+
+    /// rate limit of client address
+    ClientRateLimit,
+
+    /// requests of client address in progress
+    ClientConcurrency,
+
+    /// queue is full
+    QueueFull,
+}
+
+/// token bucket refilled at `rate` tokens per second up to `burst`
+#[derive(Debug)]
+struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: f64, burst: Option<f64>, now: Instant) -> Self {
+        let burst = burst.unwrap_or(rate).max(1.0);
+
+        TokenBucket {
+            rate,
+            burst,
+            tokens: burst,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        if now > self.updated {
+            let elapsed = now.duration_since(self.updated);
+            let secs = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+
+            self.tokens = (self.tokens + secs * self.rate).min(self.burst);
+            self.updated = now;
+        }
+    }
+
+    /// takes a token, or returns how long to wait for one
+    fn take(&mut self, now: Instant) -> Result<(), Duration> {
+        self.refill(now);
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            let wait = (1.0 - self.tokens) / self.rate;
+            Err(Duration::from_millis((wait * 1000.0).ceil() as u64))
+        }
+    }
+
+    /// checks whether the bucket refilled completely, so that it can be dropped
+    fn idle(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= self.burst
+    }
+}
+
+/// Counters of admitted and rejected requests
+#[derive(Debug, Default)]
+struct Metrics {
+    requests: AtomicUsize,
+    rate_limited: AtomicUsize,
+    client_rate_limited: AtomicUsize,
+    client_concurrency: AtomicUsize,
+    shed: AtomicUsize,
+    queued: AtomicUsize,
+}
+
+impl Metrics {
+    fn reject(&self, reason: Reason) {
+        let counter = match reason {
+            Reason::RateLimit => &self.rate_limited,
+            Reason::ClientRateLimit => &self.client_rate_limited,
+            Reason::ClientConcurrency => &self.client_concurrency,
+            Reason::QueueFull => &self.shed,
+        };
+
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// metrics in prometheus text format
+    fn render(&self) -> String {
+        let load = |counter: &AtomicUsize| counter.load(Ordering::Relaxed);
+
+        format!(
+            "kalavara_requests_total {}\n\
+             kalavara_rejected_total{{reason=\"rate_limit\"}} {}\n\
+             kalavara_rejected_total{{reason=\"client_rate_limit\"}} {}\n\
+             kalavara_rejected_total{{reason=\"client_concurrency\"}} {}\n\
+             kalavara_rejected_total{{reason=\"queue_full\"}} {}\n\
+             kalavara_queue_depth {}\n",
+            load(&self.requests),
+            load(&self.rate_limited),
+            load(&self.client_rate_limited),
+            load(&self.client_concurrency),
+            load(&self.shed),
+            load(&self.queued)
+        )
+    }
+}
+
+/// Admission control in front of worker threads
+struct Gate {
+    limits: LimitConfig,
+    global: Option<Mutex<TokenBucket>>,
+    clients: Mutex<HashMap<IpAddr, TokenBucket>>,
+
+    /// requests queued or in progress by client address
+    active: Mutex<HashMap<IpAddr, usize>>,
+    metrics: Metrics,
+}
+
+impl Gate {
+    fn new(limits: LimitConfig) -> Self {
+        let global = limits
+            .rate
+            .map(|rate| Mutex::new(TokenBucket::new(rate, limits.burst, Instant::now())));
+
+        Gate {
+            limits,
+            global,
+            clients: Mutex::new(HashMap::new()),
+            active: Mutex::new(HashMap::new()),
+            metrics: Metrics::default(),
+        }
+    }
+
+    /// takes a token from client and global buckets
+    fn check(&self, client: IpAddr, now: Instant) -> Result<(), (Reason, Duration)> {
+        if let Some(rate) = self.limits.client_rate {
+            let mut clients = self.clients.lock().unwrap();
+
+            if clients.len() >= MAX_CLIENTS {
+                clients.retain(|_, bucket| !bucket.idle(now));
+            }
+
+            clients
+                .entry(client)
+                .or_insert_with(|| TokenBucket::new(rate, self.limits.client_burst, now))
+                .take(now)
+                .map_err(|wait| (Reason::ClientRateLimit, wait))?;
+        }
+
+        if let Some(ref global) = self.global {
+            global
+                .lock()
+                .unwrap()
+                .take(now)
+                .map_err(|wait| (Reason::RateLimit, wait))?;
+        }
+
+        Ok(())
+    }
+
+    /// counts a request of client as in progress, unless it has too many.
+    /// the request is done once the returned slot is dropped
+    fn enter(self: &Arc<Self>, client: IpAddr) -> Result<Slot, Reason> {
+        if let Some(limit) = self.limits.client_concurrency {
+            let mut active = self.active.lock().unwrap();
+            let count = active.entry(client).or_insert(0);
+
+            if *count >= limit {
+                return Err(Reason::ClientConcurrency);
+            }
+            *count += 1;
+        }
+
+        Ok(Slot {
+            gate: self.clone(),
+            client,
+        })
+    }
+
+    /// counts a request of client as done
+    fn leave(&self, client: IpAddr) {
+        if self.limits.client_concurrency.is_some() {
+            let mut active = self.active.lock().unwrap();
+
+            if let Some(count) = active.get_mut(&client) {
+                *count -= 1;
+                if *count == 0 {
+                    active.remove(&client);
+                }
+            }
+        }
+    }
+
+    /// responds to a rejected request
+    fn reject(&self, req: Request, reason: Reason, wait: Duration) {
+        self.metrics.reject(reason);
+
+        // Retry-After is in whole seconds
+        let secs = (wait.as_secs() + u64::from(wait.subsec_nanos() > 0)).max(1);
+        let header = Header::from_bytes(&b"Retry-After"[..], secs.to_string().as_bytes()).unwrap();
+
+        let resp = match reason {
+            Reason::QueueFull => resp!("Server busy", 503),
+            _ => resp!("Too many requests", 429),
+        };
+
+        let _ = req.respond(resp.with_header(header));
+    }
+}
+
+/// Request of a client counted as in progress, done once dropped so that
+/// handlers panicking or failing to queue do not keep the slot
+struct Slot {
+    gate: Arc<Gate>,
+    client: IpAddr,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.gate.leave(self.client);
+    }
+}
+
+/// Requests received and waiting for a worker
+#[derive(Default)]
+struct Queue {
+    requests: VecDeque<(Request, Slot)>,
+
+    /// workers running, each handles queued requests until none is left
+    workers: usize,
+}
+
+/// Hands admitted requests to workers once their body arrived
+struct Dispatcher<F> {
+    handler: F,
+    gate: Arc<Gate>,
+    spool: PathBuf,
+
+    /// requests handled at once
+    threads: usize,
+    queue: Mutex<Queue>,
+
+    /// requests queued or being handled
+    active: AtomicUsize,
+    shutdown: Shutdown,
+}
+
+impl<F> Dispatcher<F>
+where
+    F: Fn(Request) + Send + Sync + 'static,
+{
+    /// admits requests of the connections until the server stops
+    async fn admit(self: Arc<Self>, mut requests: mpsc::UnboundedReceiver<Request>) {
+        while let Some(rq) = requests.recv().await {
+            if *rq.method() == Method::Get && rq.url() == METRICS_PATH {
+                let _ = rq.respond(resp!(self.gate.metrics.render(), 200));
+                continue;
+            }
+
+            let client = rq.remote_addr().ip();
+            if let Err((reason, wait)) = self.gate.check(client, Instant::now()) {
+                self.gate.reject(rq, reason, wait);
+                continue;
+            }
+
+            let slot = match self.gate.enter(client) {
+                Ok(slot) => slot,
+                Err(reason) => {
+                    self.gate.reject(rq, reason, Duration::from_secs(1));
+                    continue;
+                }
+            };
+
+            if rq.body_received() {
+                self.queue(rq, slot);
+            } else {
+                tokio::spawn(self.clone().receive(rq, slot));
+            }
+        }
+    }
+
+    /// receives the body of a request, then queues it
+    async fn receive(self: Arc<Self>, mut rq: Request, slot: Slot) {
+        match rq.receive_body(&self.spool).await {
+            Ok(_) => self.queue(rq, slot),
+            Err(_) => {
+                let _ = rq.respond(resp!("Failed to read body", 400));
+            }
+        }
+    }
+
+    /// hands a request to a worker, starting one on the blocking pool unless
+    /// `threads` are running already
+    fn queue(self: &Arc<Self>, rq: Request, slot: Slot) {
+        let metrics = &self.gate.metrics;
+        let mut queue = self.queue.lock().unwrap();
+
+        if queue.workers == self.threads {
+            if queue.requests.len() >= self.gate.limits.queue {
+                drop(queue);
+                self.gate
+                    .reject(rq, Reason::QueueFull, Duration::from_secs(1));
+                return;
+            }
+
+            queue.requests.push_back((rq, slot));
+            metrics.queued.fetch_add(1, Ordering::SeqCst);
+            metrics.requests.fetch_add(1, Ordering::Relaxed);
+            self.active.fetch_add(1, Ordering::SeqCst);
+            return;
+        }
+
+        queue.workers += 1;
+        drop(queue);
+        metrics.requests.fetch_add(1, Ordering::Relaxed);
+        self.active.fetch_add(1, Ordering::SeqCst);
+
+        let dispatcher = self.clone();
+        tokio::task::spawn_blocking(move || dispatcher.work(rq, slot));
+    }
+
+    /// handles `rq`, then queued requests until none is left. Taking the
+    /// next request right away saves waking another thread for each
+    fn work(&self, mut rq: Request, mut slot: Slot) {
+        loop {
+            if self.shutdown.is_set() {
+                let _ = rq.respond(resp!("Server stopped", 503));
+            } else {
+                // a panicking handler fails its request only
+                let _ = panic::catch_unwind(AssertUnwindSafe(|| (self.handler)(rq)));
+            }
+
+            drop(slot);
+            self.active.fetch_sub(1, Ordering::SeqCst);
+
+            let mut queue = self.queue.lock().unwrap();
+            match queue.requests.pop_front() {
+                Some(next) => {
+                    self.gate.metrics.queued.fetch_sub(1, Ordering::SeqCst);
+                    (rq, slot) = next;
+                }
+                None => {
+                    queue.workers -= 1;
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// admits requests from `server` and handles at most `threads` of them at
+/// once with `handler`, spooling large bodies to `spool`. Returns a thread
+/// that exits once `shutdown` is signalled and admitted requests are
+/// handled
+pub(crate) fn serve<F>(
+    server: Arc<Server>,
+    threads: u16,
+    limits: LimitConfig,
+    spool: &Path,
+    shutdown: &Shutdown,
+    handler: F,
+) -> Vec<JoinHandle<()>>
+where
+    F: Fn(Request) + Send + Sync + 'static,
+{
+    let dispatcher = Arc::new(Dispatcher {
+        handler,
+        gate: Arc::new(Gate::new(limits)),
+        spool: spool.to_owned(),
+        threads: usize::from(threads.max(1)),
+        queue: Mutex::new(Queue::default()),
+        active: AtomicUsize::new(0),
+        shutdown: shutdown.clone(),
+    });
+
+    if let Some(requests) = server.requests() {
+        server.spawn(dispatcher.clone().admit(requests));
+    }
+
+    let shutdown = shutdown.clone();
+    vec![thread::spawn(move || {
+        while shutdown.sleep(Duration::from_secs(60)) {}
+
+        while dispatcher.active.load(Ordering::SeqCst) > 0 {
+            thread::sleep(POLL_INTERVAL / 10);
+        }
+
+        // the listener stops once its last reference is dropped
+        drop(server);
+    })]
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::http;
+    use crate::tls::TlsConfig;
+    use std::env;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+
+    #[test]
+    fn test_token_bucket() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(2.0, Some(2.0), start);
+
+        assert_eq!(bucket.take(start), Ok(()));
+        assert_eq!(bucket.take(start), Ok(()));
+        assert_eq!(bucket.take(start), Err(Duration::from_millis(500)));
+        assert!(!bucket.idle(start));
+
+        // refilled at 2 tokens per second
+        let later = start + Duration::from_millis(500);
+        assert_eq!(bucket.take(later), Ok(()));
+        assert_eq!(bucket.take(later), Err(Duration::from_millis(500)));
+
+        assert!(bucket.idle(later + Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn test_gate_check() {
+        let gate = Gate::new(LimitConfig {
+            rate: Some(3.0),
+            client_rate: Some(1.0),
+            client_burst: Some(2.0),
+            ..LimitConfig::default()
+        });
+
+        let now = Instant::now();
+        let client1: IpAddr = [10, 0, 0, 1].into();
+        let client2: IpAddr = [10, 0, 0, 2].into();
+
+        assert!(gate.check(client1, now).is_ok());
+        assert!(gate.check(client1, now).is_ok());
+        assert_eq!(
+            gate.check(client1, now),
+            Err((Reason::ClientRateLimit, Duration::from_secs(1)))
+        );
+
+        // rejected requests of a client take no global tokens
+        assert!(gate.check(client2, now).is_ok());
+        assert_eq!(
+            gate.check(client2, now).map_err(|(reason, _)| reason),
+            Err(Reason::RateLimit)
+        );
+    }
+
+    #[test]
+    fn test_gate_concurrency() {
+        let gate = Arc::new(Gate::new(LimitConfig {
+            client_concurrency: Some(2),
+            ..LimitConfig::default()
+        }));
+
+        let client1: IpAddr = [10, 0, 0, 1].into();
+        let client2: IpAddr = [10, 0, 0, 2].into();
+
+        let first = gate.enter(client1).unwrap();
+        let _second = gate.enter(client1).unwrap();
+        assert_eq!(gate.enter(client1).err(), Some(Reason::ClientConcurrency));
+        let other = gate.enter(client2).unwrap();
+
+        // finished requests make room for new ones
+        drop(first);
+        assert!(gate.enter(client1).is_ok());
+
+        drop(other);
+        assert!(!gate.active.lock().unwrap().contains_key(&client2));
+
+        // requests of panicking handlers are done too
+        let slot = gate.enter(client2).unwrap();
+        let _ = thread::spawn(move || {
+            let _slot = slot;
+            panic!("handler failed");
+        })
+        .join();
+        assert!(!gate.active.lock().unwrap().contains_key(&client2));
+    }
+
+    #[test]
+    fn test_serve_sheds_load() {
+        let addr = ([127, 0, 0, 1], 0).into();
+        let server = Arc::new(Server::bind(addr, None, 16).unwrap());
+        let url = format!("http://{}/slow", server.server_addr());
+
+        // one busy worker and room for one waiting request
+        let limits = LimitConfig {
+            queue: 1,
+            ..LimitConfig::default()
+        };
+
+        serve(
+            server,
+            1,
+            limits,
+            &env::temp_dir(),
+            &Shutdown::default(),
+            |rq| {
+                thread::sleep(Duration::from_millis(500));
+                let _ = rq.respond(resp!("done"));
+            },
+        );
+
+        let clients: Vec<_> = (0..3)
+            .map(|i| {
+                let url = url.clone();
+                thread::sleep(Duration::from_millis(50 * i));
+                thread::spawn(move || {
+                    http::request("GET", &url, &[], b"", &TlsConfig::default())
+                        .unwrap()
+                        .status_code
+                })
+            })
+            .collect();
+
+        let mut statuses: Vec<u16> = clients.into_iter().map(|c| c.join().unwrap()).collect();
+        statuses.sort();
+        assert_eq!(statuses, vec![200, 200, 503]);
+
+        let metrics = http::request(
+            "GET",
+            &url.replace("/slow", METRICS_PATH),
+            &[],
+            b"",
+            &TlsConfig::default(),
+        )
+        .unwrap()
+        .text();
+
+        assert!(metrics.contains("kalavara_requests_total 2\n"));
+        assert!(metrics.contains("kalavara_rejected_total{reason=\"queue_full\"} 1\n"));
+    }
+
+    #[test]
+    fn test_serve_slow_upload() {
+        let addr = ([127, 0, 0, 1], 0).into();
+        let server = Arc::new(Server::bind(addr, None, 16).unwrap());
+        let addr = server.server_addr();
+        let url = format!("http://{}/upload", addr);
+
+        // answers with the size of the body
+        serve(
+            server,
+            1,
+            LimitConfig::default(),
+            &env::temp_dir(),
+            &Shutdown::default(),
+            |mut rq| {
+                let mut body = Vec::new();
+                rq.as_reader().read_to_end(&mut body).unwrap();
+                let _ = rq.respond(resp!(body.len().to_string()));
+            },
+        );
+
+        // an upload stalled halfway holds no worker
+        let mut stalled = TcpStream::connect(addr).unwrap();
+        stalled
+            .write_all(b"PUT /upload HTTP/1.1\r\nHost: localhost\r\nContent-Length: 9\r\n\r\nhalf")
+            .unwrap();
+        thread::sleep(Duration::from_millis(100));
+
+        // bodies larger than those kept in memory are spooled
+        let value = vec![b'a'; 1 << 20];
+        let resp = http::request("PUT", &url, &[], &value, &TlsConfig::default()).unwrap();
+        assert_eq!(resp.text(), (1 << 20).to_string());
+
+        stalled.write_all(b"-done").unwrap();
+        let mut resp = [0; 1024];
+        let read = stalled.read(&mut resp).unwrap();
+        assert!(String::from_utf8_lossy(&resp[..read]).ends_with("\r\n\r\n9"));
+    }
+}
@@ -0,0 +1,795 @@
+//! # http listener
+//!
+//! Master and volume servers accept connections on a tokio runtime with
+//! hyper. Connections, TLS handshakes and request heads are handled
+//! asynchronously, so idle keep-alive connections and clients slowly sending
+//! headers hold no thread.
+//!
+//! Requests are handed to [limit](../limit/index.html), which receives their
+//! body on the runtime before a worker handles them. Bodies up to 256 KiB are
+//! kept in memory, larger ones are spooled to a temporary file, so clients
+//! slowly uploading values hold no worker. Responses are written by the
+//! runtime, which reads their body a chunk at a time on its blocking pool, so
+//! a worker is free once its handler returns and clients slowly downloading
+//! values hold none either.
+//!
+//! `--max-connections` (10000 by default) bounds open connections, further
+//! ones wait in the listen backlog until one closes. Connections idle for 30
+//! seconds between requests are closed.
+
+use bytes::{Bytes, BytesMut};
+use http_body::{Body, Frame, SizeHint};
+use hyper::body::Incoming;
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper_util::rt::{TokioIo, TokioTimer};
+use openssl::ssl::{Ssl, SslAcceptor};
+use openssl::x509::{X509VerifyResult, X509};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use tokio::runtime::Runtime;
+use tokio::sync::{mpsc, oneshot, Semaphore};
+use tokio::task::JoinHandle;
+use tokio_openssl::SslStream;
+
+use std::convert::Infallible;
+use std::fmt;
+use std::fs::File;
+use std::future::{poll_fn, Future};
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::mem;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use crate::server::POLL_INTERVAL;
+
+/// bytes of a response body read at once
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// chunks of a streamed response buffered ahead of the client
+const BODY_BUFFER: usize = 4;
+
+/// request bodies up to this size are kept in memory, larger ones are
+/// spooled to a file
+const BODY_MEMORY: usize = 256 * 1024;
+
+/// time to send a request head, and to wait for the next one on a
+/// keep-alive connection
+const HEADER_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// time given to responses in progress when the server stops
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Method of a request
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Method {
+    Get,
+    Head,
+    Post,
+    Put,
+    Delete,
+    Options,
+    Patch,
+    Other(String),
+}
+
+impl Method {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Method::Get => "GET",
+            Method::Head => "HEAD",
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Delete => "DELETE",
+            Method::Options => "OPTIONS",
+            Method::Patch => "PATCH",
+            Method::Other(method) => method,
+        }
+    }
+}
+
+impl From<&http::Method> for Method {
+    fn from(method: &http::Method) -> Self {
+        match *method {
+            http::Method::GET => Method::Get,
+            http::Method::HEAD => Method::Head,
+            http::Method::POST => Method::Post,
+            http::Method::PUT => Method::Put,
+            http::Method::DELETE => Method::Delete,
+            http::Method::OPTIONS => Method::Options,
+            http::Method::PATCH => Method::Patch,
+            _ => Method::Other(method.as_str().to_owned()),
+        }
+    }
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Name of a header, compared ignoring case
+#[derive(Clone, Debug)]
+pub(crate) struct HeaderField(String);
+
+impl HeaderField {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// checks whether this is header `name`
+    pub fn equiv(&self, name: &str) -> bool {
+        self.0.eq_ignore_ascii_case(name)
+    }
+}
+
+/// Header of a request or response
+#[derive(Clone, Debug)]
+pub(crate) struct Header {
+    pub field: HeaderField,
+    pub value: String,
+}
+
+impl Header {
+    /// header `field: value`, `None` if either is not valid in a header
+    pub fn from_bytes(field: &[u8], value: &[u8]) -> Option<Header> {
+        http::HeaderName::from_bytes(field).ok()?;
+        http::HeaderValue::from_bytes(value).ok()?;
+
+        Some(Header {
+            field: HeaderField(String::from_utf8(field.to_vec()).ok()?),
+            value: String::from_utf8(value.to_vec()).ok()?,
+        })
+    }
+}
+
+impl FromStr for Header {
+    type Err = ();
+
+    /// parses `Field: value`
+    fn from_str(line: &str) -> Result<Header, ()> {
+        let indx = line.find(':').ok_or(())?;
+        let (field, value) = (line[..indx].trim(), line[indx + 1..].trim());
+
+        Header::from_bytes(field.as_bytes(), value.as_bytes()).ok_or(())
+    }
+}
+
+/// Body of a request, received before a worker handles it
+enum RequestBody {
+    /// still to be received from the connection
+    Incoming(Incoming),
+
+    /// small bodies are kept in memory
+    Memory(io::Cursor<Bytes>),
+
+    /// larger ones in an unnamed temporary file
+    Spooled(io::BufReader<File>),
+}
+
+impl Read for RequestBody {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            RequestBody::Incoming(_) => Err(io::Error::new(
+                ErrorKind::Other,
+                "request body not received",
+            )),
+            RequestBody::Memory(data) => data.read(buf),
+            RequestBody::Spooled(file) => file.read(buf),
+        }
+    }
+}
+
+/// appends `data` to the spool file of a body on the blocking pool, creating
+/// the file in `dir` first
+async fn spill(file: Option<File>, dir: &Path, data: Bytes) -> io::Result<File> {
+    let dir = dir.to_owned();
+
+    tokio::task::spawn_blocking(move || {
+        let mut file = match file {
+            Some(file) => file,
+            None => tempfile::tempfile_in(dir)?,
+        };
+
+        file.write_all(&data)?;
+        Ok(file)
+    })
+    .await
+    .map_err(|e| io::Error::new(ErrorKind::Other, e))?
+}
+
+/// Request handed to a worker
+pub(crate) struct Request {
+    method: Method,
+    url: String,
+    headers: Vec<Header>,
+    remote_addr: SocketAddr,
+    certificate: Option<X509>,
+    body_length: Option<usize>,
+    body: RequestBody,
+    responder: oneshot::Sender<Response>,
+}
+
+impl Request {
+    pub fn method(&self) -> &Method {
+        &self.method
+    }
+
+    /// path and query of the request
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub fn headers(&self) -> &[Header] {
+        &self.headers
+    }
+
+    pub fn remote_addr(&self) -> &SocketAddr {
+        &self.remote_addr
+    }
+
+    /// client certificate verified against the CA bundle of the listener
+    pub fn certificate(&self) -> Option<&X509> {
+        self.certificate.as_ref()
+    }
+
+    /// value of `Content-Length`, `None` for chunked bodies
+    pub fn body_length(&self) -> Option<usize> {
+        self.body_length
+    }
+
+    /// body of the request
+    pub fn as_reader(&mut self) -> &mut dyn Read {
+        &mut self.body
+    }
+
+    /// whether the body is received, which requests without one are on
+    /// arrival
+    pub fn body_received(&self) -> bool {
+        !matches!(self.body, RequestBody::Incoming(_))
+    }
+
+    /// receives the body from the connection. Bodies larger than
+    /// `BODY_MEMORY` are spooled to a temporary file in `spool`
+    pub async fn receive_body(&mut self, spool: &Path) -> io::Result<()> {
+        let mut incoming =
+            match mem::replace(&mut self.body, RequestBody::Memory(Default::default())) {
+                RequestBody::Incoming(incoming) => incoming,
+                body => {
+                    self.body = body;
+                    return Ok(());
+                }
+            };
+
+        let mut data = BytesMut::new();
+        let mut file = None;
+
+        while let Some(frame) = poll_fn(|cx| Pin::new(&mut incoming).poll_frame(cx)).await {
+            let frame = frame.map_err(|e| io::Error::new(ErrorKind::Other, e))?;
+            if let Ok(chunk) = frame.into_data() {
+                data.extend_from_slice(&chunk);
+            }
+
+            if data.len() >= BODY_MEMORY {
+                file = Some(spill(file, spool, data.split().freeze()).await?);
+            }
+        }
+
+        self.body = match file {
+            Some(file) => {
+                let mut file = spill(Some(file), spool, data.freeze()).await?;
+                file.seek(SeekFrom::Start(0))?;
+                RequestBody::Spooled(io::BufReader::new(file))
+            }
+            None => RequestBody::Memory(io::Cursor::new(data.freeze())),
+        };
+
+        Ok(())
+    }
+
+    /// takes the body, so that it can be read after responding. The request
+    /// is left with an empty body
+    pub fn take_body(&mut self) -> impl Read + Send {
+        mem::replace(&mut self.body, RequestBody::Memory(Default::default()))
+    }
+
+    /// hands `response` to the connection, which sends it. fails if the
+    /// client went away
+    pub fn respond(self, response: Response) -> io::Result<()> {
+        self.responder
+            .send(response)
+            .map_err(|_| io::Error::new(ErrorKind::BrokenPipe, "connection closed"))
+    }
+}
+
+/// Body of a response
+enum Content {
+    /// bytes in memory
+    Full(Bytes),
+
+    /// reader of the body and its length if known
+    Reader(Box<dyn Read + Send>, Option<u64>),
+
+    /// chunks written by a `BodyWriter`
+    Channel(mpsc::Receiver<Bytes>),
+}
+
+/// Response of a worker
+pub(crate) struct Response {
+    status: u16,
+    headers: Vec<Header>,
+    content: Content,
+}
+
+impl Response {
+    /// plain text response with status 200
+    pub fn from_string<S: Into<String>>(text: S) -> Response {
+        Response::from_data(text.into().into_bytes())
+            .with_header(Header::from_bytes(b"Content-Type", b"text/plain; charset=UTF-8").unwrap())
+    }
+
+    /// response with status 200 and `data` as body
+    pub fn from_data<D: Into<Vec<u8>>>(data: D) -> Response {
+        Response {
+            status: 200,
+            headers: Vec::new(),
+            content: Content::Full(Bytes::from(data.into())),
+        }
+    }
+
+    /// response with status 200 streaming `reader`, of `length` bytes if
+    /// known
+    pub fn from_reader<R: Read + Send + 'static>(reader: R, length: Option<u64>) -> Response {
+        Response {
+            status: 200,
+            headers: Vec::new(),
+            content: Content::Reader(Box::new(reader), length),
+        }
+    }
+
+    /// response with status 200 and a body of unknown length written to
+    /// the returned writer after responding, until it is dropped
+    pub fn stream() -> (Response, BodyWriter) {
+        let (sender, receiver) = mpsc::channel(BODY_BUFFER);
+
+        let resp = Response {
+            status: 200,
+            headers: Vec::new(),
+            content: Content::Channel(receiver),
+        };
+        (resp, BodyWriter { chunks: sender })
+    }
+
+    pub fn with_status_code(mut self, status: u16) -> Response {
+        self.status = status;
+        self
+    }
+
+    /// adds `header`, replacing one of the same name
+    pub fn with_header(mut self, header: Header) -> Response {
+        self.headers
+            .retain(|existing| !existing.field.equiv(header.field.as_str()));
+        self.headers.push(header);
+        self
+    }
+
+    /// response of hyper to a request, counted as `pending` until its body
+    /// is sent
+    fn into_hyper(self, pending: Pending) -> hyper::Response<ResponseBody> {
+        let body = ResponseBody {
+            state: match self.content {
+                Content::Full(data) => BodyState::Full(data),
+                Content::Reader(reader, length) => BodyState::Idle(reader, length),
+                Content::Channel(chunks) => BodyState::Channel(chunks),
+            },
+            _pending: pending,
+        };
+
+        let mut resp = hyper::Response::new(body);
+        *resp.status_mut() =
+            http::StatusCode::from_u16(self.status).unwrap_or(http::StatusCode::OK);
+
+        for header in self.headers {
+            // framing is up to hyper
+            if header.field.equiv("Content-Length")
+                || header.field.equiv("Transfer-Encoding")
+                || header.field.equiv("Connection")
+            {
+                continue;
+            }
+
+            let name = http::HeaderName::from_bytes(header.field.as_str().as_bytes());
+            let value = http::HeaderValue::from_str(&header.value);
+            if let (Ok(name), Ok(value)) = (name, value) {
+                resp.headers_mut().append(name, value);
+            }
+        }
+
+        resp
+    }
+}
+
+/// Writes the body of a streamed response
+pub(crate) struct BodyWriter {
+    chunks: mpsc::Sender<Bytes>,
+}
+
+impl Write for BodyWriter {
+    /// fails once the client went away
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.chunks
+            .blocking_send(Bytes::copy_from_slice(buf))
+            .map_err(|_| io::Error::new(ErrorKind::BrokenPipe, "connection closed"))?;
+        Ok(buf.len())
+    }
+
+    /// chunks are sent as they are written
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// State of a response body being sent
+enum BodyState {
+    Full(Bytes),
+
+    /// reader waiting for the next chunk to be asked for, with the bytes
+    /// left to send
+    Idle(Box<dyn Read + Send>, Option<u64>),
+
+    /// chunk being read on the blocking pool
+    Reading(JoinHandle<ReadChunk>),
+
+    Channel(mpsc::Receiver<Bytes>),
+
+    Done,
+}
+
+/// reader of a body with the bytes left after a chunk, and the chunk read
+type ReadChunk = (Box<dyn Read + Send>, Option<u64>, io::Result<Bytes>);
+
+/// reads the next chunk of a body of `left` bytes, failing if it ends early
+fn read_chunk(mut reader: Box<dyn Read + Send>, left: Option<u64>) -> ReadChunk {
+    let size = left.map_or(CHUNK_SIZE, |left| left.min(CHUNK_SIZE as u64) as usize);
+    let mut chunk = vec![0; size];
+
+    let read = match reader.read(&mut chunk) {
+        Ok(0) if left.is_some() => Err(io::Error::new(
+            ErrorKind::UnexpectedEof,
+            "body shorter than its length",
+        )),
+        Ok(read) => {
+            chunk.truncate(read);
+            Ok(Bytes::from(chunk))
+        }
+        Err(e) => Err(e),
+    };
+
+    let left = left.map(|left| left - read.as_ref().map_or(0, |chunk| chunk.len() as u64));
+    (reader, left, read)
+}
+
+/// Response body sent by hyper
+struct ResponseBody {
+    state: BodyState,
+    _pending: Pending,
+}
+
+impl Body for ResponseBody {
+    type Data = Bytes;
+    type Error = io::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+    ) -> Poll<Option<Result<Frame<Bytes>, io::Error>>> {
+        loop {
+            match std::mem::replace(&mut self.state, BodyState::Done) {
+                BodyState::Full(data) if data.is_empty() => return Poll::Ready(None),
+                BodyState::Full(data) => return Poll::Ready(Some(Ok(Frame::data(data)))),
+                BodyState::Idle(_, Some(0)) | BodyState::Done => return Poll::Ready(None),
+                BodyState::Idle(reader, left) => {
+                    let read = tokio::task::spawn_blocking(move || read_chunk(reader, left));
+                    self.state = BodyState::Reading(read);
+                }
+                BodyState::Reading(mut read) => match Pin::new(&mut read).poll(cx) {
+                    Poll::Pending => {
+                        self.state = BodyState::Reading(read);
+                        return Poll::Pending;
+                    }
+                    Poll::Ready(Ok((reader, left, Ok(chunk)))) => {
+                        if chunk.is_empty() {
+                            return Poll::Ready(None);
+                        }
+
+                        self.state = BodyState::Idle(reader, left);
+                        return Poll::Ready(Some(Ok(Frame::data(chunk))));
+                    }
+                    Poll::Ready(Ok((_, _, Err(e)))) => return Poll::Ready(Some(Err(e))),
+                    Poll::Ready(Err(e)) => {
+                        return Poll::Ready(Some(Err(io::Error::new(ErrorKind::Other, e))))
+                    }
+                },
+                BodyState::Channel(mut chunks) => match chunks.poll_recv(cx) {
+                    Poll::Ready(Some(chunk)) => {
+                        self.state = BodyState::Channel(chunks);
+                        return Poll::Ready(Some(Ok(Frame::data(chunk))));
+                    }
+                    Poll::Ready(None) => return Poll::Ready(None),
+                    Poll::Pending => {
+                        self.state = BodyState::Channel(chunks);
+                        return Poll::Pending;
+                    }
+                },
+            }
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        match self.state {
+            BodyState::Full(ref data) => data.is_empty(),
+            BodyState::Idle(_, Some(0)) | BodyState::Done => true,
+            _ => false,
+        }
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        match self.state {
+            BodyState::Full(ref data) => SizeHint::with_exact(data.len() as u64),
+            BodyState::Idle(_, Some(left)) => SizeHint::with_exact(left),
+            BodyState::Done => SizeHint::with_exact(0),
+            _ => SizeHint::default(),
+        }
+    }
+}
+
+/// Counts a request until its response is sent or the connection closes
+struct Pending(Arc<AtomicUsize>);
+
+impl Pending {
+    fn new(count: &Arc<AtomicUsize>) -> Pending {
+        count.fetch_add(1, Ordering::SeqCst);
+        Pending(count.clone())
+    }
+}
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Server accepting connections on a runtime of its own and queueing their
+/// requests for workers
+pub(crate) struct Server {
+    runtime: Option<Runtime>,
+    addr: SocketAddr,
+    requests: Mutex<Option<mpsc::UnboundedReceiver<Request>>>,
+    accepting: JoinHandle<()>,
+
+    /// requests not answered yet
+    pending: Arc<AtomicUsize>,
+}
+
+impl Server {
+    /// binds to `addr`, serving https if `tls` is given and at most
+    /// `connections` connections at once
+    pub fn bind(
+        addr: SocketAddr,
+        tls: Option<SslAcceptor>,
+        connections: usize,
+    ) -> io::Result<Server> {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .thread_name("kalavara-http")
+            .enable_all()
+            .build()?;
+
+        let listener = runtime.block_on(TcpListener::bind(addr))?;
+        let addr = listener.local_addr()?;
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let pending = Arc::new(AtomicUsize::new(0));
+
+        let accepting = runtime.spawn(accept(
+            listener,
+            tls.map(Arc::new),
+            Arc::new(Semaphore::new(connections.max(1))),
+            sender,
+            pending.clone(),
+        ));
+
+        Ok(Server {
+            runtime: Some(runtime),
+            addr,
+            requests: Mutex::new(Some(receiver)),
+            accepting,
+            pending,
+        })
+    }
+
+    pub fn server_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// requests of the connections, taken once by whoever handles them
+    pub fn requests(&self) -> Option<mpsc::UnboundedReceiver<Request>> {
+        self.requests.lock().unwrap().take()
+    }
+
+    /// runs `future` on the runtime of the server
+    pub fn spawn<F>(&self, future: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        if let Some(ref runtime) = self.runtime {
+            runtime.spawn(future);
+        }
+    }
+}
+
+impl Drop for Server {
+    /// stops accepting connections and waits a while for responses in
+    /// progress before closing the remaining connections
+    fn drop(&mut self) {
+        self.accepting.abort();
+
+        let until = Instant::now() + SHUTDOWN_TIMEOUT;
+        while self.pending.load(Ordering::SeqCst) > 0 && Instant::now() < until {
+            std::thread::sleep(POLL_INTERVAL / 10);
+        }
+
+        if let Some(runtime) = self.runtime.take() {
+            runtime.shutdown_background();
+        }
+    }
+}
+
+/// accepts connections while there are fewer than `connections` open
+async fn accept(
+    listener: TcpListener,
+    tls: Option<Arc<SslAcceptor>>,
+    connections: Arc<Semaphore>,
+    requests: mpsc::UnboundedSender<Request>,
+    pending: Arc<AtomicUsize>,
+) {
+    loop {
+        let permit = match connections.clone().acquire_owned().await {
+            Ok(permit) => permit,
+            Err(_) => return,
+        };
+
+        let (stream, remote_addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(_) => {
+                // out of file descriptors, or the client went away
+                tokio::time::sleep(POLL_INTERVAL).await;
+                continue;
+            }
+        };
+
+        let tls = tls.clone();
+        let requests = requests.clone();
+        let pending = pending.clone();
+
+        tokio::spawn(async move {
+            let _permit = permit;
+
+            match tls {
+                Some(acceptor) => {
+                    let stream = match Ssl::new(acceptor.context())
+                        .and_then(|ssl| SslStream::new(ssl, stream))
+                    {
+                        Ok(stream) => stream,
+                        Err(_) => return,
+                    };
+
+                    let mut stream = Box::pin(stream);
+                    let handshake = stream.as_mut().accept();
+                    match tokio::time::timeout(HEADER_TIMEOUT, handshake).await {
+                        Ok(Ok(_)) => {}
+                        _ => return,
+                    }
+
+                    let ssl = stream.ssl();
+                    let certificate = ssl
+                        .peer_certificate()
+                        .filter(|_| ssl.verify_result() == X509VerifyResult::OK);
+                    serve(stream, remote_addr, certificate, requests, pending).await;
+                }
+                None => serve(stream, remote_addr, None, requests, pending).await,
+            }
+        });
+    }
+}
+
+/// serves requests of a connection until the client closes it
+async fn serve<S>(
+    stream: S,
+    remote_addr: SocketAddr,
+    certificate: Option<X509>,
+    requests: mpsc::UnboundedSender<Request>,
+    pending: Arc<AtomicUsize>,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let service = service_fn(move |req| {
+        let certificate = certificate.clone();
+        handle(
+            req,
+            remote_addr,
+            certificate,
+            requests.clone(),
+            Pending::new(&pending),
+        )
+    });
+
+    let _ = http1::Builder::new()
+        .timer(TokioTimer::new())
+        .header_read_timeout(HEADER_TIMEOUT)
+        .keep_alive(true)
+        .serve_connection(TokioIo::new(stream), service)
+        .await;
+}
+
+/// queues a request for workers and waits for their response
+async fn handle(
+    req: hyper::Request<Incoming>,
+    remote_addr: SocketAddr,
+    certificate: Option<X509>,
+    requests: mpsc::UnboundedSender<Request>,
+    pending: Pending,
+) -> Result<hyper::Response<ResponseBody>, Infallible> {
+    let (parts, incoming) = req.into_parts();
+
+    let headers: Vec<Header> = parts
+        .headers
+        .iter()
+        .filter_map(|(name, value)| Header::from_bytes(name.as_ref(), value.as_bytes()))
+        .collect();
+
+    let body_length = if parts.headers.contains_key(http::header::TRANSFER_ENCODING) {
+        None
+    } else {
+        parts
+            .headers
+            .get(http::header::CONTENT_LENGTH)
+            .and_then(|length| length.to_str().ok())
+            .and_then(|length| length.parse::<usize>().ok())
+    };
+
+    let (responder, response) = oneshot::channel();
+    let req = Request {
+        method: Method::from(&parts.method),
+        url: parts
+            .uri
+            .path_and_query()
+            .map_or("/", |path| path.as_str())
+            .to_owned(),
+        headers,
+        remote_addr,
+        certificate,
+        body_length,
+        // requests without a body need not wait for it
+        body: if incoming.is_end_stream() {
+            RequestBody::Memory(Default::default())
+        } else {
+            RequestBody::Incoming(incoming)
+        },
+        responder,
+    };
+
+    let resp = match requests.send(req) {
+        Ok(_) => response
+            .await
+            .unwrap_or_else(|_| Response::from_string("Server error").with_status_code(500)),
+        Err(_) => Response::from_string("Server stopped").with_status_code(503),
+    };
+
+    Ok(resp.into_hyper(pending))
+}
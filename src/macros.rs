@@ -1,17 +1,19 @@
 macro_rules! redirect {
     ($url:expr) => {
-        tiny_http::Response::from_string("")
+        $crate::listener::Response::from_string("")
             .with_status_code(307)
-            .with_header(tiny_http::Header::from_str($url).unwrap())
+            .with_header(
+                $crate::listener::Header::from_bytes(b"Location", $url.as_bytes()).unwrap(),
+            )
     };
 }
 
 macro_rules! resp {
     ($body:expr, $status:expr) => {
-        tiny_http::Response::from_string($body).with_status_code($status)
+        $crate::listener::Response::from_string($body).with_status_code($status)
     };
 
     ($body:expr) => {
-        tiny_http::Response::from_string($body)
+        $crate::listener::Response::from_string($body)
     };
 }
@@ -1,7 +1,8 @@
 //! # master server
 //!
 //! Master server stores index (key, url of volume server where the value is
-//! stored) in rocksdb. Requests are redirected to curresponding volume server
+//! stored) in an [index backend](../index/index.html), rocksdb by default.
+//! Requests are redirected to curresponding volume server
 //! after metadata is updated.
 //!
 //! to start the server, run
@@ -10,26 +11,150 @@
 //! master -p 6000 -d /tmp/kalavadb -v http://volume1:6001 http://volume2:6002
 //! ```
 //!
+//! pass `--tls-cert` and `--tls-key` to serve https instead.
+//!
+//! set `--join-token` to require volumes registering via `/admin/add-volume`
+//! to authenticate, see [cluster](../cluster/index.html).
+//!
+//! keys of named buckets are served at `/bucket/<name>/<key>`, see
+//! [bucket](../bucket/index.html). Usage is limited with
+//! [quotas](../quota/index.html).
+//!
+//! `GET /store/?prefix=<prefix>` lists keys starting with a prefix, a line
+//! each, and `/bucket/<name>/?prefix=<prefix>` those of a bucket. At most
+//! `limit` keys (1000 by default) are listed, further ones are fetched with
+//! `after=<last key listed>`.
+//!
+//! volume servers are maintained at `/admin/`. `GET volumes` lists them with
+//! the number of values they hold. `POST drain-volume` stops placing values
+//! on a volume and moves its values to the others, after which
+//! `POST remove-volume` removes it. `POST rebalance` moves whole values from
+//! volumes holding more than their share to the least loaded ones. Values are
+//! copied between volumes and verified against their digests before the
+//! index is updated. Uploads in progress are not moved.
+//!
+//! `GET fsck` checks that volumes hold the values of index entries and lists
+//! those missing, `GET snapshot` streams index entries as pairs of key and
+//! value frames, see [batch](../batch/index.html), `POST gc` removes expired
+//! keys and compacts volumes and `GET stats` counts volumes, values and bytes.
+//! `kalavara-admin` calls these endpoints from the command line.
+//!
+//! puts and deletes are logged and watched at `/watch`, see
+//! [changes](../changes/index.html), and posted to
+//! [webhooks](../webhook/index.html).
+//!
+//! with `--s3-port`, master serves a subset of the Amazon S3 API as well,
+//! see [s3](../s3/index.html).
+//!
+//! programs run masters in process with `MasterBuilder`, see
+//! [server](../server/index.html). [client](../client/index.html) talks to
+//! masters from Rust programs.
+//!
 
+use rand::seq::SliceRandom;
 use rand::{thread_rng, Rng};
-use rocksdb::{IteratorMode, DB};
 
 use std::collections::hash_map::Entry;
-use std::collections::HashMap;
-use std::io::Read;
-use std::net::SocketAddr;
-use std::str::{self, FromStr};
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::io::{self, copy, sink, BufWriter, Read, Seek, SeekFrom, Write};
+use std::str;
 use std::sync::{Arc, RwLock};
 use std::thread;
-use tiny_http::{Method, Request, Server};
+use std::time::{Duration, Instant};
+
+use crate::batch::{self, Limited, BATCH_PREFIX, MAX_BATCH_SIZE};
+use crate::bucket::{self, Access, Bucket, BUCKET_TOKEN_HEADER};
+use crate::changes::{self, Change, ChangeLog, Filter, CHANGE_RETENTION, SEQUENCE_HEADER};
+use crate::checksum::{to_hex, DigestReader, CONTENT_MD5_HEADER, SHA256_HEADER, SIZE_PARAM};
+use crate::cluster::{self, ClusterConfig, Credentials, JOIN_TOKEN_HEADER, REPLICAS_SIG_PARAM};
+use crate::compress::{Encoding, COMPRESSION_PARAM};
+use crate::erasure::{self, shard_blob, Codec, CodedReader, StripeReader};
+use crate::http;
+use crate::index::{Backend, Batch, IndexStore};
+use crate::limit::{self, LimitConfig};
+use crate::listener::{Header, Method, Request, Response};
+use crate::multipart::{self, part_blob, part_location, PartsReader, MAX_PARTS};
+use crate::quota::{Quota, Tracker, Usage};
+use crate::record::{new_version, now, Part, Record};
+use crate::s3::{self, Body, Listing, S3Config, S3Error, S3Response};
+use crate::server::{self, ServerHandle, Shutdown};
+use crate::tls::TlsConfig;
+use crate::webhook::{DeadLetter, Webhook};
+use crate::{get_header, get_key, get_param};
+use crate::{Respond, Service, ADMIN_PREFIX, BUCKET_PREFIX, STORE_PREFIX, WATCH_PATH};
+
+/// prefix of internal metadata keys, urls can not produce keys starting with \0
+const META_PREFIX: &str = "\u{0}meta/";
+
+/// prefix of bucket settings
+const BUCKET_META_PREFIX: &str = "\u{0}meta/bucket/";
+
+/// prefix of quotas on key prefixes
+const QUOTA_META_PREFIX: &str = "\u{0}meta/quota/";
+
+/// prefix of multipart uploads in progress
+const UPLOAD_META_PREFIX: &str = "\u{0}meta/upload/";
+
+/// prefix of markers of buckets being converted to erasure coding
+const CONVERT_META_PREFIX: &str = "\u{0}meta/convert/";
+
+/// pause between checks for buckets to convert to erasure coding
+const CONVERT_INTERVAL: Duration = Duration::from_secs(1);
 
-use crate::get_key;
-use crate::{Respond, Service, ADMIN_PREFIX, STORE_PREFIX};
+/// prefix of webhook settings
+const WEBHOOK_META_PREFIX: &str = "\u{0}meta/webhook/";
+
+/// prefix of sequence numbers of the last change each webhook got
+const WEBHOOK_CURSOR_PREFIX: &str = "\u{0}meta/webhook-cursor/";
+
+/// prefix of changes webhooks failed to take
+const DEAD_LETTER_PREFIX: &str = "\u{0}meta/dead-letter/";
+
+/// longest pause between deliveries to webhooks, to retry failed ones
+const WEBHOOK_INTERVAL: Duration = Duration::from_millis(250);
+
+/// changes read for a webhook at once
+const WEBHOOK_BATCH: usize = 100;
+
+/// prefix of keys in named buckets
+const OBJECT_PREFIX: &str = "\u{0}obj/";
+
+/// keys listed in a response unless the request asks for fewer
+const LIST_LIMIT: usize = 1000;
+
+/// seconds a watch waits for changes by default
+const WATCH_TIMEOUT: u64 = 30;
+
+/// seconds a watch waits for changes at most
+const MAX_WATCH_TIMEOUT: u64 = 300;
 
 /// Master store
 struct Master {
-    db: Arc<DB>,
+    index: Arc<dyn IndexStore>,
     volumes: Arc<RwLock<HashMap<String, u32>>>,
+
+    /// volume servers receiving no new values
+    draining: RwLock<HashSet<String>>,
+    buckets: RwLock<HashMap<String, Bucket>>,
+
+    /// usage of buckets and prefixes with quotas
+    usage: RwLock<Tracker>,
+
+    /// committed puts and deletes
+    changes: Arc<ChangeLog>,
+    webhooks: RwLock<HashMap<String, Webhook>>,
+    cluster: ClusterConfig,
+    tls: TlsConfig,
+
+    /// stops background threads and watches
+    shutdown: Shutdown,
+}
+
+/// failed delivery to a webhook, retried at `at`
+struct Retry {
+    attempts: u32,
+    at: Instant,
 }
 
 /// Types of responses that master generates
@@ -40,6 +165,15 @@ enum ResponseKind {
     /// 200
     Ok(String),
 
+    /// Invalid request, 400
+    BadRequest(String),
+
+    /// Authentication required, 401
+    Unauthorized,
+
+    /// Access denied by bucket policy, 403
+    Forbidden,
+
     /// Key not found, 404
     NotFound,
 
@@ -49,8 +183,32 @@ enum ResponseKind {
     /// Method not allowed, 405
     NotAllowed,
 
+    /// Body of unknown length, 411
+    LengthRequired,
+
     /// Unavailable, 503
     Unavailable,
+
+    /// Bucket quota exceeded, 507
+    InsufficientStorage,
+
+    /// Value streamed by master, with its size
+    Stream(Box<dyn Read + Send>, u64),
+
+    /// Value deleted, 204
+    Deleted,
+
+    /// Value stored by master itself, 201
+    Created,
+
+    /// Request body too large, 413
+    PayloadTooLarge,
+
+    /// Changes of a watch, with the sequence number to watch from next
+    Changes(String, u64),
+
+    /// Changes no longer kept, 410
+    Gone(String),
 }
 
 /// Admin service interfaces
@@ -58,18 +216,126 @@ trait AdminService: Sync + Send {
     /// add new volume server
     fn add_volume(&self, url: String) -> ResponseKind;
 
+    /// authenticate and verify a volume server before adding it
+    fn register_volume(&self, url: String, credentials: Credentials) -> ResponseKind;
+
+    /// create a bucket or update its settings
+    fn create_bucket(&self, settings: String) -> ResponseKind;
+
+    /// delete an empty bucket
+    fn delete_bucket(&self, name: String) -> ResponseKind;
+
+    /// list buckets with their settings
+    fn list_buckets(&self) -> ResponseKind;
+
+    /// add or replace quota of a key prefix
+    fn set_quota(&self, settings: String) -> ResponseKind;
+
+    /// remove quota of a key prefix
+    fn remove_quota(&self, settings: String) -> ResponseKind;
+
+    /// usage of buckets and prefixes against their limits
+    fn usage(&self) -> ResponseKind;
+
+    /// restores blobs a volume server found corrupt from their replicas
+    fn report_corrupt(&self, report: String, credentials: Credentials) -> ResponseKind;
+
+    /// schedules conversion of values of a bucket to erasure coding
+    fn convert_erasure(&self, name: String) -> ResponseKind;
+
+    /// list volume servers with the number of values they hold
+    fn list_volumes(&self) -> ResponseKind;
+
+    /// remove a volume server holding no values
+    fn remove_volume(&self, url: String) -> ResponseKind;
+
+    /// stop placing values on a volume server and move its values away
+    fn drain_volume(&self, url: String) -> ResponseKind;
+
+    /// move values from volume servers holding more than their share
+    fn rebalance(&self) -> ResponseKind;
+
+    /// check that volume servers hold the values of index entries
+    fn fsck(&self) -> ResponseKind;
+
+    /// stream a copy of the index
+    fn snapshot(&self) -> ResponseKind;
+
+    /// remove expired keys and compact volume servers
+    fn gc(&self) -> ResponseKind;
+
+    /// count volume servers, buckets, values and bytes
+    fn stats(&self) -> ResponseKind;
+
+    /// create a webhook or update its settings
+    fn create_webhook(&self, settings: String) -> ResponseKind;
+
+    /// delete a webhook and its dead letters
+    fn delete_webhook(&self, name: String) -> ResponseKind;
+
+    /// list webhooks with their settings
+    fn list_webhooks(&self) -> ResponseKind;
+
+    /// list changes webhooks failed to take
+    fn dead_letters(&self) -> ResponseKind;
+
+    /// deliver dead letters again, those of a webhook if named
+    fn retry_dead_letters(&self, name: String) -> ResponseKind;
+
     /// dispatch request to admin service
     fn dispatch(&self, mut req: Request) {
         let path = get_key(req.url(), ADMIN_PREFIX);
 
-        let resp = match (path.as_str(), req.method()) {
-            ("add-volume", &Method::Post) => {
-                let mut body = String::new();
-                let _ = req.as_reader().read_to_string(&mut body);
+        let mut body = String::new();
+        if let Method::Post = req.method() {
+            let _ = req.as_reader().read_to_string(&mut body);
+        }
 
-                self.add_volume(body)
-            }
-            ("add-volume", _) => ResponseKind::NotAllowed,
+        let resp = match (path.as_str(), req.method()) {
+            ("add-volume", &Method::Post) => self.register_volume(body, Credentials::of(&req)),
+            ("create-bucket", &Method::Post) => self.create_bucket(body),
+            ("delete-bucket", &Method::Post) => self.delete_bucket(body),
+            ("buckets", &Method::Get) => self.list_buckets(),
+            ("set-quota", &Method::Post) => self.set_quota(body),
+            ("remove-quota", &Method::Post) => self.remove_quota(body),
+            ("usage", &Method::Get) => self.usage(),
+            ("report-corrupt", &Method::Post) => self.report_corrupt(body, Credentials::of(&req)),
+            ("convert-erasure", &Method::Post) => self.convert_erasure(body),
+            ("volumes", &Method::Get) => self.list_volumes(),
+            ("remove-volume", &Method::Post) => self.remove_volume(body),
+            ("drain-volume", &Method::Post) => self.drain_volume(body),
+            ("rebalance", &Method::Post) => self.rebalance(),
+            ("fsck", &Method::Get) => self.fsck(),
+            ("snapshot", &Method::Get) => self.snapshot(),
+            ("gc", &Method::Post) => self.gc(),
+            ("stats", &Method::Get) => self.stats(),
+            ("create-webhook", &Method::Post) => self.create_webhook(body),
+            ("delete-webhook", &Method::Post) => self.delete_webhook(body),
+            ("webhooks", &Method::Get) => self.list_webhooks(),
+            ("dead-letters", &Method::Get) => self.dead_letters(),
+            ("retry-dead-letters", &Method::Post) => self.retry_dead_letters(body),
+            ("add-volume", _)
+            | ("create-bucket", _)
+            | ("delete-bucket", _)
+            | ("buckets", _)
+            | ("set-quota", _)
+            | ("remove-quota", _)
+            | ("usage", _)
+            | ("report-corrupt", _)
+            | ("convert-erasure", _)
+            | ("volumes", _)
+            | ("remove-volume", _)
+            | ("drain-volume", _)
+            | ("rebalance", _)
+            | ("fsck", _)
+            | ("snapshot", _)
+            | ("gc", _)
+            | ("stats", _)
+            | ("create-webhook", _)
+            | ("delete-webhook", _)
+            | ("webhooks", _)
+            | ("dead-letters", _)
+            | ("retry-dead-letters", _) => ResponseKind::NotAllowed,
             (_, _) => ResponseKind::NotFound,
         };
 
@@ -88,62 +354,311 @@ impl Respond for ResponseKind {
         use ResponseKind::*;
 
         let _ = match self {
-            Redirect(url) => req.respond(redirect!(&format!("Location:{}", url))),
+            Redirect(url) => req.respond(redirect!(url)),
             Ok(txt) => req.respond(resp!(txt, 200)),
+            BadRequest(txt) => req.respond(resp!(txt, 400)),
+            Unauthorized => req.respond(resp!("Unauthorized", 401)),
+            Forbidden => req.respond(resp!("Forbidden", 403)),
             NotFound => req.respond(resp!("Key not found", 404)),
             ServerError => req.respond(resp!("Server error", 500)),
             NotAllowed => req.respond(resp!("Method not allowd", 405)),
+            LengthRequired => req.respond(resp!("Length required", 411)),
             Unavailable => req.respond(resp!("Service unavailable", 503)),
+            InsufficientStorage => req.respond(resp!("Quota exceeded", 507)),
+            PayloadTooLarge => req.respond(resp!("Payload too large", 413)),
+            Changes(txt, seq) => {
+                let header =
+                    Header::from_bytes(SEQUENCE_HEADER.as_bytes(), seq.to_string().as_bytes())
+                        .unwrap();
+                req.respond(resp!(txt, 200).with_header(header))
+            }
+            Gone(txt) => req.respond(resp!(txt, 410)),
+            Stream(value, size) => req.respond(Response::from_reader(value, Some(size))),
+            Deleted => req.respond(resp!("", 204)),
+            Created => req.respond(resp!("Created", 201)),
         };
     }
 }
 
-impl Service for Master {
-    type Response = ResponseKind;
+impl ResponseKind {
+    /// status code and body of the response as an item of a batch
+    fn into_item(self) -> (u16, Vec<u8>) {
+        use ResponseKind::*;
 
-    fn get(&self, key: String) -> Self::Response {
-        match self.db.get(key.as_bytes()) {
-            Ok(Some(volume)) => {
-                ResponseKind::Redirect(format!("{}/{}", volume.to_utf8().unwrap().to_string(), key))
+        match self {
+            Redirect(url) => (307, url.into_bytes()),
+            Ok(txt) => (200, txt.into_bytes()),
+            BadRequest(txt) => (400, txt.into_bytes()),
+            Unauthorized => (401, vec![]),
+            Forbidden => (403, vec![]),
+            NotFound => (404, vec![]),
+            ServerError => (500, vec![]),
+            NotAllowed => (405, vec![]),
+            LengthRequired => (411, vec![]),
+            Unavailable => (503, vec![]),
+            InsufficientStorage => (507, vec![]),
+            PayloadTooLarge => (413, vec![]),
+            Changes(txt, _) => (200, txt.into_bytes()),
+            Gone(txt) => (410, txt.into_bytes()),
+            Stream(mut value, _) => {
+                let mut data = Vec::new();
+                match value.read_to_end(&mut data) {
+                    io::Result::Ok(_) => (200, data),
+                    Err(_) => (500, vec![]),
+                }
             }
-            Ok(None) => ResponseKind::NotFound,
-            Err(_) => ResponseKind::ServerError,
+            Deleted => (204, vec![]),
+            Created => (201, vec![]),
         }
     }
+}
 
-    fn save(&self, key: String, _value: impl Read) -> Self::Response {
-        if self.volumes.read().unwrap().is_empty() {
-            ResponseKind::Unavailable
-        } else {
-            let volume_url = self.key_to_volume(&key);
-            match self.db.put(key.as_bytes(), volume_url.as_bytes()) {
-                Ok(_) => {
-                    // increment count in map
-                    self.increment_count(&volume_url);
-                    ResponseKind::Redirect(format!("{}/{}", volume_url, key))
-                }
-                Err(_) => ResponseKind::ServerError,
-            }
+/// changes after `since` matching `filter`, waiting up to `timeout` for some
+/// if there are none yet
+fn watch_changes(
+    log: &ChangeLog,
+    filter: &Filter,
+    mut since: u64,
+    timeout: Duration,
+    limit: usize,
+) -> ResponseKind {
+    let until = Instant::now() + timeout;
+
+    loop {
+        let (changes, seq) = log.read(since, filter, limit);
+        let now = Instant::now();
+
+        if !changes.is_empty() || now >= until || !log.wait(seq, until - now) {
+            let lines: Vec<String> = changes.iter().map(Change::encode).collect();
+            return ResponseKind::Changes(lines.join("\n"), seq);
         }
+
+        since = seq;
     }
+}
 
-    fn delete(&self, key: String) -> Self::Response {
-        match self.db.get(key.as_bytes()) {
-            Ok(Some(volume)) => {
-                let volume_url = volume.to_utf8().unwrap();
-
-                // delete it from db
-                if self.db.delete(key.as_bytes()).is_ok() {
-                    // decrement count in map
-                    self.decrement_count(&volume_url);
-                    ResponseKind::Redirect(format!("{}/{}", volume_url, key))
-                } else {
-                    ResponseKind::ServerError
-                }
-            }
-            Ok(None) => ResponseKind::NotFound,
-            Err(_) => ResponseKind::ServerError,
+/// change of `key` in bucket to be logged
+fn change(op: changes::Op, bucket: &str, key: &str, record: &Record) -> Change {
+    Change {
+        seq: 0,
+        op,
+        bucket: bucket.to_owned(),
+        key: key.to_owned(),
+        size: record.size,
+        version: record.version,
+    }
+}
+
+/// index key of settings of a webhook
+fn webhook_key(name: &str) -> String {
+    format!("{}{}", WEBHOOK_META_PREFIX, name)
+}
+
+/// index key of the sequence number of the last change a webhook got
+fn webhook_cursor_key(name: &str) -> String {
+    format!("{}{}", WEBHOOK_CURSOR_PREFIX, name)
+}
+
+/// prefix of index keys of dead letters of a webhook
+fn dead_letter_prefix(name: &str) -> String {
+    format!("{}{}/", DEAD_LETTER_PREFIX, name)
+}
+
+/// index key of the change a webhook failed to take, zero padded to sort
+/// by sequence
+fn dead_letter_key(name: &str, seq: u64) -> String {
+    format!("{}{:020}", dead_letter_prefix(name), seq)
+}
+
+/// index key of `key` in bucket
+fn index_key(bucket: &Bucket, key: &str) -> String {
+    if bucket.name.is_empty() {
+        key.to_owned()
+    } else {
+        format!("{}{}/{}", OBJECT_PREFIX, bucket.name, key)
+    }
+}
+
+/// index key of a previous version of a key
+fn version_key(index_key: &str, version: u64) -> String {
+    format!("{}\u{0}{}", index_key, version)
+}
+
+/// splits an index key into bucket name and key, without version suffix
+fn split_index_key(index_key: &str) -> (&str, &str) {
+    let (bucket, key) = if index_key.starts_with(OBJECT_PREFIX) {
+        let path = &index_key[OBJECT_PREFIX.len()..];
+        let indx = path.find('/').unwrap_or_else(|| path.len());
+        (&path[..indx], path.get(indx + 1..).unwrap_or(""))
+    } else {
+        ("", index_key)
+    };
+
+    (
+        bucket,
+        &key[..key.find('\u{0}').unwrap_or_else(|| key.len())],
+    )
+}
+
+/// index key under which quota of a prefix is stored
+fn quota_key(bucket: &str, prefix: &str) -> String {
+    format!("{}{}/{}", QUOTA_META_PREFIX, bucket, prefix)
+}
+
+/// url of a value on volume server
+/// keys of the default bucket keep their `/store/` urls, each version of a key
+/// in a versioned bucket is a separate value
+fn location(volume: &str, bucket: &Bucket, key: &str, record: &Record) -> String {
+    if bucket.name.is_empty() {
+        return format!("{}{}{}", volume, STORE_PREFIX, key);
+    }
+
+    match record.version {
+        Some(version) => format!(
+            "{}{}{}/{}@{}",
+            volume, BUCKET_PREFIX, bucket.name, key, version
+        ),
+        None => format!("{}{}{}/{}", volume, BUCKET_PREFIX, bucket.name, key),
+    }
+}
+
+/// redirect to the first volume of a record. remaining volumes are passed on,
+/// the first volume forwards uploads and deletes to them
+fn replicated_location(
+    cluster: &ClusterConfig,
+    bucket: &Bucket,
+    key: &str,
+    record: &Record,
+) -> String {
+    let url = location(&record.volumes[0], bucket, key, record);
+    with_replicas(cluster, url, &record.volumes)
+}
+
+/// adds volumes other than the first one to `url` as replicas, signed with
+/// the join token of the cluster
+fn with_replicas(cluster: &ClusterConfig, url: String, volumes: &[String]) -> String {
+    if volumes.len() < 2 {
+        return url;
+    }
+
+    let replicas = volumes[1..].join(",");
+    match cluster.sign_replicas(&replicas) {
+        Some(sig) => format!(
+            "{}?replicas={}&{}={}",
+            url, replicas, REPLICAS_SIG_PARAM, sig
+        ),
+        None => format!("{}?replicas={}", url, replicas),
+    }
+}
+
+/// passes the compression setting of a bucket on to the volume an upload is
+/// redirected to
+fn with_compression(url: String, bucket: &Bucket) -> String {
+    match bucket.compression {
+        Some(compression) => {
+            let separator = if url.contains('?') { '&' } else { '?' };
+            let name = compression.map_or("none", Encoding::name);
+            format!("{}{}{}={}", url, separator, COMPRESSION_PARAM, name)
         }
+        None => url,
+    }
+}
+
+/// passes the size accounted for an upload on to the volume, which rejects
+/// bodies of another length
+fn with_size(url: String, size: u64) -> String {
+    let separator = if url.contains('?') { '&' } else { '?' };
+    format!("{}{}{}={}", url, separator, SIZE_PARAM, size)
+}
+
+/// url an upload of a value placed as `record` is redirected to
+fn upload_location(cluster: &ClusterConfig, bucket: &Bucket, key: &str, record: &Record) -> String {
+    let url = replicated_location(cluster, bucket, key, record);
+    with_size(with_compression(url, bucket), record.size)
+}
+
+/// url of a blob on a volume server. blobs are named as volumes hash them,
+/// `/<key>` for keys of the default bucket and `<bucket>/<key>` otherwise
+fn blob_location(volume: &str, blob: &str) -> String {
+    if blob.starts_with('/') {
+        format!("{}{}{}", volume, STORE_PREFIX, &blob[1..])
+    } else {
+        format!("{}{}{}", volume, BUCKET_PREFIX, blob)
+    }
+}
+
+/// urls of blobs of parts or shards on volume servers
+fn part_urls(parts: &[Part]) -> Vec<String> {
+    parts
+        .iter()
+        .flat_map(|part| {
+            part.volumes
+                .iter()
+                .map(move |volume| part_location(volume, &part.blob))
+        })
+        .collect()
+}
+
+/// url a part is uploaded to, on the first of its volumes
+fn part_upload_location(
+    cluster: &ClusterConfig,
+    bucket: &Bucket,
+    id: &str,
+    number: u32,
+    record: &Record,
+) -> String {
+    let url = part_location(&record.volumes[0], &part_blob(id, number));
+    with_size(
+        with_compression(with_replicas(cluster, url, &record.volumes), bucket),
+        record.size,
+    )
+}
+
+/// index key of a multipart upload, its parts are stored under `<key>/<number>`
+fn upload_key(id: &str) -> String {
+    format!("{}{}", UPLOAD_META_PREFIX, id)
+}
+
+/// checks whether a request is part of a multipart upload
+fn is_upload(url: &str) -> bool {
+    get_param(url, "uploads").is_some() || get_param(url, "upload").is_some()
+}
+
+/// s3 error of a master response
+fn s3_error(resp: ResponseKind) -> S3Error {
+    match resp {
+        ResponseKind::NotFound => S3Error::NoSuchKey,
+        ResponseKind::BadRequest(msg) => S3Error::InvalidRequest(msg),
+        ResponseKind::Unauthorized | ResponseKind::Forbidden => S3Error::AccessDenied,
+        ResponseKind::NotAllowed => S3Error::MethodNotAllowed,
+        ResponseKind::LengthRequired => S3Error::MissingContentLength,
+        ResponseKind::Unavailable => S3Error::ServiceUnavailable,
+        ResponseKind::InsufficientStorage => S3Error::QuotaExceeded,
+        _ => S3Error::InternalError,
+    }
+}
+
+/// s3 error of a master response to a request for a multipart upload
+fn upload_error(resp: ResponseKind) -> S3Error {
+    match resp {
+        ResponseKind::NotFound => S3Error::NoSuchUpload,
+        resp => s3_error(resp),
+    }
+}
+
+impl Service for Master {
+    type Response = ResponseKind;
+
+    fn get(&self, key: String) -> Self::Response {
+        self.get_object(&Bucket::default(), &key, None)
+    }
+
+    fn save(&self, key: String, value: impl Read, length: Option<u64>) -> Self::Response {
+        self.save_object(&Bucket::default(), &key, value, length, None)
+    }
+
+    fn delete(&self, key: String) -> Self::Response {
+        self.delete_object(&Bucket::default(), &key, None)
     }
 }
 
@@ -160,250 +675,4106 @@ impl AdminService for Master {
             }
         }
     }
-}
-
-impl Master {
-    pub fn new(db: DB, volumes: Vec<String>) -> Master {
-        // Create HashMap from url list
-        let mut volumes_map = HashMap::<String, u32>::new();
 
-        for url in volumes {
-            volumes_map.insert(url, 0);
+    fn register_volume(&self, volume: String, credentials: Credentials) -> ResponseKind {
+        if !self.authorize_volume(&volume, &credentials) {
+            return ResponseKind::Unauthorized;
         }
 
-        // update number of keys in each server from existing db
-        let iter = db.iterator(IteratorMode::Start);
-        for (_, url_bytes) in iter {
-            if let Ok(url_raw) = str::from_utf8(&url_bytes) {
-                println!("found {}", url_raw.to_owned());
-                let count = volumes_map.entry(url_raw.to_owned()).or_default();
-                *count += 1;
-            }
+        if !self.verify_volume(&volume) {
+            return ResponseKind::BadRequest(
+                "Volume server unreachable or serving a different cluster".to_string(),
+            );
         }
 
-        Master {
-            db: Arc::new(db),
-            volumes: Arc::new(RwLock::new(volumes_map)),
-        }
+        self.add_volume(volume)
     }
 
-    /// translate key to volume url
-    /// volume server is selected based on the number of keys it holds.
-    /// Server with lesser number of keys are more likely to get selected.
-    fn key_to_volume(&self, _key: &str) -> String {
-        let volumes_map = self.volumes.read().unwrap();
-        let len = volumes_map.len();
-        let mut vlms = Vec::<&String>::with_capacity(len);
-        let mut counts = Vec::<f32>::with_capacity(len);
-
-        let mut cumulative_count = 0.0f32;
-        let mut max_count = 0;
+    fn create_bucket(&self, settings: String) -> ResponseKind {
+        let bucket = match Bucket::parse(&settings) {
+            Ok(bucket) => bucket,
+            Err(e) => return ResponseKind::BadRequest(e),
+        };
 
-        for (key, value) in volumes_map.iter() {
-            vlms.push(key);
+        let mut buckets = self.buckets.write().unwrap();
+        let meta_key = format!("{}{}", BUCKET_META_PREFIX, bucket.name);
 
-            let count = if *value == 0 { 1 } else { *value };
+        match self
+            .index
+            .put(meta_key.as_bytes(), bucket.encode().as_bytes())
+        {
+            Ok(_) => match buckets.insert(bucket.name.clone(), bucket) {
+                Some(_) => ResponseKind::Ok("Bucket updated".to_string()),
+                None => ResponseKind::Ok("Bucket created".to_string()),
+            },
+            Err(_) => ResponseKind::ServerError,
+        }
+    }
 
-            if count > max_count {
-                max_count = count;
-            }
+    fn delete_bucket(&self, name: String) -> ResponseKind {
+        let name = name.trim();
+        let mut buckets = self.buckets.write().unwrap();
 
-            cumulative_count += count as f32;
-            counts.push(cumulative_count);
+        if !buckets.contains_key(name) {
+            return ResponseKind::NotFound;
         }
 
-        // invert-normalize counts
-        for count in counts.iter_mut() {
-            *count = max_count as f32 / *count;
+        let prefix = format!("{}{}/", OBJECT_PREFIX, name);
+        if self.index.scan_prefix(prefix.as_bytes()).next().is_some() {
+            return ResponseKind::BadRequest("Bucket not empty".to_string());
         }
 
-        let mut rng = thread_rng();
-        let random = rng.gen_range(0.0, max_count as f32);
+        // quotas of the bucket go along with it
+        let mut batch = Batch::default();
+        batch.delete(format!("{}{}", BUCKET_META_PREFIX, name).as_bytes());
 
-        for indx in 0..len {
-            if random <= counts[indx] {
-                return (*vlms[indx]).to_string();
+        let quota_prefix = quota_key(name, "");
+        for (key, _) in self.index.scan_prefix(quota_prefix.as_bytes()) {
+            batch.delete(&key);
+        }
+
+        match self.index.write(batch) {
+            Ok(_) => {
+                buckets.remove(name);
+                self.usage.write().unwrap().remove_bucket(name);
+                ResponseKind::Ok("Bucket deleted".to_string())
             }
+            Err(_) => ResponseKind::ServerError,
         }
+    }
 
-        vlms[len - 1].to_string()
+    fn list_buckets(&self) -> ResponseKind {
+        let buckets = self.buckets.read().unwrap();
+
+        let mut list: Vec<String> = buckets
+            .values()
+            .map(|bucket| match bucket.access {
+                // do not leak tokens
+                Access::Private(_) => Bucket {
+                    access: Access::Private("*".to_string()),
+                    ..bucket.clone()
+                }
+                .encode(),
+                _ => bucket.encode(),
+            })
+            .collect();
+        list.sort();
+
+        ResponseKind::Ok(list.join("\n"))
     }
 
-    /// increment counter for url
-    fn increment_count(&self, url: &str) {
-        let mut volumes_map = self.volumes.write().unwrap();
+    fn set_quota(&self, settings: String) -> ResponseKind {
+        let quota = match Quota::parse(&settings) {
+            Ok(quota) => quota,
+            Err(e) => return ResponseKind::BadRequest(e),
+        };
 
-        if let Some(count) = (*volumes_map).get_mut(url) {
-            *count += 1;
+        if !quota.bucket.is_empty() && !self.buckets.read().unwrap().contains_key(&quota.bucket) {
+            return ResponseKind::BadRequest(format!("no bucket {}", quota.bucket));
         }
-    }
 
-    /// decrement counter for url
-    fn decrement_count(&self, url: &str) {
-        let mut volumes_map = self.volumes.write().unwrap();
-        if let Some(count) = (*volumes_map).get_mut(url) {
-            *count -= 1;
+        // usage can not change while it is computed
+        let mut tracker = self.usage.write().unwrap();
+        let key = quota_key(&quota.bucket, &quota.prefix);
+
+        match self.index.put(key.as_bytes(), quota.encode().as_bytes()) {
+            Ok(_) => {
+                let usage = self.scan_usage(&quota.bucket, &quota.prefix);
+                tracker.set_quota(quota, usage);
+                ResponseKind::Ok("Quota set".to_string())
+            }
+            Err(_) => ResponseKind::ServerError,
         }
     }
 
-    fn dispatch(&self, req: Request) {
-        let url = req.url();
+    fn remove_quota(&self, settings: String) -> ResponseKind {
+        let quota = match Quota::parse(&settings) {
+            Ok(quota) => quota,
+            Err(e) => return ResponseKind::BadRequest(e),
+        };
 
-        if url.starts_with(STORE_PREFIX) {
-            Service::dispatch(self, req);
-        } else if url.starts_with(ADMIN_PREFIX) {
-            AdminService::dispatch(self, req);
-        } else {
-            let _ = req.respond(resp!("Path not found", 404));
+        let mut tracker = self.usage.write().unwrap();
+        let key = quota_key(&quota.bucket, &quota.prefix);
+
+        match self.index.delete(key.as_bytes()) {
+            Ok(_) if tracker.remove_quota(&quota.bucket, &quota.prefix) => {
+                ResponseKind::Ok("Quota removed".to_string())
+            }
+            Ok(_) => ResponseKind::NotFound,
+            Err(_) => ResponseKind::ServerError,
         }
     }
-}
 
-/// starts a kalavara master server
-/// # Arguments
-///
-/// * `port` - Port name to listen at
-/// * `data_dir` - Database directory
-/// * `threads` - Number of threads to spawn
-/// * `volumes` - List of volume servers
-///
-pub fn start(port: u16, data_dir: &str, threads: u16, volumes: Vec<String>) {
-    let db = match DB::open_default(data_dir) {
-        Ok(db) => db,
-        Err(e) => panic!("failed to open database: {:?}", e),
-    };
+    fn usage(&self) -> ResponseKind {
+        let buckets = self.buckets.read().unwrap();
+        ResponseKind::Ok(self.usage.read().unwrap().report(&buckets))
+    }
 
-    let addr: SocketAddr = ([0, 0, 0, 0], port).into();
+    fn report_corrupt(&self, report: String, credentials: Credentials) -> ResponseKind {
+        let mut lines = report
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty());
 
-    let server = match Server::http(addr) {
-        Ok(server) => Arc::new(server),
-        Err(e) => panic!("failed to start http server: {:?}", e),
-    };
+        let volume = match lines.next() {
+            Some(volume) => volume,
+            None => return ResponseKind::BadRequest("volume url required".to_string()),
+        };
 
-    let master = Arc::new(Master::new(db, volumes));
+        if !self.authorize_volume(volume, &credentials) {
+            return ResponseKind::Unauthorized;
+        }
 
-    let mut handles = Vec::new();
+        if !self.volumes.read().unwrap().contains_key(volume) {
+            return ResponseKind::BadRequest(format!("unknown volume {}", volume));
+        }
 
-    for _ in 0..threads {
-        let server = server.clone();
-        let handler = master.clone();
+        let blobs: Vec<&str> = lines.collect();
+        let shards = self.shard_records(&blobs);
 
-        handles.push(thread::spawn(move || {
-            for rq in server.incoming_requests() {
-                handler.dispatch(rq);
-            }
-        }));
-    }
+        // blobs without a healthy replica are lost, reported back as such
+        let lost: Vec<&str> = blobs
+            .into_iter()
+            .filter(|blob| !self.repair_blob(volume, blob, &shards))
+            .collect();
+        for blob in lost.iter() {
+            println!("lost corrupt blob {} on {}", blob, volume);
+        }
 
-    for h in handles {
-        h.join().unwrap();
+        ResponseKind::Ok(lost.join("\n"))
     }
-}
 
-#[cfg(test)]
-mod test {
-    use super::*;
-    use tempfile::tempdir;
+    fn convert_erasure(&self, name: String) -> ResponseKind {
+        let name = name.trim();
 
-    #[test]
-    fn test_master_crud() {
-        let data_dir = tempdir().unwrap();
+        match self.buckets.read().unwrap().get(name) {
+            Some(bucket) if bucket.erasure.is_some() => {}
+            Some(_) => {
+                return ResponseKind::BadRequest(format!("bucket {} has no erasure setting", name))
+            }
+            None => return ResponseKind::NotFound,
+        }
 
-        let db = match DB::open_default(data_dir) {
-            Ok(db) => db,
-            Err(e) => panic!("failed to open database: {:?}", e),
+        // kept until done, so that conversion resumes after a restart
+        let marker = format!("{}{}", CONVERT_META_PREFIX, name);
+        match self.index.put(marker.as_bytes(), b"") {
+            Ok(_) => ResponseKind::Ok("Conversion scheduled".to_string()),
+            Err(_) => ResponseKind::ServerError,
+        }
+    }
+
+    fn list_volumes(&self) -> ResponseKind {
+        let draining = self.draining.read().unwrap().clone();
+
+        let mut list: Vec<String> = self
+            .volumes
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(url, count)| {
+                let state = if draining.contains(url) {
+                    "draining"
+                } else {
+                    "active"
+                };
+                format!("url={}&values={}&state={}", url, count, state)
+            })
+            .collect();
+        list.sort();
+
+        ResponseKind::Ok(list.join("\n"))
+    }
+
+    fn remove_volume(&self, volume: String) -> ResponseKind {
+        let volume = volume.trim();
+        let mut volumes_map = self.volumes.write().unwrap();
+
+        match volumes_map.get(volume) {
+            Some(&0) => {}
+            Some(count) => {
+                return ResponseKind::BadRequest(format!(
+                    "volume holds {} values, drain it first",
+                    count
+                ))
+            }
+            None => return ResponseKind::NotFound,
+        }
+
+        volumes_map.remove(volume);
+        self.draining.write().unwrap().remove(volume);
+
+        ResponseKind::Ok("Volume removed".to_string())
+    }
+
+    fn drain_volume(&self, volume: String) -> ResponseKind {
+        let volume = volume.trim();
+
+        if !self.volumes.read().unwrap().contains_key(volume) {
+            return ResponseKind::NotFound;
+        }
+        self.draining.write().unwrap().insert(volume.to_owned());
+
+        let (moved, failed) = self.move_values(|record| {
+            if record.all_volumes().any(|holder| holder == volume) {
+                Some(volume.to_owned())
+            } else {
+                None
+            }
+        });
+
+        ResponseKind::Ok(format!("moved={}&failed={}", moved, failed))
+    }
+
+    fn rebalance(&self) -> ResponseKind {
+        let (moved, failed) = self.move_values(|record| {
+            // values in parts or shards stay where they are
+            if !record.is_whole() {
+                return None;
+            }
+
+            let share = self.share();
+            let target = self.pick_target(&record.volumes)?;
+            let volumes_map = self.volumes.read().unwrap();
+            let lowest = volumes_map.get(&target).cloned().unwrap_or(0);
+
+            // values are moved only if the target ends up holding fewer
+            record
+                .volumes
+                .iter()
+                .filter_map(|volume| volumes_map.get(volume).map(|count| (volume, *count)))
+                .filter(|(_, count)| *count > share && *count > lowest + 1)
+                .max_by_key(|(_, count)| *count)
+                .map(|(volume, _)| volume.to_owned())
+        });
+
+        ResponseKind::Ok(format!("moved={}&failed={}", moved, failed))
+    }
+
+    fn fsck(&self) -> ResponseKind {
+        let volumes: HashSet<String> = self.volumes.read().unwrap().keys().cloned().collect();
+        let now = now();
+        let mut problems = Vec::new();
+
+        for (key, value) in self.index.scan() {
+            if key.starts_with(META_PREFIX.as_bytes()) {
+                continue;
+            }
+
+            let index_key = match str::from_utf8(&key) {
+                Ok(index_key) => index_key,
+                Err(_) => continue,
+            };
+            let record = match Record::decode(&value) {
+                Some(record) if !record.expired(now) => record,
+                _ => continue,
+            };
+
+            let (name, key) = split_index_key(index_key);
+            let bucket = Bucket {
+                name: name.to_owned(),
+                ..Bucket::default()
+            };
+
+            let mut urls: Vec<(&String, String)> = record
+                .volumes
+                .iter()
+                .map(|volume| (volume, location(volume, &bucket, key, &record)))
+                .collect();
+            for part in record.parts.iter().chain(record.shards.iter()) {
+                urls.extend(
+                    part.volumes
+                        .iter()
+                        .map(|volume| (volume, part_location(volume, &part.blob))),
+                );
+            }
+
+            for (volume, url) in urls {
+                let problem = if !volumes.contains(volume) {
+                    "unknown-volume"
+                } else {
+                    match http::open("HEAD", &url, &[], &self.tls) {
+                        Ok((ref resp, _)) if resp.status_code == 200 => continue,
+                        Ok((ref resp, _)) if resp.status_code == 404 => "missing",
+                        Ok(_) => "error",
+                        Err(_) => "unreachable",
+                    }
+                };
+
+                problems.push(format!(
+                    "problem={}&url={}&bucket={}&key={}",
+                    problem, url, name, key
+                ));
+            }
+        }
+
+        ResponseKind::Ok(problems.join("\n"))
+    }
+
+    fn snapshot(&self) -> ResponseKind {
+        // entries are spooled to a file, as the length of the response is
+        // sent before it
+        let spooled = tempfile::tempfile().and_then(|file| {
+            let mut out = BufWriter::new(file);
+            for (key, value) in self.index.scan() {
+                batch::write_frame(&mut out, &key)?;
+                batch::write_frame(&mut out, &value)?;
+            }
+
+            let mut file = out.into_inner()?;
+            let size = file.seek(SeekFrom::End(0))?;
+            file.seek(SeekFrom::Start(0))?;
+            Ok((file, size))
+        });
+
+        match spooled {
+            Ok((file, size)) => ResponseKind::Stream(Box::new(file), size),
+            Err(e) => {
+                println!("snapshot failed: {}", e);
+                ResponseKind::ServerError
+            }
+        }
+    }
+
+    fn gc(&self) -> ResponseKind {
+        let now = now();
+        let mut expired = 0;
+
+        for (key, value) in self.index.scan() {
+            if key.starts_with(META_PREFIX.as_bytes()) {
+                continue;
+            }
+
+            let index_key = match str::from_utf8(&key) {
+                Ok(index_key) => index_key,
+                Err(_) => continue,
+            };
+            let record = match Record::decode(&value) {
+                Some(record) if record.expired(now) => record,
+                _ => continue,
+            };
+
+            let (name, key) = split_index_key(index_key);
+            let bucket = Bucket {
+                name: name.to_owned(),
+                ..Bucket::default()
+            };
+
+            // keys stored again meanwhile are kept
+            if let Ok(true) = self.remove_record(index_key, &record) {
+                self.purge(&bucket, key, &record);
+                expired += 1;
+            }
+        }
+
+        // clusters with a join token expect it from admins of volumes
+        let headers: Vec<(&str, &str)> = self
+            .cluster
+            .join_token
+            .iter()
+            .map(|token| (JOIN_TOKEN_HEADER, token.as_str()))
+            .collect();
+
+        let volumes: Vec<String> = self.volumes.read().unwrap().keys().cloned().collect();
+        let mut compacted = 0;
+
+        for volume in volumes.iter() {
+            let url = format!("{}{}compact", volume, ADMIN_PREFIX);
+
+            match http::request("POST", &url, &headers, b"", &self.tls) {
+                Ok(ref res) if res.status_code == 200 => compacted += 1,
+                _ => println!("failed to compact {}", volume),
+            }
+        }
+
+        ResponseKind::Ok(format!(
+            "expired={}&compacted={}&volumes={}",
+            expired,
+            compacted,
+            volumes.len()
+        ))
+    }
+
+    fn stats(&self) -> ResponseKind {
+        let (volumes, blobs) = {
+            let volumes_map = self.volumes.read().unwrap();
+            (volumes_map.len(), volumes_map.values().sum::<u32>())
+        };
+        let draining = self.draining.read().unwrap().len();
+        let buckets = self.buckets.read().unwrap().len();
+        let usage = self.usage.read().unwrap().total();
+
+        ResponseKind::Ok(format!(
+            "volumes={}&draining={}&buckets={}&objects={}&bytes={}&blobs={}",
+            volumes, draining, buckets, usage.objects, usage.bytes, blobs
+        ))
+    }
+
+    fn create_webhook(&self, settings: String) -> ResponseKind {
+        let webhook = match Webhook::parse(&settings) {
+            Ok(webhook) => webhook,
+            Err(e) => return ResponseKind::BadRequest(e),
+        };
+
+        let mut webhooks = self.webhooks.write().unwrap();
+        let created = !webhooks.contains_key(&webhook.name);
+
+        let mut batch = Batch::default();
+        batch.put(
+            webhook_key(&webhook.name).as_bytes(),
+            webhook.encode().as_bytes(),
+        );
+
+        // new webhooks get changes from now on
+        if created {
+            let last = self.changes.last().to_string();
+            batch.put(
+                webhook_cursor_key(&webhook.name).as_bytes(),
+                last.as_bytes(),
+            );
+        }
+
+        match self.index.write(batch) {
+            Ok(_) => {
+                webhooks.insert(webhook.name.clone(), webhook);
+                if created {
+                    ResponseKind::Ok("Webhook created".to_string())
+                } else {
+                    ResponseKind::Ok("Webhook updated".to_string())
+                }
+            }
+            Err(_) => ResponseKind::ServerError,
+        }
+    }
+
+    fn delete_webhook(&self, name: String) -> ResponseKind {
+        let name = name.trim();
+        let mut webhooks = self.webhooks.write().unwrap();
+
+        if !webhooks.contains_key(name) {
+            return ResponseKind::NotFound;
+        }
+
+        let mut batch = Batch::default();
+        batch.delete(webhook_key(name).as_bytes());
+        batch.delete(webhook_cursor_key(name).as_bytes());
+        for (key, _) in self.index.scan_prefix(dead_letter_prefix(name).as_bytes()) {
+            batch.delete(&key);
+        }
+
+        match self.index.write(batch) {
+            Ok(_) => {
+                webhooks.remove(name);
+                ResponseKind::Ok("Webhook deleted".to_string())
+            }
+            Err(_) => ResponseKind::ServerError,
+        }
+    }
+
+    fn list_webhooks(&self) -> ResponseKind {
+        let webhooks = self.webhooks.read().unwrap();
+
+        // do not leak secrets
+        let mut list: Vec<String> = webhooks
+            .values()
+            .map(|webhook| {
+                Webhook {
+                    secret: "*".to_string(),
+                    ..webhook.clone()
+                }
+                .encode()
+            })
+            .collect();
+        list.sort();
+
+        ResponseKind::Ok(list.join("\n"))
+    }
+
+    fn dead_letters(&self) -> ResponseKind {
+        let letters: Vec<String> = self
+            .index
+            .scan_prefix(DEAD_LETTER_PREFIX.as_bytes())
+            .filter_map(|(_, value)| String::from_utf8(value.into_vec()).ok())
+            .collect();
+
+        ResponseKind::Ok(letters.join("\n"))
+    }
+
+    fn retry_dead_letters(&self, name: String) -> ResponseKind {
+        let name = name.trim();
+        let prefix = match name {
+            "" => DEAD_LETTER_PREFIX.to_owned(),
+            _ => dead_letter_prefix(name),
+        };
+
+        let letters: Vec<(Box<[u8]>, DeadLetter)> = self
+            .index
+            .scan_prefix(prefix.as_bytes())
+            .filter_map(|(key, value)| {
+                let letter = str::from_utf8(&value).ok()?;
+                Some((key, DeadLetter::parse(letter).ok()?))
+            })
+            .collect();
+
+        let (mut delivered, mut failed) = (0, 0);
+
+        for (key, mut letter) in letters {
+            let webhook = self.webhooks.read().unwrap().get(&letter.webhook).cloned();
+            let webhook = match webhook {
+                Some(webhook) => webhook,
+                None => continue,
+            };
+
+            match webhook.deliver(&letter.change, &self.tls) {
+                Ok(_) => {
+                    let _ = self.index.delete(&key);
+                    delivered += 1;
+                }
+                Err(e) => {
+                    letter.attempts += 1;
+                    letter.error = e;
+                    let _ = self.index.put(&key, letter.encode().as_bytes());
+                    failed += 1;
+                }
+            }
+        }
+
+        ResponseKind::Ok(format!("delivered={}&failed={}", delivered, failed))
+    }
+}
+
+impl Master {
+    pub fn new(
+        index: Arc<dyn IndexStore>,
+        volumes: Vec<String>,
+        cluster: ClusterConfig,
+        tls: TlsConfig,
+    ) -> Master {
+        // Create HashMap from url list
+        let mut volumes_map = HashMap::<String, u32>::new();
+        let mut buckets = HashMap::<String, Bucket>::new();
+        let mut webhooks = HashMap::<String, Webhook>::new();
+        let mut usage = Tracker::default();
+
+        for url in volumes {
+            volumes_map.insert(url, 0);
+        }
+
+        // quotas have to be known before values are accounted
+        for (_, value_bytes) in index.scan_prefix(QUOTA_META_PREFIX.as_bytes()) {
+            if let Ok(quota) = str::from_utf8(&value_bytes)
+                .map_err(|e| e.to_string())
+                .and_then(Quota::parse)
+            {
+                usage.set_quota(quota, Usage::default());
+            }
+        }
+
+        // update number of keys in each server and bucket usage from existing index
+        for (key_bytes, value_bytes) in index.scan() {
+            let key = match str::from_utf8(&key_bytes) {
+                Ok(key) => key,
+                Err(_) => continue,
+            };
+
+            if key.starts_with(BUCKET_META_PREFIX) {
+                match str::from_utf8(&value_bytes)
+                    .map_err(|e| e.to_string())
+                    .and_then(Bucket::parse)
+                {
+                    Ok(bucket) => {
+                        buckets.insert(bucket.name.clone(), bucket);
+                    }
+                    Err(e) => println!("skipping unreadable bucket {}: {}", key, e),
+                }
+            } else if key.starts_with(WEBHOOK_META_PREFIX) {
+                match str::from_utf8(&value_bytes)
+                    .map_err(|e| e.to_string())
+                    .and_then(Webhook::parse)
+                {
+                    Ok(webhook) => {
+                        webhooks.insert(webhook.name.clone(), webhook);
+                    }
+                    Err(e) => println!("skipping unreadable webhook {}: {}", key, e),
+                }
+            } else if !key.starts_with(META_PREFIX) {
+                if let Some(record) = Record::decode(&value_bytes) {
+                    for url in record.all_volumes() {
+                        println!("found {}", url);
+                        let count = volumes_map.entry(url.to_owned()).or_default();
+                        *count += 1;
+                    }
+
+                    let (bucket, key) = split_index_key(key);
+                    usage.add(bucket, key, record.size);
+                }
+            }
+        }
+
+        let shutdown = Shutdown::default();
+        let changes = Arc::new(ChangeLog::open(
+            index.clone(),
+            CHANGE_RETENTION,
+            shutdown.clone(),
+        ));
+
+        Master {
+            index,
+            volumes: Arc::new(RwLock::new(volumes_map)),
+            draining: RwLock::new(HashSet::new()),
+            buckets: RwLock::new(buckets),
+            usage: RwLock::new(usage),
+            changes,
+            webhooks: RwLock::new(webhooks),
+            cluster,
+            tls,
+            shutdown,
+        }
+    }
+
+    /// checks whether a volume server at `url` is allowed to join the
+    /// cluster. volumes need the join token, or a client certificate for
+    /// their host when master has a CA bundle
+    fn authorize_volume(&self, url: &str, credentials: &Credentials) -> bool {
+        if self.cluster.join_token.is_none() && self.tls.ca_file.is_none() {
+            return true;
+        }
+
+        let token = credentials.token.as_deref();
+        let certified = credentials
+            .certificate
+            .as_ref()
+            .is_some_and(|cert| cluster::certifies(cert, url));
+
+        self.cluster.verify_token(token) || (self.tls.ca_file.is_some() && certified)
+    }
+
+    /// checks that volume server is reachable at `url` and serves this cluster
+    fn verify_volume(&self, url: &str) -> bool {
+        let cluster_url = format!("{}{}cluster-id", url, ADMIN_PREFIX);
+
+        match http::request("GET", &cluster_url, &[], b"", &self.tls) {
+            Ok(ref res) if res.status_code == 200 => res.text() == self.cluster.id(),
+            _ => false,
+        }
+    }
+
+    /// reads index entry
+    fn get_record(&self, index_key: &str) -> io::Result<Option<Record>> {
+        Ok(self
+            .index
+            .get(index_key.as_bytes())?
+            .and_then(|raw| Record::decode(&raw)))
+    }
+
+    /// finds index entry of the current or given version of a key
+    fn find_record(
+        &self,
+        bucket: &Bucket,
+        key: &str,
+        version: Option<u64>,
+    ) -> io::Result<Option<(String, Record)>> {
+        let current_key = index_key(bucket, key);
+        let current = self.get_record(&current_key)?;
+
+        let version = match version {
+            Some(version) => version,
+            None => return Ok(current.map(|record| (current_key, record))),
+        };
+
+        if let Some(record) = current {
+            if record.version.unwrap_or(0) == version {
+                return Ok(Some((current_key, record)));
+            }
+        }
+
+        let old_key = version_key(&current_key, version);
+        Ok(self.get_record(&old_key)?.map(|record| (old_key, record)))
+    }
+
+    /// removes index entry, logs the delete and updates counters. returns
+    /// `false` without removing anything if the entry no longer is `record`
+    fn remove_record(&self, index_key: &str, record: &Record) -> io::Result<bool> {
+        let (bucket, key) = split_index_key(index_key);
+
+        // entry is checked and removed under the usage lock, like it is
+        // written in `commit`, so that a value stored meanwhile is neither
+        // removed nor accounted twice
+        let mut usage = self.usage.write().unwrap();
+        match self.get_record(index_key)? {
+            Some(ref current) if current == record => {}
+            _ => return Ok(false),
+        }
+
+        let mut batch = Batch::default();
+        batch.delete(index_key.as_bytes());
+        self.changes.write(
+            batch,
+            vec![change(changes::Op::Delete, bucket, key, record)],
+        )?;
+
+        for volume in record.all_volumes() {
+            self.decrement_count(volume);
+        }
+
+        usage.remove(bucket, key, record.size);
+
+        Ok(true)
+    }
+
+    /// volume servers holding a blob, according to the index
+    fn blob_holders(&self, blob: &str) -> io::Result<Option<Vec<String>>> {
+        if blob.starts_with('/') {
+            let record = self.get_record(&blob[1..])?;
+            return Ok(record
+                .filter(|record| record.is_whole())
+                .map(|record| record.volumes));
+        }
+
+        if let Some((id, number)) = multipart::parse_part_blob(blob) {
+            let part_key = format!("{}/{:05}", upload_key(id), number);
+            if let Some(record) = self.get_record(&part_key)? {
+                return Ok(Some(record.volumes));
+            }
+
+            // parts of completed uploads are only found in their values
+            for (key, value) in self.index.scan() {
+                if key.starts_with(META_PREFIX.as_bytes()) {
+                    continue;
+                }
+
+                let part = Record::decode(&value)
+                    .and_then(|record| record.parts.into_iter().find(|part| part.blob == blob));
+                if let Some(part) = part {
+                    return Ok(Some(part.volumes));
+                }
+            }
+
+            return Ok(None);
+        }
+
+        let indx = match blob.find('/') {
+            Some(indx) => indx,
+            None => return Ok(None),
+        };
+        let bucket = match self.buckets.read().unwrap().get(&blob[..indx]) {
+            Some(bucket) => bucket.clone(),
+            None => return Ok(None),
+        };
+
+        // versions are stored as `<key>@<version>`, keys may contain `@` too
+        let key = &blob[indx + 1..];
+        let mut candidates = vec![(key, None)];
+        if let Some(at) = key.rfind('@') {
+            if let Ok(version) = key[at + 1..].parse::<u64>() {
+                candidates.push((&key[..at], Some(version)));
+            }
+        }
+
+        let expected = blob_location("", blob);
+        for (key, version) in candidates {
+            if let Some((_, record)) = self.find_record(&bucket, key, version)? {
+                if record.is_whole() && location("", &bucket, key, &record) == expected {
+                    return Ok(Some(record.volumes));
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// copies a blob to `volume` from another volume holding it. volume
+    /// verifies the copy against digests sent by the source. shards are
+    /// rebuilt from the others of their record in `shards`
+    fn repair_blob(&self, volume: &str, blob: &str, shards: &HashMap<String, Record>) -> bool {
+        if erasure::is_shard_blob(blob) {
+            return self.repair_shard(volume, blob, shards.get(blob));
+        }
+
+        let holders = match self.blob_holders(blob) {
+            Ok(Some(holders)) => holders,
+            _ => return false,
+        };
+
+        if !holders.iter().any(|holder| holder == volume) {
+            return false;
+        }
+
+        let target = blob_location(volume, blob);
+
+        holders
+            .iter()
+            .filter(|holder| *holder != volume)
+            .any(|source| self.copy_value(&blob_location(source, blob), &target))
+    }
+
+    /// copies a value between volume servers. the target verifies the copy
+    /// against digests sent by the source
+    fn copy_value(&self, source: &str, target: &str) -> bool {
+        let copied = http::open("GET", source, &[], &self.tls).and_then(|(resp, mut body)| {
+            let length = match resp.header("Content-Length") {
+                Some(length) if resp.status_code == 200 => length.parse::<u64>().ok(),
+                _ => None,
+            };
+            let length = length.ok_or_else(|| {
+                io::Error::new(io::ErrorKind::Other, format!("failed to fetch {}", source))
+            })?;
+
+            let headers: Vec<(&str, &str)> = [CONTENT_MD5_HEADER, SHA256_HEADER]
+                .iter()
+                .filter_map(|field| resp.header(field).map(|value| (*field, value)))
+                .collect();
+
+            http::request_stream("PUT", target, &headers, &mut body, length, &self.tls)
+        });
+
+        match copied {
+            io::Result::Ok(ref resp) => resp.status_code == 201,
+            Err(_) => false,
+        }
+    }
+
+    /// moves values off the volume `from` picks for each index entry, returns
+    /// the number of values moved and of those failed to move
+    fn move_values(&self, from: impl Fn(&Record) -> Option<String>) -> (usize, usize) {
+        let now = now();
+        let (mut moved, mut failed) = (0, 0);
+
+        for (key, value) in self.index.scan() {
+            if key.starts_with(META_PREFIX.as_bytes()) {
+                continue;
+            }
+
+            let index_key = match str::from_utf8(&key) {
+                Ok(index_key) => index_key,
+                Err(_) => continue,
+            };
+
+            // expired values are left to gc
+            let record = match Record::decode(&value) {
+                Some(record) if !record.expired(now) => record,
+                _ => continue,
+            };
+
+            if let Some(volume) = from(&record) {
+                if self.move_record(index_key, &record, &volume) {
+                    moved += 1;
+                } else {
+                    failed += 1;
+                }
+            }
+        }
+
+        (moved, failed)
+    }
+
+    /// copies blobs of a value held by `from` to the least loaded volumes,
+    /// then points the index entry to the copies. returns false if the value
+    /// was not moved
+    fn move_record(&self, index_key: &str, record: &Record, from: &str) -> bool {
+        let (name, key) = split_index_key(index_key);
+        let bucket = self.buckets.read().unwrap().get(name).cloned();
+        let bucket = bucket.unwrap_or_else(|| Bucket {
+            name: name.to_owned(),
+            ..Bucket::default()
+        });
+
+        let mut moved = record.clone();
+        let mut copies = Vec::new();
+
+        if let Some(indx) = record.volumes.iter().position(|volume| volume == from) {
+            let target = match self.pick_target(&record.volumes) {
+                Some(target) => target,
+                None => return false,
+            };
+
+            copies.push((
+                location(from, &bucket, key, record),
+                location(&target, &bucket, key, record),
+            ));
+            moved.volumes[indx] = target;
+        }
+
+        // shards are kept on distinct volumes where possible
+        let shard_volumes: Vec<String> = record
+            .shards
+            .iter()
+            .flat_map(|shard| shard.volumes.iter().cloned())
+            .collect();
+
+        for (part, coded) in moved
+            .parts
+            .iter_mut()
+            .map(|part| (part, false))
+            .chain(moved.shards.iter_mut().map(|shard| (shard, true)))
+        {
+            let indx = match part.volumes.iter().position(|volume| volume == from) {
+                Some(indx) => indx,
+                None => continue,
+            };
+
+            let target = if coded {
+                self.pick_target(&shard_volumes)
+            } else {
+                None
+            };
+            let target = match target.or_else(|| self.pick_target(&part.volumes)) {
+                Some(target) => target,
+                None => return false,
+            };
+
+            copies.push((
+                part_location(from, &part.blob),
+                part_location(&target, &part.blob),
+            ));
+            part.volumes[indx] = target;
+        }
+
+        let mut copied = Vec::with_capacity(copies.len());
+        for (source, target) in copies.iter() {
+            if !self.copy_value(source, &with_compression(target.clone(), &bucket)) {
+                self.purge_urls(copied);
+                return false;
+            }
+            copied.push(target.clone());
+        }
+
+        // commits hold the usage lock while reading and writing index entries
+        {
+            let _usage = self.usage.write().unwrap();
+
+            match self.get_record(index_key) {
+                Ok(Some(ref current)) if current == record => {}
+                _ => {
+                    // replaced or removed meanwhile
+                    self.purge_urls(copied);
+                    return false;
+                }
+            }
+
+            if self
+                .index
+                .put(index_key.as_bytes(), moved.encode().as_bytes())
+                .is_err()
+            {
+                self.purge_urls(copied);
+                return false;
+            }
+        }
+
+        for volume in moved.all_volumes() {
+            self.increment_count(volume);
+        }
+        for volume in record.all_volumes() {
+            self.decrement_count(volume);
+        }
+        self.purge_urls(copies.into_iter().map(|(source, _)| source).collect());
+
+        true
+    }
+
+    /// computes usage of keys under `prefix` in bucket from the index
+    fn scan_usage(&self, bucket: &str, prefix: &str) -> Usage {
+        let start = index_key(
+            &Bucket {
+                name: bucket.to_owned(),
+                ..Bucket::default()
+            },
+            prefix,
+        );
+
+        let mut usage = Usage::default();
+        for (key, value) in self.index.scan_prefix(start.as_bytes()) {
+            // keys of default bucket never start with \0
+            if bucket.is_empty() && key.starts_with(b"\0") {
+                continue;
+            }
+
+            if let Some(record) = Record::decode(&value) {
+                usage.bytes += record.size;
+                usage.objects += 1;
+            }
+        }
+
+        usage
+    }
+
+    /// deletes values of an expired or replaced key from volume servers in
+    /// background
+    fn purge(&self, bucket: &Bucket, key: &str, record: &Record) {
+        let urls: Vec<String> = if record.is_whole() {
+            record
+                .volumes
+                .iter()
+                .map(|volume| location(volume, bucket, key, record))
+                .collect()
+        } else {
+            let mut urls = part_urls(&record.parts);
+            urls.extend(part_urls(&record.shards));
+            urls
+        };
+
+        self.purge_urls(urls);
+    }
+
+    /// deletes values at `urls` in background
+    fn purge_urls(&self, urls: Vec<String>) {
+        let tls = self.tls.clone();
+
+        thread::spawn(move || {
+            for url in urls {
+                let _ = http::request("DELETE", &url, &[], b"", &tls);
+            }
+        });
+    }
+
+    /// Get a key from bucket
+    fn get_object(&self, bucket: &Bucket, key: &str, version: Option<u64>) -> ResponseKind {
+        match self.live_record(bucket, key, version) {
+            Ok(Some(record)) => self.read_record(bucket, key, &record),
+            Ok(None) => ResponseKind::NotFound,
+            Err(resp) => resp,
+        }
+    }
+
+    /// finds the record of a key like `find_record`, removing it if expired
+    fn live_record(
+        &self,
+        bucket: &Bucket,
+        key: &str,
+        version: Option<u64>,
+    ) -> Result<Option<Record>, ResponseKind> {
+        match self.find_record(bucket, key, version) {
+            Ok(Some((index_key, record))) => {
+                if record.expired(now()) {
+                    if let Ok(true) = self.remove_record(&index_key, &record) {
+                        self.purge(bucket, key, &record);
+                    }
+                    return Ok(None);
+                }
+
+                Ok(Some(record))
+            }
+            Ok(None) => Ok(None),
+            Err(_) => Err(ResponseKind::ServerError),
+        }
+    }
+
+    /// redirects to the value of a record, or streams it if master assembles it
+    fn read_record(&self, bucket: &Bucket, key: &str, record: &Record) -> ResponseKind {
+        // any replica can serve reads
+        let mut rng = thread_rng();
+
+        if record.is_whole() {
+            let volume = record.volumes.choose(&mut rng).unwrap();
+            return ResponseKind::Redirect(location(volume, bucket, key, record));
+        }
+
+        // erasure coded values are rebuilt by master
+        if !record.shards.is_empty() {
+            return match self.read_coded(record) {
+                Some(value) => ResponseKind::Stream(Box::new(value), record.size),
+                None => ResponseKind::Unavailable,
+            };
+        }
+
+        // values uploaded in parts are streamed by master
+        let urls = record
+            .parts
+            .iter()
+            .map(|part| part_location(part.volumes.choose(&mut rng).unwrap(), &part.blob))
+            .collect();
+
+        ResponseKind::Stream(
+            Box::new(PartsReader::new(urls, self.tls.clone())),
+            record.size,
+        )
+    }
+
+    /// lists keys of a bucket starting with `prefix`, at most `limit` of them
+    /// and only those sorting after `after` if given
+    fn list_objects(
+        &self,
+        bucket: &Bucket,
+        prefix: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> ResponseKind {
+        let keys: Vec<String> = self
+            .scan_objects(bucket, prefix, after)
+            .take(limit)
+            .map(|(key, _)| key)
+            .collect();
+
+        ResponseKind::Ok(keys.join("\n"))
+    }
+
+    /// keys of a bucket starting with `prefix` and their records, in order.
+    /// only keys sorting after `after` if given
+    fn scan_objects(
+        &self,
+        bucket: &Bucket,
+        prefix: &str,
+        after: Option<&str>,
+    ) -> impl Iterator<Item = (String, Record)> + '_ {
+        let scope = index_key(bucket, "").len();
+        let prefix = index_key(bucket, prefix);
+        let after = after.map(|after| index_key(bucket, after));
+
+        // metadata of master sorts before keys of the default bucket
+        let mut start = if prefix.is_empty() {
+            "\u{1}".to_owned()
+        } else {
+            prefix.clone()
+        };
+        if let Some(ref after) = after {
+            if *after > start {
+                start = after.clone();
+            }
+        }
+
+        let now = now();
+
+        self.index
+            .scan_from(start.as_bytes())
+            .take_while(move |(entry_key, _)| entry_key.starts_with(prefix.as_bytes()))
+            .filter_map(move |(entry_key, raw)| {
+                let entry_key = String::from_utf8(entry_key.into_vec()).ok()?;
+
+                // previous versions are not listed
+                let key = &entry_key[scope..];
+                if key.contains('\u{0}') || after.as_ref() == Some(&entry_key) {
+                    return None;
+                }
+
+                match Record::decode(&raw) {
+                    Some(record) if !record.expired(now) => Some((key.to_owned(), record)),
+                    _ => None,
+                }
+            })
+    }
+
+    /// lists keys as asked by query params of `url`
+    fn list_url(&self, bucket: &Bucket, url: &str) -> ResponseKind {
+        let prefix = get_param(url, "prefix").unwrap_or_default();
+        let after = get_param(url, "after");
+        let limit = match get_param(url, "limit").map(|limit| limit.parse::<usize>()) {
+            Some(Ok(limit)) => limit.min(LIST_LIMIT),
+            Some(Err(_)) => return ResponseKind::BadRequest("invalid limit".to_owned()),
+            None => LIST_LIMIT,
+        };
+
+        self.list_objects(bucket, &prefix, after.as_ref().map(String::as_str), limit)
+    }
+
+    /// Save/Update key in bucket
+    fn save_object(
+        &self,
+        bucket: &Bucket,
+        key: &str,
+        mut value: impl Read,
+        length: Option<u64>,
+        ttl: Option<u64>,
+    ) -> ResponseKind {
+        if !self.has_writable_volumes() {
+            return ResponseKind::Unavailable;
+        }
+
+        if bucket.erasure.is_some() {
+            return self.save_coded(bucket, key, value, length, ttl);
+        }
+
+        // clients following the redirect send the value to master as well,
+        // it is only read to account its size if they did not announce it
+        let size = match length {
+            Some(length) => length,
+            None => match copy(&mut value, &mut sink()) {
+                Ok(size) => size,
+                Err(_) => return ResponseKind::ServerError,
+            },
+        };
+
+        match self.place_object(bucket, key, size, ttl) {
+            Ok(record) => {
+                ResponseKind::Redirect(upload_location(&self.cluster, bucket, key, &record))
+            }
+            Err(resp) => resp,
+        }
+    }
+
+    /// commits the record of a value of `size` bytes to be uploaded to the
+    /// volumes it is placed on
+    fn place_object(
+        &self,
+        bucket: &Bucket,
+        key: &str,
+        size: u64,
+        ttl: Option<u64>,
+    ) -> Result<Record, ResponseKind> {
+        let record = Record {
+            size,
+            expires: ttl.or(bucket.ttl).map(|ttl| now() + ttl),
+            ..Record::default()
+        };
+
+        self.commit(bucket, key, record, &[])
+    }
+
+    /// makes `record` the current record of a key and accounts it. values not
+    /// uploaded in parts are placed on volumes, those of the replaced value if
+    /// it is overwritten in place. Versioned buckets keep the previous record
+    /// as a version. `done` are index keys removed in the same write
+    fn commit(
+        &self,
+        bucket: &Bucket,
+        key: &str,
+        mut record: Record,
+        done: &[String],
+    ) -> Result<Record, ResponseKind> {
+        // usage is locked until the value is accounted, so that concurrent
+        // uploads can not exceed quotas together and index entries do not
+        // change between reading and writing them
+        let mut usage = self.usage.write().unwrap();
+
+        let index_key = index_key(bucket, key);
+        let old = self
+            .get_record(&index_key)
+            .map_err(|_| ResponseKind::ServerError)?;
+
+        // unversioned values are overwritten
+        let replaced = match old {
+            Some(ref old) if !bucket.versioning => Some(old),
+            _ => None,
+        };
+
+        if !usage.allows(bucket, key, replaced.map(|old| old.size), record.size) {
+            return Err(ResponseKind::InsufficientStorage);
+        }
+
+        // parts and shards are stored apart from whole values, one of them can
+        // not overwrite the other
+        let in_place = replaced.map_or(false, |old| old.is_whole() && record.is_whole());
+
+        if record.is_whole() {
+            record.volumes = match replaced {
+                Some(old) if in_place => old.volumes.clone(),
+                _ => self.pick_volumes(bucket.replicas),
+            };
+        }
+
+        if bucket.versioning {
+            record.version = Some(new_version());
+        }
+        record.modified = Some(now());
+
+        let mut batch = Batch::default();
+        batch.put(index_key.as_bytes(), record.encode().as_bytes());
+
+        // keep previous version
+        if let (true, Some(old)) = (bucket.versioning, &old) {
+            let old_key = version_key(&index_key, old.version.unwrap_or(0));
+            batch.put(old_key.as_bytes(), old.encode().as_bytes());
+        }
+
+        for done_key in done {
+            batch.delete(done_key.as_bytes());
+        }
+
+        let put = change(changes::Op::Put, &bucket.name, key, &record);
+        self.changes
+            .write(batch, vec![put])
+            .map_err(|_| ResponseKind::ServerError)?;
+
+        if !in_place {
+            // increment count in map
+            for volume in record.all_volumes() {
+                self.increment_count(volume);
+            }
+        }
+
+        if let Some(old) = replaced {
+            usage.remove(&bucket.name, key, old.size);
+
+            if !in_place {
+                for volume in old.all_volumes() {
+                    self.decrement_count(volume);
+                }
+                self.purge(bucket, key, old);
+            }
+        }
+        usage.add(&bucket.name, key, record.size);
+
+        Ok(record)
+    }
+
+    /// stores an erasure coded value, rebuilt by master when read
+    fn save_coded(
+        &self,
+        bucket: &Bucket,
+        key: &str,
+        mut value: impl Read,
+        length: Option<u64>,
+        ttl: Option<u64>,
+    ) -> ResponseKind {
+        // shards are uploaded while the value is coded, their size has to be
+        // known up front
+        let length = match length {
+            Some(length) => length,
+            None => return ResponseKind::LengthRequired,
+        };
+
+        match self.place_coded(bucket, key, &mut value, length, ttl, None) {
+            Ok(_) => ResponseKind::Created,
+            Err(resp) => resp,
+        }
+    }
+
+    /// stores shards of a value of `size` bytes and commits its record
+    fn place_coded(
+        &self,
+        bucket: &Bucket,
+        key: &str,
+        value: &mut dyn Read,
+        size: u64,
+        ttl: Option<u64>,
+        etag: Option<String>,
+    ) -> Result<Record, ResponseKind> {
+        let erasure = bucket.erasure.ok_or(ResponseKind::ServerError)?;
+
+        let shards = self.store_shards(erasure, value, size)?;
+        self.commit_coded(bucket, key, shards, size, ttl, etag)
+    }
+
+    /// commits the record of a value stored as `shards`, which are removed
+    /// again if it can not be committed
+    fn commit_coded(
+        &self,
+        bucket: &Bucket,
+        key: &str,
+        shards: Vec<Part>,
+        size: u64,
+        ttl: Option<u64>,
+        etag: Option<String>,
+    ) -> Result<Record, ResponseKind> {
+        let urls = part_urls(&shards);
+
+        let record = Record {
+            size,
+            expires: ttl.or(bucket.ttl).map(|ttl| now() + ttl),
+            erasure: bucket.erasure,
+            shards,
+            stripe: Some(erasure::STRIPE_SIZE),
+            etag,
+            ..Record::default()
+        };
+
+        let committed = self.commit(bucket, key, record, &[]);
+        if committed.is_err() {
+            self.purge_urls(urls);
+        }
+
+        committed
+    }
+
+    /// codes `size` bytes of a value into data and parity shards a stripe at
+    /// a time, streaming each shard to a different volume
+    fn store_shards(
+        &self,
+        erasure: (usize, usize),
+        value: &mut dyn Read,
+        size: u64,
+    ) -> Result<Vec<Part>, ResponseKind> {
+        let (data, parity) = erasure;
+
+        let volumes = self.pick_volumes(data + parity);
+        if volumes.len() < data + parity {
+            return Err(ResponseKind::Unavailable);
+        }
+
+        let id = erasure::shards_id();
+        let codec = Codec::new(data, parity);
+        let shard_size = codec.shard_size(size, Some(erasure::STRIPE_SIZE));
+
+        let shards: Vec<Part> = volumes
+            .into_iter()
+            .enumerate()
+            .map(|(indx, volume)| Part {
+                blob: shard_blob(&id, indx),
+                size: shard_size,
+                volumes: vec![volume],
+            })
+            .collect();
+
+        // a failed upload drops its pipe, which stops coding and with it the
+        // other uploads
+        let (coded, stored) = thread::scope(|scope| {
+            let mut writers = Vec::with_capacity(shards.len());
+            let mut uploads = Vec::with_capacity(shards.len());
+
+            for shard in shards.iter() {
+                let (writer, mut reader) = erasure::pipe();
+                let url = part_location(&shard.volumes[0], &shard.blob);
+                let tls = &self.tls;
+
+                writers.push(writer);
+                uploads.push(scope.spawn(move || {
+                    http::request_stream("PUT", &url, &[], &mut reader, shard_size, tls)
+                }));
+            }
+
+            let coded = codec.encode_stream(value, size, Some(erasure::STRIPE_SIZE), &mut writers);
+            drop(writers);
+
+            let stored = uploads
+                .into_iter()
+                .map(|upload| upload.join())
+                .all(|res| match res {
+                    Ok(Ok(ref res)) => res.status_code == 201,
+                    _ => false,
+                });
+
+            (coded, stored)
+        });
+
+        match coded {
+            Ok(_) if stored => Ok(shards),
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                self.purge_urls(part_urls(&shards));
+                Err(ResponseKind::BadRequest("truncated upload".to_owned()))
+            }
+            _ => {
+                self.purge_urls(part_urls(&shards));
+                Err(ResponseKind::ServerError)
+            }
+        }
+    }
+
+    /// reads shards of an erasure coded value a stripe at a time, leaving out
+    /// shard `skip`
+    fn read_stripes(&self, record: &Record, skip: Option<usize>) -> Option<StripeReader> {
+        let (data, parity) = record.erasure?;
+        let shards = record
+            .shards
+            .iter()
+            .map(|shard| (part_location(&shard.volumes[0], &shard.blob), shard.size))
+            .collect();
+
+        Some(StripeReader::new(
+            Codec::new(data, parity),
+            shards,
+            record.size,
+            record.stripe,
+            skip,
+            self.tls.clone(),
+        ))
+    }
+
+    /// rebuilds an erasure coded value from its shards while it is read
+    fn read_coded(&self, record: &Record) -> Option<CodedReader> {
+        CodedReader::open(self.read_stripes(record, None)?).ok()
+    }
+
+    /// records of erasure coded values holding any of `blobs`, by shard blob.
+    /// shards are only found in the values they belong to, so the index is
+    /// scanned once for all of them
+    fn shard_records(&self, blobs: &[&str]) -> HashMap<String, Record> {
+        let wanted: HashSet<&str> = blobs
+            .iter()
+            .cloned()
+            .filter(|blob| erasure::is_shard_blob(blob))
+            .collect();
+        let mut records = HashMap::new();
+
+        if wanted.is_empty() {
+            return records;
+        }
+
+        for (key, value) in self.index.scan() {
+            if key.starts_with(META_PREFIX.as_bytes()) {
+                continue;
+            }
+
+            let record = match Record::decode(&value) {
+                Some(record) if !record.shards.is_empty() => record,
+                _ => continue,
+            };
+
+            for shard in record.shards.iter() {
+                if wanted.contains(shard.blob.as_str()) {
+                    records.insert(shard.blob.clone(), record.clone());
+                }
+            }
+        }
+
+        records
+    }
+
+    /// rebuilds a shard a volume server found corrupt from the other shards
+    /// of `record`, a stripe at a time
+    fn repair_shard(&self, volume: &str, blob: &str, record: Option<&Record>) -> bool {
+        let record = match record {
+            Some(record) => record,
+            None => return false,
+        };
+
+        let indx = match record.shards.iter().position(|shard| shard.blob == blob) {
+            Some(indx) if record.shards[indx].volumes[0] == volume => indx,
+            _ => return false,
+        };
+
+        let mut stripes = match self.read_stripes(record, Some(indx)) {
+            Some(stripes) => stripes,
+            None => return false,
+        };
+
+        let url = part_location(volume, blob);
+        let size = record.shards[indx].size;
+
+        thread::scope(|scope| {
+            let (mut writer, mut reader) = erasure::pipe();
+            let tls = &self.tls;
+            let upload =
+                scope.spawn(move || http::request_stream("PUT", &url, &[], &mut reader, size, tls));
+
+            let rebuilt = loop {
+                match stripes.next_stripe() {
+                    Ok(Some((mut shards, _))) => {
+                        if writer.write_all(&shards.swap_remove(indx)).is_err() {
+                            break false;
+                        }
+                    }
+                    Ok(None) => break true,
+                    Err(_) => break false,
+                }
+            };
+            drop(writer);
+
+            match upload.join() {
+                Ok(Ok(ref res)) => rebuilt && res.status_code == 201,
+                _ => false,
+            }
+        })
+    }
+
+    /// posts changes after the cursor of each webhook to it, in order. failed
+    /// deliveries are retried after a backoff, changes failing all attempts
+    /// are dead lettered and delivery moves on
+    fn deliver_webhooks(&self, retries: &mut HashMap<String, Retry>) {
+        let webhooks: Vec<Webhook> = self.webhooks.read().unwrap().values().cloned().collect();
+        retries.retain(|name, _| webhooks.iter().any(|webhook| webhook.name == *name));
+
+        for webhook in webhooks {
+            if self.shutdown.is_set() {
+                return;
+            }
+
+            let retry = retries
+                .get(&webhook.name)
+                .map(|retry| (retry.attempts, retry.at));
+            if let Some((_, at)) = retry {
+                if at > Instant::now() {
+                    continue;
+                }
+            }
+
+            let cursor = match self.webhook_cursor(&webhook.name) {
+                Some(cursor) => cursor,
+                None => continue,
+            };
+
+            let (changes, mut done) = self.changes.read(cursor, &webhook.filter(), WEBHOOK_BATCH);
+
+            for change in changes {
+                if !webhook.wants(&change) {
+                    continue;
+                }
+
+                let error = match webhook.deliver(&change, &self.tls) {
+                    Ok(_) => {
+                        retries.remove(&webhook.name);
+                        continue;
+                    }
+                    Err(e) => e,
+                };
+
+                let attempts = retries.get(&webhook.name).map_or(0, |retry| retry.attempts) + 1;
+                if attempts < webhook.attempts {
+                    let at = Instant::now() + webhook.backoff(attempts);
+                    retries.insert(webhook.name.clone(), Retry { attempts, at });
+                    done = change.seq - 1;
+                    break;
+                }
+
+                retries.remove(&webhook.name);
+                self.dead_letter(DeadLetter {
+                    webhook: webhook.name.clone(),
+                    attempts,
+                    error,
+                    change,
+                });
+            }
+
+            if done != cursor {
+                self.set_webhook_cursor(&webhook.name, done);
+            }
+        }
+    }
+
+    /// sequence number of the last change a webhook got
+    fn webhook_cursor(&self, name: &str) -> Option<u64> {
+        let cursor = self.index.get(webhook_cursor_key(name).as_bytes()).ok()??;
+        str::from_utf8(&cursor).ok()?.parse().ok()
+    }
+
+    /// moves the cursor of a webhook unless it was deleted meanwhile
+    fn set_webhook_cursor(&self, name: &str, seq: u64) {
+        let webhooks = self.webhooks.read().unwrap();
+
+        if webhooks.contains_key(name) {
+            let _ = self.index.put(
+                webhook_cursor_key(name).as_bytes(),
+                seq.to_string().as_bytes(),
+            );
+        }
+    }
+
+    /// keeps a change a webhook failed to take
+    fn dead_letter(&self, letter: DeadLetter) {
+        let webhooks = self.webhooks.read().unwrap();
+
+        if webhooks.contains_key(&letter.webhook) {
+            let key = dead_letter_key(&letter.webhook, letter.change.seq);
+            let _ = self.index.put(key.as_bytes(), letter.encode().as_bytes());
+        }
+    }
+
+    /// converts values of buckets scheduled with `convert-erasure`. buckets
+    /// stay scheduled until all their values are converted
+    fn convert_scheduled(&self) {
+        let markers: Vec<Box<[u8]>> = self
+            .index
+            .scan_prefix(CONVERT_META_PREFIX.as_bytes())
+            .map(|(key, _)| key)
+            .collect();
+
+        for marker in markers {
+            let name = String::from_utf8_lossy(&marker[CONVERT_META_PREFIX.len()..]).into_owned();
+            let bucket = self.buckets.read().unwrap().get(&name).cloned();
+
+            // buckets deleted or without erasure setting are dropped
+            if let Some(bucket) = bucket.filter(|bucket| bucket.erasure.is_some()) {
+                if !self.convert_bucket(&bucket) {
+                    continue;
+                }
+                println!("converted bucket {} to erasure coding", name);
+            }
+
+            let _ = self.index.delete(&marker);
+        }
+    }
+
+    /// converts whole values of a bucket to erasure coding, returns false if
+    /// any of them has to be tried again
+    fn convert_bucket(&self, bucket: &Bucket) -> bool {
+        let prefix = format!("{}{}/", OBJECT_PREFIX, bucket.name);
+        let mut done = true;
+
+        for (key, value) in self.index.scan_prefix(prefix.as_bytes()) {
+            let index_key = match str::from_utf8(&key) {
+                Ok(index_key) => index_key,
+                Err(_) => continue,
+            };
+
+            if let Some(record) = Record::decode(&value).filter(|record| record.is_whole()) {
+                done &= self.convert_record(bucket, index_key, record);
+            }
+        }
+
+        done
+    }
+
+    /// replaces replicas of a value with shards, returns false on failure
+    fn convert_record(&self, bucket: &Bucket, index_key: &str, record: Record) -> bool {
+        let erasure = match bucket.erasure {
+            Some(erasure) => erasure,
+            None => return true,
+        };
+        let (_, key) = split_index_key(index_key);
+
+        let mut missing = 0;
+        let mut value = None;
+
+        for volume in record.volumes.iter() {
+            let url = location(volume, bucket, key, &record);
+            match http::open("GET", &url, &[], &self.tls) {
+                Ok((ref res, body)) if res.status_code == 200 => {
+                    let length = res
+                        .header("Content-Length")
+                        .and_then(|length| length.parse::<u64>().ok());
+                    value = Some((body, length));
+                    break;
+                }
+                Ok((ref res, _)) if res.status_code == 404 => missing += 1,
+                _ => {}
+            }
+        }
+
+        // values never uploaded to their volumes are left alone
+        let mut value = match value {
+            Some((value, Some(length))) if length == record.size => value,
+            Some(_) => return true,
+            None => return missing == record.volumes.len(),
+        };
+
+        let shards = match self.store_shards(erasure, &mut value, record.size) {
+            Ok(shards) => shards,
+            Err(_) => return false,
+        };
+        let urls = part_urls(&shards);
+
+        let coded = Record {
+            volumes: vec![],
+            erasure: Some(erasure),
+            shards,
+            stripe: Some(erasure::STRIPE_SIZE),
+            ..record.clone()
+        };
+
+        // commits hold the usage lock while reading and writing index entries
+        {
+            let _usage = self.usage.write().unwrap();
+
+            match self.get_record(index_key) {
+                Ok(Some(ref current)) if *current == record => {}
+                Ok(_) => {
+                    // replaced or removed meanwhile, nothing to convert
+                    self.purge_urls(urls);
+                    return true;
+                }
+                Err(_) => {
+                    self.purge_urls(urls);
+                    return false;
+                }
+            }
+
+            if self
+                .index
+                .put(index_key.as_bytes(), coded.encode().as_bytes())
+                .is_err()
+            {
+                self.purge_urls(urls);
+                return false;
+            }
+        }
+
+        for volume in coded.all_volumes() {
+            self.increment_count(volume);
+        }
+        for volume in record.all_volumes() {
+            self.decrement_count(volume);
+        }
+        self.purge(bucket, key, &record);
+
+        true
+    }
+
+    /// Remove a key from bucket
+    fn delete_object(&self, bucket: &Bucket, key: &str, version: Option<u64>) -> ResponseKind {
+        // a record replaced while being deleted is looked up again
+        loop {
+            return match self.find_record(bucket, key, version) {
+                Ok(Some((index_key, record))) => {
+                    match self.remove_record(&index_key, &record) {
+                        Ok(false) => continue,
+                        Ok(true) if record.is_whole() => ResponseKind::Redirect(
+                            replicated_location(&self.cluster, bucket, key, &record),
+                        ),
+                        Ok(true) => {
+                            self.purge(bucket, key, &record);
+                            ResponseKind::Deleted
+                        }
+                        Err(_) => ResponseKind::ServerError,
+                    }
+                }
+                Ok(None) => ResponseKind::NotFound,
+                Err(_) => ResponseKind::ServerError,
+            };
+        }
+    }
+
+    /// checks that `id` is an upload of key in bucket
+    fn find_upload(&self, bucket: &Bucket, key: &str, id: &str) -> Result<(), ResponseKind> {
+        if !multipart::valid_id(id) {
+            return Err(ResponseKind::NotFound);
+        }
+
+        let target = format!("{}\n{}", bucket.name, key);
+        match self.index.get(upload_key(id).as_bytes()) {
+            Ok(Some(ref meta)) if &meta[..] == target.as_bytes() => Ok(()),
+            Ok(_) => Err(ResponseKind::NotFound),
+            Err(_) => Err(ResponseKind::ServerError),
+        }
+    }
+
+    /// index keys, numbers and records of uploaded parts ordered by number
+    fn uploaded_parts(&self, id: &str) -> Vec<(String, u32, Record)> {
+        let prefix = format!("{}/", upload_key(id));
+        self.index
+            .scan_prefix(prefix.as_bytes())
+            .filter_map(|(key, value)| {
+                let key = String::from_utf8(key.to_vec()).ok()?;
+                let number = key[prefix.len()..].parse::<u32>().ok()?;
+                Some((key, number, Record::decode(&value)?))
+            })
+            .collect()
+    }
+
+    /// starts a multipart upload of a key
+    fn initiate_upload(&self, bucket: &Bucket, key: &str) -> ResponseKind {
+        let id = multipart::upload_id();
+        let meta = format!("{}\n{}", bucket.name, key);
+
+        match self.index.put(upload_key(&id).as_bytes(), meta.as_bytes()) {
+            Ok(_) => ResponseKind::Ok(id),
+            Err(_) => ResponseKind::ServerError,
+        }
+    }
+
+    /// redirects upload of a part to volume servers
+    fn upload_part(
+        &self,
+        bucket: &Bucket,
+        key: &str,
+        id: &str,
+        number: u32,
+        mut value: impl Read,
+        length: Option<u64>,
+    ) -> ResponseKind {
+        if let Err(resp) = self.check_part(bucket, key, id, number) {
+            return resp;
+        }
+
+        // the part is only read to account its size if it was not announced
+        let size = match length {
+            Some(length) => length,
+            None => match copy(&mut value, &mut sink()) {
+                Ok(size) => size,
+                Err(_) => return ResponseKind::ServerError,
+            },
+        };
+
+        match self.place_part(bucket, id, number, size) {
+            Ok((_, record)) => ResponseKind::Redirect(part_upload_location(
+                &self.cluster,
+                bucket,
+                id,
+                number,
+                &record,
+            )),
+            Err(resp) => resp,
+        }
+    }
+
+    /// checks that part `number` of an upload can be uploaded
+    fn check_part(
+        &self,
+        bucket: &Bucket,
+        key: &str,
+        id: &str,
+        number: u32,
+    ) -> Result<(), ResponseKind> {
+        self.find_upload(bucket, key, id)?;
+
+        if number == 0 || number > MAX_PARTS {
+            return Err(ResponseKind::BadRequest(format!(
+                "part number should be between 1 and {}",
+                MAX_PARTS
+            )));
+        }
+
+        if !self.has_writable_volumes() {
+            return Err(ResponseKind::Unavailable);
+        }
+
+        Ok(())
+    }
+
+    /// stores the record of a part of `size` bytes, returns its index key
+    /// and the record
+    fn place_part(
+        &self,
+        bucket: &Bucket,
+        id: &str,
+        number: u32,
+        size: u64,
+    ) -> Result<(String, Record), ResponseKind> {
+        // parts uploaded again overwrite the previous blob
+        let part_key = format!("{}/{:05}", upload_key(id), number);
+        let volumes = match self.get_record(&part_key) {
+            Ok(Some(old)) => old.volumes,
+            Ok(None) => self.pick_volumes(bucket.replicas),
+            Err(_) => return Err(ResponseKind::ServerError),
+        };
+
+        let record = Record {
+            volumes,
+            size,
+            ..Record::default()
+        };
+
+        match self
+            .index
+            .put(part_key.as_bytes(), record.encode().as_bytes())
+        {
+            Ok(_) => Ok((part_key, record)),
+            Err(_) => Err(ResponseKind::ServerError),
+        }
+    }
+
+    /// lists uploaded parts, a line of number and size each
+    fn list_parts(&self, bucket: &Bucket, key: &str, id: &str) -> ResponseKind {
+        if let Err(resp) = self.find_upload(bucket, key, id) {
+            return resp;
+        }
+
+        let parts: Vec<String> = self
+            .uploaded_parts(id)
+            .iter()
+            .map(|(_, number, part)| format!("{} {}", number, part.size))
+            .collect();
+
+        ResponseKind::Ok(parts.join("\n"))
+    }
+
+    /// stores the manifest of uploaded parts as value of the key
+    fn complete_upload(
+        &self,
+        bucket: &Bucket,
+        key: &str,
+        id: &str,
+        ttl: Option<u64>,
+        etag: Option<String>,
+    ) -> ResponseKind {
+        if let Err(resp) = self.find_upload(bucket, key, id) {
+            return resp;
+        }
+
+        let uploaded = self.uploaded_parts(id);
+        if uploaded.is_empty() {
+            return ResponseKind::BadRequest("no parts uploaded".to_owned());
+        }
+
+        let mut record = Record {
+            expires: ttl.or(bucket.ttl).map(|ttl| now() + ttl),
+            etag,
+            ..Record::default()
+        };
+        let mut done = vec![upload_key(id)];
+
+        for (part_key, number, part) in uploaded {
+            record.size += part.size;
+            record.parts.push(Part {
+                blob: part_blob(id, number),
+                size: part.size,
+                volumes: part.volumes,
+            });
+            done.push(part_key);
+        }
+
+        match self.commit(bucket, key, record, &done) {
+            Ok(_) => ResponseKind::Ok("Upload completed".to_owned()),
+            Err(resp) => resp,
+        }
+    }
+
+    /// drops an upload and deletes its parts from volume servers
+    fn abort_upload(&self, bucket: &Bucket, key: &str, id: &str) -> ResponseKind {
+        if let Err(resp) = self.find_upload(bucket, key, id) {
+            return resp;
+        }
+
+        let mut batch = Batch::default();
+        batch.delete(upload_key(id).as_bytes());
+        let mut urls = Vec::new();
+
+        for (part_key, number, part) in self.uploaded_parts(id) {
+            batch.delete(part_key.as_bytes());
+
+            let blob = part_blob(id, number);
+            urls.extend(
+                part.volumes
+                    .iter()
+                    .map(|volume| part_location(volume, &blob)),
+            );
+        }
+
+        match self.index.write(batch) {
+            Ok(_) => {
+                self.purge_urls(urls);
+                ResponseKind::Deleted
+            }
+            Err(_) => ResponseKind::ServerError,
+        }
+    }
+
+    fn dispatch_upload(&self, bucket: &Bucket, key: &str, mut req: Request) {
+        let url = req.url().to_owned();
+        let ttl = get_param(&url, "ttl").and_then(|ttl| ttl.parse::<u64>().ok());
+        let part = get_param(&url, "part");
+
+        let resp = match (req.method().clone(), get_param(&url, "upload")) {
+            (Method::Post, None) => self.initiate_upload(bucket, key),
+            (Method::Put, Some(id)) | (Method::Post, Some(id)) if part.is_some() => {
+                match part.and_then(|number| number.parse::<u32>().ok()) {
+                    Some(number) => {
+                        let length = req.body_length().map(|length| length as u64);
+                        self.upload_part(bucket, key, &id, number, req.as_reader(), length)
+                    }
+                    None => ResponseKind::BadRequest("invalid part number".to_owned()),
+                }
+            }
+            (Method::Post, Some(id)) => self.complete_upload(bucket, key, &id, ttl, None),
+            (Method::Delete, Some(id)) => self.abort_upload(bucket, key, &id),
+            (Method::Get, Some(id)) => self.list_parts(bucket, key, &id),
+            _ => ResponseKind::NotAllowed,
+        };
+
+        resp.respond(req);
+    }
+
+    /// usage of a bucket
+    #[cfg(test)]
+    fn bucket_usage(&self, name: &str) -> Usage {
+        self.usage.read().unwrap().bucket(name)
+    }
+
+    /// selects `count` distinct volume servers, or all if there are fewer.
+    /// draining volumes are skipped
+    fn pick_volumes(&self, count: usize) -> Vec<String> {
+        let (mut picked, available) = {
+            // locked in the order remove_volume locks them
+            let volumes_map = self.volumes.read().unwrap();
+            let draining = self.draining.read().unwrap();
+
+            let picked: Vec<String> = draining
+                .iter()
+                .filter(|volume| volumes_map.contains_key(*volume))
+                .cloned()
+                .collect();
+            (picked, volumes_map.len())
+        };
+        let draining = picked.len();
+
+        while picked.len() < (count + draining).min(available) {
+            let volume = self.key_to_volume(&picked);
+            picked.push(volume);
+        }
+
+        picked.split_off(draining)
+    }
+
+    /// checks whether any volume server receives new values
+    fn has_writable_volumes(&self) -> bool {
+        let draining = self.draining.read().unwrap().len();
+        self.volumes.read().unwrap().len() > draining
+    }
+
+    /// least loaded volume server receiving new values, other than those in
+    /// `exclude`
+    fn pick_target(&self, exclude: &[String]) -> Option<String> {
+        let draining = self.draining.read().unwrap().clone();
+
+        self.volumes
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(volume, _)| !exclude.contains(*volume) && !draining.contains(*volume))
+            .min_by_key(|(volume, count)| (**count, *volume))
+            .map(|(volume, _)| volume.to_owned())
+    }
+
+    /// number of values each volume server receiving new values would hold if
+    /// they were spread evenly, rounded up
+    fn share(&self) -> u32 {
+        let draining = self.draining.read().unwrap().len() as u32;
+        let volumes_map = self.volumes.read().unwrap();
+
+        let total: u32 = volumes_map.values().sum();
+        let active = (volumes_map.len() as u32).saturating_sub(draining).max(1);
+
+        (total + active - 1) / active
+    }
+
+    /// translate key to volume url
+    /// volume server is selected based on the number of keys it holds.
+    /// Server with lesser number of keys are more likely to get selected.
+    /// volumes in `exclude` are skipped
+    fn key_to_volume(&self, exclude: &[String]) -> String {
+        let volumes_map = self.volumes.read().unwrap();
+        let len = volumes_map.len() - exclude.len();
+        let mut vlms = Vec::<&String>::with_capacity(len);
+        let mut counts = Vec::<f32>::with_capacity(len);
+
+        let mut cumulative_count = 0.0f32;
+        let mut max_count = 0;
+
+        for (key, value) in volumes_map.iter() {
+            if exclude.contains(key) {
+                continue;
+            }
+
+            vlms.push(key);
+
+            let count = if *value == 0 { 1 } else { *value };
+
+            if count > max_count {
+                max_count = count;
+            }
+
+            cumulative_count += count as f32;
+            counts.push(cumulative_count);
+        }
+
+        // invert-normalize counts
+        for count in counts.iter_mut() {
+            *count = max_count as f32 / *count;
+        }
+
+        let mut rng = thread_rng();
+        let random = rng.gen_range(0.0, max_count as f32);
+
+        for indx in 0..len {
+            if random <= counts[indx] {
+                return (*vlms[indx]).to_string();
+            }
+        }
+
+        vlms[len - 1].to_string()
+    }
+
+    /// increment counter for url
+    fn increment_count(&self, url: &str) {
+        let mut volumes_map = self.volumes.write().unwrap();
+
+        if let Some(count) = (*volumes_map).get_mut(url) {
+            *count += 1;
+        }
+    }
+
+    /// decrement counter for url
+    fn decrement_count(&self, url: &str) {
+        let mut volumes_map = self.volumes.write().unwrap();
+        if let Some(count) = (*volumes_map).get_mut(url) {
+            *count -= 1;
+        }
+    }
+
+    /// fetches values of keys, or their locations unless `proxy` is set
+    fn batch_get(
+        &self,
+        bucket: &Bucket,
+        mut body: &mut dyn Read,
+        proxy: bool,
+        mut items: &mut dyn Write,
+    ) -> io::Result<()> {
+        while let Some(key) = batch::read_frame(&mut body)? {
+            let resp = match str::from_utf8(&key) {
+                Ok(key) => self.get_object(bucket, key, None),
+                Err(_) => ResponseKind::BadRequest("invalid key".to_owned()),
+            };
+
+            let (status, data) = match resp {
+                ResponseKind::Redirect(url) if proxy => {
+                    match http::request("GET", &url, &[], b"", &self.tls) {
+                        Ok(res) if res.status_code == 200 => (200, res.body),
+                        _ => ResponseKind::ServerError.into_item(),
+                    }
+                }
+                resp => resp.into_item(),
+            };
+
+            batch::write_item(&mut items, status, &data)?;
+        }
+
+        Ok(())
+    }
+
+    /// saves values of keys, streaming each of them to volume servers
+    fn batch_put(
+        &self,
+        bucket: &Bucket,
+        mut body: &mut dyn Read,
+        ttl: Option<u64>,
+        mut items: &mut dyn Write,
+    ) -> io::Result<()> {
+        while let Some(key) = batch::read_frame(&mut body)? {
+            let length = match batch::read_length(&mut body)? {
+                Some(length) => length,
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "missing value",
+                    ))
+                }
+            };
+
+            let mut value = (&mut body).take(length);
+            let (status, data) = match str::from_utf8(&key) {
+                Ok(key) => self.batch_put_value(bucket, key, &mut value, length, ttl),
+                Err(_) => ResponseKind::BadRequest("invalid key".to_owned()).into_item(),
+            };
+
+            // values not stored are skipped
+            copy(&mut value, &mut sink())?;
+            if value.limit() > 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "truncated frame",
+                ));
+            }
+
+            batch::write_item(&mut items, status, &data)?;
+        }
+
+        Ok(())
+    }
+
+    /// stores a value of a batch put as item, its record is removed again if
+    /// it fails to upload
+    fn batch_put_value(
+        &self,
+        bucket: &Bucket,
+        key: &str,
+        value: &mut dyn Read,
+        length: u64,
+        ttl: Option<u64>,
+    ) -> (u16, Vec<u8>) {
+        // master stores coded values itself
+        if bucket.erasure.is_some() || !self.has_writable_volumes() {
+            return self
+                .save_object(bucket, key, value, Some(length), ttl)
+                .into_item();
+        }
+
+        let record = match self.place_object(bucket, key, length, ttl) {
+            Ok(record) => record,
+            Err(resp) => return resp.into_item(),
+        };
+
+        // primary volume forwards the value to replicas
+        let url = upload_location(&self.cluster, bucket, key, &record);
+        match http::request_stream("PUT", &url, &[], value, length, &self.tls) {
+            Ok(ref res) if res.status_code < 300 => (201, vec![]),
+            _ => {
+                self.abandon_object(bucket, key, &record);
+                ResponseKind::ServerError.into_item()
+            }
+        }
+    }
+
+    /// deletes keys and their values from volume servers
+    fn batch_delete(
+        &self,
+        bucket: &Bucket,
+        mut body: &mut dyn Read,
+        mut items: &mut dyn Write,
+    ) -> io::Result<()> {
+        while let Some(key) = batch::read_frame(&mut body)? {
+            let resp = match str::from_utf8(&key) {
+                Ok(key) => self.delete_object(bucket, key, None),
+                Err(_) => ResponseKind::BadRequest("invalid key".to_owned()),
+            };
+
+            let (status, data) = match resp {
+                ResponseKind::Redirect(url) => {
+                    match http::request("DELETE", &url, &[], b"", &self.tls) {
+                        Ok(ref res) if res.status_code < 300 => (204, vec![]),
+                        _ => ResponseKind::ServerError.into_item(),
+                    }
+                }
+                resp => resp.into_item(),
+            };
+
+            batch::write_item(&mut items, status, &data)?;
+        }
+
+        Ok(())
+    }
+
+    fn dispatch_batch(&self, mut req: Request) {
+        if *req.method() != Method::Post {
+            return ResponseKind::NotAllowed.respond(req);
+        }
+
+        let op = get_key(req.url(), BATCH_PREFIX);
+        if !["get", "put", "delete"].contains(&op.as_str()) {
+            return ResponseKind::NotFound.respond(req);
+        }
+
+        let bucket = match get_param(req.url(), "bucket") {
+            Some(name) => match self.buckets.read().unwrap().get(&name) {
+                Some(bucket) => bucket.clone(),
+                None => return ResponseKind::NotFound.respond(req),
+            },
+            None => Bucket::default(),
+        };
+
+        let token = get_header(&req, BUCKET_TOKEN_HEADER);
+        if !bucket.allows(op != "get", token.as_deref()) {
+            return ResponseKind::Forbidden.respond(req);
+        }
+
+        let proxy = get_param(req.url(), "proxy").is_some_and(|proxy| proxy == "true");
+        let ttl = match get_param(req.url(), "ttl").map(|ttl| ttl.parse::<u64>()) {
+            Some(Ok(ttl)) => Some(ttl),
+            Some(Err(_)) => return ResponseKind::BadRequest("invalid ttl".to_owned()).respond(req),
+            None => None,
+        };
+
+        if req
+            .body_length()
+            .is_some_and(|length| length as u64 > MAX_BATCH_SIZE)
+        {
+            return ResponseKind::PayloadTooLarge.respond(req);
+        }
+
+        // items are sent as keys are read
+        let mut body = Limited::new(req.take_body(), MAX_BATCH_SIZE);
+        let (resp, mut items) = Response::stream();
+        if req.respond(resp).is_err() {
+            return;
+        }
+
+        let done = match op.as_str() {
+            "get" => self.batch_get(&bucket, &mut body, proxy, &mut items),
+            "put" => self.batch_put(&bucket, &mut body, ttl, &mut items),
+            _ => self.batch_delete(&bucket, &mut body, &mut items),
+        };
+
+        // keys after a malformed frame are left out
+        if let Err(e) = done {
+            let message = format!("malformed batch: {}", e);
+            let _ = batch::write_item(&mut items, 400, message.as_bytes());
+        }
+    }
+
+    /// dispatch requests to keys of named buckets
+    fn dispatch_bucket(&self, mut req: Request) {
+        let path = get_key(req.url(), BUCKET_PREFIX);
+
+        let bucket = match path.find('/') {
+            Some(indx) => self.buckets.read().unwrap().get(&path[..indx]).cloned(),
+            _ => None,
+        };
+
+        let bucket = match bucket {
+            Some(bucket) => bucket,
+            None => return ResponseKind::NotFound.respond(req),
+        };
+
+        let key = &path[bucket.name.len() + 1..];
+        let write = match *req.method() {
+            Method::Get | Method::Head => false,
+            _ => true,
+        };
+
+        let token = get_header(&req, BUCKET_TOKEN_HEADER);
+        if !bucket.allows(write, token.as_ref().map(String::as_str)) {
+            return ResponseKind::Forbidden.respond(req);
+        }
+
+        if key.is_empty() {
+            let resp = match *req.method() {
+                Method::Get => self.list_url(&bucket, req.url()),
+                _ => ResponseKind::NotAllowed,
+            };
+            return resp.respond(req);
+        }
+
+        if is_upload(req.url()) {
+            return self.dispatch_upload(&bucket, key, req);
+        }
+
+        let version = get_param(req.url(), "version").and_then(|v| v.parse::<u64>().ok());
+        let ttl = get_param(req.url(), "ttl").and_then(|ttl| ttl.parse::<u64>().ok());
+
+        let resp = match *req.method() {
+            Method::Get | Method::Head => self.get_object(&bucket, key, version),
+            Method::Post | Method::Put => {
+                let length = req.body_length().map(|length| length as u64);
+                self.save_object(&bucket, key, req.as_reader(), length, ttl)
+            }
+            Method::Delete => self.delete_object(&bucket, key, version),
+            _ => ResponseKind::NotAllowed,
+        };
+
+        resp.respond(req);
+    }
+
+    /// watches changes of a bucket as asked by query params, as server-sent
+    /// events if the client accepts them
+    fn dispatch_watch(&self, req: Request) {
+        if *req.method() != Method::Get {
+            return ResponseKind::NotAllowed.respond(req);
+        }
+
+        let url = req.url().to_owned();
+        let bucket = match get_param(&url, "bucket") {
+            Some(ref name) if !name.is_empty() => self.buckets.read().unwrap().get(name).cloned(),
+            _ => Some(Bucket::default()),
+        };
+
+        let bucket = match bucket {
+            Some(bucket) => bucket,
+            None => return ResponseKind::NotFound.respond(req),
+        };
+
+        let token = get_header(&req, BUCKET_TOKEN_HEADER);
+        if !bucket.allows(false, token.as_ref().map(String::as_str)) {
+            return ResponseKind::Forbidden.respond(req);
+        }
+
+        let filter = Filter {
+            bucket: bucket.name,
+            prefix: get_param(&url, "prefix").unwrap_or_default(),
+        };
+
+        // reconnecting event streams resume after the last event received
+        let since = match get_header(&req, "Last-Event-ID").or_else(|| get_param(&url, "since")) {
+            Some(since) => match since.parse::<u64>() {
+                Ok(since) => since,
+                Err(_) => return ResponseKind::BadRequest("invalid since".to_owned()).respond(req),
+            },
+            None => self.changes.last(),
+        };
+
+        let (first, last) = (self.changes.first(), self.changes.last());
+        if since + 1 < first || since > last {
+            let msg = format!("changes after {} are not kept, oldest is {}", since, first);
+            return ResponseKind::Gone(msg).respond(req);
+        }
+
+        let stream = get_header(&req, "Accept")
+            .map_or(false, |accept| accept.contains(changes::EVENT_STREAM));
+        let timeout = match get_param(&url, "timeout").map(|secs| secs.parse::<u64>()) {
+            Some(Ok(secs)) => secs.min(MAX_WATCH_TIMEOUT),
+            Some(Err(_)) => {
+                return ResponseKind::BadRequest("invalid timeout".to_owned()).respond(req)
+            }
+            None => WATCH_TIMEOUT,
+        };
+        let limit = match get_param(&url, "limit").map(|limit| limit.parse::<usize>()) {
+            Some(Ok(limit)) => limit.clamp(1, LIST_LIMIT),
+            Some(Err(_)) => {
+                return ResponseKind::BadRequest("invalid limit".to_owned()).respond(req)
+            }
+            None => LIST_LIMIT,
+        };
+
+        let watch = match self.changes.watch() {
+            Some(watch) => watch,
+            None => return ResponseKind::Unavailable.respond(req),
+        };
+
+        // watches wait on threads of their own, keeping workers free
+        thread::spawn(move || {
+            if stream {
+                watch.log.stream(&filter, since, req);
+            } else {
+                let timeout = Duration::from_secs(timeout);
+                watch_changes(&watch.log, &filter, since, timeout, limit).respond(req);
+            }
+        });
+    }
+
+    /// commits a value streamed through master and uploads it to volumes.
+    /// erasure coded values are coded while they are streamed
+    fn s3_put_object(&self, bucket: &Bucket, key: &str, body: Body) -> Result<String, S3Error> {
+        if !self.has_writable_volumes() {
+            return Err(S3Error::ServiceUnavailable);
+        }
+
+        if let Some(erasure) = bucket.erasure {
+            let size = body.length;
+            let mut value = body.verifier();
+            let shards = self
+                .store_shards(erasure, &mut value, size)
+                .map_err(s3_error)?;
+
+            // shards of a body not matching its digests are dropped
+            let etag = match value.finish() {
+                Ok(etag) => etag,
+                Err(e) => {
+                    self.purge_urls(part_urls(&shards));
+                    return Err(e);
+                }
+            };
+
+            self.commit_coded(bucket, key, shards, size, None, Some(etag.clone()))
+                .map_err(s3_error)?;
+            return Ok(etag);
+        }
+
+        let record = self
+            .place_object(bucket, key, body.length, None)
+            .map_err(s3_error)?;
+        let url = upload_location(&self.cluster, bucket, key, &record);
+
+        match self.proxy_upload(&url, body) {
+            Ok(etag) => {
+                self.tag_object(bucket, key, &record, &etag);
+                Ok(etag)
+            }
+            Err(e) => {
+                self.abandon_object(bucket, key, &record);
+                Err(e)
+            }
+        }
+    }
+
+    /// sets the entity tag of the current record of a key, unless it was
+    /// replaced meanwhile
+    fn tag_object(&self, bucket: &Bucket, key: &str, record: &Record, etag: &str) {
+        // index entries do not change while usage is locked
+        let _usage = self.usage.write().unwrap();
+        let index_key = index_key(bucket, key);
+
+        if let Ok(Some(ref current)) = self.get_record(&index_key) {
+            if current == record {
+                let tagged = Record {
+                    etag: Some(etag.to_owned()),
+                    ..record.clone()
+                };
+                let _ = self
+                    .index
+                    .put(index_key.as_bytes(), tagged.encode().as_bytes());
+            }
+        }
+    }
+
+    /// removes the record of a value that failed to upload, unless it was
+    /// replaced meanwhile
+    fn abandon_object(&self, bucket: &Bucket, key: &str, record: &Record) {
+        let index_key = index_key(bucket, key);
+
+        if let Ok(true) = self.remove_record(&index_key, record) {
+            self.purge(bucket, key, record);
+        }
+    }
+
+    /// streams a body to a volume server, returns the hex MD5 digest of it
+    fn proxy_upload(&self, url: &str, body: Body) -> Result<String, S3Error> {
+        let checks = body.checks();
+        let headers: Vec<(&str, &str)> = checks
+            .iter()
+            .map(|(field, value)| (*field, value.as_str()))
+            .collect();
+
+        let mut reader = DigestReader::new(body.reader);
+        match http::request_stream("PUT", url, &headers, &mut reader, body.length, &self.tls) {
+            Ok(ref res) if res.status_code == 201 => Ok(to_hex(&reader.finish().md5)),
+            Ok(ref res) if res.status_code == 400 => Err(S3Error::from_rejected(&res.text())),
+            Ok(_) => Err(S3Error::InternalError),
+            Err(e) => Err(S3Error::from_io(&e)),
+        }
+    }
+
+    /// value of a key with its headers, the value is left out for HEAD
+    fn s3_get_object(&self, bucket: &Bucket, key: &str, head: bool) -> Result<S3Response, S3Error> {
+        let record = self
+            .live_record(bucket, key, None)
+            .map_err(s3_error)?
+            .ok_or(S3Error::NoSuchKey)?;
+
+        let mut headers = vec![
+            ("Content-Type", "application/octet-stream".to_owned()),
+            ("ETag", format!("\"{}\"", s3::etag(&record))),
+        ];
+        if let Some(modified) = record.modified {
+            headers.push(("Last-Modified", s3::http_date(modified)));
+        }
+
+        if head {
+            return Ok(S3Response::Object(
+                Box::new(io::empty()),
+                record.size,
+                headers,
+            ));
+        }
+
+        let value: Box<dyn Read + Send> = match self.read_record(bucket, key, &record) {
+            ResponseKind::Redirect(url) => match http::open("GET", &url, &[], &self.tls) {
+                Ok((ref res, value)) if res.status_code == 200 => value,
+                Ok(_) | Err(_) => return Err(S3Error::InternalError),
+            },
+            ResponseKind::Stream(value, _) => value,
+            resp => return Err(s3_error(resp)),
+        };
+
+        Ok(S3Response::Object(value, record.size, headers))
+    }
+
+    /// removes a key, keys not found are deleted already
+    fn s3_delete_object(&self, bucket: &Bucket, key: &str) -> Result<S3Response, S3Error> {
+        match self.delete_object(bucket, key, None) {
+            ResponseKind::Redirect(url) => self.purge_urls(vec![url]),
+            ResponseKind::Deleted | ResponseKind::NotFound => {}
+            resp => return Err(s3_error(resp)),
+        }
+
+        Ok(S3Response::Empty(204, vec![]))
+    }
+
+    /// ListObjectsV2
+    fn s3_list_objects(
+        &self,
+        bucket: &Bucket,
+        params: &[(String, String)],
+    ) -> Result<S3Response, S3Error> {
+        let mut listing = Listing::from_query(&bucket.name, params, LIST_LIMIT)?;
+        let prefix = s3::to_key(&listing.prefix);
+        let after = listing.resume().0.map(|after| s3::to_key(&after));
+
+        let objects = self
+            .scan_objects(bucket, &prefix, after.as_ref().map(String::as_str))
+            .map(|(key, record)| s3::Object {
+                key: s3::from_key(&key),
+                size: record.size,
+                modified: record.modified.unwrap_or(0),
+                etag: s3::etag(&record),
+            });
+        listing.fill(objects);
+
+        Ok(S3Response::Xml(listing.to_xml()))
+    }
+
+    /// uploads a part through master, its entity tag is kept with its record
+    fn s3_put_part(
+        &self,
+        bucket: &Bucket,
+        key: &str,
+        id: &str,
+        number: u32,
+        body: Body,
+    ) -> Result<String, S3Error> {
+        self.check_part(bucket, key, id, number)
+            .map_err(upload_error)?;
+
+        let (part_key, record) = self
+            .place_part(bucket, id, number, body.length)
+            .map_err(s3_error)?;
+        let url = part_upload_location(&self.cluster, bucket, id, number, &record);
+        let etag = self.proxy_upload(&url, body)?;
+
+        let record = Record {
+            etag: Some(etag.clone()),
+            ..record
+        };
+        self.index
+            .put(part_key.as_bytes(), record.encode().as_bytes())
+            .map_err(|_| S3Error::InternalError)?;
+
+        Ok(etag)
+    }
+
+    /// ListParts
+    fn s3_list_parts(&self, bucket: &Bucket, key: &str, id: &str) -> Result<S3Response, S3Error> {
+        self.find_upload(bucket, key, id).map_err(upload_error)?;
+
+        let parts: Vec<(u32, u64, String)> = self
+            .uploaded_parts(id)
+            .iter()
+            .map(|(_, number, part)| (*number, part.size, s3::etag(part)))
+            .collect();
+
+        let key = s3::from_key(key);
+        Ok(S3Response::Xml(s3::list_parts(
+            &bucket.name,
+            &key,
+            id,
+            &parts,
+        )))
+    }
+
+    /// CompleteMultipartUpload, all uploaded parts have to be listed
+    fn s3_complete_upload(
+        &self,
+        bucket: &Bucket,
+        key: &str,
+        id: &str,
+        body: Body,
+    ) -> Result<S3Response, S3Error> {
+        if body.length > s3::MAX_XML_SIZE {
+            return Err(S3Error::EntityTooLarge);
+        }
+        let listed = s3::parse_complete(&body.read_all()?)?;
+
+        self.find_upload(bucket, key, id).map_err(upload_error)?;
+        let uploaded = self.uploaded_parts(id);
+
+        if listed.windows(2).any(|pair| pair[0] >= pair[1]) {
+            return Err(S3Error::InvalidPartOrder);
+        }
+        if listed.len() != uploaded.len()
+            || listed
+                .iter()
+                .zip(&uploaded)
+                .any(|(listed, (_, number, _))| listed != number)
+        {
+            return Err(S3Error::InvalidPart);
+        }
+
+        let etags: Vec<String> = uploaded
+            .iter()
+            .filter_map(|(_, _, part)| part.etag.clone())
+            .collect();
+        let etag = if etags.len() == uploaded.len() {
+            s3::multipart_etag(&etags)
+        } else {
+            None
+        };
+
+        match self.complete_upload(bucket, key, id, None, etag.clone()) {
+            ResponseKind::Ok(_) => {}
+            resp => return Err(upload_error(resp)),
+        }
+
+        let etag = match etag {
+            Some(etag) => etag,
+            None => match self.live_record(bucket, key, None) {
+                Ok(Some(record)) => s3::etag(&record),
+                _ => return Err(S3Error::InternalError),
+            },
         };
 
+        let key = s3::from_key(key);
+        Ok(S3Response::Xml(s3::completed(&bucket.name, &key, &etag)))
+    }
+
+    /// requests to a bucket itself
+    fn s3_bucket(
+        &self,
+        req: &Request,
+        name: &str,
+        params: &[(String, String)],
+        config: &S3Config,
+    ) -> Result<S3Response, S3Error> {
+        let bucket = self.buckets.read().unwrap().get(name).cloned();
+
+        match (req.method(), bucket) {
+            (Method::Put, Some(_)) => Err(S3Error::BucketAlreadyOwnedByYou),
+            (Method::Put, None) => {
+                if !bucket::valid_name(name) {
+                    return Err(S3Error::InvalidBucketName(format!(
+                        "invalid bucket name {}",
+                        name
+                    )));
+                }
+
+                match self.create_bucket(format!("name={}", name)) {
+                    ResponseKind::Ok(_) => Ok(S3Response::Empty(
+                        200,
+                        vec![("Location", format!("/{}", name))],
+                    )),
+                    resp => Err(s3_error(resp)),
+                }
+            }
+            (_, None) => Err(S3Error::NoSuchBucket),
+            (Method::Head, Some(_)) => Ok(S3Response::Empty(200, vec![])),
+            (Method::Delete, Some(_)) => match self.delete_bucket(name.to_owned()) {
+                ResponseKind::Ok(_) => Ok(S3Response::Empty(204, vec![])),
+                ResponseKind::NotFound => Err(S3Error::NoSuchBucket),
+                ResponseKind::BadRequest(_) => Err(S3Error::BucketNotEmpty),
+                resp => Err(s3_error(resp)),
+            },
+            (Method::Get, Some(_)) if s3::param(params, "location").is_some() => {
+                Ok(S3Response::Xml(s3::location(&config.region)))
+            }
+            (Method::Get, Some(bucket)) if s3::param(params, "list-type") == Some("2") => {
+                self.s3_list_objects(&bucket, params)
+            }
+            (Method::Get, Some(_)) => Err(S3Error::NotImplemented),
+            _ => Err(S3Error::MethodNotAllowed),
+        }
+    }
+
+    /// authenticates a request to the s3 gateway and serves it
+    fn serve_s3(&self, req: &mut Request, config: &S3Config) -> Result<S3Response, S3Error> {
+        let payload = s3::verify(req, config, now())?;
+
+        let url = req.url().to_owned();
+        let params = s3::query(&url)?;
+        let (name, key) = s3::split_path(&url)?;
+
+        if name.is_empty() {
+            if *req.method() != Method::Get {
+                return Err(S3Error::MethodNotAllowed);
+            }
+
+            let mut names: Vec<String> = self.buckets.read().unwrap().keys().cloned().collect();
+            names.sort();
+            return Ok(S3Response::Xml(s3::list_buckets(&names)));
+        }
+
+        if key.is_empty() {
+            return self.s3_bucket(req, &name, &params, config);
+        }
+
+        let bucket = self
+            .buckets
+            .read()
+            .unwrap()
+            .get(&name)
+            .cloned()
+            .ok_or(S3Error::NoSuchBucket)?;
+        let key = s3::to_key(&key);
+        let key = key.as_str();
+        let etag = |etag: String| S3Response::Empty(200, vec![("ETag", format!("\"{}\"", etag))]);
+
+        match (req.method().clone(), s3::param(&params, "uploadId")) {
+            (Method::Put, Some(id)) => {
+                let number = s3::param(&params, "partNumber")
+                    .and_then(|number| number.parse::<u32>().ok())
+                    .ok_or_else(|| S3Error::InvalidArgument("invalid partNumber".to_owned()))?;
+                let body = s3::body(req, payload)?;
+                self.s3_put_part(&bucket, key, id, number, body).map(etag)
+            }
+            (Method::Put, None) => {
+                if get_header(req, "x-amz-copy-source").is_some() {
+                    return Err(S3Error::NotImplemented);
+                }
+                let body = s3::body(req, payload)?;
+                self.s3_put_object(&bucket, key, body).map(etag)
+            }
+            (Method::Get, Some(id)) => self.s3_list_parts(&bucket, key, id),
+            (Method::Get, None) => self.s3_get_object(&bucket, key, false),
+            (Method::Head, None) => self.s3_get_object(&bucket, key, true),
+            (Method::Delete, Some(id)) => match self.abort_upload(&bucket, key, id) {
+                ResponseKind::Deleted => Ok(S3Response::Empty(204, vec![])),
+                resp => Err(upload_error(resp)),
+            },
+            (Method::Delete, None) => self.s3_delete_object(&bucket, key),
+            (Method::Post, None) if s3::param(&params, "uploads").is_some() => {
+                match self.initiate_upload(&bucket, key) {
+                    ResponseKind::Ok(id) => {
+                        let key = s3::from_key(key);
+                        Ok(S3Response::Xml(s3::initiated(&name, &key, &id)))
+                    }
+                    resp => Err(s3_error(resp)),
+                }
+            }
+            (Method::Post, Some(id)) => {
+                let body = s3::body(req, payload)?;
+                self.s3_complete_upload(&bucket, key, id, body)
+            }
+            _ => Err(S3Error::MethodNotAllowed),
+        }
+    }
+
+    fn dispatch_s3(&self, mut req: Request, config: &S3Config) {
+        let resource = req.url().split('?').next().unwrap_or("/").to_owned();
+
+        match self.serve_s3(&mut req, config) {
+            Ok(resp) => resp.respond(req),
+            Err(e) => S3Response::Error(e, resource).respond(req),
+        }
+    }
+
+    fn dispatch(&self, req: Request) {
+        let url = req.url();
+
+        if url.starts_with(STORE_PREFIX) {
+            let key = get_key(url, STORE_PREFIX);
+
+            if key.is_empty() && *req.method() == Method::Get {
+                self.list_url(&Bucket::default(), url).respond(req);
+            } else if is_upload(url) {
+                self.dispatch_upload(&Bucket::default(), &key, req);
+            } else {
+                Service::dispatch(self, req);
+            }
+        } else if url.starts_with(BUCKET_PREFIX) {
+            self.dispatch_bucket(req);
+        } else if url.starts_with(ADMIN_PREFIX) {
+            AdminService::dispatch(self, req);
+        } else if url.starts_with(BATCH_PREFIX) {
+            self.dispatch_batch(req);
+        } else if get_key(url, "") == WATCH_PATH {
+            self.dispatch_watch(req);
+        } else {
+            let _ = req.respond(resp!("Path not found", 404));
+        }
+    }
+}
+
+/// Builds master servers, to run them in process
+#[derive(Clone)]
+pub struct MasterBuilder {
+    port: u16,
+    data_dir: String,
+    threads: u16,
+    volumes: Vec<String>,
+    cluster: ClusterConfig,
+    tls: TlsConfig,
+    limits: LimitConfig,
+    index: Backend,
+    s3: Option<S3Config>,
+}
+
+impl MasterBuilder {
+    /// master keeping its index in `data_dir`, listening at port 6000
+    pub fn new(data_dir: &str) -> Self {
+        MasterBuilder {
+            port: 6000,
+            data_dir: data_dir.to_owned(),
+            threads: num_cpus::get() as u16,
+            volumes: Vec::new(),
+            cluster: ClusterConfig::default(),
+            tls: TlsConfig::default(),
+            limits: LimitConfig::default(),
+            index: Backend::default(),
+            s3: None,
+        }
+    }
+
+    /// Port to listen at, 0 picks a free one
+    pub fn port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
+    /// Number of requests handled at once, defaults to number of cpu cores
+    pub fn threads(mut self, threads: u16) -> Self {
+        self.threads = threads;
+        self
+    }
+
+    /// Volume servers known at start
+    pub fn volumes(mut self, volumes: Vec<String>) -> Self {
+        self.volumes = volumes;
+        self
+    }
+
+    /// Cluster id and join token for volume registration
+    pub fn cluster(mut self, cluster: ClusterConfig) -> Self {
+        self.cluster = cluster;
+        self
+    }
+
+    /// TLS settings, listens on https if a certificate is configured
+    pub fn tls(mut self, tls: TlsConfig) -> Self {
+        self.tls = tls;
+        self
+    }
+
+    /// Rate limits and request queue length
+    pub fn limits(mut self, limits: LimitConfig) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Backend holding the index
+    pub fn index(mut self, index: Backend) -> Self {
+        self.index = index;
+        self
+    }
+
+    /// Serves the s3 gateway as well, see [s3](../s3/index.html)
+    pub fn s3(mut self, s3: S3Config) -> Self {
+        self.s3 = Some(s3);
+        self
+    }
+
+    /// starts the server, returns once it accepts requests
+    pub fn start(self) -> io::Result<ServerHandle> {
+        let index = self.index.open(&self.data_dir)?;
+        let server = server::bind(self.port, &self.tls, self.limits.connections)?;
+        let addr = server.server_addr();
+        let https = self.tls.cert.is_some();
+
+        let s3 = match self.s3 {
+            Some(config) => Some((
+                server::bind(config.port, &self.tls, self.limits.connections)?,
+                config,
+            )),
+            None => None,
+        };
+
+        let master = Arc::new(Master::new(index, self.volumes, self.cluster, self.tls));
+        let shutdown = master.shutdown.clone();
+
+        let converter = master.clone();
+        let signal = shutdown.clone();
+        shutdown.spawn(move || loop {
+            converter.convert_scheduled();
+            if !signal.sleep(CONVERT_INTERVAL) {
+                break;
+            }
+        });
+
+        let notifier = master.clone();
+        let signal = shutdown.clone();
+        shutdown.spawn(move || {
+            let mut retries = HashMap::new();
+
+            loop {
+                let seen = notifier.changes.last();
+                notifier.deliver_webhooks(&mut retries);
+
+                // wakes up for new changes, and to retry failed deliveries
+                if !notifier.changes.wait(seen, WEBHOOK_INTERVAL) && signal.is_set() {
+                    break;
+                }
+            }
+        });
+
+        let mut s3_addr = None;
+        let mut workers = Vec::new();
+
+        if let Some((s3_server, config)) = s3 {
+            s3_addr = Some(s3_server.server_addr());
+
+            let gateway = master.clone();
+            workers = limit::serve(
+                s3_server,
+                self.threads,
+                self.limits.clone(),
+                &env::temp_dir(),
+                &shutdown,
+                move |rq| gateway.dispatch_s3(rq, &config),
+            );
+        }
+
+        workers.extend(limit::serve(
+            server,
+            self.threads,
+            self.limits,
+            &env::temp_dir(),
+            &shutdown,
+            move |rq| master.dispatch(rq),
+        ));
+
+        let handle = ServerHandle::new(addr, https, shutdown, workers);
+        Ok(match s3_addr {
+            Some(s3_addr) => handle.with_s3(s3_addr),
+            None => handle,
+        })
+    }
+}
+
+/// starts a kalavara master server and serves until it is shut down, see
+/// [`MasterBuilder`](struct.MasterBuilder.html) for further settings and to
+/// embed one
+/// # Arguments
+///
+/// * `port` - Port name to listen at
+/// * `data_dir` - Database directory
+/// * `threads` - Number of requests handled at once
+/// * `volumes` - List of volume servers
+///
+pub fn start(port: u16, data_dir: &str, threads: u16, volumes: Vec<String>) -> io::Result<()> {
+    MasterBuilder::new(data_dir)
+        .port(port)
+        .threads(threads)
+        .volumes(volumes)
+        .start()?
+        .join();
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::index::MemoryIndex;
+
+    #[test]
+    fn test_master_crud() {
+        let index = Arc::new(MemoryIndex::new());
+
+        let master = Master::new(
+            index,
+            vec![
+                "server1".to_owned(),
+                "server2".to_owned(),
+                "server3".to_owned(),
+                "server4".to_owned(),
+                "server5".to_owned(),
+            ],
+            ClusterConfig::default(),
+            TlsConfig::default(),
+        );
+        let key = "key".to_owned();
+        let val = "val".to_owned();
+
+        assert!(match master.get(key.clone()) {
+            ResponseKind::NotFound => true,
+            _ => false,
+        });
+
+        let mut url = String::new();
+
+        assert!(
+            match master.save(key.clone(), val.clone().as_bytes(), None) {
+                ResponseKind::Redirect(to) => {
+                    url = to.replace(&format!("?size={}", val.len()), "");
+                    true
+                }
+                _ => false,
+            }
+        );
+
+        // should redirect to the save volume server
+        // in which the key got stored
+        assert!(match master.get(key.clone()) {
+            ResponseKind::Redirect(to) => to == url,
+            _ => false,
+        });
+
+        assert_eq!(master.volumes.read().unwrap().get(&url[..7]), Some(&1));
+
+        assert!(match master.delete(key.clone()) {
+            ResponseKind::Redirect(to) => to == url,
+            _ => false,
+        });
+
+        assert_eq!(master.volumes.read().unwrap().get(&url[..7]), Some(&0));
+    }
+
+    #[test]
+    fn test_master_list() {
+        let master = Master::new(
+            Arc::new(MemoryIndex::new()),
+            vec!["server1".to_owned()],
+            ClusterConfig::default(),
+            TlsConfig::default(),
+        );
+
+        for key in &["b", "a/2", "a/1", "c"] {
+            master.save(key.to_string(), &b"val"[..], None);
+        }
+
+        master.create_bucket("name=logs&versioning=true".to_owned());
+        let bucket = master.buckets.read().unwrap()["logs"].clone();
+        master.save_object(&bucket, "a/1", &b"v1"[..], None, None);
+        master.save_object(&bucket, "a/1", &b"v2"[..], None, None);
+
+        fn keys(resp: ResponseKind) -> String {
+            match resp {
+                ResponseKind::Ok(keys) => keys,
+                _ => panic!("listing failed"),
+            }
+        }
+
+        let default = Bucket::default();
+        assert_eq!(
+            keys(master.list_objects(&default, "", None, 10)),
+            "a/1\na/2\nb\nc"
+        );
+        assert_eq!(
+            keys(master.list_objects(&default, "a/", None, 10)),
+            "a/1\na/2"
+        );
+        assert_eq!(keys(master.list_objects(&default, "", None, 1)), "a/1");
+        assert_eq!(
+            keys(master.list_objects(&default, "", Some("a/2"), 10)),
+            "b\nc"
+        );
+        assert_eq!(
+            keys(master.list_objects(&default, "a/", Some("a/2"), 10)),
+            ""
+        );
+
+        // previous versions and keys of other buckets are not listed
+        assert_eq!(keys(master.list_objects(&bucket, "", None, 10)), "a/1");
+    }
+
+    #[test]
+    fn test_master_admin() {
+        let index = Arc::new(MemoryIndex::new());
+
+        let master = Master::new(
+            index,
+            vec!["server1".to_owned(), "server2".to_owned()],
+            ClusterConfig::default(),
+            TlsConfig::default(),
+        );
+
+        assert!(match master.add_volume("server3".to_owned()) {
+            ResponseKind::Ok(resp) => resp == "Volume added".to_string(),
+            _ => false,
+        });
+
+        assert_eq!(master.volumes.read().unwrap().len(), 3);
+
+        assert!(match master.add_volume("server3".to_owned()) {
+            ResponseKind::Ok(resp) => resp == "Skipping duplicate volume server".to_string(),
+            _ => false,
+        });
+
+        assert_eq!(master.volumes.read().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_master_volumes() {
+        let index = Arc::new(MemoryIndex::new());
+        index.put(b"key1", b"server1").unwrap();
+
+        let master = Master::new(
+            index,
+            vec!["server1".to_owned(), "server2".to_owned()],
+            ClusterConfig::default(),
+            TlsConfig::default(),
+        );
+
+        assert!(match master.list_volumes() {
+            ResponseKind::Ok(list) => {
+                list == "url=server1&values=1&state=active\nurl=server2&values=0&state=active"
+            }
+            _ => false,
+        });
+
+        assert!(match master.remove_volume("server1".to_owned()) {
+            ResponseKind::BadRequest(_) => true,
+            _ => false,
+        });
+
+        // values can not be copied between fake servers, server1 stays drained
+        assert!(match master.drain_volume("server1".to_owned()) {
+            ResponseKind::Ok(resp) => resp == "moved=0&failed=1",
+            _ => false,
+        });
+
+        for _ in 0..10 {
+            assert_eq!(master.pick_volumes(2), vec!["server2".to_owned()]);
+        }
+        assert_eq!(master.pick_target(&[]), Some("server2".to_owned()));
+        assert_eq!(master.share(), 1);
+
+        assert!(match master.remove_volume("server2".to_owned()) {
+            ResponseKind::Ok(_) => true,
+            _ => false,
+        });
+        assert!(!master.has_writable_volumes());
+        assert!(master.pick_volumes(1).is_empty());
+    }
+
+    #[test]
+    fn test_master_counter() {
+        let index = Arc::new(MemoryIndex::new());
+
+        index.put(b"key1", b"server1").unwrap();
+        index.put(b"key2", b"server1").unwrap();
+        index.put(b"key3", b"server2").unwrap();
+        index.put(b"key4", b"server3").unwrap();
+
+        let master = Master::new(
+            index,
+            vec!["server1".to_owned(), "server4".to_owned()],
+            ClusterConfig::default(),
+            TlsConfig::default(),
+        );
+        assert_eq!(master.volumes.read().unwrap()["server1"], 2);
+        assert_eq!(master.volumes.read().unwrap()["server2"], 1);
+        assert_eq!(master.volumes.read().unwrap()["server3"], 1);
+        assert_eq!(master.volumes.read().unwrap()["server4"], 0);
+    }
+
+    #[test]
+    fn test_master_authorize_volume() {
+        let index = Arc::new(MemoryIndex::new());
+
+        let master = Master::new(
+            index,
+            vec![],
+            ClusterConfig {
+                id: Some("cluster1".to_owned()),
+                join_token: Some("secret".to_owned()),
+            },
+            TlsConfig::default(),
+        );
+
+        let token = |token: &str| Credentials {
+            token: Some(token.to_owned()),
+            certificate: None,
+        };
+
+        assert!(master.authorize_volume("http://server1", &token("secret")));
+        assert!(!master.authorize_volume("http://server1", &token("guess")));
+        assert!(!master.authorize_volume("http://server1", &Credentials::default()));
+
+        assert!(
+            match master.register_volume("http://server1".to_owned(), Credentials::default()) {
+                ResponseKind::Unauthorized => true,
+                _ => false,
+            }
+        );
+
+        // nothing listens at server1
+        assert!(
+            match master.register_volume("http://server1".to_owned(), token("secret")) {
+                ResponseKind::BadRequest(_) => true,
+                _ => false,
+            }
+        );
+
+        assert!(master.volumes.read().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_master_buckets() {
+        let index = Arc::new(MemoryIndex::new());
+
+        let master = Master::new(
+            index,
+            vec!["server1".to_owned(), "server2".to_owned()],
+            ClusterConfig::default(),
+            TlsConfig::default(),
+        );
+
+        assert!(
+            match master.create_bucket("name=logs&replicas=2&quota=8".to_owned()) {
+                ResponseKind::Ok(resp) => resp == "Bucket created",
+                _ => false,
+            }
+        );
+
+        let bucket = master.buckets.read().unwrap()["logs"].clone();
+
+        // stored on both volumes
+        assert!(
+            match master.save_object(&bucket, "key", "val".as_bytes(), None, None) {
+                ResponseKind::Redirect(to) => to.contains("/bucket/logs/key?replicas=server"),
+                _ => false,
+            }
+        );
+
+        assert_eq!(master.volumes.read().unwrap()["server1"], 1);
+        assert_eq!(master.volumes.read().unwrap()["server2"], 1);
+
+        // overwrites replace the size of previous value
+        assert!(
+            match master.save_object(&bucket, "key", "value".as_bytes(), None, None) {
+                ResponseKind::Redirect(_) => true,
+                _ => false,
+            }
+        );
+
+        assert_eq!(master.bucket_usage("logs").bytes, 5);
+        assert_eq!(master.volumes.read().unwrap()["server1"], 1);
+
+        // announced sizes are accounted without reading the value
+        struct Unreadable;
+        impl Read for Unreadable {
+            fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+                Err(io::Error::new(io::ErrorKind::Other, "value was read"))
+            }
+        }
+        assert!(
+            match master.save_object(&bucket, "key", Unreadable, Some(6), None) {
+                ResponseKind::Redirect(to) => to.ends_with("size=6"),
+                _ => false,
+            }
+        );
+        assert_eq!(master.bucket_usage("logs").bytes, 6);
+
+        assert!(
+            match master.save_object(&bucket, "key2", "value".as_bytes(), None, None) {
+                ResponseKind::InsufficientStorage => true,
+                _ => false,
+            }
+        );
+
+        // default bucket is not affected
+        assert!(
+            match master.save("key".to_owned(), "value".as_bytes(), None) {
+                ResponseKind::Redirect(to) => to.ends_with("/store/key?size=5"),
+                _ => false,
+            }
+        );
+
+        assert!(match master.delete_bucket("logs".to_owned()) {
+            ResponseKind::BadRequest(resp) => resp == "Bucket not empty",
+            _ => false,
+        });
+
+        assert!(match master.delete_object(&bucket, "key", None) {
+            ResponseKind::Redirect(to) => to.contains("/bucket/logs/key?replicas=server"),
+            _ => false,
+        });
+
+        assert_eq!(master.bucket_usage("logs").bytes, 0);
+
+        assert!(match master.delete_bucket("logs".to_owned()) {
+            ResponseKind::Ok(resp) => resp == "Bucket deleted",
+            _ => false,
+        });
+
+        assert!(master.buckets.read().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_master_bucket_persistence() {
+        let index = Arc::new(MemoryIndex::new());
+
+        {
+            let master = Master::new(
+                index.clone(),
+                vec!["server1".to_owned()],
+                ClusterConfig::default(),
+                TlsConfig::default(),
+            );
+
+            master.create_bucket("name=logs&access=read-only".to_owned());
+            let bucket = master.buckets.read().unwrap()["logs"].clone();
+            master.save_object(&bucket, "key", "value".as_bytes(), None, None);
+        }
+
+        // buckets and usage are restored from the index
         let master = Master::new(
-            db,
-            vec![
-                "server1".to_owned(),
-                "server2".to_owned(),
-                "server3".to_owned(),
-                "server4".to_owned(),
-                "server5".to_owned(),
-            ],
+            index,
+            vec![],
+            ClusterConfig::default(),
+            TlsConfig::default(),
         );
-        let key = "key".to_owned();
-        let val = "val".to_owned();
 
-        assert!(match master.get(key.clone()) {
+        assert_eq!(
+            master.buckets.read().unwrap()["logs"].access,
+            Access::ReadOnly
+        );
+        assert_eq!(master.bucket_usage("logs").bytes, 5);
+        assert_eq!(master.volumes.read().unwrap()["server1"], 1);
+    }
+
+    #[test]
+    fn test_master_versioning_ttl() {
+        let index = Arc::new(MemoryIndex::new());
+
+        let master = Master::new(
+            index,
+            vec!["server1".to_owned()],
+            ClusterConfig::default(),
+            TlsConfig::default(),
+        );
+
+        master.create_bucket("name=docs&versioning=true".to_owned());
+        master.create_bucket("name=tmp&ttl=3600".to_owned());
+        let docs = master.buckets.read().unwrap()["docs"].clone();
+        let tmp = master.buckets.read().unwrap()["tmp"].clone();
+
+        let first = match master.save_object(&docs, "key", "v1".as_bytes(), None, None) {
+            ResponseKind::Redirect(to) => to.replace("?size=2", ""),
+            _ => panic!("save failed"),
+        };
+
+        let second = match master.save_object(&docs, "key", "v2".as_bytes(), None, None) {
+            ResponseKind::Redirect(to) => to.replace("?size=2", ""),
+            _ => panic!("save failed"),
+        };
+
+        assert_ne!(first, second);
+
+        assert!(match master.get_object(&docs, "key", None) {
+            ResponseKind::Redirect(to) => to == second,
+            _ => false,
+        });
+
+        let version = first[first.rfind('@').unwrap() + 1..].parse().unwrap();
+        assert!(match master.get_object(&docs, "key", Some(version)) {
+            ResponseKind::Redirect(to) => to == first,
+            _ => false,
+        });
+
+        assert_eq!(master.bucket_usage("docs").bytes, 4);
+
+        // expires immediately
+        master.save_object(&tmp, "key", "value".as_bytes(), None, Some(0));
+        assert!(match master.get_object(&tmp, "key", None) {
             ResponseKind::NotFound => true,
             _ => false,
         });
 
-        let mut url = String::new();
+        assert_eq!(master.bucket_usage("tmp").bytes, 0);
+        assert_eq!(master.volumes.read().unwrap()["server1"], 2);
+    }
+
+    #[test]
+    fn test_master_watch() {
+        let index = Arc::new(MemoryIndex::new());
+
+        let master = Master::new(
+            index.clone(),
+            vec!["server1".to_owned()],
+            ClusterConfig::default(),
+            TlsConfig::default(),
+        );
+
+        master.create_bucket("name=tmp".to_owned());
+        let tmp = master.buckets.read().unwrap()["tmp"].clone();
+        let default = Bucket::default();
+
+        master.save_object(&default, "a/key", "value".as_bytes(), None, None);
+        master.save_object(&tmp, "a/key", "tmp".as_bytes(), None, None);
+        master.delete_object(&default, "a/key", None);
+
+        // expired keys are logged as deleted
+        master.save_object(&default, "b/key", "value".as_bytes(), None, Some(0));
+        master.get_object(&default, "b/key", None);
 
-        assert!(match master.save(key.clone(), val.clone().as_bytes()) {
-            ResponseKind::Redirect(to) => {
-                url = to;
-                true
+        let all = Filter::default();
+        let lines =
+            match watch_changes(&master.changes, &all, 0, Duration::from_secs(0), LIST_LIMIT) {
+                ResponseKind::Changes(lines, 5) => lines,
+                _ => panic!("watch failed"),
+            };
+        assert_eq!(
+            lines,
+            "seq=1&op=put&bucket=&size=5&key=a/key\n\
+             seq=3&op=delete&bucket=&size=5&key=a/key\n\
+             seq=4&op=put&bucket=&size=5&key=b/key\n\
+             seq=5&op=delete&bucket=&size=5&key=b/key"
+        );
+
+        let filter = Filter {
+            bucket: "tmp".to_owned(),
+            prefix: "a/".to_owned(),
+        };
+        assert!(match watch_changes(
+            &master.changes,
+            &filter,
+            0,
+            Duration::from_secs(0),
+            LIST_LIMIT
+        ) {
+            ResponseKind::Changes(lines, 5) => lines == "seq=2&op=put&bucket=tmp&size=3&key=a/key",
+            _ => false,
+        });
+
+        // watches wait for changes
+        assert!(match watch_changes(
+            &master.changes,
+            &all,
+            5,
+            Duration::from_millis(200),
+            LIST_LIMIT
+        ) {
+            ResponseKind::Changes(lines, 5) => lines.is_empty(),
+            _ => false,
+        });
+
+        // log continues after restart
+        let master = Master::new(
+            index,
+            vec!["server1".to_owned()],
+            ClusterConfig::default(),
+            TlsConfig::default(),
+        );
+        assert_eq!(master.changes.last(), 5);
+    }
+
+    #[test]
+    fn test_master_webhooks() {
+        let index = Arc::new(MemoryIndex::new());
+
+        let master = Master::new(
+            index.clone(),
+            vec!["server1".to_owned()],
+            ClusterConfig::default(),
+            TlsConfig::default(),
+        );
+        let default = Bucket::default();
+
+        // changes before a webhook is created are not delivered
+        master.save_object(&default, "logs/old", "old".as_bytes(), None, None);
+
+        assert!(
+            match master.create_webhook("name=logs&secret=s".to_owned()) {
+                ResponseKind::BadRequest(_) => true,
+                _ => false,
             }
+        );
+        assert!(match master.create_webhook(
+            "name=logs&prefix=logs/&secret=s&attempts=1&url=http://127.0.0.1:1/hook".to_owned()
+        ) {
+            ResponseKind::Ok(msg) => msg == "Webhook created",
             _ => false,
         });
 
-        // should redirect to the save volume server
-        // in which the key got stored
-        assert!(match master.get(key.clone()) {
-            ResponseKind::Redirect(to) => to == url,
+        assert!(match master.list_webhooks() {
+            ResponseKind::Ok(list) => {
+                list == "name=logs&bucket=&prefix=logs/&events=put,delete&secret=*\
+                         &attempts=1&backoff=1&url=http://127.0.0.1:1/hook"
+            }
             _ => false,
         });
 
-        assert_eq!(master.volumes.read().unwrap().get(&url[..7]), Some(&1));
+        master.save_object(&default, "logs/new", "new".as_bytes(), None, None);
+        master.save_object(&default, "other", "other".as_bytes(), None, None);
 
-        assert!(match master.delete(key.clone()) {
-            ResponseKind::Redirect(to) => to == url,
+        // unreachable webhooks get their changes dead lettered
+        master.deliver_webhooks(&mut HashMap::new());
+        assert_eq!(master.webhook_cursor("logs"), Some(3));
+
+        let letters = match master.dead_letters() {
+            ResponseKind::Ok(letters) => letters,
+            _ => panic!("listing dead letters failed"),
+        };
+        let letter = DeadLetter::parse(&letters).unwrap();
+        assert_eq!(letter.webhook, "logs");
+        assert_eq!(letter.change.key, "logs/new");
+
+        assert!(match master.retry_dead_letters("logs".to_owned()) {
+            ResponseKind::Ok(result) => result == "delivered=0&failed=1",
             _ => false,
         });
 
-        assert_eq!(master.volumes.read().unwrap().get(&url[..7]), Some(&0));
+        // webhooks survive restarts
+        let master = Master::new(
+            index.clone(),
+            vec!["server1".to_owned()],
+            ClusterConfig::default(),
+            TlsConfig::default(),
+        );
+        assert!(master.webhooks.read().unwrap().contains_key("logs"));
+
+        assert!(match master.delete_webhook("logs".to_owned()) {
+            ResponseKind::Ok(_) => true,
+            _ => false,
+        });
+        assert!(match master.delete_webhook("logs".to_owned()) {
+            ResponseKind::NotFound => true,
+            _ => false,
+        });
+        assert_eq!(index.scan_prefix(b"\0meta/dead-letter/").count(), 0);
+        assert_eq!(master.webhook_cursor("logs"), None);
     }
 
     #[test]
-    fn test_master_admin() {
-        let data_dir = tempdir().unwrap();
+    fn test_master_remove_replaced() {
+        let index = Arc::new(MemoryIndex::new());
+
+        let master = Master::new(
+            index,
+            vec!["server1".to_owned()],
+            ClusterConfig::default(),
+            TlsConfig::default(),
+        );
+
+        master.create_bucket("name=logs".to_owned());
+        let logs = master.buckets.read().unwrap()["logs"].clone();
+
+        let index_key = index_key(&logs, "key");
+
+        master.save_object(&logs, "key", "value".as_bytes(), None, None);
+        let old = master.get_record(&index_key).unwrap().unwrap();
+        master.save_object(&logs, "key", "val".as_bytes(), None, None);
+
+        // record replaced meanwhile is kept
+        assert!(!master.remove_record(&index_key, &old).unwrap());
+        assert_eq!(
+            master.bucket_usage("logs"),
+            Usage {
+                bytes: 3,
+                objects: 1
+            }
+        );
+
+        let current = master.get_record(&index_key).unwrap().unwrap();
+        assert!(master.remove_record(&index_key, &current).unwrap());
+        assert_eq!(master.bucket_usage("logs"), Usage::default());
+        assert!(master.get_record(&index_key).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_master_quotas() {
+        let index = Arc::new(MemoryIndex::new());
+        let report = "bucket=logs&prefix=team-a/&bytes=5&bytes_limit=8&objects=1&objects_limit=-";
+
+        {
+            let master = Master::new(
+                index.clone(),
+                vec!["server1".to_owned()],
+                ClusterConfig::default(),
+                TlsConfig::default(),
+            );
+
+            master.create_bucket("name=logs&quota_objects=3".to_owned());
+            let logs = master.buckets.read().unwrap()["logs"].clone();
+
+            master.save_object(&logs, "team-a/key1", "value".as_bytes(), None, None);
+
+            // usage of existing keys is counted against new quotas
+            assert!(
+                match master.set_quota("bucket=logs&prefix=team-a/&bytes=8".to_owned()) {
+                    ResponseKind::Ok(resp) => resp == "Quota set",
+                    _ => false,
+                }
+            );
+
+            assert!(
+                match master.save_object(&logs, "team-a/key2", "value".as_bytes(), None, None) {
+                    ResponseKind::InsufficientStorage => true,
+                    _ => false,
+                }
+            );
+
+            assert!(
+                match master.save_object(&logs, "team-b/key1", "value".as_bytes(), None, None) {
+                    ResponseKind::Redirect(_) => true,
+                    _ => false,
+                }
+            );
+
+            assert!(
+                match master.save_object(&logs, "team-b/key2", "value".as_bytes(), None, None) {
+                    ResponseKind::Redirect(_) => true,
+                    _ => false,
+                }
+            );
+
+            // object limit of bucket
+            assert!(
+                match master.save_object(&logs, "team-b/key3", "value".as_bytes(), None, None) {
+                    ResponseKind::InsufficientStorage => true,
+                    _ => false,
+                }
+            );
+
+            assert_eq!(
+                master.bucket_usage("logs"),
+                Usage {
+                    bytes: 15,
+                    objects: 3
+                }
+            );
+
+            assert!(match master.usage() {
+                ResponseKind::Ok(usage) => usage.contains(report),
+                _ => false,
+            });
+
+            assert!(match master.set_quota("bucket=none&prefix=a".to_owned()) {
+                ResponseKind::BadRequest(_) => true,
+                _ => false,
+            });
+        }
+
+        // quotas are restored from the index
+        let master = Master::new(
+            index,
+            vec![],
+            ClusterConfig::default(),
+            TlsConfig::default(),
+        );
+
+        assert!(match master.usage() {
+            ResponseKind::Ok(usage) => usage.contains(report),
+            _ => false,
+        });
+
+        assert!(
+            match master.remove_quota("bucket=logs&prefix=team-a/".to_owned()) {
+                ResponseKind::Ok(_) => true,
+                _ => false,
+            }
+        );
+
+        assert!(
+            match master.remove_quota("bucket=logs&prefix=team-a/".to_owned()) {
+                ResponseKind::NotFound => true,
+                _ => false,
+            }
+        );
+    }
+
+    #[test]
+    fn test_master_batch() {
+        let index = Arc::new(MemoryIndex::new());
+
+        let master = Master::new(
+            index,
+            vec!["server1".to_owned()],
+            ClusterConfig::default(),
+            TlsConfig::default(),
+        );
+
+        master.save("key1".to_owned(), "value".as_bytes(), None);
+
+        let body = batch::encode_keys(&["key1", "key2"]);
+        let mut items = Vec::new();
+        master
+            .batch_get(&Bucket::default(), &mut &body[..], false, &mut items)
+            .unwrap();
+
+        assert_eq!(
+            batch::decode_items(&items).unwrap(),
+            vec![(307, b"server1/store/key1".to_vec()), (404, vec![])]
+        );
+
+        // key without value
+        let body = batch::encode_keys(&["key1"]);
+        let mut items = Vec::new();
+        assert!(master
+            .batch_put(&Bucket::default(), &mut &body[..], None, &mut items)
+            .is_err());
+
+        // records of values failing to upload are removed, items before a
+        // truncated frame are kept
+        let body = batch::encode_values(&[("key2", &b"value2"[..]), ("key3", &b"value3"[..])]);
+        let mut items = Vec::new();
+        assert!(master
+            .batch_put(
+                &Bucket::default(),
+                &mut &body[..body.len() - 1],
+                None,
+                &mut items
+            )
+            .is_err());
 
-        let db = match DB::open_default(data_dir) {
-            Ok(db) => db,
-            Err(e) => panic!("failed to open database: {:?}", e),
+        assert_eq!(batch::decode_items(&items).unwrap(), vec![(500, vec![])]);
+        assert!(match master.get("key2".to_owned()) {
+            ResponseKind::NotFound => true,
+            _ => false,
+        });
+        assert_eq!(
+            master.bucket_usage(""),
+            Usage {
+                bytes: 5,
+                objects: 1
+            }
+        );
+    }
+
+    #[test]
+    fn test_master_multipart() {
+        let index = Arc::new(MemoryIndex::new());
+
+        let master = Master::new(
+            index,
+            vec!["server1".to_owned(), "server2".to_owned()],
+            ClusterConfig::default(),
+            TlsConfig::default(),
+        );
+        let bucket = Bucket::default();
+
+        let id = match master.initiate_upload(&bucket, "big") {
+            ResponseKind::Ok(id) => id,
+            _ => panic!("initiate failed"),
         };
 
-        let master = Master::new(db, vec!["server1".to_owned(), "server2".to_owned()]);
+        assert!(
+            match master.upload_part(&bucket, "big", &id, 2, "world".as_bytes(), None) {
+                ResponseKind::Redirect(to) => {
+                    to.ends_with(&format!("/bucket/~parts/{}/2?size=5", id))
+                }
+                _ => false,
+            }
+        );
 
-        assert!(match master.add_volume("server3".to_owned()) {
-            ResponseKind::Ok(resp) => resp == "Volume added".to_string(),
+        master.upload_part(&bucket, "big", &id, 1, "hello ".as_bytes(), None);
+
+        // uploads belong to a key
+        assert!(
+            match master.upload_part(&bucket, "other", &id, 1, "".as_bytes(), None) {
+                ResponseKind::NotFound => true,
+                _ => false,
+            }
+        );
+
+        assert!(
+            match master.upload_part(&bucket, "big", &id, 0, "".as_bytes(), None) {
+                ResponseKind::BadRequest(_) => true,
+                _ => false,
+            }
+        );
+
+        assert!(match master.list_parts(&bucket, "big", &id) {
+            ResponseKind::Ok(parts) => parts == "1 6\n2 5",
             _ => false,
         });
 
-        assert_eq!(master.volumes.read().unwrap().len(), 3);
+        assert!(
+            match master.complete_upload(&bucket, "big", &id, None, None) {
+                ResponseKind::Ok(_) => true,
+                _ => false,
+            }
+        );
 
-        assert!(match master.add_volume("server3".to_owned()) {
-            ResponseKind::Ok(resp) => resp == "Skipping duplicate volume server".to_string(),
+        // upload is gone once completed
+        assert!(match master.list_parts(&bucket, "big", &id) {
+            ResponseKind::NotFound => true,
             _ => false,
         });
 
-        assert_eq!(master.volumes.read().unwrap().len(), 3);
+        let record = master.get_record("big").unwrap().unwrap();
+        assert_eq!(record.size, 11);
+        assert_eq!(record.parts.len(), 2);
+        assert_eq!(record.parts[0].blob, part_blob(&id, 1));
+        assert_eq!(master.bucket_usage("").objects, 1);
+        assert_eq!(master.volumes.read().unwrap().values().sum::<u32>(), 2);
+
+        assert!(match master.get_object(&bucket, "big", None) {
+            ResponseKind::Stream(_, size) => size == 11,
+            _ => false,
+        });
+
+        assert!(match master.delete_object(&bucket, "big", None) {
+            ResponseKind::Deleted => true,
+            _ => false,
+        });
+
+        assert_eq!(master.bucket_usage("").objects, 0);
+        assert_eq!(master.volumes.read().unwrap().values().sum::<u32>(), 0);
+
+        // aborted uploads can not be completed
+        let id = match master.initiate_upload(&bucket, "big") {
+            ResponseKind::Ok(id) => id,
+            _ => panic!("initiate failed"),
+        };
+
+        assert!(
+            match master.complete_upload(&bucket, "big", &id, None, None) {
+                ResponseKind::BadRequest(_) => true,
+                _ => false,
+            }
+        );
+
+        master.upload_part(&bucket, "big", &id, 1, "hello".as_bytes(), None);
+        assert!(match master.abort_upload(&bucket, "big", &id) {
+            ResponseKind::Deleted => true,
+            _ => false,
+        });
+
+        assert!(
+            match master.complete_upload(&bucket, "big", &id, None, None) {
+                ResponseKind::NotFound => true,
+                _ => false,
+            }
+        );
     }
 
     #[test]
-    fn test_master_counter() {
-        let data_dir = tempdir().unwrap();
+    fn test_master_blob_holders() {
+        let index = Arc::new(MemoryIndex::new());
 
-        let db = match DB::open_default(data_dir) {
-            Ok(db) => {
-                db.put(b"key1", b"server1").unwrap();
-                db.put(b"key2", b"server1").unwrap();
-                db.put(b"key3", b"server2").unwrap();
-                db.put(b"key4", b"server3").unwrap();
-                db
-            }
-            Err(e) => panic!("failed to open database: {:?}", e),
+        let master = Master::new(
+            index,
+            vec!["server1".to_owned(), "server2".to_owned()],
+            ClusterConfig::default(),
+            TlsConfig::default(),
+        );
+        master.create_bucket("name=logs&replicas=2&versioning=true".to_owned());
+        let bucket = master.buckets.read().unwrap()["logs"].clone();
+
+        master.save("key".to_owned(), "val".as_bytes(), None);
+        master.save_object(&bucket, "a@b", "val".as_bytes(), None, None);
+
+        let holders = master.blob_holders("/key").unwrap().unwrap();
+        assert_eq!(holders.len(), 1);
+        assert_eq!(
+            blob_location(&holders[0], "/key"),
+            format!("{}/store/key", holders[0])
+        );
+
+        let version = master
+            .get_record("\u{0}obj/logs/a@b")
+            .unwrap()
+            .unwrap()
+            .version;
+        let blob = format!("logs/a@b@{}", version.unwrap());
+        assert_eq!(master.blob_holders(&blob).unwrap().unwrap().len(), 2);
+
+        // unversioned name of a versioned value is not stored on volumes
+        assert_eq!(master.blob_holders("logs/a@b").unwrap(), None);
+        assert_eq!(master.blob_holders("missing/key").unwrap(), None);
+        assert_eq!(master.blob_holders("/missing").unwrap(), None);
+
+        let id = match master.initiate_upload(&Bucket::default(), "big") {
+            ResponseKind::Ok(id) => id,
+            _ => panic!("initiate failed"),
         };
+        master.upload_part(&Bucket::default(), "big", &id, 1, "part".as_bytes(), None);
 
-        let master = Master::new(db, vec!["server1".to_owned(), "server4".to_owned()]);
-        assert_eq!(master.volumes.read().unwrap()["server1"], 2);
-        assert_eq!(master.volumes.read().unwrap()["server2"], 1);
-        assert_eq!(master.volumes.read().unwrap()["server3"], 1);
-        assert_eq!(master.volumes.read().unwrap()["server4"], 0);
+        let blob = part_blob(&id, 1);
+        assert_eq!(master.blob_holders(&blob).unwrap().unwrap().len(), 1);
+
+        master.complete_upload(&Bucket::default(), "big", &id, None, None);
+        assert_eq!(master.blob_holders(&blob).unwrap().unwrap().len(), 1);
+
+        // reports need a known volume
+        assert!(
+            match master.report_corrupt("server3\n/key".to_owned(), Credentials::default()) {
+                ResponseKind::BadRequest(_) => true,
+                _ => false,
+            }
+        );
+    }
+
+    #[test]
+    fn test_master_erasure() {
+        let index = Arc::new(MemoryIndex::new());
+
+        let master = Master::new(
+            index,
+            vec!["server1".to_owned(), "server2".to_owned()],
+            ClusterConfig::default(),
+            TlsConfig::default(),
+        );
+        master.create_bucket("name=logs".to_owned());
+        master.create_bucket("name=cold&erasure=2+1".to_owned());
+        let bucket = master.buckets.read().unwrap()["cold"].clone();
+
+        // shards need as many volumes
+        assert!(
+            match master.save_object(&bucket, "key", "val".as_bytes(), Some(3), None) {
+                ResponseKind::Unavailable => true,
+                _ => false,
+            }
+        );
+
+        assert!(match master.convert_erasure("logs".to_owned()) {
+            ResponseKind::BadRequest(_) => true,
+            _ => false,
+        });
+        assert!(match master.convert_erasure("missing".to_owned()) {
+            ResponseKind::NotFound => true,
+            _ => false,
+        });
+        assert!(match master.convert_erasure("cold".to_owned()) {
+            ResponseKind::Ok(_) => true,
+            _ => false,
+        });
+
+        let marker = format!("{}cold", CONVERT_META_PREFIX);
+        assert!(master.index.get(marker.as_bytes()).unwrap().is_some());
+
+        // nothing to convert in an empty bucket
+        master.convert_scheduled();
+        assert!(master.index.get(marker.as_bytes()).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_master_compression() {
+        let mut bucket = Bucket::parse("name=logs&compression=zstd").unwrap();
+
+        assert_eq!(
+            with_compression("v1/bucket/logs/key".to_owned(), &bucket),
+            "v1/bucket/logs/key?compression=zstd"
+        );
+        assert_eq!(
+            with_compression("v1/bucket/logs/key?replicas=v2".to_owned(), &bucket),
+            "v1/bucket/logs/key?replicas=v2&compression=zstd"
+        );
+
+        bucket.compression = Some(None);
+        assert_eq!(
+            with_compression("v1/bucket/logs/key".to_owned(), &bucket),
+            "v1/bucket/logs/key?compression=none"
+        );
+
+        // volumes decide for buckets without the setting
+        bucket.compression = None;
+        assert_eq!(
+            with_compression("v1/bucket/logs/key".to_owned(), &bucket),
+            "v1/bucket/logs/key"
+        );
     }
 }
@@ -0,0 +1,145 @@
+//! # multipart uploads
+//!
+//! Large values are uploaded in numbered parts, each stored on volume servers
+//! picked for it, so that a value can be larger than a single disk and a failed
+//! upload only repeats the failed part.
+//!
+//! start an upload, keeping the returned upload id
+//!
+//! ```sh
+//! curl -XPOST http://localhost:6000/store/key?uploads
+//! ```
+//!
+//! upload parts numbered from 1, in any order. Re-uploading a part replaces it
+//!
+//! ```sh
+//! curl -XPUT -L --data-binary @part1 "http://localhost:6000/store/key?upload=<id>&part=1"
+//! ```
+//!
+//! `GET` with `?upload=<id>` lists uploaded parts. Complete the upload with a
+//! `POST` or abort it with a `DELETE` to `?upload=<id>`. Completed values are
+//! served by master, streaming parts in order. Keys of buckets work the same
+//! at `/bucket/<name>/<key>`.
+
+use rand::{thread_rng, Rng};
+
+use std::collections::VecDeque;
+use std::io::{self, Read};
+
+use crate::http;
+use crate::record::new_version;
+use crate::tls::TlsConfig;
+use crate::BUCKET_PREFIX;
+
+/// blobs of parts are stored on volumes as if in this bucket, which is not a
+/// valid bucket name
+const PARTS_BUCKET: &str = "~parts";
+
+/// highest part number
+pub(crate) const MAX_PARTS: u32 = 10_000;
+
+/// creates a new upload id
+pub(crate) fn upload_id() -> String {
+    format!("{:x}{:08x}", new_version(), thread_rng().gen::<u32>())
+}
+
+/// checks that an upload id has the form created by `upload_id`
+pub(crate) fn valid_id(id: &str) -> bool {
+    !id.is_empty() && id.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// name of the blob of a part on volume servers
+pub(crate) fn part_blob(id: &str, number: u32) -> String {
+    format!("{}/{}/{}", PARTS_BUCKET, id, number)
+}
+
+/// upload id and part number of a part blob name
+pub(crate) fn parse_part_blob(blob: &str) -> Option<(&str, u32)> {
+    let mut fields = blob.splitn(3, '/');
+    if fields.next()? != PARTS_BUCKET {
+        return None;
+    }
+
+    let id = fields.next()?;
+    let number = fields.next()?.parse::<u32>().ok()?;
+
+    if valid_id(id) {
+        Some((id, number))
+    } else {
+        None
+    }
+}
+
+/// url of a part blob on a volume server
+pub(crate) fn part_location(volume: &str, blob: &str) -> String {
+    format!("{}{}{}", volume, BUCKET_PREFIX, blob)
+}
+
+/// Reads parts of a value one after another from volume servers
+pub(crate) struct PartsReader {
+    urls: VecDeque<String>,
+    current: Option<Box<dyn Read + Send>>,
+    tls: TlsConfig,
+}
+
+impl PartsReader {
+    pub fn new(urls: Vec<String>, tls: TlsConfig) -> Self {
+        PartsReader {
+            urls: urls.into(),
+            current: None,
+            tls,
+        }
+    }
+}
+
+impl Read for PartsReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        loop {
+            if let Some(ref mut current) = self.current {
+                match current.read(buf)? {
+                    0 => {}
+                    len => return Ok(len),
+                }
+            }
+
+            // current part is done, continue with next
+            let url = match self.urls.pop_front() {
+                Some(url) => url,
+                None => return Ok(0),
+            };
+
+            let (resp, body) = http::open("GET", &url, &[], &self.tls)?;
+            if resp.status_code != 200 {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("failed to fetch part {}", url),
+                ));
+            }
+
+            self.current = Some(body);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_upload_id() {
+        let id = upload_id();
+        assert!(valid_id(&id));
+        assert_ne!(id, upload_id());
+
+        assert!(!valid_id(""));
+        assert!(!valid_id("../id"));
+        assert_eq!(part_blob("1f", 2), "~parts/1f/2");
+        assert_eq!(parse_part_blob("~parts/1f/2"), Some(("1f", 2)));
+        assert_eq!(parse_part_blob("logs/1f/2"), None);
+        assert_eq!(parse_part_blob("~parts/1f"), None);
+    }
+}
@@ -0,0 +1,844 @@
+//! # packed storage engine
+//!
+//! Volume servers keep each value in a file of its own by default, which
+//! wastes inodes and gets slow with millions of small values. Volumes started
+//! with `--engine packed` append values instead to segment files under
+//! `<data_dir>/segments`, each value as a needle of a header, its key, its
+//! digests and the stored bytes. Deletes append a tombstone needle.
+//!
+//! ```sh
+//! volume -p 7000 -d /tmp/kalavarastore --engine packed --segment-size 1073741824
+//! ```
+//!
+//! Offsets of values are kept in memory. Once a segment reaches
+//! `--segment-size` bytes it is sealed and its needles are listed in an index
+//! file next to it, so that restarts read the index files and only scan the
+//! segment being written. Sealed segments with more than half of their bytes
+//! taken by overwritten or deleted values are rewritten without them every
+//! `--compact-interval` seconds, or on `POST /admin/compact`.
+
+use tempfile::NamedTempFile;
+
+use std::collections::HashMap;
+use std::fs::{create_dir_all, read_dir, remove_file, rename, File, OpenOptions};
+use std::io::{self, copy, BufReader, BufWriter, Error, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, RwLock};
+use std::time::Duration;
+
+use crate::server::Shutdown;
+use crate::store::{Blob, BlobStore, Staged};
+
+/// directory under data dir holding segment files
+pub const SEGMENTS_DIR: &str = "segments";
+
+/// first bytes of every needle
+const MAGIC: [u8; 4] = *b"KVN1";
+
+/// magic, kind, key, digests and value lengths
+const HEADER_LEN: u64 = 4 + 1 + 2 + 4 + 8;
+
+/// needle kinds
+const VALUE: u8 = 0;
+const TOMBSTONE: u8 = 1;
+
+/// Packed engine settings
+#[derive(Clone, Debug)]
+pub struct PackedConfig {
+    /// size in bytes after which a segment is sealed
+    pub segment_size: u64,
+
+    /// seconds between compactions
+    pub compact_interval: u64,
+}
+
+impl Default for PackedConfig {
+    fn default() -> Self {
+        PackedConfig {
+            segment_size: 1 << 30,
+            compact_interval: 600,
+        }
+    }
+}
+
+/// position of a needle in a segment
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Location {
+    segment: u32,
+    offset: u64,
+    key_len: u16,
+    sum_len: u32,
+    value_len: u64,
+}
+
+impl Location {
+    /// offset of the digests
+    fn sum_offset(&self) -> u64 {
+        self.offset + HEADER_LEN + u64::from(self.key_len)
+    }
+
+    /// offset of the value
+    fn value_offset(&self) -> u64 {
+        self.sum_offset() + u64::from(self.sum_len)
+    }
+
+    /// size of the whole needle
+    fn size(&self) -> u64 {
+        self.value_offset() + self.value_len - self.offset
+    }
+}
+
+/// needle as listed in index files
+#[derive(Clone, Debug, PartialEq)]
+struct Needle {
+    kind: u8,
+    key: String,
+    location: Location,
+}
+
+impl Needle {
+    /// writes the index entry of the needle
+    fn write_entry(&self, out: &mut impl Write) -> io::Result<()> {
+        let location = &self.location;
+
+        out.write_all(&[self.kind])?;
+        out.write_all(&location.key_len.to_be_bytes())?;
+        out.write_all(&location.sum_len.to_be_bytes())?;
+        out.write_all(&location.value_len.to_be_bytes())?;
+        out.write_all(&location.offset.to_be_bytes())?;
+        out.write_all(self.key.as_bytes())
+    }
+
+    /// reads an index entry, `None` at the end of the index
+    fn read_entry(segment: u32, input: &mut impl Read) -> io::Result<Option<Needle>> {
+        let mut kind = [0; 1];
+        if input.read(&mut kind)? == 0 {
+            return Ok(None);
+        }
+
+        let mut head = [0; 22];
+        input.read_exact(&mut head)?;
+        let key_len = u16::from_be_bytes([head[0], head[1]]);
+        let location = Location {
+            segment,
+            offset: u64::from_be_bytes(be_bytes(&head[14..22])),
+            key_len,
+            sum_len: u32::from_be_bytes([head[2], head[3], head[4], head[5]]),
+            value_len: u64::from_be_bytes(be_bytes(&head[6..14])),
+        };
+
+        Ok(Some(Needle {
+            kind: kind[0],
+            key: read_string(input, usize::from(key_len))?,
+            location,
+        }))
+    }
+}
+
+/// Part of a segment file holding a value
+struct Section {
+    file: File,
+    start: u64,
+    len: u64,
+    pos: u64,
+}
+
+impl Section {
+    fn new(mut file: File, start: u64, len: u64) -> io::Result<Section> {
+        file.seek(SeekFrom::Start(start))?;
+        Ok(Section {
+            file,
+            start,
+            len,
+            pos: 0,
+        })
+    }
+}
+
+impl Read for Section {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let left = (self.len - self.pos.min(self.len)) as usize;
+        let max = buf.len().min(left);
+
+        let len = self.file.read(&mut buf[..max])?;
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl Seek for Section {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(pos) => pos as i64,
+            SeekFrom::End(pos) => self.len as i64 + pos,
+            SeekFrom::Current(pos) => self.pos as i64 + pos,
+        };
+        if pos < 0 {
+            return Err(Error::new(ErrorKind::InvalidInput, "seek before start"));
+        }
+
+        self.pos = pos as u64;
+        self.file.seek(SeekFrom::Start(self.start + self.pos))?;
+        Ok(self.pos)
+    }
+}
+
+/// segment being appended to
+struct Active {
+    id: u32,
+    file: File,
+    len: u64,
+
+    /// needles written, listed in the index file once sealed
+    needles: Vec<Needle>,
+}
+
+/// Values packed into segment files
+pub(crate) struct Segments {
+    dir: PathBuf,
+    config: PackedConfig,
+
+    /// directory values are written to before they are appended
+    tmpdir: PathBuf,
+
+    /// locations of live values
+    index: RwLock<HashMap<String, Location>>,
+
+    active: Mutex<Active>,
+
+    /// held while a segment is compacted
+    compact_lock: Mutex<()>,
+}
+
+impl Segments {
+    /// opens the segments of a data dir, indexing their needles
+    pub fn open(data_dir: &Path, config: PackedConfig) -> io::Result<Segments> {
+        let dir = data_dir.join(SEGMENTS_DIR);
+        let tmpdir = data_dir.join("tmp");
+        create_dir_all(&dir)?;
+        create_dir_all(&tmpdir)?;
+
+        let ids = segment_ids(&dir)?;
+        let mut index = HashMap::new();
+
+        // the last segment is scanned, as it may have been written to since
+        // it was indexed. so are segments whose index does not cover them,
+        // left by a crash during compaction
+        let mut needles = Vec::new();
+        for (indx, &id) in ids.iter().enumerate() {
+            let last = indx + 1 == ids.len();
+            let len = segment_path(&dir, id).metadata()?.len();
+
+            let indexed = read_index(&dir, id)
+                .unwrap_or(None)
+                .filter(|needles| !last && indexed_len(needles) == len);
+            needles = match indexed {
+                Some(needles) => needles,
+                None => scan(&segment_path(&dir, id), id)?,
+            };
+
+            for needle in needles.iter() {
+                apply(&mut index, needle);
+            }
+        }
+
+        let id = ids.last().cloned().unwrap_or(1);
+        let file = open_segment(&dir, id)?;
+        let len = file.metadata()?.len();
+
+        Ok(Segments {
+            dir,
+            config,
+            tmpdir,
+            index: RwLock::new(index),
+            active: Mutex::new(Active {
+                id,
+                file,
+                len,
+                needles,
+            }),
+            compact_lock: Mutex::new(()),
+        })
+    }
+
+    /// appends a value of `len` bytes with its metadata
+    fn insert(&self, key: &str, meta: &str, value: &mut impl Read, len: u64) -> io::Result<()> {
+        self.append(VALUE, key, meta, value, len)
+    }
+
+    /// appends a tombstone, returns false if there was no value
+    fn remove(&self, key: &str) -> io::Result<bool> {
+        if !self.index.read().unwrap().contains_key(key) {
+            return Ok(false);
+        }
+
+        self.append(TOMBSTONE, key, "", &mut io::empty(), 0)?;
+        Ok(true)
+    }
+
+    /// metadata and value stored for a key
+    fn lookup(&self, key: &str) -> io::Result<Option<(String, Section)>> {
+        // segments are opened while the index is held, so that compaction
+        // does not move the value meanwhile
+        let (location, mut file) = {
+            let index = self.index.read().unwrap();
+            match index.get(key) {
+                Some(location) => (*location, File::open(self.segment_path(location.segment))?),
+                None => return Ok(None),
+            }
+        };
+
+        file.seek(SeekFrom::Start(location.sum_offset()))?;
+        let sum = read_string(&mut file, location.sum_len as usize)?;
+        let section = Section::new(file, location.value_offset(), location.value_len)?;
+
+        Ok(Some((sum, section)))
+    }
+
+    /// compacts sealed segments every interval, until `shutdown` is
+    /// signalled
+    pub fn run(&self, shutdown: &Shutdown) {
+        let interval = Duration::from_secs(self.config.compact_interval);
+
+        while shutdown.sleep(interval) {
+            match self.compact_segments(0.5) {
+                Ok(0) => {}
+                Ok(compacted) => println!("compacted {} segments", compacted),
+                Err(e) => println!("compaction failed: {}", e),
+            }
+        }
+    }
+
+    /// rewrites sealed segments with more than `ratio` of their bytes unused,
+    /// returns the number of segments rewritten
+    pub fn compact_segments(&self, ratio: f64) -> io::Result<usize> {
+        let _compacting = self.compact_lock.lock().unwrap();
+        let active = self.active.lock().unwrap().id;
+
+        let mut live: HashMap<u32, u64> = HashMap::new();
+        for location in self.index.read().unwrap().values() {
+            *live.entry(location.segment).or_insert(0) += location.size();
+        }
+
+        let mut compacted = 0;
+        let mut oldest = true;
+
+        for id in segment_ids(&self.dir)? {
+            if id == active {
+                break;
+            }
+
+            let size = self.segment_path(id).metadata()?.len();
+            let used = live.get(&id).cloned().unwrap_or(0);
+
+            let rewritten = if (size - used) as f64 > size as f64 * ratio {
+                self.compact_segment(id, oldest)?
+            } else {
+                None
+            };
+
+            if rewritten.is_some() {
+                compacted += 1;
+            }
+            if rewritten != Some(0) {
+                oldest = false;
+            }
+        }
+
+        Ok(compacted)
+    }
+
+    /// rewrites a sealed segment with its live values only, and removes it
+    /// if none are left. tombstones are kept while an older segment may hold
+    /// a value they delete. returns the new size, `None` if nothing changed
+    fn compact_segment(&self, id: u32, oldest: bool) -> io::Result<Option<u64>> {
+        let path = self.segment_path(id);
+        let tmp = path.with_extension("compact");
+
+        let needles = match read_index(&self.dir, id)? {
+            Some(needles) => needles,
+            None => scan(&path, id)?,
+        };
+        let total = needles.len();
+
+        let needles: Vec<Needle> = {
+            let index = self.index.read().unwrap();
+            needles
+                .into_iter()
+                .filter(|needle| match needle.kind {
+                    VALUE => index.get(&needle.key) == Some(&needle.location),
+                    _ => !oldest && !index.contains_key(&needle.key),
+                })
+                .collect()
+        };
+        if needles.len() == total {
+            return Ok(None);
+        }
+
+        let mut input = File::open(&path)?;
+        let mut output = BufWriter::new(File::create(&tmp)?);
+        let mut kept = Vec::new();
+        let mut offset = 0;
+
+        for needle in needles {
+            let size = needle.location.size();
+            input.seek(SeekFrom::Start(needle.location.offset))?;
+            if copy(&mut (&mut input).take(size), &mut output)? != size {
+                return Err(Error::new(ErrorKind::UnexpectedEof, "truncated segment"));
+            }
+
+            let moved = Location {
+                offset,
+                ..needle.location
+            };
+            kept.push((needle, moved));
+            offset += size;
+        }
+        output.flush()?;
+
+        let moved: Vec<Needle> = kept
+            .iter()
+            .map(|(needle, location)| Needle {
+                location: *location,
+                ..needle.clone()
+            })
+            .collect();
+
+        // values overwritten meanwhile keep their new location
+        let mut index = self.index.write().unwrap();
+        if moved.is_empty() {
+            remove_file(&tmp)?;
+            remove_file(&path)?;
+            let _ = remove_file(index_path(&self.dir, id));
+        } else {
+            rename(&tmp, &path)?;
+            write_index(&self.dir, id, &moved)?;
+        }
+
+        for (needle, location) in kept {
+            if index.get(&needle.key) == Some(&needle.location) {
+                index.insert(needle.key, location);
+            }
+        }
+
+        Ok(Some(offset))
+    }
+
+    /// appends a needle to the active segment, sealing it first if full
+    fn append(
+        &self,
+        kind: u8,
+        key: &str,
+        sum: &str,
+        value: &mut impl Read,
+        len: u64,
+    ) -> io::Result<()> {
+        if key.len() > usize::from(u16::MAX) {
+            return Err(Error::new(ErrorKind::InvalidInput, "key too long"));
+        }
+
+        let mut active = self.active.lock().unwrap();
+        let size = HEADER_LEN + key.len() as u64 + sum.len() as u64 + len;
+        if active.len > 0 && active.len + size > self.config.segment_size {
+            self.seal(&mut active)?;
+        }
+
+        let location = Location {
+            segment: active.id,
+            offset: active.len,
+            key_len: key.len() as u16,
+            sum_len: sum.len() as u32,
+            value_len: len,
+        };
+
+        let written = write_needle(&mut active.file, kind, key, sum, value, len);
+        if let Err(e) = written {
+            // partial needles are cut off
+            active.file.set_len(location.offset)?;
+            return Err(e);
+        }
+        active.len += location.size();
+
+        let needle = Needle {
+            kind,
+            key: key.to_owned(),
+            location,
+        };
+        apply(&mut self.index.write().unwrap(), &needle);
+        active.needles.push(needle);
+
+        Ok(())
+    }
+
+    /// writes the index of the active segment and starts a new one
+    fn seal(&self, active: &mut Active) -> io::Result<()> {
+        write_index(&self.dir, active.id, &active.needles)?;
+
+        let id = active.id + 1;
+        *active = Active {
+            id,
+            file: open_segment(&self.dir, id)?,
+            len: 0,
+            needles: Vec::new(),
+        };
+
+        Ok(())
+    }
+
+    fn segment_path(&self, id: u32) -> PathBuf {
+        segment_path(&self.dir, id)
+    }
+}
+
+impl BlobStore for Segments {
+    fn create(&self) -> io::Result<Box<dyn Staged + '_>> {
+        Ok(Box::new(StagedNeedle {
+            segments: self,
+            file: NamedTempFile::new_in(&self.tmpdir)?,
+        }))
+    }
+
+    fn get(&self, key: &str) -> io::Result<Option<Blob>> {
+        Ok(self.lookup(key)?.map(|(meta, section)| Blob {
+            meta,
+            len: section.len,
+            value: Box::new(section),
+        }))
+    }
+
+    fn delete(&self, key: &str) -> io::Result<bool> {
+        self.remove(key)
+    }
+
+    fn list(&self) -> io::Result<Vec<String>> {
+        Ok(self.index.read().unwrap().keys().cloned().collect())
+    }
+
+    /// the value is appended again with the new metadata
+    fn set_meta(&self, key: &str, meta: &str) -> io::Result<bool> {
+        match self.lookup(key)? {
+            Some((_, mut section)) => {
+                let len = section.len;
+                self.insert(key, meta, &mut section, len)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn compact(&self) -> io::Result<usize> {
+        self.compact_segments(0.0)
+    }
+}
+
+/// value written to a temporary file, as the length of a needle is written
+/// before its value
+struct StagedNeedle<'a> {
+    segments: &'a Segments,
+    file: NamedTempFile,
+}
+
+impl<'a> Write for StagedNeedle<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl<'a> Staged for StagedNeedle<'a> {
+    fn commit(mut self: Box<Self>, key: &str, meta: &str) -> io::Result<()> {
+        let len = self.file.seek(SeekFrom::End(0))?;
+        self.file.seek(SeekFrom::Start(0))?;
+        self.segments.insert(key, meta, &mut self.file, len)
+    }
+}
+
+/// updates the index with a needle read or written
+fn apply(index: &mut HashMap<String, Location>, needle: &Needle) {
+    match needle.kind {
+        VALUE => {
+            index.insert(needle.key.clone(), needle.location);
+        }
+        _ => {
+            index.remove(&needle.key);
+        }
+    }
+}
+
+fn write_needle(
+    file: &mut File,
+    kind: u8,
+    key: &str,
+    sum: &str,
+    value: &mut impl Read,
+    len: u64,
+) -> io::Result<()> {
+    let mut out = BufWriter::new(file);
+
+    out.write_all(&MAGIC)?;
+    out.write_all(&[kind])?;
+    out.write_all(&(key.len() as u16).to_be_bytes())?;
+    out.write_all(&(sum.len() as u32).to_be_bytes())?;
+    out.write_all(&len.to_be_bytes())?;
+    out.write_all(key.as_bytes())?;
+    out.write_all(sum.as_bytes())?;
+
+    if copy(&mut value.take(len), &mut out)? != len {
+        return Err(Error::new(
+            ErrorKind::UnexpectedEof,
+            "value shorter than announced",
+        ));
+    }
+
+    out.flush()
+}
+
+/// lists the needles of a segment by reading their headers. a needle cut
+/// off by a crash is removed
+fn scan(path: &Path, segment: u32) -> io::Result<Vec<Needle>> {
+    let file = OpenOptions::new().read(true).write(true).open(path)?;
+    let len = file.metadata()?.len();
+    let mut input = BufReader::new(&file);
+    let mut needles = Vec::new();
+    let mut offset = 0;
+
+    while offset < len {
+        let needle = match read_header(&mut input, segment, offset) {
+            Ok(Some(needle)) if offset + needle.location.size() <= len => needle,
+            Ok(_) | Err(_) => {
+                println!("truncating segment {:?} at {}", path, offset);
+                file.set_len(offset)?;
+                break;
+            }
+        };
+
+        offset += needle.location.size();
+        input.seek(SeekFrom::Start(offset))?;
+        needles.push(needle);
+    }
+
+    Ok(needles)
+}
+
+/// reads a needle header and key, `None` if it is not a needle
+fn read_header(input: &mut impl Read, segment: u32, offset: u64) -> io::Result<Option<Needle>> {
+    let mut head = [0; HEADER_LEN as usize];
+    input.read_exact(&mut head)?;
+    if head[0..4] != MAGIC || head[4] > TOMBSTONE {
+        return Ok(None);
+    }
+
+    let key_len = u16::from_be_bytes([head[5], head[6]]);
+    let location = Location {
+        segment,
+        offset,
+        key_len,
+        sum_len: u32::from_be_bytes([head[7], head[8], head[9], head[10]]),
+        value_len: u64::from_be_bytes(be_bytes(&head[11..19])),
+    };
+
+    Ok(Some(Needle {
+        kind: head[4],
+        key: read_string(input, usize::from(key_len))?,
+        location,
+    }))
+}
+
+/// length of the segment holding `needles`
+fn indexed_len(needles: &[Needle]) -> u64 {
+    needles.iter().map(|needle| needle.location.size()).sum()
+}
+
+/// needles listed in the index file of a segment, `None` if it has none
+fn read_index(dir: &Path, segment: u32) -> io::Result<Option<Vec<Needle>>> {
+    let file = match File::open(index_path(dir, segment)) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+
+    let mut input = BufReader::new(file);
+    let mut needles = Vec::new();
+    while let Some(needle) = Needle::read_entry(segment, &mut input)? {
+        needles.push(needle);
+    }
+
+    Ok(Some(needles))
+}
+
+/// writes the index file of a segment
+fn write_index(dir: &Path, segment: u32, needles: &[Needle]) -> io::Result<()> {
+    let path = index_path(dir, segment);
+    let tmp = path.with_extension("tmp");
+
+    let mut out = BufWriter::new(File::create(&tmp)?);
+    for needle in needles {
+        needle.write_entry(&mut out)?;
+    }
+    out.flush()?;
+
+    rename(tmp, path)
+}
+
+/// ids of the segments in a directory, in order of creation
+fn segment_ids(dir: &Path) -> io::Result<Vec<u32>> {
+    let mut ids = Vec::new();
+
+    for entry in read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().map_or(false, |ext| ext == "seg") {
+            if let Some(id) = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.parse().ok())
+            {
+                ids.push(id);
+            }
+        }
+    }
+
+    ids.sort();
+    Ok(ids)
+}
+
+fn open_segment(dir: &Path, id: u32) -> io::Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .read(true)
+        .append(true)
+        .open(segment_path(dir, id))
+}
+
+fn segment_path(dir: &Path, id: u32) -> PathBuf {
+    dir.join(format!("{:08}.seg", id))
+}
+
+fn index_path(dir: &Path, id: u32) -> PathBuf {
+    dir.join(format!("{:08}.idx", id))
+}
+
+fn read_string(input: &mut impl Read, len: usize) -> io::Result<String> {
+    let mut buf = vec![0; len];
+    input.read_exact(&mut buf)?;
+    String::from_utf8(buf).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+}
+
+fn be_bytes(slice: &[u8]) -> [u8; 8] {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(slice);
+    bytes
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn config(segment_size: u64) -> PackedConfig {
+        PackedConfig {
+            segment_size,
+            ..PackedConfig::default()
+        }
+    }
+
+    fn put(segments: &Segments, key: &str, value: &str) {
+        let meta = format!("key={}\n", key);
+        segments
+            .insert(key, &meta, &mut value.as_bytes(), value.len() as u64)
+            .unwrap();
+    }
+
+    fn get(segments: &Segments, key: &str) -> Option<String> {
+        segments.lookup(key).unwrap().map(|(meta, mut section)| {
+            assert_eq!(meta, format!("key={}\n", key));
+
+            let mut value = String::new();
+            section.read_to_string(&mut value).unwrap();
+            assert_eq!(value.len() as u64, section.len);
+            value
+        })
+    }
+
+    #[test]
+    fn test_packed_values() {
+        let data_dir = tempdir().unwrap();
+        let segments = Segments::open(data_dir.path(), config(1 << 20)).unwrap();
+
+        put(&segments, "first", "one");
+        put(&segments, "second", "two");
+        put(&segments, "first", "uno");
+        assert_eq!(get(&segments, "first"), Some("uno".to_owned()));
+        assert_eq!(get(&segments, "second"), Some("two".to_owned()));
+
+        assert!(segments.remove("second").unwrap());
+        assert!(!segments.remove("second").unwrap());
+        assert_eq!(get(&segments, "second"), None);
+
+        // short values are not stored
+        let res = segments.insert("third", "", &mut "ab".as_bytes(), 3);
+        assert!(res.is_err());
+        put(&segments, "fourth", "four");
+
+        let (_, mut section) = segments.lookup("fourth").unwrap().unwrap();
+        section.seek(SeekFrom::Start(2)).unwrap();
+        let mut tail = String::new();
+        section.read_to_string(&mut tail).unwrap();
+        assert_eq!(tail, "ur");
+
+        // reopened from the segment, a cut off needle is dropped
+        drop(segments);
+        let path = segment_path(&data_dir.path().join(SEGMENTS_DIR), 1);
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&MAGIC).unwrap();
+
+        let segments = Segments::open(data_dir.path(), config(1 << 20)).unwrap();
+        assert_eq!(get(&segments, "first"), Some("uno".to_owned()));
+        assert_eq!(get(&segments, "second"), None);
+        assert_eq!(get(&segments, "third"), None);
+        assert_eq!(get(&segments, "fourth"), Some("four".to_owned()));
+
+        put(&segments, "fifth", "five");
+        assert_eq!(get(&segments, "fifth"), Some("five".to_owned()));
+    }
+
+    #[test]
+    fn test_packed_compaction() {
+        let data_dir = tempdir().unwrap();
+        let dir = data_dir.path().join(SEGMENTS_DIR);
+
+        // every needle gets a segment of its own
+        let segments = Segments::open(data_dir.path(), config(1)).unwrap();
+        put(&segments, "first", "one");
+        put(&segments, "second", "two");
+        put(&segments, "first", "uno");
+        segments.remove("second").unwrap();
+        put(&segments, "third", "three");
+        assert_eq!(segment_ids(&dir).unwrap(), vec![1, 2, 3, 4, 5]);
+
+        // both values of the first two segments are gone, the tombstone in
+        // the fourth is kept as the second one is older
+        assert_eq!(segments.compact_segments(0.5).unwrap(), 2);
+        assert_eq!(segment_ids(&dir).unwrap(), vec![3, 4, 5]);
+        assert_eq!(segments.compact_segments(0.5).unwrap(), 0);
+
+        assert_eq!(get(&segments, "first"), Some("uno".to_owned()));
+        assert_eq!(get(&segments, "second"), None);
+        assert_eq!(get(&segments, "third"), Some("three".to_owned()));
+
+        // sealed segments are read from their index files
+        drop(segments);
+        let segments = Segments::open(data_dir.path(), config(1)).unwrap();
+        assert_eq!(get(&segments, "first"), Some("uno".to_owned()));
+        assert_eq!(get(&segments, "second"), None);
+        assert_eq!(get(&segments, "third"), Some("three".to_owned()));
+
+        put(&segments, "first", "eins");
+        assert_eq!(segments.compact_segments(0.5).unwrap(), 2);
+        assert_eq!(segment_ids(&dir).unwrap(), vec![5, 6]);
+        assert_eq!(get(&segments, "first"), Some("eins".to_owned()));
+        assert_eq!(segments.list().unwrap().len(), 2);
+    }
+}
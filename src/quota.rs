@@ -0,0 +1,288 @@
+//! # quotas
+//!
+//! Master tracks total size and number of values per bucket and per key
+//! prefix with a quota. Uploads that would exceed a limit are rejected with
+//! 507.
+//!
+//! bucket wide limits are set with `quota` and `quota_objects` bucket settings,
+//! limits on a key prefix with
+//!
+//! ```sh
+//! curl -XPOST -d "bucket=logs&prefix=team-a/&bytes=1073741824&objects=10000" \
+//!     http://localhost:6000/admin/set-quota
+//! ```
+//!
+//! omit `bucket` for prefixes of the default bucket. `/admin/remove-quota`
+//! takes `bucket` and `prefix` and `GET /admin/usage` reports usage against
+//! limits.
+
+use std::collections::HashMap;
+
+use crate::bucket::Bucket;
+use crate::parse_params;
+
+/// Resources used by values
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Usage {
+    /// total size in bytes
+    pub bytes: u64,
+
+    /// number of values
+    pub objects: u64,
+}
+
+/// Limits on values under a key prefix
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Quota {
+    /// bucket name, empty for default bucket
+    pub bucket: String,
+
+    /// key prefix the quota applies to
+    pub prefix: String,
+
+    /// maximum total size in bytes
+    pub bytes: Option<u64>,
+
+    /// maximum number of values
+    pub objects: Option<u64>,
+}
+
+impl Quota {
+    /// Parses quota from `name=value&..` pairs
+    pub fn parse(settings: &str) -> Result<Quota, String> {
+        let mut quota = Quota::default();
+
+        for (field, value) in parse_params(settings.trim()) {
+            let number = || {
+                value
+                    .parse::<u64>()
+                    .map_err(|_| format!("invalid {} {}", field, value))
+            };
+
+            match field.as_str() {
+                "bucket" => quota.bucket = value.clone(),
+                "prefix" => quota.prefix = value.clone(),
+                "bytes" => quota.bytes = Some(number()?),
+                "objects" => quota.objects = Some(number()?),
+                _ => return Err(format!("unknown setting {}", field)),
+            }
+        }
+
+        Ok(quota)
+    }
+
+    /// Encodes quota in the format accepted by `parse`
+    pub fn encode(&self) -> String {
+        let mut settings = format!("bucket={}&prefix={}", self.bucket, self.prefix);
+
+        if let Some(bytes) = self.bytes {
+            settings.push_str(&format!("&bytes={}", bytes));
+        }
+
+        if let Some(objects) = self.objects {
+            settings.push_str(&format!("&objects={}", objects));
+        }
+
+        settings
+    }
+
+    /// checks whether `key` of `bucket` falls under this quota
+    fn matches(&self, bucket: &str, key: &str) -> bool {
+        self.bucket == bucket && key.starts_with(&self.prefix)
+    }
+}
+
+/// checks that replacing a value of `replaced` bytes (if any) with one of
+/// `size` bytes keeps usage within limits
+fn fits(
+    usage: &Usage,
+    bytes: Option<u64>,
+    objects: Option<u64>,
+    replaced: Option<u64>,
+    size: u64,
+) -> bool {
+    let (freed_bytes, freed_objects) = replaced.map_or((0, 0), |replaced| (replaced, 1));
+
+    bytes.map_or(true, |limit| {
+        usage.bytes.saturating_sub(freed_bytes) + size <= limit
+    }) && objects.map_or(true, |limit| {
+        usage.objects.saturating_sub(freed_objects) < limit
+    })
+}
+
+/// formats a limit for usage reports
+fn limit(limit: Option<u64>) -> String {
+    limit.map_or_else(|| "-".to_owned(), |limit| limit.to_string())
+}
+
+/// Usage of buckets and prefixes with quotas
+#[derive(Debug, Default)]
+pub(crate) struct Tracker {
+    buckets: HashMap<String, Usage>,
+    quotas: Vec<(Quota, Usage)>,
+}
+
+impl Tracker {
+    /// usage of a bucket
+    pub fn bucket(&self, name: &str) -> Usage {
+        self.buckets.get(name).cloned().unwrap_or_default()
+    }
+
+    /// usage of all buckets
+    pub fn total(&self) -> Usage {
+        self.buckets
+            .values()
+            .fold(Usage::default(), |total, usage| Usage {
+                bytes: total.bytes + usage.bytes,
+                objects: total.objects + usage.objects,
+            })
+    }
+
+    /// accounts a new value
+    pub fn add(&mut self, bucket: &str, key: &str, size: u64) {
+        self.update(bucket, key, |usage| {
+            usage.bytes += size;
+            usage.objects += 1;
+        });
+    }
+
+    /// accounts a removed value
+    pub fn remove(&mut self, bucket: &str, key: &str, size: u64) {
+        self.update(bucket, key, |usage| {
+            usage.bytes = usage.bytes.saturating_sub(size);
+            usage.objects = usage.objects.saturating_sub(1);
+        });
+    }
+
+    fn update<F: Fn(&mut Usage)>(&mut self, bucket: &str, key: &str, apply: F) {
+        apply(self.buckets.entry(bucket.to_owned()).or_default());
+
+        for (quota, usage) in self.quotas.iter_mut() {
+            if quota.matches(bucket, key) {
+                apply(usage);
+            }
+        }
+    }
+
+    /// checks bucket and prefix quotas for an upload of `size` bytes,
+    /// `replaced` is the size of the value it overwrites
+    pub fn allows(&self, bucket: &Bucket, key: &str, replaced: Option<u64>, size: u64) -> bool {
+        fits(
+            &self.bucket(&bucket.name),
+            bucket.quota,
+            bucket.quota_objects,
+            replaced,
+            size,
+        ) && self
+            .quotas
+            .iter()
+            .filter(|(quota, _)| quota.matches(&bucket.name, key))
+            .all(|(quota, usage)| fits(usage, quota.bytes, quota.objects, replaced, size))
+    }
+
+    /// adds or replaces a prefix quota, `usage` is current usage of the prefix
+    pub fn set_quota(&mut self, quota: Quota, usage: Usage) {
+        self.remove_quota(&quota.bucket, &quota.prefix);
+        self.quotas.push((quota, usage));
+    }
+
+    /// removes a prefix quota, returns false if there was none
+    pub fn remove_quota(&mut self, bucket: &str, prefix: &str) -> bool {
+        let len = self.quotas.len();
+        self.quotas
+            .retain(|(quota, _)| quota.bucket != bucket || quota.prefix != prefix);
+        self.quotas.len() != len
+    }
+
+    /// forgets a deleted bucket
+    pub fn remove_bucket(&mut self, name: &str) {
+        self.buckets.remove(name);
+        self.quotas.retain(|(quota, _)| quota.bucket != name);
+    }
+
+    /// usage against limits, one `name=value&..` line per bucket and quota
+    pub fn report(&self, buckets: &HashMap<String, Bucket>) -> String {
+        let default = Bucket::default();
+        let mut names: Vec<&String> = buckets.keys().collect();
+        names.sort();
+
+        let mut lines = Vec::new();
+        for bucket in Some(&default)
+            .into_iter()
+            .chain(names.into_iter().map(|name| &buckets[name]))
+        {
+            let usage = self.bucket(&bucket.name);
+            lines.push(format!(
+                "bucket={}&prefix=&bytes={}&bytes_limit={}&objects={}&objects_limit={}",
+                bucket.name,
+                usage.bytes,
+                limit(bucket.quota),
+                usage.objects,
+                limit(bucket.quota_objects)
+            ));
+        }
+
+        for (quota, usage) in self.quotas.iter() {
+            lines.push(format!(
+                "bucket={}&prefix={}&bytes={}&bytes_limit={}&objects={}&objects_limit={}",
+                quota.bucket,
+                quota.prefix,
+                usage.bytes,
+                limit(quota.bytes),
+                usage.objects,
+                limit(quota.objects)
+            ));
+        }
+
+        lines.join("\n")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_quota_parse() {
+        let quota = Quota::parse("bucket=logs&prefix=team-a/&bytes=100&objects=2").unwrap();
+        assert_eq!(quota.bucket, "logs");
+        assert_eq!(quota.prefix, "team-a/");
+        assert_eq!(quota.bytes, Some(100));
+        assert_eq!(quota.objects, Some(2));
+        assert_eq!(Quota::parse(&quota.encode()), Ok(quota));
+
+        assert!(Quota::parse("prefix=a&bytes=many").is_err());
+        assert!(Quota::parse("prefix=a&size=1").is_err());
+    }
+
+    #[test]
+    fn test_tracker() {
+        let bucket = Bucket {
+            quota: Some(10),
+            ..Bucket::default()
+        };
+        let mut tracker = Tracker::default();
+        tracker.set_quota(
+            Quota::parse("prefix=team-a/&objects=1").unwrap(),
+            Usage::default(),
+        );
+
+        assert!(tracker.allows(&bucket, "team-a/key1", None, 4));
+        tracker.add("", "team-a/key1", 4);
+
+        // object limit of prefix
+        assert!(!tracker.allows(&bucket, "team-a/key2", None, 4));
+        assert!(tracker.allows(&bucket, "team-a/key1", Some(4), 6));
+        assert!(tracker.allows(&bucket, "team-b/key1", None, 6));
+
+        // size limit of bucket
+        assert!(!tracker.allows(&bucket, "team-b/key1", None, 7));
+
+        tracker.remove("", "team-a/key1", 4);
+        assert_eq!(tracker.bucket(""), Usage::default());
+        assert!(tracker.allows(&bucket, "team-a/key2", None, 4));
+
+        assert!(tracker.remove_quota("", "team-a/"));
+        assert!(!tracker.remove_quota("", "team-a/"));
+    }
+}
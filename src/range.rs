@@ -0,0 +1,292 @@
+//! Range requests on stored values.
+//!
+//! Values are served with `Accept-Ranges: bytes`. A `Range: bytes=` header
+//! with a single range is answered with 206 and the slice of the value, with
+//! several ranges with a `multipart/byteranges` body. Ranges outside the value
+//! get 416, headers that can not be parsed are ignored and the whole value is
+//! sent.
+//!
+//! Compressed values are decompressed while sent, a single range of them is
+//! served by skipping to it and several ranges get the whole value.
+
+use rand::{thread_rng, Rng};
+
+use std::collections::VecDeque;
+use std::io::{self, copy, Cursor, Read, Seek, SeekFrom};
+
+use crate::get_header;
+use crate::listener::{Header, Method, Request, Response};
+
+/// more ranges than this are ignored, to bound the work per request
+const MAX_RANGES: usize = 64;
+
+/// Byte ranges requested from a value
+#[derive(Debug, PartialEq)]
+pub(crate) enum Ranges {
+    /// whole value
+    Full,
+
+    /// inclusive start and end offsets
+    Partial(Vec<(u64, u64)>),
+
+    /// none of the ranges overlap the value
+    Unsatisfiable,
+}
+
+/// parses value of a `Range` header for a value of `len` bytes
+pub(crate) fn parse(header: &str, len: u64) -> Ranges {
+    let header = header.trim();
+    if !header.starts_with("bytes=") {
+        return Ranges::Full;
+    }
+
+    let specs: Vec<&str> = header[6..].split(',').map(str::trim).collect();
+    if specs.len() > MAX_RANGES {
+        return Ranges::Full;
+    }
+
+    let mut ranges = Vec::new();
+
+    for spec in specs {
+        let indx = match spec.find('-') {
+            Some(indx) => indx,
+            None => return Ranges::Full,
+        };
+
+        let (start, end) = (&spec[..indx], &spec[indx + 1..]);
+        let end_of_value = len.saturating_sub(1);
+
+        let (first, last) = if start.is_empty() {
+            // suffix range, last `end` bytes
+            match end.parse::<u64>() {
+                Ok(0) => continue,
+                Ok(suffix) => (len.saturating_sub(suffix), end_of_value),
+                Err(_) => return Ranges::Full,
+            }
+        } else {
+            match (start.parse::<u64>(), end.parse::<u64>()) {
+                (Ok(first), _) if end.is_empty() => (first, end_of_value),
+                (Ok(first), Ok(last)) if first <= last => (first, last.min(end_of_value)),
+                _ => return Ranges::Full,
+            }
+        };
+
+        // ranges starting past the end can not be served
+        if first < len {
+            ranges.push((first, last));
+        }
+    }
+
+    if ranges.is_empty() {
+        Ranges::Unsatisfiable
+    } else {
+        Ranges::Partial(ranges)
+    }
+}
+
+/// sends a stored value, or the ranges of it requested by the client.
+/// `headers` are only sent along with the whole value
+pub(crate) fn respond_file<F: Read + Seek + Send + 'static>(
+    req: Request,
+    mut file: F,
+    len: u64,
+    headers: &[(&str, String)],
+) -> io::Result<()> {
+    let ranges = match get_header(&req, "Range") {
+        Some(header) => parse(&header, len),
+        None => Ranges::Full,
+    };
+
+    let resp = match ranges {
+        Ranges::Full => with_headers(Response::from_reader(file, Some(len)), headers),
+        Ranges::Partial(ref ranges) if ranges.len() == 1 => {
+            let (first, last) = ranges[0];
+            file.seek(SeekFrom::Start(first))?;
+
+            let size = last - first + 1;
+            partial(
+                Response::from_reader(file.take(size), Some(size)),
+                first,
+                last,
+                len,
+            )
+        }
+        Ranges::Partial(ranges) => {
+            let boundary = format!("{:016x}", thread_rng().gen::<u64>());
+            let parts: VecDeque<(Cursor<Vec<u8>>, u64, u64)> = ranges
+                .iter()
+                .map(|&(first, last)| {
+                    let head = format!(
+                        "\r\n--{}\r\nContent-Type: application/octet-stream\r\n\
+                         Content-Range: bytes {}-{}/{}\r\n\r\n",
+                        boundary, first, last, len
+                    );
+                    (Cursor::new(head.into_bytes()), first, last)
+                })
+                .collect();
+            let tail = format!("\r\n--{}--\r\n", boundary);
+
+            let length = parts
+                .iter()
+                .map(|(head, first, last)| head.get_ref().len() as u64 + last - first + 1)
+                .sum::<u64>()
+                + tail.len() as u64;
+
+            let body = Multipart {
+                file,
+                parts,
+                tail: Cursor::new(tail.into_bytes()),
+                left: 0,
+            };
+
+            Response::from_reader(body, Some(length))
+                .with_status_code(206)
+                .with_header(header(
+                    "Content-Type",
+                    &format!("multipart/byteranges; boundary={}", boundary),
+                ))
+        }
+        Ranges::Unsatisfiable => unsatisfiable(len),
+    };
+
+    req.respond(resp.with_header(header("Accept-Ranges", "bytes")))
+}
+
+/// sends a value read from a stream of `len` bytes, such as a decompressed
+/// one. as the stream can not seek, a single range is served by skipping to
+/// it and several ranges are answered with the whole value
+pub(crate) fn respond_stream<R: Read + Send + 'static>(
+    req: Request,
+    mut stream: R,
+    len: u64,
+    headers: &[(&str, String)],
+) -> io::Result<()> {
+    let ranges = match get_header(&req, "Range") {
+        Some(header) => parse(&header, len),
+        None => Ranges::Full,
+    };
+
+    let resp = match ranges {
+        Ranges::Partial(ref ranges) if ranges.len() == 1 => {
+            let (first, last) = ranges[0];
+            if *req.method() != Method::Head {
+                copy(&mut (&mut stream).take(first), &mut io::sink())?;
+            }
+
+            let size = last - first + 1;
+            partial(
+                Response::from_reader(stream.take(size), Some(size)),
+                first,
+                last,
+                len,
+            )
+        }
+        Ranges::Unsatisfiable => unsatisfiable(len),
+        _ => with_headers(Response::from_reader(stream, Some(len)), headers),
+    };
+
+    req.respond(resp.with_header(header("Accept-Ranges", "bytes")))
+}
+
+/// Body of a `multipart/byteranges` response, reading its ranges from a file
+struct Multipart<F> {
+    file: F,
+
+    /// heads of the parts not sent yet, with their ranges
+    parts: VecDeque<(Cursor<Vec<u8>>, u64, u64)>,
+
+    /// closing delimiter
+    tail: Cursor<Vec<u8>>,
+
+    /// bytes of the current range left to read
+    left: u64,
+}
+
+impl<F: Read + Seek> Read for Multipart<F> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if self.left > 0 {
+                let read = (&mut self.file).take(self.left).read(buf)?;
+                if read == 0 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "value shorter than its length",
+                    ));
+                }
+
+                self.left -= read as u64;
+                return Ok(read);
+            }
+
+            match self.parts.front_mut() {
+                Some((head, first, last)) => {
+                    let read = head.read(buf)?;
+                    if read > 0 {
+                        return Ok(read);
+                    }
+
+                    self.file.seek(SeekFrom::Start(*first))?;
+                    self.left = *last - *first + 1;
+                    self.parts.pop_front();
+                }
+                None => return self.tail.read(buf),
+            }
+        }
+    }
+}
+
+fn header(field: &str, value: &str) -> Header {
+    Header::from_bytes(field.as_bytes(), value.as_bytes()).unwrap()
+}
+
+/// `resp` with `headers` of the whole value
+fn with_headers(resp: Response, headers: &[(&str, String)]) -> Response {
+    headers.iter().fold(resp, |resp, (field, value)| {
+        resp.with_header(header(field, value))
+    })
+}
+
+/// `resp` as the range `first` to `last` of a value of `len` bytes
+fn partial(resp: Response, first: u64, last: u64, len: u64) -> Response {
+    resp.with_status_code(206).with_header(header(
+        "Content-Range",
+        &format!("bytes {}-{}/{}", first, last, len),
+    ))
+}
+
+/// answer to ranges outside a value of `len` bytes
+fn unsatisfiable(len: u64) -> Response {
+    Response::from_data(Vec::new())
+        .with_status_code(416)
+        .with_header(header("Content-Range", &format!("bytes */{}", len)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_range_parse() {
+        assert_eq!(parse("bytes=0-4", 10), Ranges::Partial(vec![(0, 4)]));
+        assert_eq!(parse("bytes=5-", 10), Ranges::Partial(vec![(5, 9)]));
+        assert_eq!(parse("bytes=-3", 10), Ranges::Partial(vec![(7, 9)]));
+        assert_eq!(parse("bytes=-30", 10), Ranges::Partial(vec![(0, 9)]));
+        assert_eq!(parse("bytes=8-20", 10), Ranges::Partial(vec![(8, 9)]));
+        assert_eq!(
+            parse("bytes=0-1, 4-5,-2", 10),
+            Ranges::Partial(vec![(0, 1), (4, 5), (8, 9)])
+        );
+
+        // ranges past the end are dropped
+        assert_eq!(parse("bytes=0-1,20-30", 10), Ranges::Partial(vec![(0, 1)]));
+        assert_eq!(parse("bytes=10-", 10), Ranges::Unsatisfiable);
+        assert_eq!(parse("bytes=-0", 10), Ranges::Unsatisfiable);
+        assert_eq!(parse("bytes=0-", 0), Ranges::Unsatisfiable);
+
+        // malformed headers are ignored
+        assert_eq!(parse("items=0-4", 10), Ranges::Full);
+        assert_eq!(parse("bytes=4-0", 10), Ranges::Full);
+        assert_eq!(parse("bytes=a-b", 10), Ranges::Full);
+        assert_eq!(parse("bytes=0", 10), Ranges::Full);
+    }
+}
@@ -0,0 +1,304 @@
+//! Index entries stored by master.
+//!
+//! A record is encoded as a line of space separated volume urls followed by
+//! `field=value` lines. Databases written by earlier versions, holding only a
+//! volume url, decode to a record of unknown size. Values uploaded in parts
+//! have no volumes of their own but a `part=<blob> <size> <volumes>` line per
+//! part. Erasure coded values have an `erasure=<k>+<m>` line and a
+//! `shard=<blob> <size> <volume>` line per shard, and a `stripe=<size>` line
+//! unless they are coded as a single stripe.
+
+use std::str;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::erasure;
+
+/// Index entry of a key
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct Record {
+    /// volume servers holding the value, first one receives uploads
+    pub volumes: Vec<String>,
+
+    /// size of the value in bytes
+    pub size: u64,
+
+    /// expiry time, seconds since unix epoch
+    pub expires: Option<u64>,
+
+    /// version of the value in versioned buckets
+    pub version: Option<u64>,
+
+    /// parts of a value uploaded in parts, in order
+    pub parts: Vec<Part>,
+
+    /// number of data and parity shards of erasure coded values
+    pub erasure: Option<(usize, usize)>,
+
+    /// shards of erasure coded values, data shards first
+    pub shards: Vec<Part>,
+
+    /// bytes each shard holds of a stripe, `None` for values coded whole
+    pub stripe: Option<u64>,
+
+    /// time the value was stored, seconds since unix epoch
+    pub modified: Option<u64>,
+
+    /// entity tag of values written through the s3 gateway
+    pub etag: Option<String>,
+}
+
+/// Part of a value uploaded in parts
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct Part {
+    /// name of the blob on volume servers
+    pub blob: String,
+
+    /// size of the part in bytes
+    pub size: u64,
+
+    /// volume servers holding the part
+    pub volumes: Vec<String>,
+}
+
+impl Part {
+    fn decode(raw: &str) -> Option<Part> {
+        let mut fields = raw.split_whitespace();
+
+        let part = Part {
+            blob: fields.next()?.to_owned(),
+            size: fields.next()?.parse::<u64>().ok()?,
+            volumes: fields.map(str::to_owned).collect(),
+        };
+
+        if part.volumes.is_empty() {
+            return None;
+        }
+
+        Some(part)
+    }
+
+    fn encode(&self) -> String {
+        format!("{} {} {}", self.blob, self.size, self.volumes.join(" "))
+    }
+}
+
+impl Record {
+    /// Decodes a record, returns `None` for malformed entries
+    pub fn decode(raw: &[u8]) -> Option<Record> {
+        let raw = str::from_utf8(raw).ok()?;
+        let mut lines = raw.lines();
+
+        let mut record = Record {
+            volumes: lines
+                .next()?
+                .split_whitespace()
+                .map(str::to_owned)
+                .collect(),
+            ..Default::default()
+        };
+
+        for line in lines {
+            let mut parts = line.splitn(2, '=');
+            let (field, value) = (parts.next()?, parts.next()?);
+
+            match field {
+                "part" => record.parts.push(Part::decode(value)?),
+                "shard" => record.shards.push(Part::decode(value)?),
+                "erasure" => record.erasure = Some(erasure::parse_setting(value).ok()?),
+                "stripe" => {
+                    record.stripe = Some(value.parse::<u64>().ok().filter(|&size| size > 0)?)
+                }
+                "size" => record.size = value.parse::<u64>().ok()?,
+                "expires" => record.expires = Some(value.parse::<u64>().ok()?),
+                "version" => record.version = Some(value.parse::<u64>().ok()?),
+                "modified" => record.modified = Some(value.parse::<u64>().ok()?),
+                "etag" => record.etag = Some(value.to_owned()),
+                _ => {}
+            }
+        }
+
+        if record.volumes.is_empty() && record.parts.is_empty() && record.shards.is_empty() {
+            return None;
+        }
+
+        // every shard is needed to know its position
+        if let Some((data, parity)) = record.erasure {
+            if record.shards.len() != data + parity {
+                return None;
+            }
+        }
+
+        Some(record)
+    }
+
+    /// Encodes the record
+    pub fn encode(&self) -> String {
+        let mut raw = self.volumes.join(" ");
+        raw.push_str(&format!("\nsize={}", self.size));
+
+        if let Some(expires) = self.expires {
+            raw.push_str(&format!("\nexpires={}", expires));
+        }
+
+        if let Some(version) = self.version {
+            raw.push_str(&format!("\nversion={}", version));
+        }
+
+        if let Some(modified) = self.modified {
+            raw.push_str(&format!("\nmodified={}", modified));
+        }
+
+        if let Some(ref etag) = self.etag {
+            raw.push_str(&format!("\netag={}", etag));
+        }
+
+        for part in self.parts.iter() {
+            raw.push_str(&format!("\npart={}", part.encode()));
+        }
+
+        if let Some((data, parity)) = self.erasure {
+            raw.push_str(&format!("\nerasure={}+{}", data, parity));
+        }
+
+        if let Some(stripe) = self.stripe {
+            raw.push_str(&format!("\nstripe={}", stripe));
+        }
+
+        for shard in self.shards.iter() {
+            raw.push_str(&format!("\nshard={}", shard.encode()));
+        }
+
+        raw
+    }
+
+    /// volume servers holding the value or any of its parts or shards
+    pub fn all_volumes(&self) -> impl Iterator<Item = &String> {
+        self.volumes.iter().chain(
+            self.parts
+                .iter()
+                .chain(self.shards.iter())
+                .flat_map(|part| part.volumes.iter()),
+        )
+    }
+
+    /// checks whether the value is stored whole on its volumes, neither in
+    /// parts nor in shards
+    pub fn is_whole(&self) -> bool {
+        self.parts.is_empty() && self.shards.is_empty()
+    }
+
+    /// checks whether the key has expired at `now`
+    pub fn expired(&self, now: u64) -> bool {
+        self.expires.map_or(false, |expires| expires <= now)
+    }
+}
+
+/// current time in seconds since unix epoch
+pub(crate) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or(0)
+}
+
+/// new version number, increasing over time
+pub(crate) fn new_version() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_nanos() as u64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_record_encoding() {
+        let record = Record {
+            volumes: vec!["http://volume1".to_owned(), "http://volume2".to_owned()],
+            size: 42,
+            expires: Some(1000),
+            version: Some(7),
+            modified: Some(900),
+            etag: Some("5d41402abc4b2a76b9719d911017c592".to_owned()),
+            ..Default::default()
+        };
+
+        assert_eq!(Record::decode(record.encode().as_bytes()), Some(record));
+
+        let record = Record {
+            size: 3,
+            parts: vec![
+                Part {
+                    blob: "~parts/id/1".to_owned(),
+                    size: 2,
+                    volumes: vec!["http://volume1".to_owned(), "http://volume2".to_owned()],
+                },
+                Part {
+                    blob: "~parts/id/2".to_owned(),
+                    size: 1,
+                    volumes: vec!["http://volume2".to_owned()],
+                },
+            ],
+            ..Default::default()
+        };
+
+        assert_eq!(
+            Record::decode(record.encode().as_bytes()),
+            Some(record.clone())
+        );
+        assert_eq!(record.all_volumes().count(), 3);
+        assert!(!record.is_whole());
+
+        let shard = |blob: &str| Part {
+            blob: blob.to_owned(),
+            size: 2,
+            volumes: vec!["http://volume1".to_owned()],
+        };
+        let mut record = Record {
+            size: 3,
+            erasure: Some((2, 1)),
+            shards: vec![
+                shard("~shards/id/0"),
+                shard("~shards/id/1"),
+                shard("~shards/id/2"),
+            ],
+            ..Default::default()
+        };
+
+        assert_eq!(
+            Record::decode(record.encode().as_bytes()),
+            Some(record.clone())
+        );
+
+        record.stripe = Some(1);
+        assert_eq!(
+            Record::decode(record.encode().as_bytes()),
+            Some(record.clone())
+        );
+        assert_eq!(Record::decode(b"http://volume1\nstripe=0"), None);
+
+        // shards can not be placed without all of them
+        record.shards.pop();
+        assert_eq!(Record::decode(record.encode().as_bytes()), None);
+
+        // written by earlier versions
+        let record = Record::decode(b"http://volume1").unwrap();
+        assert_eq!(record.volumes, vec!["http://volume1".to_owned()]);
+        assert_eq!(record.size, 0);
+        assert_eq!(record.expires, None);
+
+        assert_eq!(Record::decode(b""), None);
+        assert_eq!(Record::decode(b"http://volume1\nsize=big"), None);
+    }
+
+    #[test]
+    fn test_record_expiry() {
+        let mut record = Record::decode(b"http://volume1").unwrap();
+        assert!(!record.expired(now()));
+
+        record.expires = Some(now());
+        assert!(record.expired(now()));
+    }
+}
@@ -0,0 +1,398 @@
+//! # scrubbing
+//!
+//! Values on disk can rot silently. Volume servers started with `--scrub-rate`
+//! re-read all stored values in a background thread, at most that many bytes
+//! per second, and compare them against the digests kept since upload. Values
+//! no longer matching are moved to `<data_dir>/quarantine` and reported to
+//! master at `/admin/report-corrupt`, which copies them back from a healthy
+//! replica if there is one. A pass starts every `--scrub-interval` seconds.
+//!
+//! ```sh
+//! volume -p 7000 -d /tmp/kalavarastore --scrub-rate 10485760 --scrub-interval 86400 \
+//!     -m http://master.server -b http://this.volume.server:7000
+//! ```
+
+use std::fs::{create_dir_all, read_dir, read_to_string, remove_file, rename, File};
+use std::io::{self, copy, sink, Read};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::checksum::{Checksum, DigestReader};
+use crate::cluster::JOIN_TOKEN_HEADER;
+use crate::compress;
+use crate::dedup;
+use crate::encryption::{self, Decryptor, KeyRing};
+use crate::http;
+use crate::server::Shutdown;
+use crate::tls::TlsConfig;
+use crate::volume::checksum_path;
+use crate::ADMIN_PREFIX;
+
+/// directory under data dir holding corrupt values
+pub const QUARANTINE_DIR: &str = "quarantine";
+
+/// Scrubber settings
+#[derive(Clone, Debug)]
+pub struct ScrubConfig {
+    /// bytes read per second, scrubbing is off if not set
+    pub rate: Option<u64>,
+
+    /// seconds between start of passes
+    pub interval: u64,
+}
+
+impl Default for ScrubConfig {
+    fn default() -> Self {
+        ScrubConfig {
+            rate: None,
+            interval: 86400,
+        }
+    }
+}
+
+/// reads the key a value was stored under from its checksum file
+pub(crate) fn stored_key(raw: &str) -> Option<&str> {
+    raw.lines()
+        .find(|line| line.starts_with("key="))
+        .map(|line| &line[4..])
+}
+
+/// Master that corrupt values are reported to
+pub(crate) struct Reporter {
+    pub master: String,
+
+    /// base url of this volume, as registered with master
+    pub base: String,
+    pub token: Option<String>,
+    pub tls: TlsConfig,
+}
+
+impl Reporter {
+    /// reports keys of corrupt values, returns the ones master could not
+    /// restore
+    fn report(&self, keys: &[String]) -> io::Result<String> {
+        let mut body = format!("{}\n", self.base);
+        for key in keys {
+            body.push_str(key);
+            body.push('\n');
+        }
+
+        let url = format!("{}{}report-corrupt", self.master, ADMIN_PREFIX);
+        let token = self.token.clone().unwrap_or_default();
+
+        let resp = http::request(
+            "POST",
+            &url,
+            &[(JOIN_TOKEN_HEADER, token.as_str())],
+            body.as_bytes(),
+            &self.tls,
+        )?;
+
+        if resp.status_code == 200 {
+            Ok(resp.text())
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("master answered {}", resp.status_code),
+            ))
+        }
+    }
+}
+
+/// limits bytes read per second over a pass
+struct Pace {
+    rate: Option<u64>,
+    start: Instant,
+    bytes: u64,
+}
+
+impl Pace {
+    fn new(rate: Option<u64>) -> Self {
+        Pace {
+            rate,
+            start: Instant::now(),
+            bytes: 0,
+        }
+    }
+
+    /// accounts `bytes` read, sleeping until they are within the rate
+    fn consume(&mut self, bytes: usize) {
+        self.bytes += bytes as u64;
+
+        if let Some(rate) = self.rate {
+            let due = Duration::from_millis(self.bytes.saturating_mul(1000) / rate.max(1));
+            let elapsed = self.start.elapsed();
+
+            if due > elapsed {
+                thread::sleep(due - elapsed);
+            }
+        }
+    }
+}
+
+/// Reader keeping to a pace
+struct Paced<'a, R> {
+    inner: R,
+    pace: &'a mut Pace,
+}
+
+impl<'a, R: Read> Read for Paced<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.pace.consume(len);
+        Ok(len)
+    }
+}
+
+/// Result of a pass over all values
+#[derive(Debug, Default)]
+pub(crate) struct Pass {
+    /// values found, including ones without digests
+    pub checked: usize,
+
+    /// keys of quarantined values, `None` if the key is not known
+    pub corrupt: Vec<Option<String>>,
+}
+
+/// Checks stored values against their digests
+pub(crate) struct Scrubber {
+    data_dir: PathBuf,
+    rate: Option<u64>,
+    reporter: Option<Reporter>,
+
+    /// master keys of encrypted values
+    keys: Option<KeyRing>,
+}
+
+impl Scrubber {
+    pub fn new(
+        data_dir: &str,
+        rate: Option<u64>,
+        reporter: Option<Reporter>,
+        keys: Option<KeyRing>,
+    ) -> Self {
+        Scrubber {
+            data_dir: PathBuf::from(data_dir),
+            rate,
+            reporter,
+            keys,
+        }
+    }
+
+    /// runs a pass every `interval`, until `shutdown` is signalled
+    pub fn run(&self, interval: Duration, shutdown: &Shutdown) {
+        while shutdown.sleep(interval) {
+            match self.pass() {
+                Ok(pass) => {
+                    println!(
+                        "scrubbed {} values, {} corrupt",
+                        pass.checked,
+                        pass.corrupt.len()
+                    );
+                    self.report(pass.corrupt);
+                }
+                Err(e) => println!("scrubbing failed: {}", e),
+            }
+        }
+    }
+
+    /// reports quarantined values to master
+    fn report(&self, corrupt: Vec<Option<String>>) {
+        let keys: Vec<String> = corrupt.into_iter().flatten().collect();
+
+        match self.reporter {
+            Some(ref reporter) if !keys.is_empty() => match reporter.report(&keys) {
+                Ok(ref lost) if lost.is_empty() => {}
+                Ok(lost) => println!("values lost, no healthy replica:\n{}", lost),
+                Err(e) => println!("failed to report corrupt values: {}", e),
+            },
+            _ => {}
+        }
+    }
+
+    /// checks all values once, quarantining corrupt ones
+    pub fn pass(&self) -> io::Result<Pass> {
+        let mut pace = Pace::new(self.rate);
+        let mut pass = Pass::default();
+        let keys = self.keys.as_ref();
+
+        for_each_value(&self.data_dir, |path| {
+            pass.checked += 1;
+            if verify(&path, &mut pace, keys).unwrap_or(true) {
+                return Ok(());
+            }
+
+            // value may have been replaced while it was read
+            if verify(&path, &mut Pace::new(None), keys).unwrap_or(true) {
+                return Ok(());
+            }
+
+            pass.corrupt.push(self.quarantine(&path)?);
+            Ok(())
+        })?;
+
+        Ok(pass)
+    }
+
+    /// moves a value and its digests to quarantine, returns its key
+    fn quarantine(&self, path: &Path) -> io::Result<Option<String>> {
+        let key = read_to_string(checksum_path(path))
+            .ok()
+            .and_then(|raw| stored_key(&raw).map(str::to_owned));
+
+        let dir = self.data_dir.join(QUARANTINE_DIR);
+        create_dir_all(&dir)?;
+
+        // named by the full md5 of the key
+        let name: String = path
+            .strip_prefix(&self.data_dir)
+            .unwrap_or(path)
+            .iter()
+            .map(|part| part.to_string_lossy())
+            .collect();
+        let dest = dir.join(name);
+
+        // uploads of the same content are not linked to a corrupt blob
+        let blob = dedup::linked_blob(&self.data_dir, path);
+
+        rename(path, &dest)?;
+        let _ = rename(checksum_path(path), checksum_path(&dest));
+
+        if let Some(blob) = blob {
+            let _ = remove_file(checksum_path(&blob));
+            remove_file(blob)?;
+        }
+
+        println!("quarantined corrupt value {:?}", key);
+        Ok(key)
+    }
+}
+
+/// calls `f` with the path of each value stored under `data_dir`
+pub(crate) fn for_each_value(
+    data_dir: &Path,
+    mut f: impl FnMut(PathBuf) -> io::Result<()>,
+) -> io::Result<()> {
+    // values are stored at <data_dir>/<x>/<y>/<rest of md5>
+    for first in shards(data_dir)? {
+        for second in shards(&first)? {
+            for entry in read_dir(second)? {
+                let path = entry?.path();
+                if path.is_file() && path.extension().is_none() {
+                    f(path)?;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// subdirectories named by a single hex digit
+fn shards(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut shards = Vec::new();
+
+    for entry in read_dir(dir)? {
+        let path = entry?.path();
+        let single = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .map_or(false, |name| name.len() == 1);
+
+        if single && path.is_dir() {
+            shards.push(path);
+        }
+    }
+
+    shards.sort();
+    Ok(shards)
+}
+
+/// compares a value with its stored digests. values without digests, stored
+/// by earlier versions, can not be verified and pass
+fn verify(path: &Path, pace: &mut Pace, keys: Option<&KeyRing>) -> io::Result<bool> {
+    let stored = read_to_string(checksum_path(path))?;
+    let expected = match Checksum::decode(&stored) {
+        Some(checksum) => checksum,
+        None => return Ok(true),
+    };
+
+    // values encrypted with a key not at hand are skipped
+    let key = match keys {
+        Some(keys) => keys.data_key(&stored)?,
+        None if encryption::is_encrypted(&stored) => {
+            return Err(io::Error::new(io::ErrorKind::Other, "no key file"));
+        }
+        None => None,
+    };
+    let encoding = compress::decode_stored(&stored).map(|(encoding, _)| encoding);
+
+    // digests are those of decrypted and decompressed values
+    let mut value: Box<dyn Read + Send + '_> = Box::new(Paced {
+        inner: File::open(path)?,
+        pace,
+    });
+    if let Some(key) = key {
+        value = Box::new(Decryptor::new(value, &key));
+    }
+    if let Some(encoding) = encoding {
+        value = compress::decompress(encoding, value)?;
+    }
+
+    let mut reader = DigestReader::new(value);
+    match copy(&mut reader, &mut sink()) {
+        Ok(_) => Ok(reader.finish() == expected),
+        // corrupt values fail to decrypt or decompress
+        Err(ref e)
+            if (key.is_some() || encoding.is_some())
+                && (e.kind() == io::ErrorKind::InvalidData || e.kind() == io::ErrorKind::Other) =>
+        {
+            Ok(false)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs::write;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_scrub_pass() {
+        let data_dir = tempdir().unwrap();
+        let shard = data_dir.path().join("a").join("b");
+        create_dir_all(&shard).unwrap();
+        create_dir_all(data_dir.path().join("tmp")).unwrap();
+
+        let mut reader = DigestReader::new("value".as_bytes());
+        copy(&mut reader, &mut sink()).unwrap();
+        let sum = format!("key=/key\n{}", reader.finish().encode());
+
+        for name in ["healthy", "rotten"].iter() {
+            write(shard.join(name), "value").unwrap();
+            write(shard.join(format!("{}.sum", name)), &sum).unwrap();
+        }
+        write(shard.join("rotten"), "valve").unwrap();
+
+        // values without digests are skipped
+        write(shard.join("legacy"), "value").unwrap();
+        write(data_dir.path().join("tmp").join("partial"), "val").unwrap();
+
+        let scrubber = Scrubber::new(data_dir.path().to_str().unwrap(), Some(1 << 20), None, None);
+        let pass = scrubber.pass().unwrap();
+
+        assert_eq!(pass.checked, 3);
+        assert_eq!(pass.corrupt, vec![Some("/key".to_owned())]);
+        assert!(!shard.join("rotten").exists());
+        assert!(shard.join("healthy").exists());
+
+        let quarantine = data_dir.path().join(QUARANTINE_DIR);
+        assert!(quarantine.join("abrotten").exists());
+        assert!(quarantine.join("abrotten.sum").exists());
+
+        assert_eq!(scrubber.pass().unwrap().corrupt.len(), 0);
+    }
+}
@@ -0,0 +1,221 @@
+//! # embedding servers
+//!
+//! Master and volume servers are started in process with
+//! [`MasterBuilder`](../master/struct.MasterBuilder.html) and
+//! [`VolumeBuilder`](../volume/struct.VolumeBuilder.html). Both return a
+//! [`ServerHandle`](struct.ServerHandle.html) once the server accepts
+//! requests, volumes after registering with master, so the handle is the
+//! readiness signal. Port 0 binds a free port, read back with `addr()`
+//!
+//! ```no_run
+//! use kalavara::master::MasterBuilder;
+//! use kalavara::volume::VolumeBuilder;
+//!
+//! let volume = VolumeBuilder::new("/tmp/kalavarastore").port(0).start()?;
+//! let master = MasterBuilder::new("/tmp/kalavaradb")
+//!     .port(0)
+//!     .volumes(vec![volume.url()])
+//!     .start()?;
+//!
+//! println!("master listening at {}", master.url());
+//! master.shutdown();
+//! # Ok::<(), std::io::Error>(())
+//! ```
+//!
+//! `shutdown()` stops accepting requests, answers those already accepted and
+//! joins all threads of the server. Dropping a handle shuts the server down
+//! as well.
+
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::listener::Server;
+use crate::tls::TlsConfig;
+
+/// interval at which threads check for shutdown
+pub(crate) const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Signal asking the threads of a server to stop
+#[derive(Clone, Default)]
+pub struct Shutdown {
+    stopped: Arc<AtomicBool>,
+
+    /// background threads joined on shutdown
+    threads: Arc<Mutex<Vec<JoinHandle<()>>>>,
+}
+
+impl Shutdown {
+    /// asks the server to stop
+    pub fn signal(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+    }
+
+    /// checks whether the server was asked to stop
+    pub fn is_set(&self) -> bool {
+        self.stopped.load(Ordering::SeqCst)
+    }
+
+    /// sleeps for `duration` unless the server is stopped meanwhile. returns
+    /// false if it was
+    pub fn sleep(&self, duration: Duration) -> bool {
+        let until = Instant::now() + duration;
+
+        loop {
+            if self.is_set() {
+                return false;
+            }
+
+            let now = Instant::now();
+            if now >= until {
+                return true;
+            }
+            thread::sleep((until - now).min(POLL_INTERVAL));
+        }
+    }
+
+    /// spawns a background thread joined on shutdown
+    pub(crate) fn spawn<F>(&self, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let handle = thread::spawn(f);
+        self.threads.lock().unwrap().push(handle);
+    }
+
+    /// waits for background threads
+    fn join(&self) {
+        let threads: Vec<JoinHandle<()>> = self.threads.lock().unwrap().drain(..).collect();
+
+        for handle in threads {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// binds a listener at `port` on all interfaces, serving https if `tls` has
+/// a certificate and keeping at most `connections` connections open
+pub(crate) fn bind(port: u16, tls: &TlsConfig, connections: usize) -> io::Result<Arc<Server>> {
+    let addr: SocketAddr = ([0, 0, 0, 0], port).into();
+
+    Server::bind(addr, tls.server_config()?, connections).map(Arc::new)
+}
+
+/// Running master or volume server
+pub struct ServerHandle {
+    addr: SocketAddr,
+
+    /// whether the server speaks https
+    https: bool,
+
+    shutdown: Shutdown,
+
+    /// threads exiting once the server stopped and handled its requests
+    workers: Vec<JoinHandle<()>>,
+
+    /// address of the s3 gateway of master, if enabled
+    s3: Option<SocketAddr>,
+}
+
+impl ServerHandle {
+    pub(crate) fn new(
+        addr: SocketAddr,
+        https: bool,
+        shutdown: Shutdown,
+        workers: Vec<JoinHandle<()>>,
+    ) -> Self {
+        ServerHandle {
+            addr,
+            https,
+            shutdown,
+            workers,
+            s3: None,
+        }
+    }
+
+    /// records the address of the s3 gateway served by the same threads
+    pub(crate) fn with_s3(mut self, addr: SocketAddr) -> Self {
+        self.s3 = Some(addr);
+        self
+    }
+
+    /// address the server is bound to
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// port the server listens at
+    pub fn port(&self) -> u16 {
+        self.addr.port()
+    }
+
+    /// base url of the server on this host
+    pub fn url(&self) -> String {
+        let scheme = if self.https { "https" } else { "http" };
+        format!("{}://localhost:{}", scheme, self.port())
+    }
+
+    /// base url of the s3 gateway on this host, if master serves one
+    pub fn s3_url(&self) -> Option<String> {
+        let scheme = if self.https { "https" } else { "http" };
+        self.s3
+            .map(|addr| format!("{}://localhost:{}", scheme, addr.port()))
+    }
+
+    /// signal that stops the server from other threads
+    pub fn shutdown_signal(&self) -> Shutdown {
+        self.shutdown.clone()
+    }
+
+    /// checks whether the server still accepts requests
+    pub fn is_running(&self) -> bool {
+        !self.shutdown.is_set()
+    }
+
+    /// blocks until the server is stopped with its shutdown signal
+    pub fn join(mut self) {
+        for handle in self.workers.drain(..) {
+            let _ = handle.join();
+        }
+    }
+
+    /// stops accepting requests, answers accepted ones and waits for all
+    /// threads of the server
+    pub fn shutdown(self) {
+        drop(self);
+    }
+}
+
+impl Drop for ServerHandle {
+    fn drop(&mut self) {
+        self.shutdown.signal();
+
+        for handle in self.workers.drain(..) {
+            let _ = handle.join();
+        }
+        self.shutdown.join();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_shutdown_sleep() {
+        let shutdown = Shutdown::default();
+        assert!(shutdown.sleep(Duration::from_millis(10)));
+
+        let signal = shutdown.clone();
+        shutdown.spawn(move || while signal.sleep(Duration::from_secs(60)) {});
+
+        let started = Instant::now();
+        shutdown.signal();
+        shutdown.join();
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(!shutdown.sleep(Duration::from_secs(60)));
+    }
+}
@@ -0,0 +1,587 @@
+//! # storage engines
+//!
+//! Volume servers keep values in a [`BlobStore`](trait.BlobStore.html), which
+//! stores the bytes of a value as handed to it, compressed and encrypted as
+//! the volume decided, along with their metadata: digests, compression and
+//! the wrapped data key as `name=value` lines. Engines are chosen with
+//! `--engine`
+//!
+//! * `files` - a file per value, the default. see [dedup](../dedup/index.html)
+//! * `packed` - values appended to [segment files](../packed/index.html)
+//! * `memory` - values kept in memory and lost on restart, for tests and
+//!   ephemeral caches
+//!
+//! ```sh
+//! volume -p 7000 --engine memory
+//! ```
+//!
+//! Embedders can run volumes on engines of their own with
+//! `Engine::Custom`.
+
+use md5::compute as compute_md5;
+use tempfile::NamedTempFile;
+
+use std::collections::HashMap;
+use std::fs::{create_dir_all, hard_link, read_to_string, remove_file, rename, File};
+use std::io::{self, copy, Cursor, Error, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+
+use crate::checksum::{to_hex, Checksum};
+use crate::dedup;
+use crate::packed::{PackedConfig, Segments};
+use crate::scrub::{for_each_value, stored_key};
+use crate::server::Shutdown;
+use crate::volume::checksum_path;
+
+/// Stream of a stored value
+pub trait ReadSeek: Read + Seek + Send {}
+
+impl<T: Read + Seek + Send> ReadSeek for T {}
+
+/// Metadata and size of a stored value
+#[derive(Clone, Debug, PartialEq)]
+pub struct Stat {
+    pub meta: String,
+    pub len: u64,
+}
+
+/// Stored value
+pub struct Blob {
+    pub meta: String,
+    pub len: u64,
+    pub value: Box<dyn ReadSeek>,
+}
+
+/// Value being written, stored once committed
+pub trait Staged: Write {
+    /// stores the written bytes under `key` with `meta`, replacing any
+    /// value stored before
+    fn commit(self: Box<Self>, key: &str, meta: &str) -> io::Result<()>;
+}
+
+/// Storage of values on a volume
+pub trait BlobStore: Send + Sync {
+    /// starts writing a value. values not committed are dropped
+    fn create(&self) -> io::Result<Box<dyn Staged + '_>>;
+
+    /// stored value of a key, `None` if there is none
+    fn get(&self, key: &str) -> io::Result<Option<Blob>>;
+
+    /// removes the value of a key, returns false if there was none
+    fn delete(&self, key: &str) -> io::Result<bool>;
+
+    /// keys of all stored values
+    fn list(&self) -> io::Result<Vec<String>>;
+
+    /// replaces the metadata of a value, returns false if there is none
+    fn set_meta(&self, key: &str, meta: &str) -> io::Result<bool>;
+
+    /// stores a value read from a stream, returns its size
+    fn put(&self, key: &str, meta: &str, value: &mut dyn Read) -> io::Result<u64> {
+        let mut staged = self.create()?;
+        let len = copy(value, &mut staged)?;
+        staged.commit(key, meta)?;
+        Ok(len)
+    }
+
+    /// metadata and size of a value
+    fn stat(&self, key: &str) -> io::Result<Option<Stat>> {
+        Ok(self.get(key)?.map(|blob| Stat {
+            meta: blob.meta,
+            len: blob.len,
+        }))
+    }
+
+    /// `len` bytes of a value from `offset`
+    fn get_range(&self, key: &str, offset: u64, len: u64) -> io::Result<Option<Box<dyn Read>>> {
+        match self.get(key)? {
+            Some(mut blob) => {
+                blob.value.seek(SeekFrom::Start(offset))?;
+                Ok(Some(Box::new(blob.value.take(len))))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// reclaims space of deleted and overwritten values, returns the number
+    /// of files rewritten
+    fn compact(&self) -> io::Result<usize> {
+        Ok(0)
+    }
+}
+
+/// Storage engine of a volume
+#[derive(Clone)]
+pub enum Engine {
+    /// a file per value, identical values are stored once with `dedup`
+    Files { dedup: bool },
+
+    /// values appended to segment files
+    Packed(PackedConfig),
+
+    /// values kept in memory
+    Memory,
+
+    /// engine provided by the embedder
+    Custom(Arc<dyn BlobStore>),
+}
+
+impl Default for Engine {
+    fn default() -> Self {
+        Engine::Files { dedup: false }
+    }
+}
+
+impl Engine {
+    /// opens the engine on a data directory. background work stops with
+    /// `shutdown`
+    pub(crate) fn open(
+        self,
+        data_dir: &Path,
+        shutdown: &Shutdown,
+    ) -> io::Result<Arc<dyn BlobStore>> {
+        Ok(match self {
+            Engine::Files { dedup } => Arc::new(FileStore::new(data_dir, dedup)?),
+            Engine::Packed(config) => {
+                // overwritten and deleted values are compacted in background
+                let segments = Arc::new(Segments::open(data_dir, config)?);
+                let compactor = segments.clone();
+                let signal = shutdown.clone();
+                shutdown.spawn(move || compactor.run(&signal));
+                segments
+            }
+            Engine::Memory => Arc::new(MemoryStore::default()),
+            Engine::Custom(store) => store,
+        })
+    }
+}
+
+/// Values stored as files of their own. For atomicity values are first
+/// written to `<data_dir>/tmp` and then moved to their path, so both should
+/// be on the same file system
+pub struct FileStore {
+    data_dir: PathBuf,
+
+    /// values are stored once per content if set
+    dedup: bool,
+
+    /// held while content blobs are linked or released
+    link_lock: Mutex<()>,
+}
+
+impl FileStore {
+    pub fn new(data_dir: &Path, dedup: bool) -> io::Result<FileStore> {
+        create_dir_all(data_dir.join("tmp"))?;
+
+        Ok(FileStore {
+            data_dir: data_dir.to_owned(),
+            dedup,
+            link_lock: Mutex::new(()),
+        })
+    }
+
+    /// Calcualtes destination file path from key
+    fn key_to_path(&self, key: &str) -> PathBuf {
+        let path = format!("{:x}", compute_md5(key.as_bytes()));
+
+        let mut dest_path = self.data_dir.clone();
+        dest_path.push(path.get(0..1).unwrap());
+        dest_path.push(path.get(1..2).unwrap());
+        dest_path.push(path.get(2..).unwrap());
+
+        dest_path
+    }
+
+    fn tmpdir(&self) -> PathBuf {
+        self.data_dir.join("tmp")
+    }
+
+    /// moves a written value to its path and writes its `.sum` file. the key
+    /// lets the scrubber report corrupt values to master
+    fn save(&self, tmpfile: NamedTempFile, key: &str, meta: &str) -> io::Result<()> {
+        let dest_path = self.key_to_path(key);
+        create_dir_all(dest_path.parent().unwrap())?;
+
+        let sha256 = Checksum::decode(meta).map(|checksum| checksum.sha256);
+        let meta = match sha256 {
+            Some(ref sha256) if self.dedup => {
+                self.link_blob(tmpfile, &dest_path, meta.to_owned(), sha256)?
+            }
+            _ => {
+                // value may have been deduplicated before
+                let replaced = dedup::linked_blob(&self.data_dir, &dest_path);
+                persist(tmpfile, &dest_path)?;
+                self.release_blob(replaced)?;
+                meta.to_owned()
+            }
+        };
+
+        self.write_sum(
+            &checksum_path(&dest_path),
+            &format!("key={}\n{}", key, meta),
+        )
+    }
+
+    /// moves an uploaded value to the blob of its content, unless that is
+    /// stored already, and links `dest` to the blob. returns metadata of the
+    /// blob, which is that of the upload if it is new
+    fn link_blob(
+        &self,
+        tmpfile: NamedTempFile,
+        dest: &Path,
+        meta: String,
+        sha256: &[u8],
+    ) -> io::Result<String> {
+        let blob = dedup::blob_path(&self.data_dir, sha256);
+        let link = self.tmpdir().join(format!("{}.link", to_hex(sha256)));
+
+        let _links = self.link_lock.lock().unwrap();
+        let meta = match read_to_string(checksum_path(&blob)) {
+            // upload is dropped with the temporary file
+            Ok(existing) if blob.exists() => existing,
+            _ => {
+                create_dir_all(blob.parent().unwrap())?;
+                persist(tmpfile, &blob)?;
+                self.write_sum(&checksum_path(&blob), &meta)?;
+                meta
+            }
+        };
+
+        let replaced = dedup::linked_blob(&self.data_dir, dest);
+        if replaced.as_ref() == Some(&blob) {
+            return Ok(meta);
+        }
+
+        // linked next to the value first, as a link can not replace a file
+        let _ = remove_file(&link);
+        hard_link(&blob, &link)?;
+        rename(&link, dest)?;
+
+        if let Some(replaced) = replaced {
+            dedup::release(&replaced)?;
+        }
+
+        Ok(meta)
+    }
+
+    /// removes a blob a value no longer links to, if it was the last one
+    fn release_blob(&self, blob: Option<PathBuf>) -> io::Result<()> {
+        if let Some(blob) = blob {
+            let _links = self.link_lock.lock().unwrap();
+            dedup::release(&blob)?;
+        }
+
+        Ok(())
+    }
+
+    fn write_sum(&self, path: &Path, sum: &str) -> io::Result<()> {
+        let mut sumfile = NamedTempFile::new_in(self.tmpdir())?;
+        sumfile.write_all(sum.as_bytes())?;
+        persist(sumfile, path).map(|_| ())
+    }
+}
+
+impl BlobStore for FileStore {
+    fn create(&self) -> io::Result<Box<dyn Staged + '_>> {
+        Ok(Box::new(StagedFile {
+            store: self,
+            file: NamedTempFile::new_in(self.tmpdir())?,
+        }))
+    }
+
+    fn get(&self, key: &str) -> io::Result<Option<Blob>> {
+        let path = self.key_to_path(key);
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+
+        // values stored by earlier versions have no checksum
+        let sum = read_to_string(checksum_path(&path)).unwrap_or_default();
+
+        Ok(Some(Blob {
+            meta: strip_key(&sum),
+            len: file.metadata()?.len(),
+            value: Box::new(file),
+        }))
+    }
+
+    fn delete(&self, key: &str) -> io::Result<bool> {
+        let dest_path = self.key_to_path(key);
+
+        let _links = self.link_lock.lock().unwrap();
+        let blob = dedup::linked_blob(&self.data_dir, &dest_path);
+
+        match remove_file(&dest_path) {
+            Ok(_) => {}
+            Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e),
+        }
+
+        let _ = remove_file(checksum_path(&dest_path));
+        if let Some(blob) = blob {
+            dedup::release(&blob)?;
+        }
+
+        Ok(true)
+    }
+
+    /// keys are read from `.sum` files, values stored by earlier versions
+    /// without one are not listed
+    fn list(&self) -> io::Result<Vec<String>> {
+        let mut keys = Vec::new();
+
+        for_each_value(&self.data_dir, |path| {
+            if let Ok(raw) = read_to_string(checksum_path(&path)) {
+                keys.extend(stored_key(&raw).map(str::to_owned));
+            }
+            Ok(())
+        })?;
+
+        Ok(keys)
+    }
+
+    /// blobs of deduplicated values get the metadata too, as values linked
+    /// to them later take it over
+    fn set_meta(&self, key: &str, meta: &str) -> io::Result<bool> {
+        let dest_path = self.key_to_path(key);
+        if !dest_path.exists() {
+            return Ok(false);
+        }
+
+        let _links = self.link_lock.lock().unwrap();
+        if let Some(blob) = dedup::linked_blob(&self.data_dir, &dest_path) {
+            self.write_sum(&checksum_path(&blob), meta)?;
+        }
+
+        let sum = format!("key={}\n{}", key, meta);
+        self.write_sum(&checksum_path(&dest_path), &sum)?;
+        Ok(true)
+    }
+}
+
+/// value being written to a temporary file
+struct StagedFile<'a> {
+    store: &'a FileStore,
+    file: NamedTempFile,
+}
+
+impl<'a> Write for StagedFile<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl<'a> Staged for StagedFile<'a> {
+    fn commit(self: Box<Self>, key: &str, meta: &str) -> io::Result<()> {
+        let StagedFile { store, file } = *self;
+        store.save(file, key, meta)
+    }
+}
+
+/// Values kept in memory
+#[derive(Default)]
+pub struct MemoryStore {
+    values: RwLock<HashMap<String, (String, Shared)>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore::default()
+    }
+}
+
+impl BlobStore for MemoryStore {
+    fn create(&self) -> io::Result<Box<dyn Staged + '_>> {
+        Ok(Box::new(StagedMemory {
+            store: self,
+            value: Vec::new(),
+        }))
+    }
+
+    fn get(&self, key: &str) -> io::Result<Option<Blob>> {
+        let values = self.values.read().unwrap();
+
+        Ok(values.get(key).map(|(meta, value)| Blob {
+            meta: meta.clone(),
+            len: value.0.len() as u64,
+            value: Box::new(Cursor::new(value.clone())),
+        }))
+    }
+
+    fn delete(&self, key: &str) -> io::Result<bool> {
+        Ok(self.values.write().unwrap().remove(key).is_some())
+    }
+
+    fn list(&self) -> io::Result<Vec<String>> {
+        Ok(self.values.read().unwrap().keys().cloned().collect())
+    }
+
+    fn set_meta(&self, key: &str, meta: &str) -> io::Result<bool> {
+        match self.values.write().unwrap().get_mut(key) {
+            Some(value) => {
+                value.0 = meta.to_owned();
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+}
+
+/// value kept in memory, shared with readers
+#[derive(Clone, Debug)]
+struct Shared(Arc<Vec<u8>>);
+
+impl AsRef<[u8]> for Shared {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+/// value being written to memory
+struct StagedMemory<'a> {
+    store: &'a MemoryStore,
+    value: Vec<u8>,
+}
+
+impl<'a> Write for StagedMemory<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.value.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> Staged for StagedMemory<'a> {
+    fn commit(self: Box<Self>, key: &str, meta: &str) -> io::Result<()> {
+        let StagedMemory { store, value } = *self;
+
+        let mut values = store.values.write().unwrap();
+        values.insert(key.to_owned(), (meta.to_owned(), Shared(Arc::new(value))));
+        Ok(())
+    }
+}
+
+/// moves a temporary file to `path`
+pub(crate) fn persist(tmpfile: NamedTempFile, path: &Path) -> io::Result<File> {
+    tmpfile
+        .persist(path)
+        .map_err(|_| Error::new(ErrorKind::Other, "could not move temporary file"))
+}
+
+/// metadata of a `.sum` file, without the key line
+fn strip_key(sum: &str) -> String {
+    sum.lines()
+        .filter(|line| !line.starts_with("key="))
+        .map(|line| format!("{}\n", line))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn read(store: &dyn BlobStore, key: &str) -> Option<(String, String)> {
+        store.get(key).unwrap().map(|mut blob| {
+            let mut value = String::new();
+            blob.value.read_to_string(&mut value).unwrap();
+            assert_eq!(blob.len, value.len() as u64);
+            (blob.meta, value)
+        })
+    }
+
+    /// checks the behaviour all engines share
+    fn check_store(store: &dyn BlobStore) {
+        let meta = "md5=00\n";
+        assert_eq!(store.put("/first", meta, &mut "one".as_bytes()).unwrap(), 3);
+        store.put("/second", meta, &mut "two".as_bytes()).unwrap();
+        store.put("/first", meta, &mut "uno".as_bytes()).unwrap();
+
+        assert_eq!(
+            read(store, "/first"),
+            Some((meta.to_owned(), "uno".to_owned()))
+        );
+        assert_eq!(read(store, "/third"), None);
+
+        let mut listed = store.list().unwrap();
+        listed.sort();
+        assert_eq!(listed, vec!["/first", "/second"]);
+
+        let stat = store.stat("/second").unwrap().unwrap();
+        assert_eq!(stat.len, 3);
+
+        let mut range = String::new();
+        let mut value = store.get_range("/first", 1, 1).unwrap().unwrap();
+        value.read_to_string(&mut range).unwrap();
+        assert_eq!(range, "n");
+
+        // values not committed are dropped
+        let mut staged = store.create().unwrap();
+        staged.write_all(b"lost").unwrap();
+        drop(staged);
+        assert_eq!(read(store, "/lost"), None);
+
+        assert!(store.set_meta("/second", "md5=11\n").unwrap());
+        assert!(!store.set_meta("/third", "md5=11\n").unwrap());
+        assert_eq!(store.stat("/second").unwrap().unwrap().meta, "md5=11\n");
+
+        assert!(store.delete("/second").unwrap());
+        assert!(!store.delete("/second").unwrap());
+        assert_eq!(read(store, "/second"), None);
+    }
+
+    #[test]
+    fn test_store_engines() {
+        check_store(&MemoryStore::new());
+
+        let data_dir = tempdir().unwrap();
+        check_store(&FileStore::new(data_dir.path(), false).unwrap());
+
+        let data_dir = tempdir().unwrap();
+        check_store(
+            &*Engine::Packed(PackedConfig::default())
+                .open(data_dir.path(), &Shutdown::default())
+                .unwrap(),
+        );
+    }
+
+    #[test]
+    fn test_store_dedup() {
+        let data_dir = tempdir().unwrap();
+        let store = FileStore::new(data_dir.path(), true).unwrap();
+        let checksum = Checksum {
+            md5: [0; 16],
+            sha256: [0xcd; 32],
+        };
+        let blob = dedup::blob_path(data_dir.path(), &checksum.sha256);
+
+        let meta = checksum.encode();
+        store.put("/first", &meta, &mut "same".as_bytes()).unwrap();
+        store.put("/second", &meta, &mut "same".as_bytes()).unwrap();
+        assert_eq!(
+            read(&store, "/second"),
+            Some((meta.clone(), "same".to_owned()))
+        );
+
+        // metadata is shared with values linked later
+        let rewrapped = format!("{}key_id=new\n", meta);
+        assert!(store.set_meta("/first", &rewrapped).unwrap());
+        store.put("/third", &meta, &mut "same".as_bytes()).unwrap();
+        assert_eq!(store.stat("/third").unwrap().unwrap().meta, rewrapped);
+
+        store.delete("/first").unwrap();
+        store.delete("/second").unwrap();
+        assert!(blob.exists());
+        store.delete("/third").unwrap();
+        assert!(!blob.exists());
+    }
+}
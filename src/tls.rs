@@ -0,0 +1,115 @@
+//! # tls
+//!
+//! Master and volume servers are served over https when a certificate and a
+//! private key (both PEM encoded) are configured. Outgoing calls to `https://`
+//! urls are verified against the system trust store, or against `ca_file` when
+//! one is given, which allows clusters running on self-signed certificates.
+//!
+//! https listeners with a CA bundle ask clients for a certificate and verify
+//! it against the bundle, clients without one are served as well. Servers with
+//! a certificate present it on their outgoing calls, so that volumes can
+//! register with master by their certificate, see
+//! [cluster](../cluster/index.html).
+//!
+//! ```sh
+//! master -p 6000 --tls-cert cert.pem --tls-key key.pem --tls-ca ca.pem
+//! ```
+
+use openssl::ssl::{SslAcceptor, SslConnector, SslFiletype, SslMethod, SslVerifyMode};
+
+use std::io::{Error, ErrorKind, Result};
+use std::sync::Mutex;
+
+/// connectors built by settings, so that certificates and bundles are read
+/// once
+static CONNECTORS: Mutex<Vec<(TlsConfig, SslConnector)>> = Mutex::new(Vec::new());
+
+/// TLS settings of a server
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TlsConfig {
+    /// PEM certificate (chain) presented to clients
+    pub cert: Option<String>,
+
+    /// PEM private key of `cert`
+    pub key: Option<String>,
+
+    /// PEM CA bundle used to verify peers on outgoing https calls and client
+    /// certificates presented to the listener
+    pub ca_file: Option<String>,
+}
+
+impl TlsConfig {
+    /// Loads certificate and key for an https listener.
+    /// returns `None` if the server should listen on plain http
+    pub(crate) fn server_config(&self) -> Result<Option<SslAcceptor>> {
+        match (&self.cert, &self.key) {
+            (Some(cert), Some(key)) => {
+                let mut builder =
+                    SslAcceptor::mozilla_intermediate_v5(SslMethod::tls()).map_err(to_io_error)?;
+                builder
+                    .set_certificate_chain_file(cert)
+                    .map_err(to_io_error)?;
+                builder
+                    .set_private_key_file(key, SslFiletype::PEM)
+                    .map_err(to_io_error)?;
+                builder.check_private_key().map_err(to_io_error)?;
+
+                // clients are asked for a certificate, those without one
+                // are served as well
+                if let Some(ref ca_file) = self.ca_file {
+                    builder.set_ca_file(ca_file).map_err(to_io_error)?;
+                    builder.set_verify(SslVerifyMode::PEER);
+                    builder
+                        .set_session_id_context(b"kalavara")
+                        .map_err(to_io_error)?;
+                }
+
+                Ok(Some(builder.build()))
+            }
+            (None, None) => Ok(None),
+            _ => Err(Error::new(
+                ErrorKind::InvalidInput,
+                "both certificate and private key are required",
+            )),
+        }
+    }
+
+    /// Connector for outgoing https calls, built once per settings
+    pub(crate) fn connector(&self) -> Result<SslConnector> {
+        let mut connectors = CONNECTORS.lock().unwrap();
+
+        if let Some((_, connector)) = connectors.iter().find(|(tls, _)| tls == self) {
+            return Ok(connector.clone());
+        }
+
+        let connector = self.build_connector()?;
+        connectors.push((self.clone(), connector.clone()));
+        Ok(connector)
+    }
+
+    /// builds a connector verifying peers against `ca_file`, if given, and
+    /// presenting `cert` to them
+    fn build_connector(&self) -> Result<SslConnector> {
+        let mut builder = SslConnector::builder(SslMethod::tls()).map_err(to_io_error)?;
+
+        if let Some(ref ca_file) = self.ca_file {
+            builder.set_ca_file(ca_file).map_err(to_io_error)?;
+        }
+
+        if let (Some(cert), Some(key)) = (&self.cert, &self.key) {
+            builder
+                .set_certificate_chain_file(cert)
+                .map_err(to_io_error)?;
+            builder
+                .set_private_key_file(key, SslFiletype::PEM)
+                .map_err(to_io_error)?;
+        }
+
+        Ok(builder.build())
+    }
+}
+
+/// converts openssl errors to io errors
+pub(crate) fn to_io_error<E: ToString>(err: E) -> Error {
+    Error::new(ErrorKind::Other, err.to_string())
+}
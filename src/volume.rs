@@ -1,8 +1,9 @@
 //! # volume server
 //!
-//! Volume server stores values in file system. For atomicity temporary files are
-//! first created in `destdir/tmp` directory and then moved to destination path.
-//! For this approach to work, `destdir/tmp` and destination path should be in same
+//! Volume server stores values in a [storage engine](../store/index.html), by
+//! default in the file system. For atomicity temporary files are first created
+//! in `destdir/tmp` directory and then moved to destination path. For this
+//! approach to work, `destdir/tmp` and destination path should be in same
 //! file system
 //!
 //! to start the volume server, run
@@ -15,30 +16,82 @@
 //! ```sh
 //! volume -p 7000 -d /tmp/kalavarastore -m http://master.server -b http://volume.server:7000
 //! ```
+//!
+//! pass `--tls-cert` and `--tls-key` to serve https, and `--tls-ca` to verify a
+//! master using a self-signed certificate. `--join-token` and `--cluster-id`
+//! have to match the settings of master.
+//!
+//! values are served at `/store/<key>` and `/bucket/<bucket>/<key>`, admin
+//! endpoints at `/admin/`. Slices of values are fetched with `Range` headers.
+//! Uploads are checked against `Content-MD5` or `X-Content-SHA256` headers and
+//! their digests are kept in a `.sum` file next to the value, which
+//! [scrubbing](../scrub/index.html) checks values against. Uploads and
+//! deletes with a `replicas` query param are forwarded to the listed volume
+//! servers once done locally, if master signed the list or, in clusters
+//! without a join token, the replicas serve the cluster id of this volume, see
+//! [cluster](../cluster/index.html). Uploads failing on a replica are removed
+//! from all volumes again. Uploads with a `size` param have to be of that
+//! length. Values are
+//! [compressed](../compress/index.html) with `--compression` and
+//! [encrypted](../encryption/index.html) with `--encryption-key-file`, and
+//! [deduplicated](../dedup/index.html) with `--dedup`. `--engine packed`
+//! stores values in [segment files](../packed/index.html), `--engine memory`
+//! keeps them in memory only.
+//!
+//! programs run volumes in process with `VolumeBuilder`, see
+//! [server](../server/index.html).
 
-use md5::compute as compute_md5;
-use tempfile::NamedTempFile;
-use tiny_http::{Request, Response};
-
-use std::fs::{create_dir_all, remove_file, File};
-use std::io::{copy, Error, ErrorKind, Read};
-use std::net::SocketAddr;
+use std::collections::HashSet;
+use std::fs::create_dir_all;
+use std::io::{self, copy, Error, ErrorKind, Read, Seek, Write};
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
-use std::thread;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
 
-use crate::{Respond, Service};
+use crate::checksum::{Checksum, DigestReader, Expected, SIZE_PARAM};
+use crate::cluster::{ClusterConfig, JOIN_TOKEN_HEADER, REPLICAS_SIG_PARAM};
+use crate::compress::{self, Encoding, COMPRESSION_PARAM};
+use crate::encryption::{self, Decryptor, Encryptor, Key, KeyRing};
+use crate::http;
+use crate::limit::{self, LimitConfig};
+use crate::listener::{Method, Request};
+use crate::range;
+use crate::scrub::{Reporter, ScrubConfig, Scrubber};
+use crate::server::{self, ServerHandle, Shutdown};
+use crate::store::{Blob, BlobStore, Engine};
+use crate::tls::TlsConfig;
+use crate::{get_header, get_key, get_param};
+use crate::{Respond, Service, ADMIN_PREFIX, BUCKET_PREFIX, STORE_PREFIX};
 
 /// volume store
 struct Volume {
-    /// directory to store file blobs
-    data_dir: Arc<String>,
+    /// storage engine holding values
+    blobs: Arc<dyn BlobStore>,
+
+    /// cluster membership settings
+    cluster: ClusterConfig,
+
+    /// TLS settings for forwarding to replicas
+    tls: TlsConfig,
+
+    /// compression of values uploaded without `?compression=`
+    compression: Option<Encoding>,
+
+    /// master keys, values are encrypted if given
+    keys: Option<KeyRing>,
+
+    /// held for reading while values are stored or removed, and for
+    /// writing while the key of one is rewrapped
+    sum_lock: RwLock<()>,
+
+    /// replicas found serving the cluster id of this volume
+    peers: RwLock<HashSet<String>>,
 }
 
 /// Types of responses that master generates
 enum ResponseKind {
-    /// Path to file blob
-    FilePath(PathBuf),
+    /// Stored value, with the data key of encrypted values
+    Value(Blob, Option<Key>),
 
     /// Value saved
     Created,
@@ -46,11 +99,29 @@ enum ResponseKind {
     /// Value deleted
     Deleted,
 
+    /// 200
+    Ok(String),
+
+    /// Invalid upload, 400
+    BadRequest(String),
+
+    /// Missing or invalid join token, 401
+    Unauthorized,
+
+    /// Replicas not signed by master, 403
+    Forbidden(String),
+
+    /// Path not found, 404
+    NotFound,
+
     /// Error occured, 500
     ServerError,
 
     /// Method not allowed, 405
     NotAllowed,
+
+    /// Replica failed, 502
+    BadGateway(String),
 }
 
 impl Default for ResponseKind {
@@ -64,36 +135,478 @@ impl Respond for ResponseKind {
         use ResponseKind::*;
 
         let _ = match self {
-            FilePath(path) => match File::open(path) {
-                Ok(file) => req.respond(Response::from_file(file)),
-                Err(_) => req.respond(resp!("Server Error", 500)),
-            },
+            Value(blob, key) => respond_stored(req, blob.value, blob.len, key, &blob.meta),
             Created => req.respond(resp!("Created", 201)),
             Deleted => req.respond(resp!("Deleted", 204)),
+            Ok(txt) => req.respond(resp!(txt, 200)),
+            BadRequest(txt) => req.respond(resp!(txt, 400)),
+            Unauthorized => req.respond(resp!("Unauthorized", 401)),
+            Forbidden(txt) => req.respond(resp!(txt, 403)),
+            NotFound => req.respond(resp!("Path not found", 404)),
             ServerError => req.respond(resp!("Server error", 500)),
             NotAllowed => req.respond(resp!("Method not allowd", 405)),
+            BadGateway(txt) => req.respond(resp!(txt, 502)),
         };
     }
 }
 
+/// path of the file holding digests and key of a value
+pub(crate) fn checksum_path(path: &Path) -> PathBuf {
+    path.with_extension("sum")
+}
+
+/// sends a stored value of `len` bytes along with its digests, decrypting
+/// and decompressing it as needed. compressed values are sent as stored to
+/// clients accepting their encoding, ranges are served from the decompressed
+/// value
+fn respond_stored(
+    req: Request,
+    value: impl Read + Seek + Send + 'static,
+    len: u64,
+    key: Option<Key>,
+    stored: &str,
+) -> io::Result<()> {
+    let headers = Checksum::decode(stored)
+        .map(|checksum| checksum.headers())
+        .unwrap_or_default();
+    let headers = &headers;
+    let compressed = compress::decode_stored(stored);
+
+    // plain values are read directly, seeking to ranges
+    if key.is_none() && compressed.is_none() {
+        return range::respond_file(req, value, len, headers);
+    }
+
+    let (stored, len) = open_stored(value, len, key)?;
+
+    let (encoding, size) = match compressed {
+        Some(compressed) => compressed,
+        None => return range::respond_stream(req, stored, len, headers),
+    };
+
+    let accepted = get_header(&req, "Range").is_none()
+        && get_header(&req, "Accept-Encoding")
+            .map_or(false, |header| compress::accepts(&header, encoding));
+
+    if accepted {
+        let headers = [
+            ("Content-Encoding", encoding.name().to_owned()),
+            ("Vary", "Accept-Encoding".to_owned()),
+        ];
+        return range::respond_stream(req, stored, len, &headers);
+    }
+
+    match compress::decompress(encoding, stored) {
+        Ok(value) => range::respond_stream(req, value, size, headers),
+        Err(_) => req.respond(resp!("Server error", 500)),
+    }
+}
+
+/// stored bytes of a value, decrypted if it is encrypted, and their length
+fn open_stored(
+    value: impl Read + Send + 'static,
+    len: u64,
+    key: Option<Key>,
+) -> io::Result<(Box<dyn Read + Send>, u64)> {
+    Ok(match key {
+        Some(key) => (
+            Box::new(Decryptor::new(value, &key)),
+            encryption::plain_size(len),
+        ),
+        None => (Box::new(value), len),
+    })
+}
+
+/// writes a value to `dest`, compressed if asked. returns the size of the value
+fn write_value(
+    value: &mut impl Read,
+    mut dest: impl Write,
+    compression: Option<Encoding>,
+) -> io::Result<u64> {
+    match compression {
+        Some(encoding) => compress::compress(encoding, value, dest),
+        None => copy(value, &mut dest),
+    }
+}
+
 impl Volume {
     /// Create new volume service
-    fn new(data_dir: String) -> Self {
+    fn new(
+        blobs: Arc<dyn BlobStore>,
+        cluster: ClusterConfig,
+        tls: TlsConfig,
+        compression: Option<Encoding>,
+        keys: Option<KeyRing>,
+    ) -> Self {
         Self {
-            data_dir: Arc::new(data_dir),
+            blobs,
+            cluster,
+            tls,
+            compression,
+            keys,
+            sum_lock: RwLock::new(()),
+            peers: RwLock::new(HashSet::new()),
+        }
+    }
+
+    /// data key of a value stored with `stored` metadata, `None` if it is
+    /// not encrypted
+    fn data_key(&self, stored: &str) -> io::Result<Option<Key>> {
+        match self.keys {
+            Some(ref keys) => keys.data_key(stored),
+            None if encryption::is_encrypted(stored) => Err(Error::new(
+                ErrorKind::Other,
+                "value is encrypted, no key file given",
+            )),
+            None => Ok(None),
+        }
+    }
+
+    /// opens a stored value for reading, decrypted and decompressed. returns
+    /// it with its size
+    fn open_value(&self, key: &str) -> io::Result<(Box<dyn Read>, u64)> {
+        let blob = match self.blobs.get(key)? {
+            Some(blob) => blob,
+            None => return Err(Error::new(ErrorKind::NotFound, "no such value")),
+        };
+
+        let data_key = self.data_key(&blob.meta)?;
+        let (value, len) = open_stored(blob.value, blob.len, data_key)?;
+
+        match compress::decode_stored(&blob.meta) {
+            Some((encoding, size)) => Ok((compress::decompress(encoding, value)?, size)),
+            None => Ok((value, len)),
+        }
+    }
+
+    /// Stores a value, verifying it against expected digests
+    fn store(
+        &self,
+        key: &str,
+        value: impl Read,
+        expected: &Expected,
+        compression: Option<Encoding>,
+    ) -> ResponseKind {
+        let mut value = DigestReader::new(value);
+
+        let mut staged = match self.blobs.create() {
+            Ok(staged) => staged,
+            Err(_) => return ResponseKind::ServerError,
+        };
+
+        let sealed = match self.keys {
+            Some(ref keys) => keys
+                .new_data_key()
+                .map(|(data_key, lines)| (Some(data_key), lines)),
+            None => Ok((None, String::new())),
+        };
+        let (data_key, sealed) = match sealed {
+            Ok(sealed) => sealed,
+            Err(_) => return ResponseKind::ServerError,
+        };
+
+        // values are compressed before they are encrypted, so that no
+        // plaintext reaches the disk
+        let length = match data_key {
+            Some(data_key) => {
+                let mut encryptor = Encryptor::new(&mut staged, &data_key);
+                let length = write_value(&mut value, &mut encryptor, compression);
+                length.and_then(|length| encryptor.finish().map(|_| length))
+            }
+            None => write_value(&mut value, &mut staged, compression),
+        };
+        let length = match length {
+            Ok(length) => length,
+            Err(_) => return ResponseKind::ServerError,
+        };
+
+        // rejected uploads are dropped with the staged value
+        let checksum = value.finish();
+        if let Err(e) = expected.verify(length, &checksum) {
+            return ResponseKind::BadRequest(e);
+        }
+
+        let stored = compression
+            .map(|encoding| compress::encode_stored(encoding, length))
+            .unwrap_or_default();
+        let meta = format!("{}{}{}", stored, sealed, checksum.encode());
+
+        let _sums = self.sum_lock.read().unwrap();
+        match staged.commit(key, &meta) {
+            Ok(_) => ResponseKind::Created,
+            Err(_) => ResponseKind::ServerError,
+        }
+    }
+
+    /// Handles requests to admin endpoints
+    fn admin(&self, path: &str, method: &Method, token: Option<String>) -> ResponseKind {
+        match (path, method) {
+            ("cluster-id", &Method::Get) => ResponseKind::Ok(self.cluster.id().to_owned()),
+            ("rewrap-keys", &Method::Post) | ("compact", &Method::Post) => {
+                // clusters with a join token expect it from admins as well
+                let token = token.as_ref().map(String::as_str);
+                if self.cluster.join_token.is_some() && !self.cluster.verify_token(token) {
+                    return ResponseKind::Unauthorized;
+                }
+
+                match path {
+                    "compact" => self.compact(),
+                    _ => self.rewrap_keys(),
+                }
+            }
+            ("cluster-id", _) | ("rewrap-keys", _) | ("compact", _) => ResponseKind::NotAllowed,
+            (_, _) => ResponseKind::NotFound,
+        }
+    }
+
+    /// rewrites storage of the engine without deleted values
+    fn compact(&self) -> ResponseKind {
+        match self.blobs.compact() {
+            Ok(compacted) => ResponseKind::Ok(format!("{} segments compacted", compacted)),
+            Err(e) => {
+                println!("compaction failed: {}", e);
+                ResponseKind::ServerError
+            }
+        }
+    }
+
+    /// wraps data keys of all stored values with the current master key
+    fn rewrap_keys(&self) -> ResponseKind {
+        let keys = match self.keys {
+            Some(ref keys) => keys,
+            None => return ResponseKind::BadRequest("values are not encrypted".to_owned()),
+        };
+
+        let rewrap = |key: &str| -> io::Result<bool> {
+            // values replaced meanwhile keep their new key
+            let _sums = self.sum_lock.write().unwrap();
+
+            let stat = match self.blobs.stat(key)? {
+                Some(stat) => stat,
+                None => return Ok(false),
+            };
+
+            match keys.rewrap(&stat.meta)? {
+                Some(meta) => self.blobs.set_meta(key, &meta),
+                None => Ok(false),
+            }
+        };
+
+        let mut rewrapped = 0;
+        let done = self.blobs.list().and_then(|stored| {
+            for key in stored {
+                if rewrap(&key)? {
+                    rewrapped += 1;
+                }
+            }
+            Ok(())
+        });
+
+        match done {
+            Ok(_) => ResponseKind::Ok(format!("{} keys rewrapped", rewrapped)),
+            Err(e) => {
+                println!("rewrapping keys failed: {}", e);
+                ResponseKind::ServerError
+            }
         }
     }
 
-    /// Calcualtes destination file path from key
-    fn key_to_path(&self, key: &str) -> PathBuf {
-        let path = format!("{:x}", compute_md5(key.as_bytes()));
+    /// checks that a volume may forward to `replicas`. master signs them in
+    /// clusters with a join token, otherwise they have to serve the cluster
+    /// id of this volume
+    fn authorize_replicas(&self, replicas: &[String], sig: Option<&str>) -> bool {
+        if self.cluster.join_token.is_some() {
+            return self.cluster.verify_replicas(&replicas.join(","), sig);
+        }
+
+        replicas.iter().all(|replica| {
+            if self.peers.read().unwrap().contains(replica) {
+                return true;
+            }
 
-        let mut dest_path = PathBuf::from(self.data_dir.as_ref());
-        dest_path.push(path.get(0..1).unwrap());
-        dest_path.push(path.get(1..2).unwrap());
-        dest_path.push(path.get(2..).unwrap());
+            let url = format!("{}{}cluster-id", replica, ADMIN_PREFIX);
+            let serving = match http::request("GET", &url, &[], b"", &self.tls) {
+                Ok(ref res) => res.status_code == 200 && res.text() == self.cluster.id(),
+                Err(_) => false,
+            };
 
-        dest_path
+            if serving {
+                self.peers.write().unwrap().insert(replica.clone());
+            }
+            serving
+        })
+    }
+
+    /// Forwards an upload to replica volume servers, the value is read back
+    /// from the store. If a replica fails, the value is removed from this
+    /// volume and the replicas that took it, so that none is left holding it
+    fn replicate_upload(
+        &self,
+        path: &str,
+        key: &str,
+        replicas: &[String],
+        compression: Option<Encoding>,
+    ) -> ResponseKind {
+        // replicas verify the value against digests computed here
+        let stored = match self.blobs.stat(key) {
+            Ok(Some(stat)) => stat.meta,
+            _ => String::new(),
+        };
+        let checksum = Checksum::decode(&stored)
+            .map(|checksum| checksum.headers())
+            .unwrap_or_default();
+        let headers: Vec<(&str, &str)> = checksum
+            .iter()
+            .map(|(field, value)| (*field, value.as_str()))
+            .collect();
+
+        for (indx, replica) in replicas.iter().enumerate() {
+            // replicas compress the value the same way
+            let url = format!(
+                "{}{}?{}={}",
+                replica,
+                path,
+                COMPRESSION_PARAM,
+                compression.map_or("none", Encoding::name)
+            );
+            let res = self.open_value(key).and_then(|(mut value, length)| {
+                http::request_stream("PUT", &url, &headers, &mut value, length, &self.tls)
+            });
+
+            match res {
+                Ok(ref res) if res.status_code < 300 => {}
+                _ => {
+                    self.delete(key.to_owned());
+                    for replica in &replicas[..indx] {
+                        let url = format!("{}{}", replica, path);
+                        let _ = http::request("DELETE", &url, &[], b"", &self.tls);
+                    }
+
+                    return ResponseKind::BadGateway(format!(
+                        "upload to replica {} failed",
+                        replica
+                    ));
+                }
+            }
+        }
+
+        ResponseKind::Created
+    }
+
+    /// Forwards a delete to replica volume servers, whether or not this
+    /// volume held the value. Replicas failing are reported
+    fn replicate_delete(
+        &self,
+        path: &str,
+        replicas: &[String],
+        resp: ResponseKind,
+    ) -> ResponseKind {
+        let mut deleted = match resp {
+            ResponseKind::Deleted => true,
+            ResponseKind::NotFound => false,
+            resp => return resp,
+        };
+        let mut failed = Vec::new();
+
+        for replica in replicas {
+            let url = format!("{}{}", replica, path);
+
+            match http::request("DELETE", &url, &[], b"", &self.tls) {
+                Ok(ref res) if res.status_code < 300 => deleted = true,
+                Ok(ref res) if res.status_code == 404 => {}
+                _ => failed.push(replica.as_str()),
+            }
+        }
+
+        match (failed.is_empty(), deleted) {
+            (false, _) => {
+                ResponseKind::BadGateway(format!("delete on replicas {} failed", failed.join(",")))
+            }
+            (true, true) => ResponseKind::Deleted,
+            (true, false) => ResponseKind::NotFound,
+        }
+    }
+
+    fn dispatch(&self, mut req: Request) {
+        let url = req.url().to_owned();
+
+        // keys of default bucket are hashed with a leading slash, as volumes used
+        // to be addressed with `/<key>`, to keep the layout of existing data directories.
+        // bucket keys never start with a slash
+        let key = if url.starts_with(STORE_PREFIX) {
+            format!("/{}", get_key(&url, STORE_PREFIX))
+        } else if url.starts_with(BUCKET_PREFIX) {
+            get_key(&url, BUCKET_PREFIX)
+        } else if url.starts_with(ADMIN_PREFIX) {
+            let path = get_key(&url, ADMIN_PREFIX);
+            let token = get_header(&req, JOIN_TOKEN_HEADER);
+            return self.admin(&path, req.method(), token).respond(req);
+        } else {
+            return ResponseKind::NotFound.respond(req);
+        };
+
+        let replicas: Vec<String> = get_param(&url, "replicas")
+            .map(|replicas| {
+                replicas
+                    .split(',')
+                    .filter(|replica| !replica.is_empty())
+                    .map(str::to_owned)
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let path = &url[..url.find('?').unwrap_or_else(|| url.len())];
+        let method = req.method().clone();
+
+        // bodies are only forwarded to volumes master placed the value on
+        let sig = get_param(&url, REPLICAS_SIG_PARAM);
+        if !replicas.is_empty() && !self.authorize_replicas(&replicas, sig.as_deref()) {
+            return ResponseKind::Forbidden("replicas not signed by master".to_owned())
+                .respond(req);
+        }
+
+        let resp = match method {
+            Method::Post | Method::Put => {
+                let compression = match get_param(&url, COMPRESSION_PARAM) {
+                    Some(setting) => compress::parse_setting(&setting),
+                    None => Ok(self.compression),
+                };
+
+                // uploads redirected by master are held to the accounted size,
+                // longer bodies are cut off one byte past it and rejected
+                let expected = Expected::from_request(&req).and_then(|expected| {
+                    match get_param(&url, SIZE_PARAM) {
+                        Some(size) => match size.parse::<u64>() {
+                            Ok(size) => expected.with_size(size),
+                            Err(_) => Err(format!("invalid {} {}", SIZE_PARAM, size)),
+                        },
+                        None => Ok(expected),
+                    }
+                });
+
+                let resp = match (expected, &compression) {
+                    (Ok(expected), Ok(compression)) => {
+                        let value = req.as_reader().take(expected.limit());
+                        self.store(&key, value, &expected, *compression)
+                    }
+                    (Err(e), _) => ResponseKind::BadRequest(e),
+                    (_, Err(e)) => ResponseKind::BadRequest(e.to_owned()),
+                };
+
+                match (resp, compression) {
+                    (ResponseKind::Created, Ok(compression)) if !replicas.is_empty() => {
+                        self.replicate_upload(path, &key, &replicas, compression)
+                    }
+                    (resp, _) => resp,
+                }
+            }
+            Method::Delete if !replicas.is_empty() => {
+                self.replicate_delete(path, &replicas, self.delete(key.clone()))
+            }
+            _ => return Service::dispatch_key(self, key, req),
+        };
+
+        resp.respond(req);
     }
 }
 
@@ -102,50 +615,224 @@ impl Service for Volume {
 
     /// Get value of a key from store
     fn get(&self, key: String) -> Self::Response {
-        let dest_path = self.key_to_path(&key);
-        ResponseKind::FilePath(dest_path)
+        match self.blobs.get(&key) {
+            Ok(Some(blob)) => match self.data_key(&blob.meta) {
+                Ok(data_key) => ResponseKind::Value(blob, data_key),
+                Err(_) => ResponseKind::ServerError,
+            },
+            Ok(None) => ResponseKind::NotFound,
+            Err(_) => ResponseKind::ServerError,
+        }
     }
 
     /// Save/Update key in store
-    fn save(&self, key: String, mut value: impl Read) -> Self::Response {
-        let tmpdir = Path::new(self.data_dir.as_ref()).join("tmp");
-        let dest_path = self.key_to_path(&key);
-
-        match NamedTempFile::new_in(tmpdir) {
-            Ok(mut tmpfile) => {
-                // copy data to file
-                match copy(&mut value, &mut tmpfile)
-                    .and(create_dir_all(dest_path.parent().unwrap()))
-                    .and(
-                        tmpfile
-                            .persist(dest_path)
-                            .map_err(|_| Error::new(ErrorKind::Other, "")),
-                    ) {
-                    Ok(_) => ResponseKind::Created,
-                    _ => ResponseKind::ServerError,
-                }
-            }
-            Err(_) => ResponseKind::ServerError,
-        }
+    fn save(&self, key: String, value: impl Read, _length: Option<u64>) -> Self::Response {
+        self.store(&key, value, &Expected::default(), self.compression)
     }
 
     /// Remove a key from store
     fn delete(&self, key: String) -> Self::Response {
-        let dest_path = self.key_to_path(&key);
-
-        match remove_file(dest_path) {
-            Ok(_) => ResponseKind::Deleted,
+        let _sums = self.sum_lock.read().unwrap();
+        match self.blobs.delete(&key) {
+            Ok(true) => ResponseKind::Deleted,
+            Ok(false) => ResponseKind::NotFound,
             Err(_) => ResponseKind::ServerError,
         }
     }
 }
 
-/// starts a kalavara volume server
+/// Builds volume servers, to run them in process
+#[derive(Clone)]
+pub struct VolumeBuilder {
+    port: u16,
+    data_dir: String,
+    threads: u16,
+    master: Option<String>,
+    base: Option<String>,
+    cluster: ClusterConfig,
+    tls: TlsConfig,
+    limits: LimitConfig,
+    scrub: ScrubConfig,
+    compression: Option<Encoding>,
+    keys: Option<KeyRing>,
+    engine: Engine,
+}
+
+impl VolumeBuilder {
+    /// volume storing values in `data_dir`, listening at port 7000
+    pub fn new(data_dir: &str) -> Self {
+        VolumeBuilder {
+            port: 7000,
+            data_dir: data_dir.to_owned(),
+            threads: num_cpus::get() as u16,
+            master: None,
+            base: None,
+            cluster: ClusterConfig::default(),
+            tls: TlsConfig::default(),
+            limits: LimitConfig::default(),
+            scrub: ScrubConfig::default(),
+            compression: None,
+            keys: None,
+            engine: Engine::default(),
+        }
+    }
+
+    /// Port to listen at, 0 picks a free one
+    pub fn port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
+    /// Number of requests handled at once, defaults to number of cpu cores
+    pub fn threads(mut self, threads: u16) -> Self {
+        self.threads = threads;
+        self
+    }
+
+    /// url of master server to register at
+    pub fn master(mut self, master: &str) -> Self {
+        self.master = Some(master.to_owned());
+        self
+    }
+
+    /// base url to register with master, defaults to the url of the server
+    /// on this host
+    pub fn base(mut self, base: &str) -> Self {
+        self.base = Some(base.to_owned());
+        self
+    }
+
+    /// Cluster id and join token presented to master
+    pub fn cluster(mut self, cluster: ClusterConfig) -> Self {
+        self.cluster = cluster;
+        self
+    }
+
+    /// TLS settings for the listener and the registration call
+    pub fn tls(mut self, tls: TlsConfig) -> Self {
+        self.tls = tls;
+        self
+    }
+
+    /// Rate limits and request queue length
+    pub fn limits(mut self, limits: LimitConfig) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Rate and interval of background checks of stored values
+    pub fn scrub(mut self, scrub: ScrubConfig) -> Self {
+        self.scrub = scrub;
+        self
+    }
+
+    /// Compression of values uploaded without `?compression=`
+    pub fn compression(mut self, compression: Option<Encoding>) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Master keys to encrypt values with
+    pub fn keys(mut self, keys: KeyRing) -> Self {
+        self.keys = Some(keys);
+        self
+    }
+
+    /// Storage engine holding values
+    pub fn engine(mut self, engine: Engine) -> Self {
+        self.engine = engine;
+        self
+    }
+
+    /// starts the server, returns once it accepts requests and is
+    /// registered with master
+    pub fn start(self) -> io::Result<ServerHandle> {
+        let server = server::bind(self.port, &self.tls, self.limits.connections)?;
+        let https = self.tls.cert.is_some();
+        let addr = server.server_addr();
+        let shutdown = Shutdown::default();
+
+        // opens the storage engine, creating the data directory
+        let blobs = self.engine.open(Path::new(&self.data_dir), &shutdown)?;
+
+        let base = self.base.unwrap_or_else(|| {
+            let scheme = if https { "https" } else { "http" };
+            format!("{}://localhost:{}", scheme, addr.port())
+        });
+
+        if let Some(rate) = self.scrub.rate {
+            // corrupt values are reported to master this volume registers with
+            let token = self.cluster.join_token.clone();
+            let tls = self.tls.clone();
+            let reporter = self.master.as_ref().map(|master| Reporter {
+                master: master.clone(),
+                base: base.clone(),
+                token,
+                tls,
+            });
+
+            let scrubber = Scrubber::new(&self.data_dir, Some(rate), reporter, self.keys.clone());
+            let interval = Duration::from_secs(self.scrub.interval);
+            let signal = shutdown.clone();
+            shutdown.spawn(move || scrubber.run(interval, &signal));
+        }
+
+        let volume = Volume::new(
+            blobs,
+            self.cluster.clone(),
+            self.tls.clone(),
+            self.compression,
+            self.keys,
+        );
+        // large uploads are spooled next to the values they become
+        let spool = Path::new(&self.data_dir).join("tmp");
+        create_dir_all(&spool)?;
+
+        let workers = limit::serve(
+            server,
+            self.threads,
+            self.limits,
+            &spool,
+            &shutdown,
+            move |rq| volume.dispatch(rq),
+        );
+
+        // server is shut down along with the handle if registration fails
+        let handle = ServerHandle::new(addr, https, shutdown, workers);
+
+        // register at master. workers are already running as master calls back
+        // to verify this volume
+        if let Some(master) = self.master {
+            let token = self.cluster.join_token.unwrap_or_default();
+            let resp = http::request(
+                "POST",
+                &format!("{}{}", master, "/admin/add-volume"),
+                &[(JOIN_TOKEN_HEADER, token.as_str())],
+                base.as_bytes(),
+                &self.tls,
+            )?;
+
+            if resp.status_code != 200 {
+                return Err(Error::new(
+                    ErrorKind::Other,
+                    format!("could not register with master: {}", resp.text()),
+                ));
+            }
+            println!("Successfully registered with master");
+        }
+
+        Ok(handle)
+    }
+}
+
+/// starts a kalavara volume server and serves until it is shut down, see
+/// [`VolumeBuilder`](struct.VolumeBuilder.html) for further settings and to
+/// embed one. Fails if `master` is given without `base`
 /// # Arguments
 ///
 /// * `port` - Port name to listen at
 /// * `data_dir` - Storage directory
-/// * `threads` - Number of threads to spawn
+/// * `threads` - Number of requests handled at once
 /// * `master` - url of master server to register at
 /// * `base` -  base url of server to register with master
 ///
@@ -155,51 +842,20 @@ pub fn start(
     threads: u16,
     master: Option<String>,
     base: Option<String>,
-) {
-    let addr: SocketAddr = ([0, 0, 0, 0], port).into();
-    let server = Arc::new(tiny_http::Server::http(addr).unwrap());
-    let mut handles = Vec::new();
+) -> io::Result<()> {
+    let mut builder = VolumeBuilder::new(&data_dir).port(port).threads(threads);
 
-    // creates data directory. files are initially created in tmp dir then moved to corresponding
-    // path
-    if create_dir_all(Path::new(&data_dir).join("tmp")).is_err() {
-        panic!("Could not create data dir. exiting\n");
-    }
-
-    // register at master
     match (master, base) {
-        (Some(master), Some(base)) => {
-            let resp = minreq::post(format!("{}{}", master, "/admin/add-volume"))
-                .with_body(base)
-                .send();
-
-            match resp {
-                Ok(ref res) if res.status_code == 200 => {
-                    println!("Successfully registered with master");
-                }
-                _ => {
-                    panic!("Could not register with master");
-                }
-            };
+        (Some(master), Some(base)) => builder = builder.master(&master).base(&base),
+        (Some(_), None) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "base url required to register with master",
+            ))
         }
-        (Some(_), _) => panic!("Host required"),
-        (_, _) => {} // skip if only host is provided
-    };
-
-    let volume = Arc::new(Volume::new(data_dir));
-
-    for _ in 0..threads {
-        let server = server.clone();
-        let handler = volume.clone();
-
-        handles.push(thread::spawn(move || {
-            for rq in server.incoming_requests() {
-                handler.dispatch(rq);
-            }
-        }));
+        (None, _) => {} // skip if only host is provided
     }
 
-    for h in handles {
-        h.join().unwrap();
-    }
+    builder.start()?.join();
+    Ok(())
 }
@@ -0,0 +1,373 @@
+//! # webhooks
+//!
+//! Master posts changes of keys to webhooks subscribed to a key prefix, to
+//! trigger processing once values land. Webhooks are created (or updated)
+//! with
+//!
+//! ```sh
+//! curl -XPOST -d "name=thumbs&bucket=images&prefix=uploads/&events=put&secret=s3cr3t&url=http://pipeline:8080/hook" \
+//!     http://localhost:6000/admin/create-webhook
+//! ```
+//!
+//! * `bucket` - bucket of the keys, the default bucket if not given
+//! * `prefix` - key prefix, all keys if not given
+//! * `events` - `put`, `delete` or both separated by comma (default)
+//! * `secret` - key of the signature, required
+//! * `attempts` - deliveries of a change before it is given up, 5 by default
+//! * `backoff` - seconds to wait before the first retry, doubled for each
+//!   further one, 1 by default
+//! * `url` - where changes are posted, comes last and is taken as is
+//!
+//! Changes committed after a webhook is created are posted in order, one at
+//! a time, from a background thread of master. The body is the change as
+//! served by [watches](../changes/index.html), `X-Webhook-Event` holds the
+//! operation and `X-Webhook-Delivery` the webhook name and sequence number,
+//! which stay the same on retries. `X-Webhook-Signature` is
+//! `sha256=<hex HMAC-SHA256 of the body keyed with the secret>`, see
+//! [`signature`](fn.signature.html).
+//!
+//! deliveries answered with anything but 2xx are retried. Changes still
+//! failing after all attempts are moved to the dead letters and delivery goes
+//! on with the next change. `GET /admin/dead-letters` lists them and
+//! `POST /admin/retry-dead-letters` delivers them again, those of a webhook
+//! if its name is given. `GET /admin/webhooks` lists webhooks and
+//! `POST /admin/delete-webhook` removes one along with its dead letters.
+
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+
+use std::time::Duration;
+
+use crate::bucket::valid_name;
+use crate::changes::{Change, Filter, Op};
+use crate::checksum::to_hex;
+use crate::http;
+use crate::tls::TlsConfig;
+use crate::{encode_param, parse_params};
+
+/// header holding the signature of a delivery
+pub const SIGNATURE_HEADER: &str = "X-Webhook-Signature";
+
+/// header holding the operation of the change delivered
+pub const EVENT_HEADER: &str = "X-Webhook-Event";
+
+/// header identifying a delivery, `<webhook>/<sequence number>`
+pub const DELIVERY_HEADER: &str = "X-Webhook-Delivery";
+
+/// seconds a webhook has to answer
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// longest wait between retries
+const MAX_BACKOFF: Duration = Duration::from_secs(3600);
+
+/// Subscription to changes of keys under a prefix
+#[derive(Clone, Debug, PartialEq)]
+pub struct Webhook {
+    pub name: String,
+
+    /// bucket name, empty for default bucket
+    pub bucket: String,
+
+    pub prefix: String,
+
+    /// operations delivered
+    pub events: Vec<Op>,
+
+    /// key of signatures
+    pub secret: String,
+
+    /// deliveries of a change before it is dead lettered
+    pub attempts: u32,
+
+    /// wait before the first retry
+    pub backoff: Duration,
+
+    /// where changes are posted
+    pub url: String,
+}
+
+impl Webhook {
+    /// Parses webhook settings from `name=value&..` pairs, `url` last and
+    /// taken as is
+    pub fn parse(settings: &str) -> Result<Webhook, String> {
+        let settings = settings.trim();
+        let indx = if settings.starts_with("url=") {
+            0
+        } else {
+            settings
+                .find("&url=")
+                .map(|indx| indx + 1)
+                .ok_or_else(|| "url required".to_owned())?
+        };
+
+        let mut webhook = Webhook {
+            name: String::new(),
+            bucket: String::new(),
+            prefix: String::new(),
+            events: vec![Op::Put, Op::Delete],
+            secret: String::new(),
+            attempts: 5,
+            backoff: Duration::from_secs(1),
+            url: settings[indx + "url=".len()..].to_owned(),
+        };
+
+        for (field, value) in parse_params(&settings[..indx]) {
+            let number = || {
+                value
+                    .parse::<u64>()
+                    .map_err(|_| format!("invalid {} {}", field, value))
+            };
+
+            match field.as_str() {
+                "name" => webhook.name = value.clone(),
+                "bucket" => webhook.bucket = value.clone(),
+                "prefix" => webhook.prefix = value.clone(),
+                "events" => {
+                    webhook.events = value
+                        .split(',')
+                        .map(|event| match event {
+                            "put" => Ok(Op::Put),
+                            "delete" => Ok(Op::Delete),
+                            _ => Err(format!("invalid event {}", event)),
+                        })
+                        .collect::<Result<Vec<Op>, String>>()?;
+                }
+                "secret" => webhook.secret = value.clone(),
+                "attempts" => webhook.attempts = number()? as u32,
+                "backoff" => webhook.backoff = Duration::from_secs(number()?),
+                _ => return Err(format!("unknown setting {}", field)),
+            }
+        }
+
+        if !valid_name(&webhook.name) {
+            return Err(format!("invalid webhook name {}", webhook.name));
+        }
+        if !webhook.url.starts_with("http://") && !webhook.url.starts_with("https://") {
+            return Err(format!("invalid url {}", webhook.url));
+        }
+        if webhook.secret.is_empty() {
+            return Err("secret required".to_owned());
+        }
+        if webhook.events.is_empty() || webhook.attempts == 0 {
+            return Err("webhook would deliver nothing".to_owned());
+        }
+
+        Ok(webhook)
+    }
+
+    /// Encodes webhook in the format accepted by `parse`
+    pub fn encode(&self) -> String {
+        let events: Vec<&str> = self.events.iter().map(|op| op.name()).collect();
+
+        format!(
+            "name={}&bucket={}&prefix={}&events={}&secret={}&attempts={}&backoff={}&url={}",
+            encode_param(&self.name),
+            encode_param(&self.bucket),
+            encode_param(&self.prefix),
+            events.join(","),
+            encode_param(&self.secret),
+            self.attempts,
+            self.backoff.as_secs(),
+            self.url
+        )
+    }
+
+    /// keys whose changes are delivered
+    pub(crate) fn filter(&self) -> Filter {
+        Filter {
+            bucket: self.bucket.clone(),
+            prefix: self.prefix.clone(),
+        }
+    }
+
+    /// checks whether the operation of a change is delivered
+    pub(crate) fn wants(&self, change: &Change) -> bool {
+        self.events.contains(&change.op)
+    }
+
+    /// wait before retrying a delivery failed `attempts` times
+    pub(crate) fn backoff(&self, attempts: u32) -> Duration {
+        let factor = 1u32 << attempts.saturating_sub(1).min(16);
+        (self.backoff * factor).min(MAX_BACKOFF)
+    }
+
+    /// posts a change, returns why it failed if it did
+    pub(crate) fn deliver(&self, change: &Change, tls: &TlsConfig) -> Result<(), String> {
+        let body = change.encode();
+        let signature = signature(&self.secret, body.as_bytes());
+        let delivery = format!("{}/{}", self.name, change.seq);
+
+        let headers = [
+            ("Content-Type", "text/plain"),
+            (EVENT_HEADER, change.op.name()),
+            (DELIVERY_HEADER, delivery.as_str()),
+            (SIGNATURE_HEADER, signature.as_str()),
+        ];
+
+        let mut reader = body.as_bytes();
+        let length = body.len() as u64;
+        match http::send(
+            "POST",
+            &self.url,
+            &headers,
+            &mut reader,
+            length,
+            tls,
+            DELIVERY_TIMEOUT,
+        ) {
+            Ok((ref resp, _)) if resp.status_code / 100 == 2 => Ok(()),
+            Ok((resp, _)) => Err(format!("status {}", resp.status_code)),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+}
+
+/// signature of a delivery body, `sha256=<hex HMAC-SHA256>`. Receivers
+/// compute it over the body they got and compare
+pub fn signature(secret: &str, body: &[u8]) -> String {
+    let mac = PKey::hmac(secret.as_bytes())
+        .and_then(|key| {
+            let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+            signer.update(body)?;
+            signer.sign_to_vec()
+        })
+        .expect("hmac failed");
+
+    format!("sha256={}", to_hex(&mac))
+}
+
+/// Change given up after all delivery attempts failed
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeadLetter {
+    pub webhook: String,
+    pub attempts: u32,
+
+    /// why the last attempt failed
+    pub error: String,
+    pub change: Change,
+}
+
+impl DeadLetter {
+    /// Parses a dead letter from `webhook=..&attempts=..&error=..&` followed
+    /// by the change
+    pub fn parse(line: &str) -> Result<DeadLetter, String> {
+        let indx = line
+            .find("&seq=")
+            .ok_or_else(|| format!("no change in dead letter {}", line))?;
+
+        let mut letter = DeadLetter {
+            webhook: String::new(),
+            attempts: 0,
+            error: String::new(),
+            change: Change::parse(&line[indx + 1..])?,
+        };
+
+        for (field, value) in parse_params(&line[..indx]) {
+            match field.as_str() {
+                "webhook" => letter.webhook = value,
+                "attempts" => {
+                    letter.attempts = value
+                        .parse()
+                        .map_err(|_| format!("invalid attempts {}", value))?
+                }
+                "error" => letter.error = value,
+                _ => return Err(format!("unknown field {}", field)),
+            }
+        }
+
+        Ok(letter)
+    }
+
+    /// Encodes dead letter in the format accepted by `parse`
+    pub fn encode(&self) -> String {
+        format!(
+            "webhook={}&attempts={}&error={}&{}",
+            encode_param(&self.webhook),
+            self.attempts,
+            encode_param(&self.error),
+            self.change.encode()
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_webhook_parse() {
+        let webhook = Webhook::parse(
+            "name=thumbs&bucket=images&prefix=uploads/&events=put&secret=s3cr3t\
+             &attempts=3&backoff=2&url=http://pipeline:8080/hook?a=1&b=2",
+        )
+        .unwrap();
+
+        assert_eq!(webhook.name, "thumbs");
+        assert_eq!(webhook.bucket, "images");
+        assert_eq!(webhook.prefix, "uploads/");
+        assert_eq!(webhook.events, vec![Op::Put]);
+        assert_eq!(webhook.attempts, 3);
+        assert_eq!(webhook.url, "http://pipeline:8080/hook?a=1&b=2");
+        assert_eq!(Webhook::parse(&webhook.encode()), Ok(webhook.clone()));
+
+        // retries wait twice as long each time
+        assert_eq!(webhook.backoff(1), Duration::from_secs(2));
+        assert_eq!(webhook.backoff(3), Duration::from_secs(8));
+        assert_eq!(webhook.backoff(40), MAX_BACKOFF);
+
+        let defaults = Webhook::parse("name=all&secret=s&url=https://hook").unwrap();
+        assert_eq!(defaults.events, vec![Op::Put, Op::Delete]);
+        assert_eq!(defaults.attempts, 5);
+
+        assert!(Webhook::parse("name=all&secret=s").is_err());
+        assert!(Webhook::parse("name=all&url=http://hook").is_err());
+        assert!(Webhook::parse("name=a/b&secret=s&url=http://hook").is_err());
+        assert!(Webhook::parse("name=all&secret=s&url=ftp://hook").is_err());
+        assert!(Webhook::parse("name=all&secret=s&events=get&url=http://hook").is_err());
+        assert!(Webhook::parse("name=all&secret=s&color=red&url=http://hook").is_err());
+        assert!(Webhook::parse("name=all&secret=s&myurl=x&url=http://hook").is_err());
+
+        // settings are read back as they were set
+        let webhook = Webhook {
+            prefix: "a&b=c/%20".to_owned(),
+            secret: "a%41&url=x".to_owned(),
+            ..defaults
+        };
+        assert_eq!(Webhook::parse(&webhook.encode()), Ok(webhook));
+    }
+
+    #[test]
+    fn test_signature() {
+        // RFC 4231 test case 2
+        assert_eq!(
+            signature("Jefe", b"what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn test_dead_letter() {
+        let letter = DeadLetter {
+            webhook: "thumbs".to_owned(),
+            attempts: 5,
+            error: "status 500&seq=1\nmore".to_owned(),
+            change: Change {
+                seq: 9,
+                op: Op::Put,
+                bucket: "images".to_owned(),
+                key: "uploads/a&b".to_owned(),
+                size: 3,
+                version: None,
+            },
+        };
+
+        let line = letter.encode();
+        assert_eq!(
+            line,
+            "webhook=thumbs&attempts=5&error=status%20500%26seq%3D1%0Amore&\
+             seq=9&op=put&bucket=images&size=3&key=uploads/a&b"
+        );
+        assert_eq!(DeadLetter::parse(&line), Ok(letter));
+    }
+}
@@ -1,50 +1,53 @@
-use tempfile::tempdir;
+use tempfile::{tempdir, TempDir};
 
-use kalavara::master::start as master_start;
-use kalavara::volume::start as volume_start;
-
-use std::sync::{Once, ONCE_INIT};
-use std::thread;
-use std::time::Duration;
+use kalavara::master::MasterBuilder;
+use kalavara::server::ServerHandle;
+use kalavara::volume::VolumeBuilder;
 
 mod basic;
 
-static INIT: Once = ONCE_INIT;
+/// master not knowing its volume yet, both shut down when dropped
+struct Servers {
+    master: ServerHandle,
+    volume: ServerHandle,
+    _dirs: (TempDir, TempDir),
+}
+
+impl Servers {
+    fn url(&self, path: &str) -> String {
+        format!("{}{}", self.master.url(), path)
+    }
+}
 
-fn run() {
+fn run() -> Servers {
     let master_data_dir = tempdir().unwrap();
     let volume_data_dir = tempdir().unwrap();
 
-    thread::spawn(move || {
-        master_start(6001, master_data_dir.path().to_str().unwrap(), 4, vec![]);
-    });
-
-    thread::spawn(move || {
-        volume_start(
-            7001,
-            volume_data_dir.path().to_str().unwrap().to_owned(),
-            4,
-            None,
-            None,
-        );
-    });
-}
-
-/// Setup function that is only run once, even if called multiple times.
-/// to be called by all tests.
-fn setup() {
-    INIT.call_once(|| {
-        run();
-        thread::sleep(Duration::from_millis(1000));
-    });
+    let master = MasterBuilder::new(master_data_dir.path().to_str().unwrap())
+        .port(0)
+        .threads(4)
+        .start()
+        .unwrap();
+
+    let volume = VolumeBuilder::new(volume_data_dir.path().to_str().unwrap())
+        .port(0)
+        .threads(4)
+        .start()
+        .unwrap();
+
+    Servers {
+        master,
+        volume,
+        _dirs: (master_data_dir, volume_data_dir),
+    }
 }
 
 #[test]
 fn test_add_volume() {
-    setup();
+    let servers = run();
 
     // no volume servers registered
-    let res = minreq::put("http://localhost:6001/store/key1")
+    let res = minreq::put(servers.url("/store/key1"))
         .with_body("val1")
         .send();
 
@@ -52,8 +55,8 @@ fn test_add_volume() {
     assert_eq!(res.unwrap().status_code, 503);
 
     // add volume
-    let res = minreq::post("http://localhost:6001/admin/add-volume")
-        .with_body("http://localhost:7001")
+    let res = minreq::post(servers.url("/admin/add-volume"))
+        .with_body(servers.volume.url())
         .send();
 
     assert!(res.is_ok());
@@ -61,8 +64,8 @@ fn test_add_volume() {
     assert_eq!(res.body, "Volume added");
 
     // trying to insert again
-    let res = minreq::post("http://localhost:6001/admin/add-volume")
-        .with_body("http://localhost:7001")
+    let res = minreq::post(servers.url("/admin/add-volume"))
+        .with_body(servers.volume.url())
         .send();
 
     assert!(res.is_ok());
@@ -70,24 +73,24 @@ fn test_add_volume() {
     assert_eq!(res.body.trim(), "Skipping duplicate volume server");
 
     // api tests
-    let res = minreq::put("http://localhost:6001/store/key1")
+    let res = minreq::put(servers.url("/store/key1"))
         .with_body("val1")
         .send();
 
     assert!(res.is_ok());
     assert_eq!(res.unwrap().status_code, 201);
 
-    let res = minreq::get("http://localhost:6001/store/key1").send();
+    let res = minreq::get(servers.url("/store/key1")).send();
     assert!(res.is_ok());
     let res = res.unwrap();
     assert_eq!(res.status_code, 200);
     assert_eq!(res.body.trim(), "val1");
 
-    let res = minreq::delete("http://localhost:6001/store/key1").send();
+    let res = minreq::delete(servers.url("/store/key1")).send();
     assert!(res.is_ok());
     assert_eq!(res.unwrap().status_code, 204);
 
-    let res = minreq::get("http://localhost:6001/store/key1").send();
+    let res = minreq::get(servers.url("/store/key1")).send();
     assert!(res.is_ok());
     assert_eq!(res.unwrap().status_code, 404);
 }
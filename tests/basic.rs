@@ -1,103 +1,103 @@
-use tempfile::tempdir;
+use tempfile::{tempdir, TempDir};
 
-use kalavara::master::start as master_start;
-use kalavara::volume::start as volume_start;
+use kalavara::master::MasterBuilder;
+use kalavara::server::ServerHandle;
+use kalavara::volume::VolumeBuilder;
 
-use std::sync::{Once, ONCE_INIT};
-use std::thread;
-use std::time::Duration;
+/// master with a volume, both shut down when dropped
+struct Cluster {
+    master: ServerHandle,
+    _volume: ServerHandle,
+    _dirs: (TempDir, TempDir),
+}
 
-static INIT: Once = ONCE_INIT;
+impl Cluster {
+    fn url(&self, path: &str) -> String {
+        format!("{}{}", self.master.url(), path)
+    }
+}
 
-fn run() {
+/// starts a master and a volume at free ports
+fn run() -> Cluster {
     let master_data_dir = tempdir().unwrap();
     let volume_data_dir = tempdir().unwrap();
 
-    thread::spawn(move || {
-        master_start(
-            6000,
-            master_data_dir.path().to_str().unwrap(),
-            4,
-            vec!["http://localhost:7000".to_string()],
-        );
-    });
-
-    thread::spawn(move || {
-        volume_start(
-            7000,
-            volume_data_dir.path().to_str().unwrap().to_owned(),
-            4,
-            None,
-            None,
-        );
-    });
-}
-
-/// Setup function that is only run once, even if called multiple times.
-/// to be called by all tests.
-fn setup() {
-    INIT.call_once(|| {
-        run();
-        thread::sleep(Duration::from_millis(1000));
-    });
+    let volume = VolumeBuilder::new(volume_data_dir.path().to_str().unwrap())
+        .port(0)
+        .threads(4)
+        .start()
+        .unwrap();
+
+    let master = MasterBuilder::new(master_data_dir.path().to_str().unwrap())
+        .port(0)
+        .threads(4)
+        .volumes(vec![volume.url()])
+        .start()
+        .unwrap();
+
+    Cluster {
+        master,
+        _volume: volume,
+        _dirs: (master_data_dir, volume_data_dir),
+    }
 }
 
 #[test]
 fn test_kv() {
-    setup();
+    let cluster = run();
 
-    let res = minreq::put("http://localhost:6000/store/key1")
+    let res = minreq::put(cluster.url("/store/key1"))
         .with_body("val1")
         .send();
 
     assert!(res.is_ok());
     assert_eq!(res.unwrap().status_code, 201);
 
-    let res = minreq::get("http://localhost:6000/store/key1").send();
+    let res = minreq::get(cluster.url("/store/key1")).send();
     assert!(res.is_ok());
     let res = res.unwrap();
     assert_eq!(res.status_code, 200);
     assert_eq!(res.body, "val1");
 
-    let res = minreq::delete("http://localhost:6000/store/key1").send();
+    let res = minreq::delete(cluster.url("/store/key1")).send();
     assert!(res.is_ok());
     assert_eq!(res.unwrap().status_code, 204);
 
-    let res = minreq::get("http://localhost:6000/store/key1").send();
+    let res = minreq::get(cluster.url("/store/key1")).send();
     assert!(res.is_ok());
     assert_eq!(res.unwrap().status_code, 404);
 }
 
 #[test]
 fn test_remove_query_params() {
-    setup();
-    let res = minreq::put("http://localhost:6000/store/key2?query=value")
+    let cluster = run();
+    let res = minreq::put(cluster.url("/store/key2?query=value"))
         .with_body("val2")
         .send();
 
     assert!(res.is_ok());
     assert_eq!(res.unwrap().status_code, 201);
 
-    let res = minreq::get("http://localhost:6000/store/key2?que=valu").send();
+    let res = minreq::get(cluster.url("/store/key2?que=valu")).send();
     assert!(res.is_ok());
     let res = res.unwrap();
     assert_eq!(res.status_code, 200);
     assert_eq!(res.body, "val2");
 
-    let res = minreq::delete("http://localhost:6000/store/key2?q=v").send();
+    let res = minreq::delete(cluster.url("/store/key2?q=v")).send();
     assert!(res.is_ok());
     assert_eq!(res.unwrap().status_code, 204);
 
-    let res = minreq::get("http://localhost:6000/store/key2").send();
+    let res = minreq::get(cluster.url("/store/key2")).send();
     assert!(res.is_ok());
     assert_eq!(res.unwrap().status_code, 404);
 }
 
 #[test]
 fn test_no_prefix() {
-    setup();
+    let cluster = run();
     // without store prefix
-    let res = minreq::put("http://localhost:6000/key2?query=value")
+    let res = minreq::put(cluster.url("/key2?query=value"))
         .with_body("val2")
         .send();
 
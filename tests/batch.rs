@@ -0,0 +1,135 @@
+use tempfile::{tempdir, TempDir};
+
+use kalavara::batch::{decode_items, encode_keys, encode_values};
+use kalavara::http::request;
+use kalavara::master::MasterBuilder;
+use kalavara::server::ServerHandle;
+use kalavara::tls::TlsConfig;
+use kalavara::volume::VolumeBuilder;
+
+/// master with a volume, both shut down when dropped
+struct Cluster {
+    master: ServerHandle,
+    volume: ServerHandle,
+    _dirs: (TempDir, TempDir),
+}
+
+impl Cluster {
+    fn url(&self, path: &str) -> String {
+        format!("{}{}", self.master.url(), path)
+    }
+}
+
+/// starts a master and a volume at free ports
+fn run() -> Cluster {
+    let master_data_dir = tempdir().unwrap();
+    let volume_data_dir = tempdir().unwrap();
+
+    let volume = VolumeBuilder::new(volume_data_dir.path().to_str().unwrap())
+        .port(0)
+        .threads(4)
+        .start()
+        .unwrap();
+
+    let master = MasterBuilder::new(master_data_dir.path().to_str().unwrap())
+        .port(0)
+        .threads(4)
+        .volumes(vec![volume.url()])
+        .start()
+        .unwrap();
+
+    Cluster {
+        master,
+        volume,
+        _dirs: (master_data_dir, volume_data_dir),
+    }
+}
+
+/// sends a batch request and returns status code of each item
+fn batch(cluster: &Cluster, op: &str, body: &[u8]) -> Vec<(u16, Vec<u8>)> {
+    let resp = request(
+        "POST",
+        &cluster.url(&format!("/batch/{}", op)),
+        &[],
+        body,
+        &TlsConfig::default(),
+    )
+    .unwrap();
+
+    assert_eq!(resp.status_code, 200);
+    decode_items(&resp.body).unwrap()
+}
+
+#[test]
+fn test_batch_crud() {
+    let cluster = run();
+
+    let items = batch(
+        &cluster,
+        "put",
+        &encode_values(&[("key1", &b"value1"[..]), ("key2", &b"value2"[..])]),
+    );
+    assert_eq!(items, vec![(201, vec![]), (201, vec![])]);
+
+    let items = batch(&cluster, "get", &encode_keys(&["key1", "key3"]));
+    assert_eq!(
+        items,
+        vec![
+            (
+                307,
+                format!("{}/store/key1", cluster.volume.url()).into_bytes()
+            ),
+            (404, vec![])
+        ]
+    );
+
+    let items = batch(&cluster, "get?proxy=true", &encode_keys(&["key1", "key2"]));
+    assert_eq!(
+        items,
+        vec![(200, b"value1".to_vec()), (200, b"value2".to_vec())]
+    );
+
+    let items = batch(&cluster, "delete", &encode_keys(&["key1", "key3"]));
+    assert_eq!(items, vec![(204, vec![]), (404, vec![])]);
+
+    let items = batch(&cluster, "get?proxy=true", &encode_keys(&["key1", "key2"]));
+    assert_eq!(items, vec![(404, vec![]), (200, b"value2".to_vec())]);
+}
+
+#[test]
+fn test_batch_malformed() {
+    let cluster = run();
+
+    // values before a malformed frame are stored, the frame is reported as
+    // last item
+    let mut body = encode_values(&[("key1", &b"value1"[..])]);
+    body.extend(encode_keys(&["key2"]));
+
+    let items = batch(&cluster, "put", &body);
+    assert_eq!(items.len(), 2);
+    assert_eq!(items[0], (201, vec![]));
+    assert_eq!(items[1].0, 400);
+
+    let items = batch(&cluster, "get?proxy=true", &encode_keys(&["key1", "key2"]));
+    assert_eq!(items, vec![(200, b"value1".to_vec()), (404, vec![])]);
+
+    let resp = request(
+        "POST",
+        &cluster.url("/batch/put?ttl=soon"),
+        &[],
+        &encode_values(&[("key3", &b"value3"[..])]),
+        &TlsConfig::default(),
+    )
+    .unwrap();
+    assert_eq!(resp.status_code, 400);
+
+    let resp = request(
+        "GET",
+        &cluster.url("/batch/get"),
+        &[],
+        b"",
+        &TlsConfig::default(),
+    )
+    .unwrap();
+    assert_eq!(resp.status_code, 405);
+}
@@ -0,0 +1,146 @@
+use tempfile::{tempdir, TempDir};
+
+use kalavara::http::request;
+use kalavara::master::MasterBuilder;
+use kalavara::server::ServerHandle;
+use kalavara::tls::TlsConfig;
+use kalavara::volume::VolumeBuilder;
+
+/// master with two volumes, shut down when dropped
+struct Cluster {
+    master: ServerHandle,
+    volumes: Vec<ServerHandle>,
+    _dirs: Vec<TempDir>,
+}
+
+impl Cluster {
+    fn url(&self, path: &str) -> String {
+        format!("{}{}", self.master.url(), path)
+    }
+}
+
+fn run() -> Cluster {
+    let mut dirs = vec![];
+    let mut volumes = vec![];
+
+    for _ in 0..2 {
+        let volume_data_dir = tempdir().unwrap();
+        let volume = VolumeBuilder::new(volume_data_dir.path().to_str().unwrap())
+            .port(0)
+            .threads(4)
+            .start()
+            .unwrap();
+
+        dirs.push(volume_data_dir);
+        volumes.push(volume);
+    }
+
+    let master_data_dir = tempdir().unwrap();
+    let master = MasterBuilder::new(master_data_dir.path().to_str().unwrap())
+        .port(0)
+        .threads(4)
+        .volumes(volumes.iter().map(ServerHandle::url).collect())
+        .start()
+        .unwrap();
+    dirs.push(master_data_dir);
+
+    Cluster {
+        master,
+        volumes,
+        _dirs: dirs,
+    }
+}
+
+#[test]
+fn test_bucket_replication() {
+    let cluster = run();
+
+    let res = minreq::post(cluster.url("/admin/create-bucket"))
+        .with_body("name=logs&replicas=2")
+        .send();
+    assert_eq!(res.unwrap().body, "Bucket created");
+
+    let res = minreq::put(cluster.url("/bucket/logs/key1"))
+        .with_body("val1")
+        .send();
+    assert_eq!(res.unwrap().status_code, 201);
+
+    // both volumes hold the value
+    for volume in &cluster.volumes {
+        let url = format!("{}/bucket/logs/key1", volume.url());
+        let res = minreq::get(url).send().unwrap();
+        assert_eq!(res.status_code, 200);
+        assert_eq!(res.body, "val1");
+    }
+
+    let res = minreq::get(cluster.url("/bucket/logs/key1")).send();
+    assert_eq!(res.unwrap().body, "val1");
+
+    // same key in default bucket is a different value
+    let res = minreq::get(cluster.url("/store/logs/key1")).send();
+    assert_eq!(res.unwrap().status_code, 404);
+
+    let res = minreq::delete(cluster.url("/bucket/logs/key1")).send();
+    assert_eq!(res.unwrap().status_code, 204);
+
+    for volume in &cluster.volumes {
+        let url = format!("{}/bucket/logs/key1", volume.url());
+        assert_ne!(minreq::get(url).send().unwrap().status_code, 200);
+    }
+}
+
+#[test]
+fn test_bucket_access() {
+    let cluster = run();
+
+    let res = minreq::post(cluster.url("/admin/create-bucket"))
+        .with_body("name=private&access=private:secret")
+        .send();
+    assert_eq!(res.unwrap().status_code, 200);
+
+    let res = minreq::put(cluster.url("/bucket/private/key1"))
+        .with_body("val1")
+        .send();
+    assert_eq!(res.unwrap().status_code, 403);
+
+    let res = minreq::put(cluster.url("/bucket/private/key1"))
+        .with_header("X-Bucket-Token", "secret")
+        .with_body("val1")
+        .send();
+    assert_eq!(res.unwrap().status_code, 201);
+
+    let res = minreq::get(cluster.url("/bucket/missing/key1")).send();
+    assert_eq!(res.unwrap().status_code, 404);
+}
+
+#[test]
+fn test_replica_forwarding() {
+    let mut cluster = run();
+    let tls = TlsConfig::default();
+    let primary = cluster.volumes[0].url();
+    let replica = cluster.volumes[1].url();
+    let send = |method: &str, url: String, body: &[u8]| {
+        request(method, &url, &[], body, &tls).unwrap().status_code
+    };
+
+    // bodies are not forwarded to servers of other clusters
+    let url = format!("{}/store/key1?replicas=http://localhost:1", primary);
+    assert_eq!(send("PUT", url, b"val1"), 403);
+    assert_eq!(send("GET", format!("{}/store/key1", primary), b""), 404);
+
+    // deletes reach replicas the primary volume holds no value for
+    assert_eq!(send("PUT", format!("{}/store/key2", replica), b"val2"), 201);
+    let url = format!("{}/store/key2?replicas={}", primary, replica);
+    assert_eq!(send("DELETE", url, b""), 204);
+    assert_eq!(send("GET", format!("{}/store/key2", replica), b""), 404);
+
+    let url = format!("{}/store/key3?replicas={}", primary, replica);
+    assert_eq!(send("PUT", url.clone(), b"val3"), 201);
+    assert_eq!(send("GET", format!("{}/store/key3", replica), b""), 200);
+
+    // uploads failing on a replica are removed from the primary volume
+    drop(cluster.volumes.pop());
+    let url = format!("{}/store/key4?replicas={}", primary, replica);
+    assert_eq!(send("PUT", url, b"val4"), 502);
+    assert_eq!(send("GET", format!("{}/store/key4", primary), b""), 404);
+}
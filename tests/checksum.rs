@@ -0,0 +1,116 @@
+use tempfile::{tempdir, TempDir};
+
+use kalavara::checksum::{CONTENT_MD5_HEADER, SHA256_HEADER};
+use kalavara::http::request;
+use kalavara::server::ServerHandle;
+use kalavara::tls::TlsConfig;
+use kalavara::volume::VolumeBuilder;
+
+/// digests of "value"
+const MD5: &str = "IGPBYI1uC6+AJJxC4r5YBA==";
+const SHA256: &str = "cd42404d52ad55ccfa9aca4adc828aa5800ad9d385a0671fbcbf724118320619";
+
+/// volume server, shut down when dropped
+struct Volume {
+    server: ServerHandle,
+    _dir: TempDir,
+}
+
+impl Volume {
+    fn url(&self, path: &str) -> String {
+        format!("{}{}", self.server.url(), path)
+    }
+}
+
+fn run() -> Volume {
+    let volume_data_dir = tempdir().unwrap();
+
+    let server = VolumeBuilder::new(volume_data_dir.path().to_str().unwrap())
+        .port(0)
+        .threads(2)
+        .start()
+        .unwrap();
+
+    Volume {
+        server,
+        _dir: volume_data_dir,
+    }
+}
+
+fn put(volume: &Volume, key: &str, headers: &[(&str, &str)]) -> u16 {
+    let url = volume.url(&format!("/store/{}", key));
+    request("PUT", &url, headers, b"value", &TlsConfig::default())
+        .unwrap()
+        .status_code
+}
+
+#[test]
+fn test_checksum_verified() {
+    let volume = run();
+
+    assert_eq!(put(&volume, "md5", &[(CONTENT_MD5_HEADER, MD5)]), 201);
+    assert_eq!(put(&volume, "sha256", &[(SHA256_HEADER, SHA256)]), 201);
+
+    let resp = request(
+        "GET",
+        &volume.url("/store/md5"),
+        &[],
+        b"",
+        &TlsConfig::default(),
+    )
+    .unwrap();
+    assert_eq!(resp.status_code, 200);
+    assert_eq!(resp.body, b"value");
+    assert_eq!(resp.header(CONTENT_MD5_HEADER), Some(MD5));
+    assert_eq!(resp.header(SHA256_HEADER), Some(SHA256));
+}
+
+#[test]
+fn test_checksum_computed() {
+    let volume = run();
+
+    // digests are stored even when the client did not send any
+    assert_eq!(put(&volume, "plain", &[]), 201);
+
+    let resp = request(
+        "GET",
+        &volume.url("/store/plain"),
+        &[],
+        b"",
+        &TlsConfig::default(),
+    )
+    .unwrap();
+    assert_eq!(resp.header(CONTENT_MD5_HEADER), Some(MD5));
+}
+
+#[test]
+fn test_checksum_mismatch() {
+    let volume = run();
+
+    let md5 = "AAAAAAAAAAAAAAAAAAAAAA==";
+    assert_eq!(put(&volume, "corrupt", &[(CONTENT_MD5_HEADER, md5)]), 400);
+    assert_eq!(
+        put(&volume, "corrupt", &[(SHA256_HEADER, &"0".repeat(64))]),
+        400
+    );
+    assert_eq!(
+        put(&volume, "corrupt", &[(CONTENT_MD5_HEADER, "invalid")]),
+        400
+    );
+
+    // uploads redirected by master have to be of the accounted size
+    assert_eq!(put(&volume, "corrupt?size=3", &[]), 400);
+    assert_eq!(put(&volume, "corrupt?size=many", &[]), 400);
+    assert_eq!(put(&volume, "sized?size=5", &[]), 201);
+
+    // rejected values are not stored
+    let resp = request(
+        "GET",
+        &volume.url("/store/corrupt"),
+        &[],
+        b"",
+        &TlsConfig::default(),
+    )
+    .unwrap();
+    assert_eq!(resp.status_code, 404);
+}
@@ -0,0 +1,144 @@
+use tempfile::{tempdir, TempDir};
+
+use kalavara::client::{Client, Error};
+use kalavara::master::MasterBuilder;
+use kalavara::server::ServerHandle;
+use kalavara::volume::VolumeBuilder;
+
+use std::io::{Cursor, Read};
+
+/// master with a volume, both shut down when dropped
+struct Cluster {
+    master: ServerHandle,
+    _volume: ServerHandle,
+    _dirs: (TempDir, TempDir),
+}
+
+/// starts a master and a volume at free ports
+fn run() -> Cluster {
+    let master_data_dir = tempdir().unwrap();
+    let volume_data_dir = tempdir().unwrap();
+
+    let volume = VolumeBuilder::new(volume_data_dir.path().to_str().unwrap())
+        .port(0)
+        .threads(4)
+        .start()
+        .unwrap();
+
+    let master = MasterBuilder::new(master_data_dir.path().to_str().unwrap())
+        .port(0)
+        .threads(4)
+        .volumes(vec![volume.url()])
+        .start()
+        .unwrap();
+
+    Cluster {
+        master,
+        _volume: volume,
+        _dirs: (master_data_dir, volume_data_dir),
+    }
+}
+
+#[test]
+fn test_client_crud() {
+    let cluster = run();
+    let client = Client::new(&cluster.master.url()).cache_locations(100);
+
+    client.put_bytes("logs/1", b"first").unwrap();
+    client.put_bytes("logs/2", b"second").unwrap();
+    client.put_bytes("other", b"other").unwrap();
+
+    // values are streamed both ways
+    let value = "streamed value\n".repeat(10000);
+    client.put("big", Cursor::new(value.as_bytes())).unwrap();
+
+    let mut read = String::new();
+    let mut stored = client.get("big").unwrap();
+    assert_eq!(stored.meta.size, Some(value.len() as u64));
+    stored.read_to_string(&mut read).unwrap();
+    assert_eq!(read, value);
+
+    assert_eq!(client.get("logs/1").unwrap().bytes().unwrap(), b"first");
+    let meta = client.head("logs/2").unwrap();
+    assert_eq!(meta.size, Some(6));
+    assert!(meta.header("Content-MD5").is_some());
+
+    assert_eq!(client.list("logs/").unwrap(), vec!["logs/1", "logs/2"]);
+    assert_eq!(client.list("").unwrap().len(), 4);
+    assert!(client.list("none").unwrap().is_empty());
+
+    // the cached location is dropped with the value
+    client.delete("logs/1").unwrap();
+    assert!(match client.get("logs/1") {
+        Err(Error::NotFound) => true,
+        _ => false,
+    });
+    assert!(match client.delete("logs/1") {
+        Err(Error::NotFound) => true,
+        _ => false,
+    });
+}
+
+#[test]
+fn test_client_bucket() {
+    let cluster = run();
+
+    let res = minreq::post(format!("{}/admin/create-bucket", cluster.master.url()))
+        .with_body("name=private&access=private:secret&quota=8")
+        .send();
+    assert_eq!(res.unwrap().status_code, 200);
+
+    let client = Client::new(&cluster.master.url()).bucket("private");
+    assert!(match client.put_bytes("key", b"value") {
+        Err(Error::Forbidden) => true,
+        _ => false,
+    });
+
+    let client = client.token("secret");
+    client.put_bytes("key", b"value").unwrap();
+    assert_eq!(client.get("key").unwrap().bytes().unwrap(), b"value");
+    assert_eq!(client.list("").unwrap(), vec!["key"]);
+
+    assert!(match client.put_bytes("large", b"too large") {
+        Err(Error::InsufficientStorage) => true,
+        _ => false,
+    });
+
+    // keys of the bucket are not in the default one
+    let client = Client::new(&cluster.master.url());
+    assert!(client.list("").unwrap().is_empty());
+}
+
+#[test]
+fn test_client_key_encoding() {
+    let cluster = run();
+    let client = Client::new(&cluster.master.url());
+
+    let keys = vec!["a b", "a#b", "a%20b", "a&b=c", "a?b"];
+    for key in &keys {
+        client.put_bytes(key, key.as_bytes()).unwrap();
+    }
+
+    for key in &keys {
+        assert_eq!(client.get(key).unwrap().bytes().unwrap(), key.as_bytes());
+    }
+
+    let mut listed = client.list("a").unwrap();
+    listed.sort();
+    assert_eq!(listed, keys);
+    assert_eq!(client.list("a&").unwrap(), vec!["a&b=c"]);
+    assert_eq!(client.list("a%").unwrap(), vec!["a%20b"]);
+    assert_eq!(client.list("a ").unwrap(), vec!["a b"]);
+
+    let (changes, _) = client
+        .watch("a?", Some(0), std::time::Duration::from_secs(0))
+        .unwrap();
+    let changed: Vec<&str> = changes.iter().map(|change| change.key.as_str()).collect();
+    assert_eq!(changed, vec!["a?b"]);
+
+    client.delete("a#b").unwrap();
+    assert!(match client.get("a#b") {
+        Err(Error::NotFound) => true,
+        _ => false,
+    });
+}
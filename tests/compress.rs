@@ -0,0 +1,144 @@
+use tempfile::{tempdir, TempDir};
+
+use kalavara::checksum::CONTENT_MD5_HEADER;
+use kalavara::compress::Encoding;
+use kalavara::http::{request, Response};
+use kalavara::master::MasterBuilder;
+use kalavara::server::ServerHandle;
+use kalavara::tls::TlsConfig;
+use kalavara::volume::VolumeBuilder;
+
+/// servers of a test, shut down when dropped
+struct Servers {
+    master: Option<ServerHandle>,
+    volumes: Vec<ServerHandle>,
+    _dirs: Vec<TempDir>,
+}
+
+/// starts a volume with the given compression
+fn start_volume(compression: Option<Encoding>, dirs: &mut Vec<TempDir>) -> ServerHandle {
+    let volume_data_dir = tempdir().unwrap();
+    let volume = VolumeBuilder::new(volume_data_dir.path().to_str().unwrap())
+        .port(0)
+        .threads(2)
+        .compression(compression)
+        .start()
+        .unwrap();
+
+    dirs.push(volume_data_dir);
+    volume
+}
+
+/// starts a volume compressing with zstd
+fn run_zstd() -> Servers {
+    let mut dirs = vec![];
+    let volume = start_volume(Some(Encoding::Zstd), &mut dirs);
+
+    Servers {
+        master: None,
+        volumes: vec![volume],
+        _dirs: dirs,
+    }
+}
+
+/// starts master with two volumes compressing nothing by default
+fn run_cluster() -> Servers {
+    let mut dirs = vec![];
+    let volumes = vec![start_volume(None, &mut dirs), start_volume(None, &mut dirs)];
+
+    let master_data_dir = tempdir().unwrap();
+    let master = MasterBuilder::new(master_data_dir.path().to_str().unwrap())
+        .port(0)
+        .threads(4)
+        .volumes(volumes.iter().map(ServerHandle::url).collect())
+        .start()
+        .unwrap();
+    dirs.push(master_data_dir);
+
+    Servers {
+        master: Some(master),
+        volumes,
+        _dirs: dirs,
+    }
+}
+
+fn get(url: &str, headers: &[(&str, &str)]) -> Response {
+    request("GET", url, headers, b"", &TlsConfig::default()).unwrap()
+}
+
+#[test]
+fn test_compress_volume() {
+    let servers = run_zstd();
+    let volume = servers.volumes[0].url();
+
+    let value = "{\"level\": \"info\", \"msg\": \"started\"}\n".repeat(100);
+    let res = minreq::put(format!("{}/store/log", volume))
+        .with_body(value.as_str())
+        .send();
+    assert_eq!(res.unwrap().status_code, 201);
+
+    // decompressed for clients not accepting the encoding
+    let res = get(&format!("{}/store/log", volume), &[]);
+    assert_eq!(res.status_code, 200);
+    assert_eq!(res.body, value.as_bytes());
+    assert!(res.header(CONTENT_MD5_HEADER).is_some());
+    assert_eq!(res.header("Content-Encoding"), None);
+
+    let res = get(
+        &format!("{}/store/log", volume),
+        &[("Accept-Encoding", "gzip, zstd")],
+    );
+    assert_eq!(res.status_code, 200);
+    assert_eq!(res.header("Content-Encoding"), Some("zstd"));
+    assert!(res.body.len() < value.len());
+
+    // ranges are slices of the decompressed value
+    let res = get(
+        &format!("{}/store/log", volume),
+        &[("Range", "bytes=2-6"), ("Accept-Encoding", "zstd")],
+    );
+    assert_eq!(res.status_code, 206);
+    assert_eq!(res.body, b"level");
+
+    // compression is skipped on request
+    let res = minreq::put(format!("{}/store/raw?compression=none", volume))
+        .with_body(value.as_str())
+        .send();
+    assert_eq!(res.unwrap().status_code, 201);
+
+    let res = get(
+        &format!("{}/store/raw", volume),
+        &[("Accept-Encoding", "zstd")],
+    );
+    assert_eq!(res.header("Content-Encoding"), None);
+    assert_eq!(res.body, value.as_bytes());
+}
+
+#[test]
+fn test_compress_bucket() {
+    let servers = run_cluster();
+    let master = servers.master.as_ref().unwrap().url();
+
+    let res = minreq::post(format!("{}/admin/create-bucket", master))
+        .with_body("name=logs&replicas=2&compression=lz4")
+        .send();
+    assert_eq!(res.unwrap().status_code, 200);
+
+    let value = "GET /index.html 200\n".repeat(100);
+    let res = minreq::put(format!("{}/bucket/logs/access", master))
+        .with_body(value.as_str())
+        .send();
+    assert_eq!(res.unwrap().status_code, 201);
+
+    // both replicas compress the value as the bucket asks
+    for volume in &servers.volumes {
+        let url = format!("{}/bucket/logs/access", volume.url());
+
+        let res = get(&url, &[("Accept-Encoding", "lz4")]);
+        assert_eq!(res.status_code, 200);
+        assert_eq!(res.header("Content-Encoding"), Some("lz4"));
+
+        let res = get(&url, &[]);
+        assert_eq!(res.body, value.as_bytes());
+    }
+}
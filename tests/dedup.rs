@@ -0,0 +1,107 @@
+use tempfile::{tempdir, TempDir};
+
+use kalavara::dedup::BLOBS_DIR;
+use kalavara::server::ServerHandle;
+use kalavara::store::Engine;
+use kalavara::volume::VolumeBuilder;
+
+use std::fs::{metadata, read_dir};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+/// volume storing identical values once, shut down when dropped
+struct Volume {
+    server: ServerHandle,
+    dir: TempDir,
+}
+
+impl Volume {
+    fn url(&self, key: &str) -> String {
+        format!("{}/store/{}", self.server.url(), key)
+    }
+}
+
+fn run() -> Volume {
+    let dir = tempdir().unwrap();
+
+    let server = VolumeBuilder::new(dir.path().to_str().unwrap())
+        .port(0)
+        .threads(2)
+        .engine(Engine::Files { dedup: true })
+        .start()
+        .unwrap();
+
+    Volume { server, dir }
+}
+
+/// content blobs under `dir` with their link counts
+fn blobs(dir: &Path) -> Vec<(PathBuf, u64)> {
+    let mut found = Vec::new();
+    if !dir.exists() {
+        return found;
+    }
+
+    for entry in read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+
+        if path.is_dir() {
+            found.extend(blobs(&path));
+        } else if path.extension().is_none() {
+            let nlink = metadata(&path).unwrap().nlink();
+            found.push((path, nlink));
+        }
+    }
+
+    found
+}
+
+fn put(volume: &Volume, key: &str, value: &str) {
+    let res = minreq::put(volume.url(key)).with_body(value).send();
+    assert_eq!(res.unwrap().status_code, 201);
+}
+
+fn get(volume: &Volume, key: &str) -> minreq::Response {
+    minreq::get(volume.url(key)).send().unwrap()
+}
+
+fn delete(volume: &Volume, key: &str) {
+    let res = minreq::delete(volume.url(key)).send();
+    assert_eq!(res.unwrap().status_code, 204);
+}
+
+#[test]
+fn test_dedup() {
+    let volume = run();
+    let dir = volume.dir.path().join(BLOBS_DIR);
+
+    put(&volume, "first", "shared value");
+    put(&volume, "second", "shared value");
+
+    // one blob, linked by both keys
+    let stored = blobs(&dir);
+    assert_eq!(stored.len(), 1);
+    assert_eq!(stored[0].1, 3);
+
+    delete(&volume, "first");
+    assert_ne!(get(&volume, "first").status_code, 200);
+    let res = get(&volume, "second");
+    assert_eq!(res.status_code, 200);
+    assert_eq!(res.body, "shared value");
+    assert_eq!(blobs(&dir)[0].1, 2);
+
+    // blob goes with the last key
+    delete(&volume, "second");
+    assert!(blobs(&dir).is_empty());
+
+    // overwriting a key releases the blob of its old value
+    put(&volume, "third", "old value");
+    put(&volume, "third", "new value");
+    let stored = blobs(&dir);
+    assert_eq!(stored.len(), 1);
+    assert_eq!(stored[0].1, 2);
+    assert_eq!(get(&volume, "third").body, "new value");
+
+    // uploading the same value again keeps the blob
+    put(&volume, "third", "new value");
+    assert_eq!(blobs(&dir), stored);
+}
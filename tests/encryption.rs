@@ -0,0 +1,116 @@
+use tempfile::tempdir;
+
+use kalavara::encryption::KeyRing;
+use kalavara::http::{request, Response};
+use kalavara::server::ServerHandle;
+use kalavara::tls::TlsConfig;
+use kalavara::volume::VolumeBuilder;
+
+use std::fs::{read, read_dir};
+use std::path::Path;
+
+const OLD_KEY: &str = "old:1f1e1d1c1b1a191817161514131211100f0e0d0c0b0a09080706050403020100";
+const NEW_KEY: &str = "new:000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+/// starts a volume on `data_dir` encrypting with `keys`
+fn start_volume(data_dir: &Path, keys: &str) -> ServerHandle {
+    VolumeBuilder::new(data_dir.to_str().unwrap())
+        .port(0)
+        .threads(2)
+        .keys(KeyRing::parse(keys).unwrap())
+        .start()
+        .unwrap()
+}
+
+fn get(url: &str, headers: &[(&str, &str)]) -> Response {
+    request("GET", url, headers, b"", &TlsConfig::default()).unwrap()
+}
+
+/// checks that no file under `dir` contains `needle`
+fn assert_absent(dir: &Path, needle: &[u8]) {
+    for entry in read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+
+        if path.is_dir() {
+            assert_absent(&path, needle);
+        } else {
+            let content = read(&path).unwrap();
+            assert!(
+                !content.windows(needle.len()).any(|window| window == needle),
+                "plaintext found in {:?}",
+                path
+            );
+        }
+    }
+}
+
+#[test]
+fn test_encryption_no_plaintext() {
+    let data_dir = tempdir().unwrap();
+    let server = start_volume(data_dir.path(), OLD_KEY);
+    let volume = server.url();
+
+    // spans several encrypted chunks
+    let value = "top secret value\n".repeat(5000);
+
+    let res = minreq::put(format!("{}/store/secret", volume))
+        .with_body(value.as_str())
+        .send();
+    assert_eq!(res.unwrap().status_code, 201);
+
+    let res = minreq::put(format!("{}/store/packed?compression=zstd", volume))
+        .with_body(value.as_str())
+        .send();
+    assert_eq!(res.unwrap().status_code, 201);
+
+    assert_absent(data_dir.path(), b"top secret");
+
+    for key in ["secret", "packed"].iter() {
+        let url = format!("{}/store/{}", volume, key);
+
+        let res = get(&url, &[]);
+        assert_eq!(res.status_code, 200);
+        assert_eq!(res.body, value.as_bytes());
+
+        let res = get(&url, &[("Range", "bytes=70006-70015")]);
+        assert_eq!(res.status_code, 206);
+        assert_eq!(res.body, b"top secret");
+    }
+}
+
+#[test]
+fn test_encryption_key_rotation() {
+    // two volumes share a data directory, the first knowing only the old key
+    // and the second wrapping with the new one
+    let data_dir = tempdir().unwrap();
+    let old_server = start_volume(data_dir.path(), OLD_KEY);
+    let both_server = start_volume(data_dir.path(), &format!("{}\n{}", NEW_KEY, OLD_KEY));
+    let (old, both) = (old_server.url(), both_server.url());
+
+    let res = minreq::put(format!("{}/store/rotated", old))
+        .with_body("value")
+        .send();
+    assert_eq!(res.unwrap().status_code, 201);
+
+    // volume with both keys reads values wrapped by the old one
+    let res = get(&format!("{}/store/rotated", both), &[]);
+    assert_eq!(res.status_code, 200);
+    assert_eq!(res.body, b"value");
+
+    let res = minreq::post(format!("{}/admin/rewrap-keys", both)).send();
+    let res = res.unwrap();
+    assert_eq!(res.status_code, 200);
+    assert_eq!(res.body, "1 keys rewrapped");
+
+    let res = get(&format!("{}/store/rotated", both), &[]);
+    assert_eq!(res.status_code, 200);
+    assert_eq!(res.body, b"value");
+
+    // the old key alone no longer unwraps it
+    let res = get(&format!("{}/store/rotated", old), &[]);
+    assert_eq!(res.status_code, 500);
+
+    // nothing left to rewrap
+    let res = minreq::post(format!("{}/admin/rewrap-keys", both)).send();
+    assert_eq!(res.unwrap().body, "0 keys rewrapped");
+}
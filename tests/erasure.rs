@@ -0,0 +1,231 @@
+use tempfile::{tempdir, TempDir};
+
+use kalavara::http::request;
+use kalavara::master::MasterBuilder;
+use kalavara::server::ServerHandle;
+use kalavara::tls::TlsConfig;
+use kalavara::volume::VolumeBuilder;
+
+use std::fs::{read, read_dir, read_to_string, remove_file};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+/// master with four volumes, shut down when dropped
+struct Cluster {
+    master: ServerHandle,
+    volumes: Vec<ServerHandle>,
+    _master_dir: TempDir,
+    volume_dirs: Vec<TempDir>,
+}
+
+impl Cluster {
+    fn url(&self, path: &str) -> String {
+        format!("{}{}", self.master.url(), path)
+    }
+}
+
+fn run() -> Cluster {
+    let mut volumes = vec![];
+    let mut volume_dirs = vec![];
+
+    for _ in 0..4 {
+        let volume_data_dir = tempdir().unwrap();
+        let volume = VolumeBuilder::new(volume_data_dir.path().to_str().unwrap())
+            .port(0)
+            .threads(4)
+            .start()
+            .unwrap();
+
+        volumes.push(volume);
+        volume_dirs.push(volume_data_dir);
+    }
+
+    let master_data_dir = tempdir().unwrap();
+    let master = MasterBuilder::new(master_data_dir.path().to_str().unwrap())
+        .port(0)
+        .threads(4)
+        .volumes(volumes.iter().map(ServerHandle::url).collect())
+        .start()
+        .unwrap();
+
+    Cluster {
+        master,
+        volumes,
+        _master_dir: master_data_dir,
+        volume_dirs,
+    }
+}
+
+fn get(url: &str) -> (u16, Vec<u8>) {
+    let res = request("GET", url, &[], b"", &TlsConfig::default()).unwrap();
+    (res.status_code, res.body)
+}
+
+/// blobs stored for keys starting with `prefix` under `dir`
+fn find_blobs(dir: &Path, prefix: &str) -> Vec<PathBuf> {
+    let mut blobs = Vec::new();
+
+    for entry in read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+
+        if path.is_dir() {
+            blobs.extend(find_blobs(&path, prefix));
+        } else if path.extension().map_or(false, |ext| ext == "sum") {
+            let sum = read_to_string(&path).unwrap();
+            if sum.starts_with(&format!("key={}", prefix)) {
+                blobs.push(path.with_extension(""));
+            }
+        }
+    }
+
+    blobs
+}
+
+#[test]
+fn test_erasure_reconstructs() {
+    let cluster = run();
+
+    let res = minreq::post(cluster.url("/admin/create-bucket"))
+        .with_body("name=cold&erasure=2+1")
+        .send();
+    assert_eq!(res.unwrap().status_code, 200);
+
+    let res = minreq::put(cluster.url("/bucket/cold/key"))
+        .with_body("erasure coded value")
+        .send();
+    assert_eq!(res.unwrap().status_code, 201);
+
+    let (status, body) = get(&cluster.url("/bucket/cold/key"));
+    assert_eq!(status, 200);
+    assert_eq!(body, b"erasure coded value");
+
+    // shards are spread over distinct volumes
+    let holders: Vec<Vec<PathBuf>> = cluster
+        .volume_dirs
+        .iter()
+        .map(|dir| find_blobs(dir.path(), "~shards/"))
+        .filter(|blobs| !blobs.is_empty())
+        .collect();
+    assert_eq!(holders.len(), 3);
+
+    // one missing shard is rebuilt from the others
+    for blob in holders[0].iter() {
+        remove_file(blob).unwrap();
+    }
+
+    let (status, body) = get(&cluster.url("/bucket/cold/key"));
+    assert_eq!(status, 200);
+    assert_eq!(body, b"erasure coded value");
+
+    let res = minreq::delete(cluster.url("/bucket/cold/key")).send();
+    assert_eq!(res.unwrap().status_code, 204);
+
+    let (status, _) = get(&cluster.url("/bucket/cold/key"));
+    assert_eq!(status, 404);
+}
+
+#[test]
+fn test_erasure_conversion() {
+    let cluster = run();
+
+    let res = minreq::post(cluster.url("/admin/create-bucket"))
+        .with_body("name=warm&replicas=2")
+        .send();
+    assert_eq!(res.unwrap().status_code, 200);
+
+    let res = minreq::put(cluster.url("/bucket/warm/key"))
+        .with_body("value")
+        .send();
+    assert_eq!(res.unwrap().status_code, 201);
+
+    // replicated values are served by volumes
+    let (status, _) = get(&cluster.url("/bucket/warm/key"));
+    assert_eq!(status, 307);
+
+    let res = minreq::post(cluster.url("/admin/convert-erasure"))
+        .with_body("warm")
+        .send();
+    assert_eq!(res.unwrap().status_code, 400);
+
+    let res = minreq::post(cluster.url("/admin/create-bucket"))
+        .with_body("name=warm&replicas=2&erasure=2+1")
+        .send();
+    assert_eq!(res.unwrap().status_code, 200);
+
+    let res = minreq::post(cluster.url("/admin/convert-erasure"))
+        .with_body("warm")
+        .send();
+    assert_eq!(res.unwrap().status_code, 200);
+
+    thread::sleep(Duration::from_millis(3000));
+
+    let (status, body) = get(&cluster.url("/bucket/warm/key"));
+    assert_eq!(status, 200);
+    assert_eq!(body, b"value");
+
+    // replicas are removed once the value is coded
+    let replicas: usize = cluster
+        .volume_dirs
+        .iter()
+        .map(|dir| find_blobs(dir.path(), "warm/").len())
+        .sum();
+    assert_eq!(replicas, 0);
+}
+
+#[test]
+fn test_erasure_stripes() {
+    let cluster = run();
+    let tls = TlsConfig::default();
+
+    let res = request(
+        "POST",
+        &cluster.url("/admin/create-bucket"),
+        &[],
+        b"name=cold&erasure=2+1",
+        &tls,
+    )
+    .unwrap();
+    assert_eq!(res.status_code, 200);
+
+    // coded in several stripes
+    let value: Vec<u8> = (0..300_001u32).map(|x| (x * 7 % 251) as u8).collect();
+    let res = request("PUT", &cluster.url("/bucket/cold/key"), &[], &value, &tls).unwrap();
+    assert_eq!(res.status_code, 201);
+
+    let (status, body) = get(&cluster.url("/bucket/cold/key"));
+    assert_eq!(status, 200);
+    assert!(body == value);
+
+    let (indx, shard) = cluster
+        .volume_dirs
+        .iter()
+        .enumerate()
+        .find_map(|(indx, dir)| {
+            let blobs = find_blobs(dir.path(), "~shards/");
+            blobs.into_iter().next().map(|blob| (indx, blob))
+        })
+        .unwrap();
+    let stored = read(&shard).unwrap();
+    let blob = read_to_string(shard.with_extension("sum")).unwrap();
+    let blob = blob.lines().next().unwrap()["key=".len()..].to_owned();
+
+    remove_file(&shard).unwrap();
+    let (status, body) = get(&cluster.url("/bucket/cold/key"));
+    assert_eq!(status, 200);
+    assert!(body == value);
+
+    // a shard reported corrupt is rebuilt from the others
+    let report = format!("{}\n{}", cluster.volumes[indx].url(), blob);
+    let res = request(
+        "POST",
+        &cluster.url("/admin/report-corrupt"),
+        &[],
+        report.as_bytes(),
+        &tls,
+    )
+    .unwrap();
+    assert_eq!(res.status_code, 200);
+    assert_eq!(res.text(), "");
+    assert!(read(&shard).unwrap() == stored);
+}
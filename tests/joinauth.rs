@@ -0,0 +1,161 @@
+use tempfile::{tempdir, TempDir};
+
+use kalavara::cluster::ClusterConfig;
+use kalavara::master::MasterBuilder;
+use kalavara::server::ServerHandle;
+use kalavara::volume::VolumeBuilder;
+
+fn cluster(id: &str) -> ClusterConfig {
+    ClusterConfig {
+        id: Some(id.to_string()),
+        join_token: Some("secret".to_string()),
+    }
+}
+
+/// master of cluster1 with a registered volume, and a volume of cluster2
+struct Servers {
+    master: ServerHandle,
+    volume: ServerHandle,
+    other: ServerHandle,
+    _dirs: Vec<TempDir>,
+}
+
+impl Servers {
+    fn url(&self, path: &str) -> String {
+        format!("{}{}", self.master.url(), path)
+    }
+}
+
+fn run() -> Servers {
+    let master_data_dir = tempdir().unwrap();
+    let volume_data_dir = tempdir().unwrap();
+    let other_data_dir = tempdir().unwrap();
+
+    let master = MasterBuilder::new(master_data_dir.path().to_str().unwrap())
+        .port(0)
+        .threads(4)
+        .cluster(cluster("cluster1"))
+        .start()
+        .unwrap();
+
+    let volume = VolumeBuilder::new(volume_data_dir.path().to_str().unwrap())
+        .port(0)
+        .threads(4)
+        .master(&master.url())
+        .cluster(cluster("cluster1"))
+        .start()
+        .unwrap();
+
+    // volume of another cluster, not registered
+    let other = VolumeBuilder::new(other_data_dir.path().to_str().unwrap())
+        .port(0)
+        .threads(4)
+        .cluster(cluster("cluster2"))
+        .start()
+        .unwrap();
+
+    Servers {
+        master,
+        volume,
+        other,
+        _dirs: vec![master_data_dir, volume_data_dir, other_data_dir],
+    }
+}
+
+#[test]
+fn test_registration_auth() {
+    let servers = run();
+
+    // missing token
+    let res = minreq::post(servers.url("/admin/add-volume"))
+        .with_body(servers.other.url())
+        .send();
+    assert_eq!(res.unwrap().status_code, 401);
+
+    // wrong token
+    let res = minreq::post(servers.url("/admin/add-volume"))
+        .with_header("X-Join-Token", "guess")
+        .with_body(servers.other.url())
+        .send();
+    assert_eq!(res.unwrap().status_code, 401);
+
+    // cluster id mismatch
+    let res = minreq::post(servers.url("/admin/add-volume"))
+        .with_header("X-Join-Token", "secret")
+        .with_body(servers.other.url())
+        .send();
+    assert_eq!(res.unwrap().status_code, 400);
+
+    // unreachable volume
+    let res = minreq::post(servers.url("/admin/add-volume"))
+        .with_header("X-Join-Token", "secret")
+        .with_body("http://localhost:7999")
+        .send();
+    assert_eq!(res.unwrap().status_code, 400);
+
+    let res = minreq::get(format!("{}/admin/cluster-id", servers.volume.url())).send();
+    assert_eq!(res.unwrap().body, "cluster1");
+
+    // volume maintenance needs the token as well
+    let res = minreq::post(format!("{}/admin/compact", servers.volume.url()))
+        .with_header("X-Join-Token", "guess")
+        .send();
+    assert_eq!(res.unwrap().status_code, 401);
+}
+
+#[test]
+fn test_registered_volume() {
+    let servers = run();
+
+    let res = minreq::put(servers.url("/store/key1"))
+        .with_body("val1")
+        .send();
+    assert_eq!(res.unwrap().status_code, 201);
+
+    let res = minreq::get(servers.url("/store/key1")).send();
+    let res = res.unwrap();
+    assert_eq!(res.status_code, 200);
+    assert_eq!(res.body, "val1");
+}
+
+#[test]
+fn test_signed_replicas() {
+    let servers = run();
+
+    let second_data_dir = tempdir().unwrap();
+    let second = VolumeBuilder::new(second_data_dir.path().to_str().unwrap())
+        .port(0)
+        .threads(4)
+        .master(&servers.master.url())
+        .cluster(cluster("cluster1"))
+        .start()
+        .unwrap();
+
+    let res = minreq::post(servers.url("/admin/create-bucket"))
+        .with_body("name=logs&replicas=2")
+        .send();
+    assert_eq!(res.unwrap().status_code, 200);
+
+    // replicas master redirects to are signed
+    let res = minreq::put(servers.url("/bucket/logs/key1"))
+        .with_body("val1")
+        .send();
+    assert_eq!(res.unwrap().status_code, 201);
+
+    for volume in &[&servers.volume, &second] {
+        let url = format!("{}/bucket/logs/key1", volume.url());
+        assert_eq!(minreq::get(url).send().unwrap().body, "val1");
+    }
+
+    // others are not forwarded to
+    for sig in &["", "&replicas_sig=00"] {
+        let url = format!(
+            "{}/store/key2?replicas={}{}",
+            servers.volume.url(),
+            second.url(),
+            sig
+        );
+        let res = minreq::put(url).with_body("val2").send();
+        assert_eq!(res.unwrap().status_code, 403);
+    }
+}
@@ -0,0 +1,202 @@
+use tempfile::{tempdir, TempDir};
+
+use kalavara::client::{Client, Error};
+use kalavara::master::MasterBuilder;
+use kalavara::server::ServerHandle;
+use kalavara::volume::VolumeBuilder;
+
+use std::io::Read;
+use std::thread;
+use std::time::Duration;
+
+/// master with the first of two volumes, all shut down when dropped
+struct Cluster {
+    master: ServerHandle,
+    volumes: Vec<ServerHandle>,
+    _dirs: Vec<TempDir>,
+}
+
+impl Cluster {
+    fn client(&self) -> Client {
+        Client::new(&self.master.url())
+    }
+
+    /// sends a request to an admin endpoint of master
+    fn admin(&self, method: &str, path: &str, body: &str) -> Result<String, Error> {
+        let mut text = String::new();
+        self.client()
+            .admin(method, path, body.as_bytes())?
+            .read_to_string(&mut text)?;
+        Ok(text)
+    }
+
+    /// number of values each volume holds, in order of volumes
+    fn counts(&self) -> Vec<u32> {
+        let list = self.admin("GET", "volumes", "").unwrap();
+
+        self.volumes
+            .iter()
+            .map(|volume| {
+                let prefix = format!("url={}&values=", volume.url());
+                list.lines()
+                    .find(|line| line.starts_with(&prefix))
+                    .map_or(0, |line| {
+                        line[prefix.len()..]
+                            .split('&')
+                            .next()
+                            .unwrap()
+                            .parse()
+                            .unwrap()
+                    })
+            })
+            .collect()
+    }
+}
+
+/// starts a master knowing only the first of two volumes
+fn run() -> Cluster {
+    let dirs: Vec<TempDir> = (0..3).map(|_| tempdir().unwrap()).collect();
+
+    let volumes: Vec<ServerHandle> = dirs[1..]
+        .iter()
+        .map(|dir| {
+            VolumeBuilder::new(dir.path().to_str().unwrap())
+                .port(0)
+                .threads(4)
+                .start()
+                .unwrap()
+        })
+        .collect();
+
+    let master = MasterBuilder::new(dirs[0].path().to_str().unwrap())
+        .port(0)
+        .threads(4)
+        .volumes(vec![volumes[0].url()])
+        .start()
+        .unwrap();
+
+    Cluster {
+        master,
+        volumes,
+        _dirs: dirs,
+    }
+}
+
+#[test]
+fn test_rebalance_drain_remove() {
+    let cluster = run();
+    let client = cluster.client();
+
+    for indx in 0..10 {
+        client
+            .put_bytes(&format!("key{}", indx), format!("value{}", indx).as_bytes())
+            .unwrap();
+    }
+    assert_eq!(cluster.counts(), vec![10, 0]);
+
+    let second = cluster.volumes[1].url();
+    assert_eq!(
+        cluster.admin("POST", "add-volume", &second).unwrap(),
+        "Volume added"
+    );
+
+    // values move until volumes hold their share
+    assert_eq!(
+        cluster.admin("POST", "rebalance", "").unwrap(),
+        "moved=5&failed=0"
+    );
+    assert_eq!(cluster.counts(), vec![5, 5]);
+
+    // volumes holding values are drained before they are removed
+    let first = cluster.volumes[0].url();
+    assert!(match cluster.admin("POST", "remove-volume", &first) {
+        Err(Error::BadRequest(_)) => true,
+        _ => false,
+    });
+
+    assert_eq!(
+        cluster.admin("POST", "drain-volume", &first).unwrap(),
+        "moved=5&failed=0"
+    );
+    assert_eq!(cluster.counts(), vec![0, 10]);
+
+    let list = cluster.admin("GET", "volumes", "").unwrap();
+    assert!(list.contains(&format!("url={}&values=0&state=draining", first)));
+
+    // draining volumes receive no new values
+    client.put_bytes("new", b"new value").unwrap();
+    assert_eq!(cluster.counts(), vec![0, 11]);
+
+    assert_eq!(
+        cluster.admin("POST", "remove-volume", &first).unwrap(),
+        "Volume removed"
+    );
+    assert!(match cluster.admin("POST", "remove-volume", &first) {
+        Err(Error::NotFound) => true,
+        _ => false,
+    });
+
+    for indx in 0..10 {
+        let value = client.get(&format!("key{}", indx)).unwrap();
+        assert_eq!(value.bytes().unwrap(), format!("value{}", indx).as_bytes());
+    }
+}
+
+#[test]
+fn test_fsck_gc_snapshot_stats() {
+    let cluster = run();
+    let client = cluster.client();
+
+    client.put_bytes("kept", b"kept value").unwrap();
+    client.put_bytes("lost", b"lost value").unwrap();
+    assert_eq!(cluster.admin("GET", "fsck", "").unwrap(), "");
+
+    // value removed behind the back of master
+    let volume = Client::new(&cluster.volumes[0].url());
+    volume.delete("lost").unwrap();
+
+    let problems = cluster.admin("GET", "fsck", "").unwrap();
+    assert_eq!(
+        problems,
+        format!(
+            "problem=missing&url={}/store/lost&bucket=&key=lost",
+            cluster.volumes[0].url()
+        )
+    );
+
+    // keys of a bucket expiring right away are collected
+    cluster
+        .admin("POST", "create-bucket", "name=tmp&ttl=1")
+        .unwrap();
+    let tmp = cluster.client().bucket("tmp");
+    tmp.put_bytes("short", b"short lived").unwrap();
+    thread::sleep(Duration::from_secs(2));
+
+    assert_eq!(
+        cluster.admin("POST", "gc", "").unwrap(),
+        "expired=1&compacted=1&volumes=1"
+    );
+
+    assert_eq!(
+        cluster.admin("GET", "stats", "").unwrap(),
+        "volumes=1&draining=0&buckets=1&objects=2&bytes=20&blobs=2"
+    );
+
+    // snapshot holds a key and a value frame per index entry
+    let mut snapshot = Vec::new();
+    client
+        .admin("GET", "snapshot", b"")
+        .unwrap()
+        .read_to_end(&mut snapshot)
+        .unwrap();
+
+    let mut frames = Vec::new();
+    let mut rest = &snapshot[..];
+    while let Some(frame) = kalavara::batch::read_frame(&mut rest).unwrap() {
+        frames.push(frame);
+    }
+
+    assert_eq!(frames.len() % 2, 0);
+    assert!(frames.iter().step_by(2).any(|key| key == b"kept"));
+    assert!(frames.iter().step_by(2).any(|key| key == b"lost"));
+}
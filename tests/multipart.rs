@@ -0,0 +1,114 @@
+use tempfile::{tempdir, TempDir};
+
+use kalavara::http::{request, Response};
+use kalavara::master::MasterBuilder;
+use kalavara::server::ServerHandle;
+use kalavara::tls::TlsConfig;
+use kalavara::volume::VolumeBuilder;
+
+/// master with two volumes, shut down when dropped
+struct Cluster {
+    master: ServerHandle,
+    _volumes: Vec<ServerHandle>,
+    _dirs: Vec<TempDir>,
+}
+
+fn run() -> Cluster {
+    let mut dirs = vec![];
+    let mut volumes = vec![];
+
+    for _ in 0..2 {
+        let volume_data_dir = tempdir().unwrap();
+        let volume = VolumeBuilder::new(volume_data_dir.path().to_str().unwrap())
+            .port(0)
+            .threads(4)
+            .start()
+            .unwrap();
+
+        dirs.push(volume_data_dir);
+        volumes.push(volume);
+    }
+
+    let master_data_dir = tempdir().unwrap();
+    let master = MasterBuilder::new(master_data_dir.path().to_str().unwrap())
+        .port(0)
+        .threads(4)
+        .volumes(volumes.iter().map(ServerHandle::url).collect())
+        .start()
+        .unwrap();
+    dirs.push(master_data_dir);
+
+    Cluster {
+        master,
+        _volumes: volumes,
+        _dirs: dirs,
+    }
+}
+
+/// sends request to master and follows the redirect to volume server
+fn send(cluster: &Cluster, method: &str, path: &str, body: &[u8]) -> Response {
+    let tls = TlsConfig::default();
+    let resp = request(
+        method,
+        &format!("{}{}", cluster.master.url(), path),
+        &[],
+        body,
+        &tls,
+    )
+    .unwrap();
+
+    match resp.header("Location") {
+        Some(location) if resp.status_code == 307 => {
+            request(method, location, &[], body, &tls).unwrap()
+        }
+        _ => resp,
+    }
+}
+
+#[test]
+fn test_multipart_upload() {
+    let cluster = run();
+
+    let resp = send(&cluster, "POST", "/store/big?uploads", b"");
+    assert_eq!(resp.status_code, 200);
+    let id = resp.text();
+
+    // parts in any order, retried parts replace previous ones
+    for (part, value) in &[(2, "part two"), (1, "part 1 "), (2, "part 2")] {
+        let path = format!("/store/big?upload={}&part={}", id, part);
+        assert_eq!(
+            send(&cluster, "PUT", &path, value.as_bytes()).status_code,
+            201
+        );
+    }
+
+    let resp = send(&cluster, "GET", &format!("/store/big?upload={}", id), b"");
+    assert_eq!(resp.text(), "1 7\n2 6");
+
+    let resp = send(&cluster, "POST", &format!("/store/big?upload={}", id), b"");
+    assert_eq!(resp.status_code, 200);
+
+    let resp = send(&cluster, "GET", "/store/big", b"");
+    assert_eq!(resp.status_code, 200);
+    assert_eq!(resp.text(), "part 1 part 2");
+
+    assert_eq!(send(&cluster, "DELETE", "/store/big", b"").status_code, 204);
+    assert_eq!(send(&cluster, "GET", "/store/big", b"").status_code, 404);
+}
+
+#[test]
+fn test_multipart_abort() {
+    let cluster = run();
+
+    let id = send(&cluster, "POST", "/store/aborted?uploads", b"").text();
+    let path = format!("/store/aborted?upload={}&part=1", id);
+    assert_eq!(send(&cluster, "PUT", &path, b"value").status_code, 201);
+
+    let path = format!("/store/aborted?upload={}", id);
+    assert_eq!(send(&cluster, "DELETE", &path, b"").status_code, 204);
+    assert_eq!(send(&cluster, "POST", &path, b"").status_code, 404);
+    assert_eq!(
+        send(&cluster, "GET", "/store/aborted", b"").status_code,
+        404
+    );
+}
@@ -0,0 +1,118 @@
+use tempfile::{tempdir, TempDir};
+
+use kalavara::http::{request, Response};
+use kalavara::packed::{PackedConfig, SEGMENTS_DIR};
+use kalavara::server::ServerHandle;
+use kalavara::store::Engine;
+use kalavara::tls::TlsConfig;
+use kalavara::volume::VolumeBuilder;
+
+use std::fs::read_dir;
+
+/// volume packing values into small segments, shut down when dropped
+struct Volume {
+    server: ServerHandle,
+    dir: TempDir,
+}
+
+impl Volume {
+    fn url(&self, path: &str) -> String {
+        format!("{}{}", self.server.url(), path)
+    }
+}
+
+fn run() -> Volume {
+    let dir = tempdir().unwrap();
+
+    let server = VolumeBuilder::new(dir.path().to_str().unwrap())
+        .port(0)
+        .threads(2)
+        .engine(Engine::Packed(PackedConfig {
+            segment_size: 256,
+            ..PackedConfig::default()
+        }))
+        .start()
+        .unwrap();
+
+    Volume { server, dir }
+}
+
+fn get(volume: &Volume, key: &str, headers: &[(&str, &str)]) -> Response {
+    let url = volume.url(&format!("/store/{}", key));
+    request("GET", &url, headers, b"", &TlsConfig::default()).unwrap()
+}
+
+fn put(volume: &Volume, key: &str, value: &str) {
+    let res = minreq::put(volume.url(&format!("/store/{}", key)))
+        .with_body(value)
+        .send();
+    assert_eq!(res.unwrap().status_code, 201);
+}
+
+/// names of the entries of the data directory
+fn entries(volume: &Volume) -> Vec<String> {
+    let mut entries: Vec<String> = read_dir(volume.dir.path())
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+        .collect();
+    entries.sort();
+    entries
+}
+
+#[test]
+fn test_packed_values() {
+    let volume = run();
+
+    put(&volume, "first", "first value");
+    put(&volume, "second", "second value");
+
+    // values are not files of their own
+    assert_eq!(entries(&volume), vec![SEGMENTS_DIR, "tmp"]);
+
+    let res = get(&volume, "first", &[]);
+    assert_eq!(res.status_code, 200);
+    assert_eq!(res.body, b"first value");
+    assert!(res.header("Content-MD5").is_some());
+
+    let res = get(&volume, "second", &[("Range", "bytes=7-11")]);
+    assert_eq!(res.status_code, 206);
+    assert_eq!(res.body, b"value");
+
+    let res = minreq::delete(volume.url("/store/second")).send();
+    assert_eq!(res.unwrap().status_code, 204);
+    assert_eq!(get(&volume, "second", &[]).status_code, 404);
+
+    let res = minreq::delete(volume.url("/store/second")).send();
+    assert_eq!(res.unwrap().status_code, 404);
+
+    // compressed values are packed as stored
+    let value = "packed and compressed\n".repeat(100);
+    let res = minreq::put(volume.url("/store/third?compression=zstd"))
+        .with_body(value.as_str())
+        .send();
+    assert_eq!(res.unwrap().status_code, 201);
+
+    let res = get(&volume, "third", &[("Accept-Encoding", "zstd")]);
+    assert_eq!(res.header("Content-Encoding"), Some("zstd"));
+    assert!(res.body.len() < value.len());
+    assert_eq!(get(&volume, "third", &[]).body, value.as_bytes());
+}
+
+#[test]
+fn test_packed_compaction() {
+    let volume = run();
+
+    for indx in 0..10 {
+        put(&volume, "overwritten", &format!("value {}", indx));
+    }
+
+    let res = minreq::post(volume.url("/admin/compact")).send();
+    let res = res.unwrap();
+    assert_eq!(res.status_code, 200);
+    assert!(res.body.ends_with(" segments compacted"));
+    assert_ne!(res.body, "0 segments compacted");
+
+    let res = get(&volume, "overwritten", &[]);
+    assert_eq!(res.status_code, 200);
+    assert_eq!(res.body, b"value 9");
+}
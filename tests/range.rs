@@ -0,0 +1,114 @@
+use tempfile::{tempdir, TempDir};
+
+use kalavara::http::{request, Response};
+use kalavara::server::ServerHandle;
+use kalavara::tls::TlsConfig;
+use kalavara::volume::VolumeBuilder;
+
+const VALUE: &[u8] = b"0123456789";
+
+/// volume holding `VALUE` at key digits, shut down when dropped
+struct Volume {
+    server: ServerHandle,
+    _dir: TempDir,
+}
+
+impl Volume {
+    fn url(&self) -> String {
+        format!("{}/store/digits", self.server.url())
+    }
+}
+
+fn run() -> Volume {
+    let volume_data_dir = tempdir().unwrap();
+
+    let server = VolumeBuilder::new(volume_data_dir.path().to_str().unwrap())
+        .port(0)
+        .threads(2)
+        .start()
+        .unwrap();
+
+    let volume = Volume {
+        server,
+        _dir: volume_data_dir,
+    };
+
+    let resp = request("PUT", &volume.url(), &[], VALUE, &TlsConfig::default()).unwrap();
+    assert_eq!(resp.status_code, 201);
+
+    volume
+}
+
+fn get(volume: &Volume, range: Option<&str>) -> Response {
+    let headers: Vec<(&str, &str)> = range.into_iter().map(|range| ("Range", range)).collect();
+
+    request("GET", &volume.url(), &headers, b"", &TlsConfig::default()).unwrap()
+}
+
+#[test]
+fn test_range_full() {
+    let volume = run();
+
+    let resp = get(&volume, None);
+    assert_eq!(resp.status_code, 200);
+    assert_eq!(resp.header("Accept-Ranges"), Some("bytes"));
+    assert_eq!(resp.body, VALUE);
+
+    // malformed ranges are ignored
+    assert_eq!(get(&volume, Some("bytes=5-1")).status_code, 200);
+}
+
+#[test]
+fn test_range_single() {
+    let volume = run();
+
+    let resp = get(&volume, Some("bytes=2-4"));
+    assert_eq!(resp.status_code, 206);
+    assert_eq!(resp.header("Content-Range"), Some("bytes 2-4/10"));
+    assert_eq!(resp.body, b"234");
+
+    // resuming a download
+    let resp = get(&volume, Some("bytes=7-"));
+    assert_eq!(resp.status_code, 206);
+    assert_eq!(resp.body, b"789");
+
+    let resp = get(&volume, Some("bytes=-2"));
+    assert_eq!(resp.status_code, 206);
+    assert_eq!(resp.header("Content-Range"), Some("bytes 8-9/10"));
+    assert_eq!(resp.body, b"89");
+}
+
+#[test]
+fn test_range_multiple() {
+    let volume = run();
+
+    let resp = get(&volume, Some("bytes=0-1,-2"));
+    assert_eq!(resp.status_code, 206);
+
+    let content_type = resp.header("Content-Type").unwrap().to_owned();
+    assert!(content_type.starts_with("multipart/byteranges; boundary="));
+
+    let boundary = &content_type[content_type.find('=').unwrap() + 1..];
+    let body = resp.text();
+
+    assert_eq!(
+        body,
+        format!(
+            "\r\n--{b}\r\nContent-Type: application/octet-stream\r\n\
+             Content-Range: bytes 0-1/10\r\n\r\n01\
+             \r\n--{b}\r\nContent-Type: application/octet-stream\r\n\
+             Content-Range: bytes 8-9/10\r\n\r\n89\
+             \r\n--{b}--\r\n",
+            b = boundary
+        )
+    );
+}
+
+#[test]
+fn test_range_unsatisfiable() {
+    let volume = run();
+
+    let resp = get(&volume, Some("bytes=10-"));
+    assert_eq!(resp.status_code, 416);
+    assert_eq!(resp.header("Content-Range"), Some("bytes */10"));
+}
@@ -0,0 +1,56 @@
+use tempfile::{tempdir, TempDir};
+
+use kalavara::http::{request, Response};
+use kalavara::limit::LimitConfig;
+use kalavara::master::MasterBuilder;
+use kalavara::server::ServerHandle;
+use kalavara::tls::TlsConfig;
+
+/// master limiting each client to a burst of two requests
+fn run() -> (ServerHandle, TempDir) {
+    let master_data_dir = tempdir().unwrap();
+
+    let master = MasterBuilder::new(master_data_dir.path().to_str().unwrap())
+        .port(0)
+        .threads(2)
+        .limits(LimitConfig {
+            client_rate: Some(0.1),
+            client_burst: Some(2.0),
+            ..LimitConfig::default()
+        })
+        .start()
+        .unwrap();
+
+    (master, master_data_dir)
+}
+
+fn get(master: &ServerHandle, path: &str) -> Response {
+    request(
+        "GET",
+        &format!("{}{}", master.url(), path),
+        &[],
+        b"",
+        &TlsConfig::default(),
+    )
+    .unwrap()
+}
+
+#[test]
+fn test_client_rate_limit() {
+    let (master, _dir) = run();
+
+    assert_eq!(get(&master, "/store/key").status_code, 404);
+    assert_eq!(get(&master, "/store/key").status_code, 404);
+
+    // burst is used up, next token is 10 seconds away
+    let resp = get(&master, "/store/key");
+    assert_eq!(resp.status_code, 429);
+    assert_eq!(resp.header("Retry-After"), Some("10"));
+
+    // metrics are not rate limited
+    let metrics = get(&master, "/admin/metrics");
+    assert_eq!(metrics.status_code, 200);
+    assert!(metrics
+        .text()
+        .contains("kalavara_rejected_total{reason=\"client_rate_limit\"} 1\n"));
+}
@@ -0,0 +1,389 @@
+use openssl::sha::sha256;
+use tempfile::{tempdir, TempDir};
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use kalavara::http::{request, Response};
+use kalavara::master::MasterBuilder;
+use kalavara::s3::{amz_date, authorization, uri_encode, S3Config, UNSIGNED_PAYLOAD};
+use kalavara::server::ServerHandle;
+use kalavara::tls::TlsConfig;
+use kalavara::volume::VolumeBuilder;
+
+/// master serving the s3 gateway with two volumes, shut down when dropped
+struct Cluster {
+    master: ServerHandle,
+    config: S3Config,
+    _volumes: Vec<ServerHandle>,
+    _dirs: Vec<TempDir>,
+}
+
+fn run() -> Cluster {
+    let mut dirs = vec![];
+    let mut volumes = vec![];
+
+    for _ in 0..2 {
+        let volume_data_dir = tempdir().unwrap();
+        let volume = VolumeBuilder::new(volume_data_dir.path().to_str().unwrap())
+            .port(0)
+            .threads(4)
+            .start()
+            .unwrap();
+
+        dirs.push(volume_data_dir);
+        volumes.push(volume);
+    }
+
+    let config = S3Config::new(0, "backup", "s3cr3t");
+    let master_data_dir = tempdir().unwrap();
+    let master = MasterBuilder::new(master_data_dir.path().to_str().unwrap())
+        .port(0)
+        .threads(4)
+        .volumes(volumes.iter().map(ServerHandle::url).collect())
+        .s3(config.clone())
+        .start()
+        .unwrap();
+    dirs.push(master_data_dir);
+
+    Cluster {
+        master,
+        config,
+        _volumes: volumes,
+        _dirs: dirs,
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// percent encodes path and query params of a url
+fn encode(url: &str) -> String {
+    let mut parts = url.splitn(2, '?');
+    let path = uri_encode(parts.next().unwrap(), false);
+
+    match parts.next() {
+        Some(query) => {
+            let params: Vec<String> = query
+                .split('&')
+                .map(|param| {
+                    let mut pair = param.splitn(2, '=');
+                    let name = uri_encode(pair.next().unwrap(), true);
+                    let value = uri_encode(pair.next().unwrap_or(""), true);
+                    format!("{}={}", name, value)
+                })
+                .collect();
+            format!("{}?{}", path, params.join("&"))
+        }
+        None => path,
+    }
+}
+
+/// sends a request signed with `config` to the gateway. `url` is not
+/// percent encoded, payloads are unsigned unless `headers` set their hash
+fn send_as(
+    cluster: &Cluster,
+    config: &S3Config,
+    method: &str,
+    url: &str,
+    headers: &[(&str, &str)],
+    body: &[u8],
+) -> Response {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let date = amz_date(now);
+    let s3_url = cluster.master.s3_url().unwrap();
+
+    // host is sent with the port of the gateway
+    let host = &s3_url[s3_url.find("localhost").unwrap()..];
+    let mut signed = vec![("host", host), ("x-amz-date", date.as_str())];
+    signed.extend_from_slice(headers);
+    if !headers
+        .iter()
+        .any(|(name, _)| *name == "x-amz-content-sha256")
+    {
+        signed.push(("x-amz-content-sha256", UNSIGNED_PAYLOAD));
+    }
+    let payload_hash = signed
+        .iter()
+        .find(|(name, _)| *name == "x-amz-content-sha256")
+        .map(|(_, hash)| *hash)
+        .unwrap();
+
+    let auth = authorization(config, method, url, &signed, payload_hash);
+    let mut sent: Vec<(&str, &str)> = signed[1..].to_vec();
+    sent.push(("Authorization", &auth));
+
+    let url = format!("{}{}", s3_url, encode(url));
+    request(method, &url, &sent, body, &TlsConfig::default()).unwrap()
+}
+
+fn send(cluster: &Cluster, method: &str, url: &str, body: &[u8]) -> Response {
+    send_as(cluster, &cluster.config, method, url, &[], body)
+}
+
+/// text of the first element `name` of a xml document
+fn element<'a>(xml: &'a str, name: &str) -> &'a str {
+    let start = xml.find(&format!("<{}>", name)).unwrap() + name.len() + 2;
+    let end = start + xml[start..].find(&format!("</{}>", name)).unwrap();
+    &xml[start..end]
+}
+
+#[test]
+fn test_s3_objects() {
+    let cluster = run();
+
+    assert_eq!(send(&cluster, "PUT", "/photos", b"").status_code, 200);
+    assert_eq!(send(&cluster, "PUT", "/photos", b"").status_code, 409);
+    assert_eq!(send(&cluster, "HEAD", "/photos", b"").status_code, 200);
+    assert_eq!(send(&cluster, "HEAD", "/videos", b"").status_code, 404);
+    assert!(send(&cluster, "GET", "/", b"")
+        .text()
+        .contains("<Name>photos</Name>"));
+
+    // keys with spaces and reserved characters, payload signed with its hash
+    let key = "/photos/2019/summer trip+1.jpg";
+    let value = b"summer photo";
+    let hash = hex(&sha256(value));
+    let headers = [("x-amz-content-sha256", hash.as_str())];
+    let resp = send_as(&cluster, &cluster.config, "PUT", key, &headers, value);
+    assert_eq!(resp.status_code, 200);
+    let etag = format!("\"{}\"", hex(&md5::compute(value).0));
+    assert_eq!(resp.header("ETag"), Some(etag.as_str()));
+
+    let resp = send(&cluster, "GET", key, b"");
+    assert_eq!(resp.status_code, 200);
+    assert_eq!(resp.body, value);
+    assert_eq!(resp.header("ETag"), Some(etag.as_str()));
+    assert!(resp.header("Last-Modified").unwrap().ends_with(" GMT"));
+
+    let range = [("Range", "bytes=7-11")];
+    let resp = send_as(&cluster, &cluster.config, "GET", key, &range, b"");
+    assert_eq!(resp.status_code, 206);
+    assert_eq!(resp.text(), "photo");
+
+    let resp = send(&cluster, "HEAD", key, b"");
+    assert_eq!(resp.status_code, 200);
+    assert_eq!(resp.header("Content-Length"), Some("12"));
+
+    // unsigned aws-chunked body with a trailer
+    let chunked = [
+        ("x-amz-content-sha256", "STREAMING-UNSIGNED-PAYLOAD-TRAILER"),
+        ("Content-Encoding", "aws-chunked"),
+        ("x-amz-decoded-content-length", "11"),
+    ];
+    let body = b"6\r\nhello \r\n5\r\nworld\r\n0\r\nx-amz-checksum-crc32:DUoRhQ==\r\n\r\n";
+    let resp = send_as(
+        &cluster,
+        &cluster.config,
+        "PUT",
+        "/photos/hello",
+        &chunked,
+        body,
+    );
+    assert_eq!(resp.status_code, 200);
+    assert_eq!(
+        send(&cluster, "GET", "/photos/hello", b"").text(),
+        "hello world"
+    );
+
+    assert_eq!(send(&cluster, "DELETE", "/photos", b"").status_code, 409);
+    assert_eq!(send(&cluster, "DELETE", key, b"").status_code, 204);
+    assert_eq!(
+        send(&cluster, "DELETE", "/photos/hello", b"").status_code,
+        204
+    );
+
+    let resp = send(&cluster, "GET", key, b"");
+    assert_eq!(resp.status_code, 404);
+    assert_eq!(element(&resp.text(), "Code"), "NoSuchKey");
+
+    assert_eq!(send(&cluster, "DELETE", "/photos", b"").status_code, 204);
+    assert_eq!(send(&cluster, "GET", "/photos/hello", b"").status_code, 404);
+}
+
+#[test]
+fn test_s3_erasure() {
+    let cluster = run();
+
+    let url = format!("{}/admin/create-bucket", cluster.master.url());
+    let res = request(
+        "POST",
+        &url,
+        &[],
+        b"name=cold&erasure=1+1",
+        &TlsConfig::default(),
+    );
+    assert_eq!(res.unwrap().status_code, 200);
+
+    // coded while it is streamed, verified before it is committed
+    let value = vec![7u8; 200_000];
+    let hash = hex(&sha256(&value));
+    let headers = [("x-amz-content-sha256", hash.as_str())];
+    let resp = send_as(
+        &cluster,
+        &cluster.config,
+        "PUT",
+        "/cold/key",
+        &headers,
+        &value,
+    );
+    assert_eq!(resp.status_code, 200);
+    let etag = format!("\"{}\"", hex(&md5::compute(&value).0));
+    assert_eq!(resp.header("ETag"), Some(etag.as_str()));
+
+    let resp = send(&cluster, "GET", "/cold/key", b"");
+    assert_eq!(resp.status_code, 200);
+    assert!(resp.body == value);
+
+    let hash = hex(&sha256(b"other value"));
+    let headers = [("x-amz-content-sha256", hash.as_str())];
+    let resp = send_as(
+        &cluster,
+        &cluster.config,
+        "PUT",
+        "/cold/bad",
+        &headers,
+        &value,
+    );
+    assert_eq!(resp.status_code, 400);
+    assert_eq!(element(&resp.text(), "Code"), "XAmzContentSHA256Mismatch");
+    assert_eq!(send(&cluster, "GET", "/cold/bad", b"").status_code, 404);
+}
+
+#[test]
+fn test_s3_listing() {
+    let cluster = run();
+
+    assert_eq!(send(&cluster, "PUT", "/logs", b"").status_code, 200);
+    for key in &["a/1", "a/2", "b", "c/1", "c/2 x"] {
+        let url = format!("/logs/{}", key);
+        assert_eq!(send(&cluster, "PUT", &url, key.as_bytes()).status_code, 200);
+    }
+
+    let resp = send(&cluster, "GET", "/logs?list-type=2", b"");
+    assert_eq!(resp.status_code, 200);
+    let xml = resp.text();
+    assert_eq!(element(&xml, "KeyCount"), "5");
+    assert!(xml.contains("<Key>c/2 x</Key><LastModified>"));
+    assert!(xml.contains("<Size>5</Size>"));
+
+    // common prefixes count towards max-keys, pages continue after them
+    let url = "/logs?list-type=2&delimiter=/&max-keys=2";
+    let xml = send(&cluster, "GET", url, b"").text();
+    assert_eq!(element(&xml, "IsTruncated"), "true");
+    assert!(xml.contains("<CommonPrefixes><Prefix>a/</Prefix></CommonPrefixes>"));
+    assert_eq!(element(&xml, "Key"), "b");
+
+    let token = element(&xml, "NextContinuationToken").to_owned();
+    let url = format!("{}&continuation-token={}", url, token);
+    let xml = send(&cluster, "GET", &url, b"").text();
+    assert_eq!(element(&xml, "IsTruncated"), "false");
+    assert_eq!(element(&xml, "KeyCount"), "1");
+    assert!(xml.contains("<CommonPrefixes><Prefix>c/</Prefix></CommonPrefixes>"));
+
+    let url = "/logs?list-type=2&prefix=c/&encoding-type=url";
+    let xml = send(&cluster, "GET", url, b"").text();
+    assert!(xml.contains("<Key>c/1</Key>"));
+    assert!(xml.contains("<Key>c/2%20x</Key>"));
+
+    let resp = send(&cluster, "GET", "/logs?location", b"");
+    assert!(resp.text().contains(">us-east-1</LocationConstraint>"));
+
+    let resp = send(&cluster, "GET", "/missing?list-type=2", b"");
+    assert_eq!(element(&resp.text(), "Code"), "NoSuchBucket");
+}
+
+#[test]
+fn test_s3_multipart() {
+    let cluster = run();
+    assert_eq!(send(&cluster, "PUT", "/backups", b"").status_code, 200);
+
+    let resp = send(&cluster, "POST", "/backups/db.tar?uploads", b"");
+    assert_eq!(resp.status_code, 200);
+    let id = element(&resp.text(), "UploadId").to_owned();
+
+    let mut etags = vec![];
+    for (number, value) in &[(2, "part two"), (1, "part one, ")] {
+        let url = format!("/backups/db.tar?partNumber={}&uploadId={}", number, id);
+        let resp = send(&cluster, "PUT", &url, value.as_bytes());
+        assert_eq!(resp.status_code, 200);
+        etags.push(resp.header("ETag").unwrap().to_owned());
+    }
+
+    let url = format!("/backups/db.tar?uploadId={}", id);
+    let xml = send(&cluster, "GET", &url, b"").text();
+    assert!(xml.contains("<PartNumber>1</PartNumber>"));
+    assert!(xml.contains(&format!("<ETag>{}</ETag>", etags[0].replace('"', "&quot;"))));
+
+    // parts are listed in ascending order
+    let complete = |numbers: &[u32]| {
+        let parts: Vec<String> = numbers
+            .iter()
+            .map(|number| format!("<Part><PartNumber>{}</PartNumber></Part>", number))
+            .collect();
+        format!(
+            "<CompleteMultipartUpload>{}</CompleteMultipartUpload>",
+            parts.join("")
+        )
+    };
+
+    let resp = send(&cluster, "POST", &url, complete(&[2, 1]).as_bytes());
+    assert_eq!(element(&resp.text(), "Code"), "InvalidPartOrder");
+    let resp = send(&cluster, "POST", &url, complete(&[1]).as_bytes());
+    assert_eq!(element(&resp.text(), "Code"), "InvalidPart");
+
+    let resp = send(&cluster, "POST", &url, complete(&[1, 2]).as_bytes());
+    assert_eq!(resp.status_code, 200);
+    let etag = element(&resp.text(), "ETag").replace("&quot;", "");
+    assert!(etag.ends_with("-2"));
+
+    let resp = send(&cluster, "GET", "/backups/db.tar", b"");
+    assert_eq!(resp.text(), "part one, part two");
+    assert_eq!(resp.header("ETag"), Some(format!("\"{}\"", etag).as_str()));
+
+    // aborted uploads are gone
+    let resp = send(&cluster, "POST", "/backups/other?uploads", b"");
+    let id = element(&resp.text(), "UploadId").to_owned();
+    let url = format!("/backups/other?uploadId={}", id);
+    assert_eq!(send(&cluster, "DELETE", &url, b"").status_code, 204);
+    let resp = send(&cluster, "GET", &url, b"");
+    assert_eq!(element(&resp.text(), "Code"), "NoSuchUpload");
+}
+
+#[test]
+fn test_s3_auth() {
+    let cluster = run();
+    assert_eq!(send(&cluster, "PUT", "/private", b"").status_code, 200);
+
+    let other = S3Config::new(0, "backup", "guess");
+    let resp = send_as(&cluster, &other, "GET", "/", &[], b"");
+    assert_eq!(resp.status_code, 403);
+    assert_eq!(element(&resp.text(), "Code"), "SignatureDoesNotMatch");
+
+    let other = S3Config::new(0, "intruder", "s3cr3t");
+    let resp = send_as(&cluster, &other, "GET", "/", &[], b"");
+    assert_eq!(element(&resp.text(), "Code"), "InvalidAccessKeyId");
+
+    let url = format!("{}/", cluster.master.s3_url().unwrap());
+    let resp = request("GET", &url, &[], b"", &TlsConfig::default()).unwrap();
+    assert_eq!(resp.status_code, 403);
+    assert_eq!(element(&resp.text(), "Code"), "AccessDenied");
+
+    // values not matching their signed hash are not stored
+    let hash = hex(&sha256(b"expected"));
+    let headers = [("x-amz-content-sha256", hash.as_str())];
+    let resp = send_as(
+        &cluster,
+        &cluster.config,
+        "PUT",
+        "/private/key",
+        &headers,
+        b"tampered",
+    );
+    assert_eq!(resp.status_code, 400);
+    assert_eq!(element(&resp.text(), "Code"), "XAmzContentSHA256Mismatch");
+    assert_eq!(send(&cluster, "GET", "/private/key", b"").status_code, 404);
+}
@@ -0,0 +1,75 @@
+use tempfile::tempdir;
+
+use kalavara::master::MasterBuilder;
+use kalavara::scrub::{ScrubConfig, QUARANTINE_DIR};
+use kalavara::volume::VolumeBuilder;
+
+use std::fs::{read_dir, write};
+use std::thread;
+use std::time::Duration;
+
+#[test]
+fn test_scrub_repairs_corrupt_values() {
+    let master_data_dir = tempdir().unwrap();
+    let volume_data_dir = tempdir().unwrap();
+    let scrubbed_dir = tempdir().unwrap();
+    let data_dir = scrubbed_dir.path();
+
+    let volume = VolumeBuilder::new(volume_data_dir.path().to_str().unwrap())
+        .port(0)
+        .threads(4)
+        .start()
+        .unwrap();
+
+    let master = MasterBuilder::new(master_data_dir.path().to_str().unwrap())
+        .port(0)
+        .threads(4)
+        .volumes(vec![volume.url()])
+        .start()
+        .unwrap();
+
+    // the second volume scrubs every second and registers itself
+    let scrubbed = VolumeBuilder::new(data_dir.to_str().unwrap())
+        .port(0)
+        .threads(4)
+        .master(&master.url())
+        .scrub(ScrubConfig {
+            rate: Some(1 << 20),
+            interval: 1,
+        })
+        .start()
+        .unwrap();
+
+    let res = minreq::post(format!("{}/admin/create-bucket", master.url()))
+        .with_body("name=logs&replicas=2")
+        .send();
+    assert_eq!(res.unwrap().status_code, 200);
+
+    let res = minreq::put(format!("{}/bucket/logs/key", master.url()))
+        .with_body("value")
+        .send();
+    assert_eq!(res.unwrap().status_code, 201);
+
+    // flip the value on disk of the scrubbing volume
+    let hash = format!("{:x}", md5::compute(b"logs/key"));
+    let path = data_dir
+        .join(&hash[0..1])
+        .join(&hash[1..2])
+        .join(&hash[2..]);
+    write(&path, "valve").unwrap();
+
+    thread::sleep(Duration::from_millis(3000));
+
+    let quarantined: Vec<_> = read_dir(data_dir.join(QUARANTINE_DIR))
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect();
+    assert!(quarantined.contains(&hash));
+
+    // master restored the value from the other replica
+    let res = minreq::get(format!("{}/bucket/logs/key", scrubbed.url()))
+        .send()
+        .unwrap();
+    assert_eq!(res.status_code, 200);
+    assert_eq!(res.body, "value");
+}
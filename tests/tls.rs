@@ -0,0 +1,194 @@
+use openssl::asn1::Asn1Time;
+use openssl::bn::BigNum;
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::rsa::Rsa;
+use openssl::x509::extension::{BasicConstraints, SubjectAlternativeName};
+use openssl::x509::{X509NameBuilder, X509};
+use tempfile::{tempdir, TempDir};
+
+use kalavara::cluster::ClusterConfig;
+use kalavara::http::{request, Response};
+use kalavara::master::MasterBuilder;
+use kalavara::server::ServerHandle;
+use kalavara::tls::TlsConfig;
+use kalavara::volume::VolumeBuilder;
+
+use std::fs::write;
+use std::path::Path;
+
+/// generates a self-signed certificate for localhost
+fn self_signed(dir: &Path) -> TlsConfig {
+    let pkey = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+
+    let mut name = X509NameBuilder::new().unwrap();
+    name.append_entry_by_text("CN", "localhost").unwrap();
+    let name = name.build();
+
+    let mut builder = X509::builder().unwrap();
+    builder.set_version(2).unwrap();
+    let serial = BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap();
+    builder.set_serial_number(&serial).unwrap();
+    builder.set_subject_name(&name).unwrap();
+    builder.set_issuer_name(&name).unwrap();
+    builder.set_pubkey(&pkey).unwrap();
+    builder
+        .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+        .unwrap();
+    builder
+        .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+        .unwrap();
+    builder
+        .append_extension(BasicConstraints::new().critical().ca().build().unwrap())
+        .unwrap();
+    let san = SubjectAlternativeName::new()
+        .dns("localhost")
+        .build(&builder.x509v3_context(None, None))
+        .unwrap();
+    builder.append_extension(san).unwrap();
+    builder.sign(&pkey, MessageDigest::sha256()).unwrap();
+
+    let cert_path = dir.join("cert.pem");
+    let key_path = dir.join("key.pem");
+    write(&cert_path, builder.build().to_pem().unwrap()).unwrap();
+    write(&key_path, pkey.private_key_to_pem_pkcs8().unwrap()).unwrap();
+
+    let cert = cert_path.to_str().unwrap().to_owned();
+
+    TlsConfig {
+        cert: Some(cert.clone()),
+        key: Some(key_path.to_str().unwrap().to_owned()),
+        ca_file: Some(cert),
+    }
+}
+
+/// master and volume sharing a certificate, shut down when dropped
+struct Cluster {
+    master: ServerHandle,
+    _volume: ServerHandle,
+    tls: TlsConfig,
+    _dirs: (TempDir, TempDir, TempDir),
+}
+
+fn run() -> Cluster {
+    let cert_dir = tempdir().unwrap();
+    let master_data_dir = tempdir().unwrap();
+    let volume_data_dir = tempdir().unwrap();
+    let tls = self_signed(cert_dir.path());
+
+    let master = MasterBuilder::new(master_data_dir.path().to_str().unwrap())
+        .port(0)
+        .threads(4)
+        .tls(tls.clone())
+        .start()
+        .unwrap();
+
+    let volume = VolumeBuilder::new(volume_data_dir.path().to_str().unwrap())
+        .port(0)
+        .threads(4)
+        .master(&master.url())
+        .tls(tls.clone())
+        .start()
+        .unwrap();
+
+    Cluster {
+        master,
+        _volume: volume,
+        tls,
+        _dirs: (cert_dir, master_data_dir, volume_data_dir),
+    }
+}
+
+/// sends request to master and follows the redirect to volume server
+fn send(cluster: &Cluster, method: &str, key: &str, body: &[u8]) -> Response {
+    let tls = &cluster.tls;
+    let url = format!("{}/store/{}", cluster.master.url(), key);
+
+    let res = request(method, &url, &[], body, tls).unwrap();
+    match res.header("Location") {
+        Some(location) if res.status_code == 307 => {
+            request(method, location, &[], body, tls).unwrap()
+        }
+        _ => res,
+    }
+}
+
+#[test]
+fn test_tls_kv() {
+    let cluster = run();
+
+    let res = send(&cluster, "PUT", "key1", b"val1");
+    assert_eq!(res.status_code, 201);
+
+    let res = send(&cluster, "GET", "key1", b"");
+    assert_eq!(res.status_code, 200);
+    assert_eq!(res.text(), "val1");
+
+    let res = send(&cluster, "DELETE", "key1", b"");
+    assert_eq!(res.status_code, 204);
+
+    let res = send(&cluster, "GET", "key1", b"");
+    assert_eq!(res.status_code, 404);
+}
+
+#[test]
+fn test_tls_untrusted() {
+    let cluster = run();
+
+    // certificate is self-signed, not trusted without the CA bundle
+    let res = request(
+        "GET",
+        &format!("{}/store/key1", cluster.master.url()),
+        &[],
+        b"",
+        &TlsConfig::default(),
+    );
+    assert!(res.is_err());
+
+    // plain http is not served
+    let url = format!("http://localhost:{}/store/key1", cluster.master.port());
+    let res = minreq::get(url).send();
+    assert!(res.is_err());
+}
+
+#[test]
+fn test_tls_client_certificate() {
+    let cert_dir = tempdir().unwrap();
+    let master_data_dir = tempdir().unwrap();
+    let volume_data_dir = tempdir().unwrap();
+    let tls = self_signed(cert_dir.path());
+
+    let master = MasterBuilder::new(master_data_dir.path().to_str().unwrap())
+        .port(0)
+        .threads(4)
+        .cluster(ClusterConfig {
+            id: None,
+            join_token: Some("secret".to_owned()),
+        })
+        .tls(tls.clone())
+        .start()
+        .unwrap();
+
+    // volumes without the token register by their certificate
+    let volume = VolumeBuilder::new(volume_data_dir.path().to_str().unwrap())
+        .port(0)
+        .threads(4)
+        .master(&master.url())
+        .tls(tls.clone())
+        .start();
+    assert!(volume.is_ok());
+
+    // and are refused without one
+    let plain = TlsConfig {
+        cert: None,
+        key: None,
+        ca_file: tls.ca_file.clone(),
+    };
+    let volume = VolumeBuilder::new(volume_data_dir.path().to_str().unwrap())
+        .port(0)
+        .threads(4)
+        .master(&master.url())
+        .tls(plain)
+        .start();
+    assert!(volume.is_err());
+}
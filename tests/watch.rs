@@ -0,0 +1,173 @@
+use tempfile::{tempdir, TempDir};
+
+use kalavara::changes::Op;
+use kalavara::client::{Client, Error};
+use kalavara::http;
+use kalavara::master::MasterBuilder;
+use kalavara::server::ServerHandle;
+use kalavara::tls::TlsConfig;
+use kalavara::volume::VolumeBuilder;
+
+use std::io::{BufRead, BufReader};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// master with a volume, both shut down when dropped
+struct Cluster {
+    master: ServerHandle,
+    _volume: ServerHandle,
+    _dirs: (TempDir, TempDir),
+}
+
+/// starts a master and a volume at free ports
+fn run() -> Cluster {
+    let master_data_dir = tempdir().unwrap();
+    let volume_data_dir = tempdir().unwrap();
+
+    let volume = VolumeBuilder::new(volume_data_dir.path().to_str().unwrap())
+        .port(0)
+        .threads(4)
+        .start()
+        .unwrap();
+
+    let master = MasterBuilder::new(master_data_dir.path().to_str().unwrap())
+        .port(0)
+        .threads(4)
+        .volumes(vec![volume.url()])
+        .start()
+        .unwrap();
+
+    Cluster {
+        master,
+        _volume: volume,
+        _dirs: (master_data_dir, volume_data_dir),
+    }
+}
+
+#[test]
+fn test_watch_long_poll() {
+    let cluster = run();
+    let client = Client::new(&cluster.master.url());
+
+    client.put_bytes("logs/1", b"first").unwrap();
+    client.put_bytes("other", b"other").unwrap();
+    client.delete("logs/1").unwrap();
+
+    let (changes, seq) = client
+        .watch("logs/", Some(0), Duration::from_secs(1))
+        .unwrap();
+    assert_eq!(seq, 3);
+    assert_eq!(changes.len(), 2);
+    assert_eq!((changes[0].seq, changes[0].op), (1, Op::Put));
+    assert_eq!((changes[1].seq, changes[1].op), (3, Op::Delete));
+    assert_eq!(changes[1].key, "logs/1");
+
+    // watches wait for the next change
+    let writer = Client::new(&cluster.master.url());
+    let put = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(300));
+        writer.put_bytes("logs/2", b"second").unwrap();
+    });
+
+    let started = Instant::now();
+    let (changes, seq) = client
+        .watch("logs/", Some(seq), Duration::from_secs(10))
+        .unwrap();
+    assert!(started.elapsed() < Duration::from_secs(5));
+    assert_eq!(seq, 4);
+    assert_eq!(changes[0].key, "logs/2");
+    put.join().unwrap();
+
+    // watches without since start from now
+    let (changes, seq) = client.watch("", None, Duration::from_secs(0)).unwrap();
+    assert!(changes.is_empty());
+    assert_eq!(seq, 4);
+
+    assert!(match client.watch("", Some(10), Duration::from_secs(0)) {
+        Err(Error::Status(410, _)) => true,
+        _ => false,
+    });
+}
+
+#[test]
+fn test_watch_keeps_workers_free() {
+    let cluster = run();
+
+    // more watches than master has workers
+    let watches: Vec<_> = (0..8)
+        .map(|_| {
+            let client = Client::new(&cluster.master.url());
+            thread::spawn(move || client.watch("logs/", Some(0), Duration::from_secs(10)))
+        })
+        .collect();
+    thread::sleep(Duration::from_millis(300));
+
+    let started = Instant::now();
+    let client = Client::new(&cluster.master.url());
+    client.put_bytes("logs/1", b"first").unwrap();
+    assert_eq!(client.get("logs/1").unwrap().bytes().unwrap(), b"first");
+    assert!(started.elapsed() < Duration::from_secs(5));
+
+    for watch in watches {
+        let (changes, seq) = watch.join().unwrap().unwrap();
+        assert_eq!(seq, 1);
+        assert_eq!(changes[0].key, "logs/1");
+    }
+}
+
+#[test]
+fn test_watch_event_stream() {
+    let cluster = run();
+    let client = Client::new(&cluster.master.url());
+
+    client.put_bytes("logs/1", b"first").unwrap();
+    client.put_bytes("logs/2", b"second").unwrap();
+
+    // reconnecting clients resume after their last event
+    let url = format!("{}/watch?prefix=logs/", cluster.master.url());
+    let (resp, body) = http::open(
+        "GET",
+        &url,
+        &[("Accept", "text/event-stream"), ("Last-Event-ID", "1")],
+        &TlsConfig::default(),
+    )
+    .unwrap();
+    assert_eq!(resp.status_code, 200);
+
+    let mut reader = BufReader::new(body);
+    let mut event = || {
+        let mut lines = Vec::new();
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            if line.trim_end().is_empty() {
+                return lines;
+            }
+            lines.push(line.trim_end().to_owned());
+        }
+    };
+
+    assert_eq!(
+        event(),
+        vec![
+            "id: 2",
+            "event: put",
+            "data: seq=2&op=put&bucket=&size=6&key=logs/2"
+        ]
+    );
+
+    client.delete("logs/2").unwrap();
+    assert_eq!(
+        event(),
+        vec![
+            "id: 3",
+            "event: delete",
+            "data: seq=3&op=delete&bucket=&size=6&key=logs/2"
+        ]
+    );
+
+    // open streams do not keep master from shutting down
+    let started = Instant::now();
+    cluster.master.shutdown();
+    assert!(started.elapsed() < Duration::from_secs(5));
+}
@@ -0,0 +1,193 @@
+use tempfile::{tempdir, TempDir};
+use tiny_http::{Response, Server};
+
+use kalavara::client::Client;
+use kalavara::master::MasterBuilder;
+use kalavara::server::ServerHandle;
+use kalavara::volume::VolumeBuilder;
+use kalavara::webhook::{signature, DeadLetter, DELIVERY_HEADER, EVENT_HEADER, SIGNATURE_HEADER};
+
+use std::io::Read;
+use std::sync::mpsc::{channel, Receiver};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// master with a volume, both shut down when dropped
+struct Cluster {
+    master: ServerHandle,
+    _volume: ServerHandle,
+    _dirs: (TempDir, TempDir),
+}
+
+impl Cluster {
+    /// sends a request to an admin endpoint of master
+    fn admin(&self, method: &str, path: &str, body: &str) -> String {
+        let mut text = String::new();
+        Client::new(&self.master.url())
+            .admin(method, path, body.as_bytes())
+            .unwrap()
+            .read_to_string(&mut text)
+            .unwrap();
+        text
+    }
+}
+
+/// starts a master and a volume at free ports
+fn run() -> Cluster {
+    let master_data_dir = tempdir().unwrap();
+    let volume_data_dir = tempdir().unwrap();
+
+    let volume = VolumeBuilder::new(volume_data_dir.path().to_str().unwrap())
+        .port(0)
+        .threads(4)
+        .start()
+        .unwrap();
+
+    let master = MasterBuilder::new(master_data_dir.path().to_str().unwrap())
+        .port(0)
+        .threads(4)
+        .volumes(vec![volume.url()])
+        .start()
+        .unwrap();
+
+    Cluster {
+        master,
+        _volume: volume,
+        _dirs: (master_data_dir, volume_data_dir),
+    }
+}
+
+/// delivery received by a listener
+struct Delivery {
+    event: String,
+    id: String,
+    signature: String,
+    body: String,
+}
+
+/// listens at a free port and answers deliveries with `statuses` in turn,
+/// 200 once they run out. returns the url and the deliveries received
+fn listen(statuses: Vec<u16>) -> (String, Receiver<Delivery>) {
+    let server = Server::http("127.0.0.1:0").unwrap();
+    let url = format!("http://127.0.0.1:{}/hook", server.server_addr().port());
+    let (sender, receiver) = channel();
+
+    thread::spawn(move || {
+        let mut statuses = statuses.into_iter();
+
+        for mut req in server.incoming_requests() {
+            let header = |name: &'static str| {
+                req.headers()
+                    .iter()
+                    .find(|header| header.field.equiv(name))
+                    .map(|header| header.value.as_str().to_owned())
+                    .unwrap_or_default()
+            };
+
+            let mut delivery = Delivery {
+                event: header(EVENT_HEADER),
+                id: header(DELIVERY_HEADER),
+                signature: header(SIGNATURE_HEADER),
+                body: String::new(),
+            };
+            req.as_reader().read_to_string(&mut delivery.body).unwrap();
+
+            let status = statuses.next().unwrap_or(200);
+            let _ = req.respond(Response::from_string("").with_status_code(status));
+
+            if sender.send(delivery).is_err() {
+                return;
+            }
+        }
+    });
+
+    (url, receiver)
+}
+
+#[test]
+fn test_webhook_delivery() {
+    let cluster = run();
+    let client = Client::new(&cluster.master.url());
+
+    // the first delivery fails and is retried
+    let (url, deliveries) = listen(vec![500]);
+    let settings = format!(
+        "name=uploads&prefix=in/&events=put&secret=s3cr3t&backoff=1&url={}",
+        url
+    );
+    assert_eq!(
+        cluster.admin("POST", "create-webhook", &settings),
+        "Webhook created"
+    );
+
+    client.put_bytes("other", b"other").unwrap();
+    client.put_bytes("in/a", b"value").unwrap();
+    client.delete("in/a").unwrap();
+
+    let timeout = Duration::from_secs(10);
+    let first = deliveries.recv_timeout(timeout).unwrap();
+    let started = Instant::now();
+    let retry = deliveries.recv_timeout(timeout).unwrap();
+    assert!(started.elapsed() >= Duration::from_millis(500));
+
+    assert_eq!(first.id, "uploads/2");
+    assert_eq!(retry.id, first.id);
+    assert_eq!(retry.event, "put");
+    assert_eq!(retry.body, "seq=2&op=put&bucket=&size=5&key=in/a");
+    assert_eq!(retry.signature, signature("s3cr3t", retry.body.as_bytes()));
+
+    // deletes are not subscribed to
+    assert!(deliveries.recv_timeout(Duration::from_millis(500)).is_err());
+
+    let list = cluster.admin("GET", "webhooks", "");
+    assert!(list.contains("name=uploads&") && list.contains("secret=*&"));
+}
+
+#[test]
+fn test_webhook_dead_letters() {
+    let cluster = run();
+    let client = Client::new(&cluster.master.url());
+
+    // every attempt fails
+    let (url, deliveries) = listen(vec![503, 503]);
+    let settings = format!(
+        "name=pipeline&secret=s3cr3t&attempts=2&backoff=1&url={}",
+        url
+    );
+    cluster.admin("POST", "create-webhook", &settings);
+
+    client.put_bytes("key", b"value").unwrap();
+
+    let timeout = Duration::from_secs(10);
+    deliveries.recv_timeout(timeout).unwrap();
+    deliveries.recv_timeout(timeout).unwrap();
+
+    let started = Instant::now();
+    let letters = loop {
+        let letters = cluster.admin("GET", "dead-letters", "");
+        if !letters.is_empty() || started.elapsed() > timeout {
+            break letters;
+        }
+        thread::sleep(Duration::from_millis(100));
+    };
+
+    let letter = DeadLetter::parse(&letters).unwrap();
+    assert_eq!(letter.webhook, "pipeline");
+    assert_eq!(letter.attempts, 2);
+    assert_eq!(letter.error, "status 503");
+    assert_eq!(letter.change.key, "key");
+
+    // listener answers 200 by now
+    assert_eq!(
+        cluster.admin("POST", "retry-dead-letters", "pipeline"),
+        "delivered=1&failed=0"
+    );
+    assert_eq!(deliveries.recv_timeout(timeout).unwrap().id, "pipeline/1");
+    assert_eq!(cluster.admin("GET", "dead-letters", ""), "");
+
+    assert_eq!(
+        cluster.admin("POST", "delete-webhook", "pipeline"),
+        "Webhook deleted"
+    );
+    assert_eq!(cluster.admin("GET", "webhooks", ""), "");
+}